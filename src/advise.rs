@@ -0,0 +1,105 @@
+use libc::{self, c_int};
+use std::{
+    io::{self, Read, Seek},
+    os::unix::io::AsRawFd,
+};
+
+/// how far behind the read position pages are dropped from the page cache
+pub const DROP_BEHIND: u64 = 8 * 1024 * 1024;
+
+/// a file origin which tells the kernel it's being read once, front to back
+///
+/// on creation the file is advised as sequential, so readahead is more
+/// aggressive. as reading progresses, the pages already read are advised as
+/// no longer needed, so splicing a huge file doesn't evict the rest of the
+/// page cache. the advice is only a hint: failures after creation are ignored.
+pub struct Sequential<F> {
+    file: F,
+    position: u64,
+    dropped: u64,
+    drop_behind: u64,
+}
+
+impl<F: Read + Seek + AsRawFd> Sequential<F> {
+    /// advise the file as read sequentially from its current position
+    pub fn new(mut file: F) -> io::Result<Sequential<F>> {
+        let position = file.stream_position()?;
+        advise(&file, position, 0, libc::POSIX_FADV_SEQUENTIAL)?;
+        Ok(Sequential {
+            file,
+            position,
+            dropped: position,
+            drop_behind: DROP_BEHIND,
+        })
+    }
+
+    /// drop pages from the cache once this many bytes have been read past them
+    ///
+    /// 0 keeps everything cached
+    pub fn drop_behind(mut self, bytes: u64) -> Self {
+        self.drop_behind = bytes;
+        self
+    }
+
+    /// recover the file
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+}
+
+impl<F: Read + AsRawFd> Read for Sequential<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.file.read(buf)?;
+        self.position += bytes_read as u64;
+        let behind = self.position - self.dropped;
+        if self.drop_behind > 0 && (behind >= self.drop_behind || bytes_read == 0) && behind > 0 {
+            let _ = advise(&self.file, self.dropped, behind, libc::POSIX_FADV_DONTNEED);
+            self.dropped = self.position;
+        }
+        Ok(bytes_read)
+    }
+}
+
+fn advise<F: AsRawFd>(file: &F, offset: u64, len: u64, advice: c_int) -> io::Result<()> {
+    match unsafe {
+        libc::posix_fadvise(
+            file.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            advice,
+        )
+    } {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{self, File},
+        io::{Cursor, SeekFrom},
+    };
+    use Inserter;
+
+    #[test]
+    fn reads_through() {
+        let path = ::std::env::temp_dir().join(format!("advise-{}", ::std::process::id()));
+        let origin: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        fs::write(&path, &origin).unwrap();
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        let mut dest = Vec::new();
+        Inserter::new(
+            Sequential::new(file).unwrap().drop_behind(4096),
+            Cursor::new(&mut dest),
+        )
+        .insert(0, "head")
+        .execute()
+        .unwrap();
+        assert_eq!(b"head", &dest[..4]);
+        assert_eq!(&origin[1000..], &dest[4..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+use cdc::{Chunker, Cutter};
+#[cfg(feature = "regex")]
+use regex::bytes::Regex;
+use scan::Finder;
+use std::{
+    io::{self, Read},
+    ops::Range,
+};
+
+use inserter::BUFFER_SIZE;
+
+/// a position in the origin described by its content rather than its offset
+#[derive(Debug, Clone)]
+pub enum Anchor {
+    /// this origin index
+    Offset(usize),
+    /// the first multiple of this alignment at or after this origin index
+    Aligned(usize, usize),
+    /// the start of the first occurrence of these bytes
+    Before(Vec<u8>),
+    /// just past the first occurrence of these bytes
+    After(Vec<u8>),
+    /// the start of this line, counting from 1
+    BeforeLine(usize),
+    /// just past the newline ending this line, counting from 1
+    AfterLine(usize),
+    /// the start of the final line, ignoring the empty one after a trailing
+    /// newline
+    BeforeLastLine,
+    /// just past the end of the final line, which is the end of the origin
+    AfterLastLine,
+    /// just past this occurrence of the delimiter byte, counting from 1
+    AfterDelimiter(u8, usize),
+    /// the start of the first match of this regex within a line
+    #[cfg(feature = "regex")]
+    BeforeRegex(Regex),
+    /// just past the first match of this regex within a line
+    #[cfg(feature = "regex")]
+    AfterRegex(Regex),
+    /// the first boundary between content-defined chunks at or after this
+    /// origin index, where the start and end of the origin are boundaries
+    ChunkBoundary(usize, Chunker),
+}
+
+impl PartialEq for Anchor {
+    fn eq(&self, other: &Anchor) -> bool {
+        use self::Anchor::*;
+        match (self, other) {
+            (Offset(a), Offset(b))
+            | (BeforeLine(a), BeforeLine(b))
+            | (AfterLine(a), AfterLine(b)) => a == b,
+            (Aligned(a, c), Aligned(b, d)) => a == b && c == d,
+            (AfterDelimiter(a, c), AfterDelimiter(b, d)) => a == b && c == d,
+            (Before(a), Before(b)) | (After(a), After(b)) => a == b,
+            (BeforeLastLine, BeforeLastLine) | (AfterLastLine, AfterLastLine) => true,
+            (ChunkBoundary(a, c), ChunkBoundary(b, d)) => a == b && c == d,
+            #[cfg(feature = "regex")]
+            (BeforeRegex(a), BeforeRegex(b)) | (AfterRegex(a), AfterRegex(b)) => {
+                a.as_str() == b.as_str()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// find the origin index of each anchor in a single pass over the origin
+///
+/// the results are in the same order as the anchors. regex anchors match
+/// within a single line, excluding its newline, so only the current line is
+/// held in memory. the origin isn't read at all if every anchor is an offset
+/// or aligned. fails with `NotFound` if any anchor doesn't occur, and with
+/// `InvalidInput` if an alignment is zero or overflows.
+pub fn resolve<R: Read>(origin: R, anchors: &[Anchor]) -> io::Result<Vec<usize>> {
+    Ok(locate(origin, anchors)?
+        .into_iter()
+        .zip(anchors)
+        .map(|(range, anchor)| anchor.position(range))
+        .collect())
+}
+
+/// find the origin range each anchor matched, as `resolve` does
+///
+/// the range of a pattern or regex anchor is the matched text; that of an
+/// offset or line anchor is empty, at its position
+pub fn locate<R: Read>(mut origin: R, anchors: &[Anchor]) -> io::Result<Vec<Range<usize>>> {
+    let mut positions = anchors
+        .iter()
+        .map(Anchor::fixed)
+        .collect::<io::Result<Vec<_>>>()?;
+    if positions.iter().all(Option::is_some) {
+        return Ok(positions.into_iter().flatten().collect());
+    }
+    let mut finders: Vec<Option<Finder>> = anchors
+        .iter()
+        .map(|anchor| match anchor {
+            Anchor::Before(needle) | Anchor::After(needle) if !needle.is_empty() => {
+                Some(Finder::new(needle))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut cutters: Vec<Option<Cutter>> = anchors
+        .iter()
+        .map(|anchor| match *anchor {
+            Anchor::ChunkBoundary(_, ref chunker) => Some(Cutter::new(chunker)),
+            _ => None,
+        })
+        .collect();
+    let mut counts = vec![0; anchors.len()];
+    let buffer_lines = anchors.iter().any(Anchor::is_regex);
+
+    let mut offset = 0;
+    let mut line_number = 1;
+    let mut line_start = 0;
+    let mut previous_start = 0;
+    let mut line = Vec::new();
+    let mut buffer = [0_u8; BUFFER_SIZE];
+    loop {
+        let n = match origin.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &byte in &buffer[..n] {
+            offset += 1;
+            for ((anchor, position), finder) in anchors
+                .iter()
+                .zip(positions.iter_mut())
+                .zip(finders.iter_mut())
+            {
+                if let Some(ref mut finder) = *finder {
+                    if position.is_none() && finder.feed(byte) {
+                        *position = Some(match *anchor {
+                            Anchor::Before(ref needle) | Anchor::After(ref needle) => {
+                                offset - needle.len()..offset
+                            }
+                            _ => unreachable!("only patterns have finders"),
+                        });
+                    }
+                }
+            }
+            for ((anchor, position), count) in anchors
+                .iter()
+                .zip(positions.iter_mut())
+                .zip(counts.iter_mut())
+            {
+                match *anchor {
+                    Anchor::AfterDelimiter(delimiter, n)
+                        if position.is_none() && byte == delimiter =>
+                    {
+                        *count += 1;
+                        if *count == n {
+                            *position = Some(offset - 1..offset);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            for ((anchor, position), cutter) in anchors
+                .iter()
+                .zip(positions.iter_mut())
+                .zip(cutters.iter_mut())
+            {
+                if let Some(ref mut cutter) = *cutter {
+                    if position.is_none() && cutter.feed(byte) {
+                        match *anchor {
+                            Anchor::ChunkBoundary(after, _) if offset >= after => {
+                                *position = Some(offset..offset)
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            if byte == b'\n' {
+                #[cfg(feature = "regex")]
+                match_line(anchors, &mut positions, line_start, &line);
+                end_line(
+                    anchors,
+                    &mut positions,
+                    line_number,
+                    line_start,
+                    Some(offset),
+                );
+                line.clear();
+                line_number += 1;
+                previous_start = line_start;
+                line_start = offset;
+            } else if buffer_lines {
+                line.push(byte);
+            }
+        }
+    }
+    // the final line, which may be empty or lack a newline
+    #[cfg(feature = "regex")]
+    match_line(anchors, &mut positions, line_start, &line);
+    let end = if offset > line_start {
+        Some(offset)
+    } else {
+        None
+    };
+    end_line(anchors, &mut positions, line_number, line_start, end);
+    let last_start = if end.is_some() {
+        line_start
+    } else {
+        previous_start
+    };
+
+    positions
+        .into_iter()
+        .zip(anchors)
+        .map(|(position, anchor)| match (position, anchor) {
+            (Some(position), _) => Ok(position),
+            (None, &Anchor::ChunkBoundary(after, _)) if after <= offset => Ok(offset..offset),
+            (None, &Anchor::BeforeLastLine) => Ok(last_start..last_start),
+            (None, &Anchor::AfterLastLine) => Ok(offset..offset),
+            (None, &Anchor::Before(ref needle)) | (None, &Anchor::After(ref needle))
+                if needle.is_empty() =>
+            {
+                Ok(0..0)
+            }
+            (None, anchor) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("anchor not found: {}", anchor.describe()),
+            )),
+        })
+        .collect()
+}
+
+/// resolve the line anchors for a line which has just ended
+fn end_line(
+    anchors: &[Anchor],
+    positions: &mut [Option<Range<usize>>],
+    number: usize,
+    start: usize,
+    end: Option<usize>,
+) {
+    for (anchor, position) in anchors.iter().zip(positions.iter_mut()) {
+        if position.is_none() {
+            *position = match *anchor {
+                Anchor::BeforeLine(n) if n == number => Some(start..start),
+                Anchor::AfterLine(n) if n == number => end.map(|end| end..end),
+                _ => None,
+            };
+        }
+    }
+}
+
+/// resolve the regex anchors matching within a line, ignoring a trailing CR
+#[cfg(feature = "regex")]
+fn match_line(
+    anchors: &[Anchor],
+    positions: &mut [Option<Range<usize>>],
+    start: usize,
+    line: &[u8],
+) {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    for (anchor, position) in anchors.iter().zip(positions.iter_mut()) {
+        if position.is_none() {
+            *position = match *anchor {
+                Anchor::BeforeRegex(ref re) | Anchor::AfterRegex(ref re) => {
+                    re.find(line).map(|m| start + m.start()..start + m.end())
+                }
+                _ => None,
+            };
+        }
+    }
+}
+
+impl Anchor {
+    /// the position of an anchor which doesn't depend on the origin's content
+    fn fixed(&self) -> io::Result<Option<Range<usize>>> {
+        let position = match *self {
+            Anchor::Offset(offset) => offset,
+            Anchor::Aligned(after, alignment) => {
+                after.checked_next_multiple_of(alignment).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no multiple of {} at or after {}", alignment, after),
+                    )
+                })?
+            }
+            Anchor::ChunkBoundary(0, _) | Anchor::AfterDelimiter(_, 0) => 0,
+            _ => return Ok(None),
+        };
+        Ok(Some(position..position))
+    }
+
+    /// the position of this anchor given the range it matched
+    pub(crate) fn position(&self, matched: Range<usize>) -> usize {
+        match *self {
+            Anchor::Before(_) => matched.start,
+            #[cfg(feature = "regex")]
+            Anchor::BeforeRegex(_) => matched.start,
+            _ => matched.end,
+        }
+    }
+
+    fn is_regex(&self) -> bool {
+        match *self {
+            #[cfg(feature = "regex")]
+            Anchor::BeforeRegex(_) | Anchor::AfterRegex(_) => true,
+            _ => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match *self {
+            Anchor::Offset(offset) => format!("offset {}", offset),
+            Anchor::Aligned(after, alignment) => {
+                format!("multiple of {} after {}", alignment, after)
+            }
+            Anchor::Before(ref needle) => format!("before {:?}", String::from_utf8_lossy(needle)),
+            Anchor::After(ref needle) => format!("after {:?}", String::from_utf8_lossy(needle)),
+            Anchor::BeforeLine(n) => format!("before line {}", n),
+            Anchor::AfterLine(n) => format!("after line {}", n),
+            Anchor::BeforeLastLine => "before the last line".into(),
+            Anchor::AfterLastLine => "after the last line".into(),
+            Anchor::AfterDelimiter(delimiter, n) => {
+                format!("after delimiter {:#04x} number {}", delimiter, n)
+            }
+            Anchor::ChunkBoundary(after, _) => format!("chunk boundary after {}", after),
+            #[cfg(feature = "regex")]
+            Anchor::BeforeRegex(ref re) => format!("before regex {:?}", re.as_str()),
+            #[cfg(feature = "regex")]
+            Anchor::AfterRegex(ref re) => format!("after regex {:?}", re.as_str()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"first line\nsecond marker line\nthird";
+
+    #[test]
+    fn resolves_patterns() {
+        let anchors = [
+            Anchor::Before(b"marker".to_vec()),
+            Anchor::After(b"marker".to_vec()),
+            Anchor::After(b"third".to_vec()),
+            Anchor::Offset(3),
+        ];
+        assert_eq!(vec![18, 24, 35, 3], resolve(TEXT, &anchors).unwrap());
+    }
+
+    #[test]
+    fn resolves_lines() {
+        let anchors = [
+            Anchor::BeforeLine(1),
+            Anchor::AfterLine(1),
+            Anchor::BeforeLine(3),
+            Anchor::AfterLine(3),
+        ];
+        assert_eq!(vec![0, 11, 30, 35], resolve(TEXT, &anchors).unwrap());
+        let err = resolve(TEXT, &[Anchor::AfterLine(4)]).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+        let last = [Anchor::BeforeLastLine, Anchor::AfterLastLine];
+        assert_eq!(vec![30, 35], resolve(TEXT, &last).unwrap());
+        assert_eq!(vec![2, 4], resolve(&b"a\nb\n"[..], &last).unwrap());
+        assert_eq!(vec![0, 0], resolve(&b""[..], &last).unwrap());
+    }
+
+    #[test]
+    fn resolves_alignments() {
+        let anchors = [
+            Anchor::Aligned(0, 512),
+            Anchor::Aligned(1, 512),
+            Anchor::Aligned(1024, 512),
+            Anchor::Aligned(3, 4),
+        ];
+        assert_eq!(vec![0, 512, 1024, 4], resolve(TEXT, &anchors).unwrap());
+        let anchors = [Anchor::Aligned(5, 4), Anchor::After(b"line".to_vec())];
+        assert_eq!(vec![8, 10], resolve(TEXT, &anchors).unwrap());
+        for anchor in [Anchor::Aligned(3, 0), Anchor::Aligned(usize::MAX, 2)] {
+            let err = resolve(TEXT, &[anchor]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn counts_delimiters() {
+        let records = b"a\0bb\0ccc\0dddd";
+        let anchors = [
+            Anchor::AfterDelimiter(0, 1),
+            Anchor::AfterDelimiter(0, 3),
+            Anchor::AfterDelimiter(b'c', 2),
+            Anchor::AfterDelimiter(0, 0),
+        ];
+        assert_eq!(vec![2, 9, 7, 0], resolve(&records[..], &anchors).unwrap());
+        assert_eq!(vec![1..2], locate(&records[..], &anchors[..1]).unwrap());
+        let err = resolve(&records[..], &[Anchor::AfterDelimiter(0, 4)]).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn missing_pattern() {
+        let err = resolve(TEXT, &[Anchor::Before(b"absent".to_vec())]).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().contains("absent"));
+    }
+
+    #[test]
+    fn locates_matched_text() {
+        let anchors = [
+            Anchor::After(b"marker ".to_vec()),
+            Anchor::BeforeLine(2),
+            Anchor::Offset(3),
+        ];
+        assert_eq!(vec![18..25, 11..11, 3..3], locate(TEXT, &anchors).unwrap());
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn resolves_regexes() {
+        let anchors = [
+            Anchor::BeforeRegex(Regex::new("m[a-z]+").unwrap()),
+            Anchor::AfterRegex(Regex::new("^th").unwrap()),
+            Anchor::AfterRegex(Regex::new("line$").unwrap()),
+        ];
+        assert_eq!(vec![18, 32, 10], resolve(TEXT, &anchors).unwrap());
+    }
+
+    #[test]
+    fn resolves_chunk_boundaries() {
+        let data: Vec<u8> = (0..1_u32 << 16)
+            .map(|idx| (idx.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let chunker = Chunker::new(512);
+        let boundaries = chunker.boundaries(data.as_slice()).unwrap();
+        let anchors = [
+            Anchor::ChunkBoundary(0, chunker),
+            Anchor::ChunkBoundary(boundaries[3], chunker),
+            Anchor::ChunkBoundary(boundaries[3] + 1, chunker),
+            Anchor::ChunkBoundary(data.len(), chunker),
+        ];
+        assert_eq!(
+            vec![0, boundaries[3], boundaries[4], data.len()],
+            resolve(data.as_slice(), &anchors).unwrap()
+        );
+        let past = [Anchor::ChunkBoundary(data.len() + 1, chunker)];
+        let err = resolve(data.as_slice(), &past).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
@@ -0,0 +1,95 @@
+/// the standard base64 alphabet
+pub(crate) const ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// the 6-bit value of a base64 character
+pub(crate) fn value(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// encode as standard base64, with padding
+#[cfg(feature = "minisign")]
+pub(crate) fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let acc = chunk
+            .iter()
+            .enumerate()
+            .fold(0_u32, |acc, (idx, &b)| acc | u32::from(b) << (16 - 8 * idx));
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.push(ALPHABET[(acc >> (18 - 6 * idx) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// decode standard base64, ignoring ascii whitespace
+///
+/// returns `None` on any invalid character or misplaced padding
+pub(crate) fn decode(text: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc = 0_u32;
+    let mut bits = 0;
+    let mut padding = 0;
+    for &byte in text.iter().filter(|b| !b.is_ascii_whitespace()) {
+        if byte == b'=' {
+            padding += 1;
+            continue;
+        }
+        if padding > 0 {
+            return None;
+        }
+        acc = (acc << 6) | u32::from(value(byte)?);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    if padding > 2 || bits >= 6 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_padding() {
+        assert_eq!(Some(b"fooba".to_vec()), decode(b"Zm9vYmE="));
+        assert_eq!(Some(b"foob".to_vec()), decode(b"Zm9vYg=="));
+        assert_eq!(Some(Vec::new()), decode(b""));
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        assert_eq!(None, decode(b"Zm9v!"));
+        assert_eq!(None, decode(b"Zg=a"));
+        assert_eq!(Some(b"foo".to_vec()), decode(b"Zm\n9v"));
+    }
+
+    #[test]
+    #[cfg(feature = "minisign")]
+    fn encodes_padding() {
+        assert_eq!("Zm9vYmE=", encode(b"fooba"));
+        assert_eq!("Zm9vYg==", encode(b"foob"));
+        assert_eq!("Zm9v", encode(b"foo"));
+        assert_eq!(
+            Some(b"foobar".to_vec()),
+            decode(encode(b"foobar").as_bytes())
+        );
+    }
+}
@@ -0,0 +1,1329 @@
+extern crate insert_multiple;
+extern crate regex;
+extern crate serde_json;
+
+use insert_multiple::{
+    checksum::Crc32,
+    comment::CommentStyle,
+    header::Header,
+    plan::{FinalLine, InsertionPlan, Position, Source},
+    Inserter, Operation,
+};
+use regex::bytes::Regex;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    convert::TryFrom,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+use util::temp_path;
+
+// shared with the library, which keeps it private
+#[allow(dead_code)]
+#[path = "../util.rs"]
+mod util;
+
+const USAGE: &str = "\
+usage: insert-multiple [POSITION FILE]... [--plan PLAN] INPUT [OUTPUT]
+       insert-multiple [POSITION FILE]... [--plan PLAN] (--in-place | --out-dir DIR) INPUT...
+
+insert the contents of each FILE into INPUT at the given POSITION, writing the
+result to OUTPUT. an INPUT or OUTPUT of `-`, or a missing OUTPUT, means stdin
+or stdout. with --in-place or --out-dir, any number of INPUTs may be given,
+and `*` and `?` wildcards in their file names are expanded.
+
+options:
+    --at OFFSET=FILE    insert FILE at OFFSET in the input; may be repeated.
+                        OFFSET may be hex (0x1F4), octal (0o764), binary
+                        (0b101) or decimal with a size suffix (4KiB, 1MB)
+    --before TEXT FILE  insert FILE before the first occurrence of TEXT
+    --after TEXT FILE   insert FILE after the first occurrence of TEXT
+    --before-line N FILE
+                        insert FILE at the start of line N, counting from 1
+    --after-line N FILE insert FILE after line N
+    --before-regex RE FILE
+                        insert FILE before the first match of RE in any line
+    --after-regex RE FILE
+                        insert FILE after the first match of RE in any line
+    -e, --expression EXPR
+                        apply a sed-style expression; may be repeated:
+                          Na\\TEXT     append a line of TEXT after line N,
+                                      or after the last line if N is `$`
+                          Ni\\TEXT     insert a line of TEXT before line N
+                          s/OLD/NEW/g replace every occurrence of the text
+                                      OLD with NEW; any delimiter may be used
+    --header FILE       insert FILE at the top of each input, after any byte
+                        order mark, shebang or encoding line, commented in
+                        the language of the input's file extension, unless
+                        the input already has it there
+    -p, --plan PLAN     also apply the insertions of a JSON InsertionPlan;
+                        relative file sources are relative to PLAN
+    -i, --in-place      replace each INPUT with the result
+    -o, --out-dir DIR   write each result to DIR under its INPUT's file name
+    -j, --jobs N        process up to N inputs at once
+    -n, --dry-run       report the planned insertions and the output size
+                        without writing anything
+    --json              report as JSON; without --dry-run, print statistics
+                        of the bytes copied, inserted and written, with the
+                        CRC-32 of the output, to stdout, or to stderr if the
+                        output goes to stdout
+    -h, --help          print this message";
+
+/// the parsed command line
+#[derive(Debug, PartialEq)]
+struct Args {
+    plan: InsertionPlan,
+    replacements: Vec<(String, String)>,
+    header: Option<PathBuf>,
+    plan_file: Option<PathBuf>,
+    in_place: bool,
+    out_dir: Option<PathBuf>,
+    jobs: usize,
+    dry_run: bool,
+    json: bool,
+    inputs: Vec<Option<PathBuf>>,
+    output: Option<PathBuf>,
+}
+
+impl Default for Args {
+    fn default() -> Args {
+        Args {
+            plan: InsertionPlan::new(),
+            replacements: Vec::new(),
+            header: None,
+            plan_file: None,
+            in_place: false,
+            out_dir: None,
+            jobs: 1,
+            dry_run: false,
+            json: false,
+            inputs: Vec::new(),
+            output: None,
+        }
+    }
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut paths = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = |what: &str| {
+                args.next()
+                    .ok_or_else(|| format!("{} requires {}", arg, what))
+            };
+            match arg.as_str() {
+                "--at" => {
+                    let (at, file) = parse_insertion(&value("OFFSET=FILE")?)?;
+                    parsed.plan = parsed.plan.insert(at, file);
+                }
+                "--before" | "--after" | "--before-line" | "--after-line" | "--before-regex"
+                | "--after-regex" => {
+                    let at = parse_position(&arg, &value("a value and a FILE")?)?;
+                    let file = Source::File(value("a value and a FILE")?.into());
+                    parsed.plan = parsed.plan.insert(at, file);
+                }
+                "-e" | "--expression" => match parse_expression(&value("EXPR")?)? {
+                    Expression::Insert(at, text) => {
+                        parsed.plan = parsed.plan.insert(at, Source::Text(text));
+                        // as in sed, text appended to the final line goes on
+                        // a line of its own
+                        parsed.plan.final_line = FinalLine::Terminate;
+                    }
+                    Expression::Replace(old, new) => parsed.replacements.push((old, new)),
+                },
+                "--header" => parsed.header = Some(value("FILE")?.into()),
+                "-p" | "--plan" => parsed.plan_file = Some(value("PLAN")?.into()),
+                "-i" | "--in-place" => parsed.in_place = true,
+                "-o" | "--out-dir" => parsed.out_dir = Some(value("DIR")?.into()),
+                "-j" | "--jobs" => {
+                    let jobs = value("N")?;
+                    parsed.jobs =
+                        jobs.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                            format!("{} `{}`: expected a positive number", arg, jobs)
+                        })?;
+                }
+                "-n" | "--dry-run" => parsed.dry_run = true,
+                "--json" => parsed.json = true,
+                "--" => paths.extend(args.by_ref()),
+                _ if arg.starts_with("--at=") => {
+                    let (at, file) = parse_insertion(&arg["--at=".len()..])?;
+                    parsed.plan = parsed.plan.insert(at, file);
+                }
+                _ if arg.starts_with('-') && arg != "-" => {
+                    return Err(format!("unknown option `{}`", arg));
+                }
+                _ => paths.push(arg),
+            }
+        }
+
+        let mut paths = paths.into_iter().map(|p| match p.as_str() {
+            "-" => None,
+            _ => Some(PathBuf::from(p)),
+        });
+        if parsed.in_place && parsed.out_dir.is_some() {
+            return Err("--in-place conflicts with --out-dir".to_string());
+        }
+        if parsed.in_place || parsed.out_dir.is_some() {
+            parsed.inputs = paths.collect();
+            if parsed.inputs.is_empty() {
+                return Err("missing INPUT".to_string());
+            }
+            if parsed.inputs.iter().any(Option::is_none) {
+                return Err("--in-place and --out-dir need INPUT files".to_string());
+            }
+        } else {
+            parsed.inputs.push(paths.next().ok_or("missing INPUT")?);
+            parsed.output = paths.next().and_then(|p| p);
+            if paths.next().is_some() {
+                return Err("too many arguments; use --in-place or --out-dir".to_string());
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_insertion(value: &str) -> Result<(Position, Source), String> {
+    let eq = value
+        .find('=')
+        .ok_or_else(|| format!("`{}`: expected OFFSET=FILE", value))?;
+    let offset = parse_offset(&value[..eq])
+        .map_err(|e| format!("`{}`: invalid offset `{}`: {}", value, &value[..eq], e))?;
+    Ok((
+        Position::Offset(offset),
+        Source::File(PathBuf::from(&value[eq + 1..])),
+    ))
+}
+
+fn parse_position(option: &str, value: &str) -> Result<Position, String> {
+    let line = || {
+        value
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("{} `{}`: expected a line number from 1", option, value))
+    };
+    let regex = || {
+        Regex::new(value)
+            .map(|_| value.to_string())
+            .map_err(|e| format!("{} `{}`: {}", option, value, e))
+    };
+    Ok(match option {
+        "--before" => Position::Before(value.to_string()),
+        "--after" => Position::After(value.to_string()),
+        "--before-line" => Position::BeforeLine(line()?),
+        "--after-line" => Position::AfterLine(line()?),
+        "--before-regex" => Position::BeforeRegex(regex()?),
+        _ => Position::AfterRegex(regex()?),
+    })
+}
+
+/// a sed-style expression
+#[derive(Debug, PartialEq)]
+enum Expression {
+    Insert(Position, String),
+    Replace(String, String),
+}
+
+/// parse an `a\`, `i\` or `s///g` expression
+fn parse_expression(expr: &str) -> Result<Expression, String> {
+    let invalid = |why: &str| format!("expression `{}`: {}", expr, why);
+    if let Some(rest) = expr.strip_prefix('s') {
+        let delimiter = rest
+            .chars()
+            .next()
+            .ok_or_else(|| invalid("missing `s` delimiter"))?;
+        let mut parts = vec![String::new()];
+        let mut chars = rest[delimiter.len_utf8()..].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(next) if next == delimiter => parts.last_mut().unwrap().push(next),
+                    Some(next) => {
+                        let part = parts.last_mut().unwrap();
+                        part.push('\\');
+                        part.push(next);
+                    }
+                    None => parts.last_mut().unwrap().push('\\'),
+                },
+                c if c == delimiter => parts.push(String::new()),
+                c => parts.last_mut().unwrap().push(c),
+            }
+        }
+        return match parts.as_slice() {
+            [old, _, flags] if old.is_empty() || flags != "g" => Err(invalid(
+                "expected s/OLD/NEW/g with non-empty OLD; every occurrence is replaced",
+            )),
+            [old, new, _] => Ok(Expression::Replace(old.clone(), new.clone())),
+            _ => Err(invalid("expected s/OLD/NEW/g")),
+        };
+    }
+    let split = expr
+        .find(|c: char| !c.is_ascii_digit() && c != '$')
+        .ok_or_else(|| invalid("missing command"))?;
+    let (address, command) = expr.split_at(split);
+    let command = command.trim_start();
+    let (append, text) = if let Some(text) = command.strip_prefix('a') {
+        (true, text)
+    } else if let Some(text) = command.strip_prefix('i') {
+        (false, text)
+    } else {
+        return Err(invalid("expected a command of `a`, `i` or `s`"));
+    };
+    let text = text.strip_prefix('\\').unwrap_or(text.trim_start());
+    let line = match address {
+        "$" if append => {
+            return Ok(Expression::Insert(
+                Position::AfterLastLine,
+                format!("{}\n", text),
+            ))
+        }
+        _ => address
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid("expected a line number from 1, or `$` for `a`"))?,
+    };
+    let at = if append {
+        Position::AfterLine(line)
+    } else {
+        Position::BeforeLine(line)
+    };
+    Ok(Expression::Insert(at, format!("{}\n", text)))
+}
+
+/// parse a decimal, `0x` hex, `0o` octal or `0b` binary offset
+///
+/// decimal offsets may carry a size suffix: `K`, `M`, `G` or `T` for powers of
+/// 1000, with `iB` for powers of 1024; a trailing `B` alone is ignored
+fn parse_offset(text: &str) -> Result<usize, String> {
+    let lower = text.to_ascii_lowercase();
+    for &(prefix, radix) in &[("0x", 16), ("0o", 8), ("0b", 2)] {
+        if let Some(digits) = lower.strip_prefix(prefix) {
+            return usize::from_str_radix(digits, radix).map_err(|e| e.to_string());
+        }
+    }
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, suffix) = lower.split_at(split);
+    let multiplier: u64 = match suffix.trim_start() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        other => return Err(format!("unknown size suffix `{}`", other)),
+    };
+    let number: usize = digits.parse().map_err(|e| format!("{}", e))?;
+    usize::try_from(multiplier)
+        .ok()
+        .and_then(|m| number.checked_mul(m))
+        .ok_or_else(|| "offset too large".to_string())
+}
+
+/// read a plan file, resolving its relative file sources against its directory
+fn load_plan(path: &Path) -> io::Result<InsertionPlan> {
+    let mut plan: InsertionPlan = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    for insertion in &mut plan.insertions {
+        if let Source::File(ref mut file) = insertion.source {
+            if file.is_relative() {
+                *file = dir.join(&file);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// expand `*` and `?` wildcards in the file name of `path`
+///
+/// as in a shell, wildcards don't match a leading `.`
+fn expand(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let pattern = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name,
+        _ => return Ok(vec![path.to_path_buf()]),
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut matches = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(name) = entry.file_name().to_str() {
+            if !name.starts_with('.')
+                && wildcard(pattern.as_bytes(), name.as_bytes())
+                && entry.file_type()?.is_file()
+            {
+                matches.push(path.with_file_name(name));
+            }
+        }
+    }
+    if matches.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no files match `{}`", path.display()),
+        ));
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| wildcard(rest, &name[skip..])),
+        Some((&b'?', rest)) => !name.is_empty() && wildcard(rest, &name[1..]),
+        Some((&c, rest)) => name.first() == Some(&c) && wildcard(rest, &name[1..]),
+    }
+}
+
+/// a reader or writer which counts the bytes passing through it
+struct Counter<T> {
+    inner: T,
+    /// shared, so that several readers can count into one tally
+    count: Rc<Cell<u64>>,
+    crc: Crc32,
+}
+
+impl<T> Counter<T> {
+    fn new(inner: T) -> Counter<T> {
+        Counter::tallied(inner, Rc::default())
+    }
+
+    fn tallied(inner: T, count: Rc<Cell<u64>>) -> Counter<T> {
+        Counter {
+            inner,
+            count,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn add(&self, n: usize) {
+        self.count.set(self.count.get() + n as u64);
+    }
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.add(n);
+        Ok(n)
+    }
+}
+
+impl<S: Seek> Seek for Counter<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.add(n);
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// what an execution did
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    copied: u64,
+    inserted: u64,
+    written: u64,
+    crc32: u32,
+}
+
+impl Stats {
+    fn to_json(self, input: Option<&Path>) -> String {
+        format!(
+            "{{{}\"copied\":{},\"inserted\":{},\"written\":{},\"crc32\":\"{:08x}\"}}",
+            json_file(input),
+            self.copied,
+            self.inserted,
+            self.written,
+            self.crc32
+        )
+    }
+}
+
+/// apply the plan and the replacements from a seekable `origin` to `target`
+///
+/// the plan is applied as `InsertionPlan::apply` does, so content inserted
+/// once, removed anchors and the final line are all handled by the library
+fn splice<R: Read + Seek, W: Write>(
+    plan: &InsertionPlan,
+    replacements: &[(String, String)],
+    origin: R,
+    target: W,
+) -> io::Result<Stats> {
+    let inserted = Rc::new(Cell::new(0));
+    let mut origin = Counter::new(origin);
+    let copied = origin.count.clone();
+    let mut target = Counter::new(target);
+    let inserter = plan.inserter_with(&mut origin, &mut target, |insertion| {
+        let source = Counter::tallied(insertion.open()?, inserted.clone());
+        Ok(Box::new(source) as Box<dyn Read>)
+    })?;
+    // reading the origin and content to prepare isn't copying or inserting
+    copied.set(0);
+    inserted.set(0);
+    // the library's own sources, such as content inserted once, aren't
+    // counted as they're read, but their lengths are known
+    let known: u64 = inserter
+        .insertions()
+        .filter_map(|(_, source)| source.len_hint())
+        .sum();
+    replace(inserter, replacements).execute()?;
+    Ok(Stats {
+        copied: copied.get(),
+        inserted: inserted.get() + known,
+        written: target.count.get(),
+        crc32: target.crc.finish(),
+    })
+}
+
+/// apply the plan and the replacements from `origin` as it arrives
+///
+/// this is only for stdin when every position is an offset and no content is
+/// inserted once, so that the library would have nothing to scan for
+fn stream<R: Read, W: Write>(
+    plan: &InsertionPlan,
+    replacements: &[(String, String)],
+    offsets: &[usize],
+    origin: R,
+    target: W,
+) -> io::Result<Stats> {
+    let mut sources = Vec::with_capacity(offsets.len());
+    for insertion in &plan.insertions {
+        sources.push(Counter::new(insertion.open()?));
+    }
+    let mut origin = Counter::new(origin);
+    let mut target = Counter::new(target);
+    let mut inserter = Inserter::new(&mut origin, &mut target);
+    for (offset, source) in offsets.iter().zip(sources.iter_mut()) {
+        inserter = inserter.insert(*offset, source);
+    }
+    replace(inserter, replacements).execute()?;
+    Ok(Stats {
+        copied: origin.count.get(),
+        inserted: sources.iter().map(|s| s.count.get()).sum(),
+        written: target.count.get(),
+        crc32: target.crc.finish(),
+    })
+}
+
+/// add every replacement to the inserter
+fn replace<'i, R: Read, W: Write>(
+    mut inserter: Inserter<'i, R, W>,
+    replacements: &[(String, String)],
+) -> Inserter<'i, R, W> {
+    for (old, new) in replacements {
+        inserter = inserter.operation(Operation::ReplaceAll(
+            old.clone().into_bytes(),
+            new.clone().into_bytes(),
+        ));
+    }
+    inserter
+}
+
+/// describe the insertions which would be made, without making them
+fn report<R: Read>(
+    plan: &InsertionPlan,
+    replacements: &[(String, String)],
+    offsets: &[usize],
+    mut origin: R,
+    input: Option<&Path>,
+    json: bool,
+) -> io::Result<String> {
+    let size = io::copy(&mut origin, &mut io::sink())?;
+    let mut operations = Vec::with_capacity(offsets.len());
+    for (&offset, insertion) in offsets.iter().zip(&plan.insertions) {
+        operations.push((offset, &insertion.source, insertion.source.size()?));
+    }
+    operations.sort_by_key(|&(offset, _, _)| offset);
+    let output = size + operations.iter().map(|&(_, _, len)| len).sum::<u64>();
+    // the number of replacements isn't known without making them, nor are
+    // the lengths of removed anchors, nor whether content inserted once is
+    // already in place
+    let output = Some(output).filter(|_| {
+        !plan.remove_anchors
+            && plan.insertions.iter().all(|i| !i.once)
+            && replacements.iter().all(|(old, new)| old.len() == new.len())
+    });
+
+    let describe = |source: &Source| match *source {
+        Source::File(ref path) => path.display().to_string(),
+        Source::Text(_) => "text".to_string(),
+    };
+    let mut report = String::new();
+    if json {
+        let operations: Vec<String> = operations
+            .iter()
+            .map(|&(offset, source, len)| {
+                format!(
+                    "{{\"offset\":{},\"source\":{},\"bytes\":{}}}",
+                    offset,
+                    json_string(&describe(source)),
+                    len
+                )
+            })
+            .collect();
+        let replacements: Vec<String> = replacements
+            .iter()
+            .map(|(old, new)| {
+                format!(
+                    "{{\"replace\":{},\"with\":{}}}",
+                    json_string(old),
+                    json_string(new)
+                )
+            })
+            .collect();
+        let replacements = if replacements.is_empty() {
+            String::new()
+        } else {
+            format!("\"replacements\":[{}],", replacements.join(","))
+        };
+        report.push_str(&format!(
+            "{{{}\"operations\":[{}],{}\"input_bytes\":{},\"output_bytes\":{}}}",
+            json_file(input),
+            operations.join(","),
+            replacements,
+            size,
+            output.map_or("null".to_string(), |output| output.to_string())
+        ));
+    } else {
+        if let Some(input) = input {
+            report.push_str(&format!("{}:\n", input.display()));
+        }
+        for (offset, source, len) in operations {
+            report.push_str(&format!(
+                "insert {} ({} bytes) at {}\n",
+                describe(source),
+                len,
+                offset
+            ));
+        }
+        for (old, new) in replacements {
+            report.push_str(&format!("replace {:?} with {:?}\n", old, new));
+        }
+        match output {
+            Some(output) => report.push_str(&format!("{} bytes in, {} bytes out", size, output)),
+            None => report.push_str(&format!("{} bytes in, unknown bytes out", size)),
+        }
+    }
+    Ok(report)
+}
+
+fn json_file(input: Option<&Path>) -> String {
+    match input {
+        Some(path) => format!("\"file\":{},", json_string(&path.to_string_lossy())),
+        None => String::new(),
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// apply the plan to a single input
+fn process(
+    args: &Args,
+    plan: &InsertionPlan,
+    header: Option<&Header>,
+    input: Option<&Path>,
+) -> io::Result<()> {
+    let stdin = io::stdin();
+    // stdin can only be read once, so it's buffered if it has to be scanned
+    let data = match input {
+        None if header.is_some()
+            || plan
+                .insertions
+                .iter()
+                .any(|i| i.once || !matches!(i.at, Position::Offset(_))) =>
+        {
+            let mut data = Vec::new();
+            stdin.lock().read_to_end(&mut data)?;
+            Some(data)
+        }
+        _ => None,
+    };
+
+    let headed;
+    let plan = match header {
+        Some(header) => {
+            let header = header
+                .clone()
+                .comment(input.and_then(CommentStyle::for_path));
+            let insertion = match (input, &data) {
+                (Some(path), _) => header.insertion(BufReader::new(File::open(path)?))?,
+                (None, data) => header.insertion(data.as_deref().unwrap_or_default())?,
+            };
+            headed = match insertion {
+                Some((offset, text)) => plan
+                    .clone()
+                    .insert(Position::Offset(offset), Source::Text(text)),
+                None => plan.clone(),
+            };
+            &headed
+        }
+        None => plan,
+    };
+
+    if args.dry_run {
+        let (offsets, origin): (Vec<usize>, Box<dyn Read>) = match (input, data) {
+            (Some(path), _) => (
+                plan.resolve(BufReader::new(File::open(path)?))?,
+                Box::new(BufReader::new(File::open(path)?)),
+            ),
+            (None, Some(data)) => (
+                plan.resolve(data.as_slice())?,
+                Box::new(io::Cursor::new(data)),
+            ),
+            (None, None) => (plan.resolve(io::empty())?, Box::new(stdin.lock())),
+        };
+        println!(
+            "{}",
+            report(plan, &args.replacements, &offsets, origin, input, args.json)?
+        );
+        return Ok(());
+    }
+
+    let apply = |target: &mut dyn Write| match (input, data) {
+        (Some(path), _) => splice(
+            plan,
+            &args.replacements,
+            BufReader::new(File::open(path)?),
+            target,
+        ),
+        (None, Some(data)) => splice(plan, &args.replacements, io::Cursor::new(data), target),
+        (None, None) => {
+            let offsets = plan.resolve(io::empty())?;
+            stream(plan, &args.replacements, &offsets, stdin.lock(), target)
+        }
+    };
+
+    let destination = match (input, &args.out_dir) {
+        (Some(input), Some(dir)) => Some(dir.join(input.file_name().unwrap_or_default())),
+        (Some(input), None) if args.in_place => Some(input.to_path_buf()),
+        _ => None,
+    };
+    let stats = if let Some(destination) = destination {
+        let temp = temp_path(&destination);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        let mut target = BufWriter::new(file);
+        let result = apply(&mut target).and_then(|stats| {
+            target.into_inner()?.sync_all()?;
+            if let Some(input) = input {
+                // keep the input's mode, such as a script's executable bit
+                fs::set_permissions(&temp, fs::metadata(input)?.permissions())?;
+            }
+            fs::rename(&temp, &destination)?;
+            Ok(stats)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+        result?
+    } else {
+        if let (Some(input), Some(output)) = (input, &args.output) {
+            // creating the output would truncate the input before it's read
+            if same_file(input, output) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} is also the output; use --in-place to replace it",
+                        input.display()
+                    ),
+                ));
+            }
+        }
+        let stdout = io::stdout();
+        let mut target: Box<dyn Write> = match args.output {
+            Some(ref path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(BufWriter::new(stdout.lock())),
+        };
+        let stats = apply(&mut target)?;
+        target.flush()?;
+        stats
+    };
+
+    if args.json {
+        if args.output.is_none() && !args.in_place && args.out_dir.is_none() {
+            eprintln!("{}", stats.to_json(input));
+        } else {
+            println!("{}", stats.to_json(input));
+        }
+    }
+    Ok(())
+}
+
+/// whether both paths name the same existing file, even through a link
+fn same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        match (fs::canonicalize(a), fs::canonicalize(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// apply the plan to every input, returning how many failed
+fn run(args: &Args) -> io::Result<usize> {
+    let mut plan = match args.plan_file {
+        Some(ref path) => load_plan(path)?,
+        None => InsertionPlan::new(),
+    };
+    plan.insertions.extend(args.plan.insertions.iter().cloned());
+    if args.plan.final_line == FinalLine::Terminate {
+        plan.final_line = FinalLine::Terminate;
+    }
+    let header = match args.header {
+        Some(ref path) => Some(Header::new(fs::read_to_string(path)?)),
+        None => None,
+    };
+
+    let mut inputs = Vec::new();
+    for input in &args.inputs {
+        match *input {
+            Some(ref path) if args.in_place || args.out_dir.is_some() => {
+                inputs.extend(expand(path)?.into_iter().map(Some))
+            }
+            ref input => inputs.push(input.clone()),
+        }
+    }
+    if let Some(ref dir) = args.out_dir {
+        // inputs of the same name from different directories would overwrite
+        // each other's results
+        let mut names = HashMap::new();
+        for path in inputs.iter().flatten() {
+            let name = path.file_name().unwrap_or_default();
+            if let Some(other) = names.insert(name, path) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} and {} would both be written to {}",
+                        other.display(),
+                        path.display(),
+                        dir.join(name).display()
+                    ),
+                ));
+            }
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let work = || loop {
+        let index = next.fetch_add(1, Ordering::SeqCst);
+        let input = match inputs.get(index) {
+            Some(input) => input.as_ref().map(PathBuf::as_path),
+            None => break,
+        };
+        if let Err(e) = process(args, &plan, header.as_ref(), input) {
+            match input {
+                Some(path) => eprintln!("insert-multiple: {}: {}", path.display(), e),
+                None => eprintln!("insert-multiple: {}", e),
+            }
+            failed.fetch_add(1, Ordering::SeqCst);
+        }
+    };
+    thread::scope(|scope| {
+        for _ in 1..args.jobs.min(inputs.len()) {
+            scope.spawn(work);
+        }
+        work();
+    });
+    Ok(failed.into_inner())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("insert-multiple: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    match run(&args) {
+        Ok(0) => {}
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("insert-multiple: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("insert-multiple-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_arguments() {
+        let args = parse(&["--at", "10=a.bin", "--at=0=b.bin", "in", "out"]).unwrap();
+        assert_eq!(
+            Args {
+                plan: InsertionPlan::new()
+                    .insert(Position::Offset(10), Source::File("a.bin".into()))
+                    .insert(Position::Offset(0), Source::File("b.bin".into())),
+                inputs: vec![Some("in".into())],
+                output: Some("out".into()),
+                ..Args::default()
+            },
+            args
+        );
+        let args = parse(&["-", "-"]).unwrap();
+        assert_eq!((vec![None], None), (args.inputs, args.output));
+        let args = parse(&["-p", "plan.json", "-j", "4", "-i", "a", "b", "c"]).unwrap();
+        assert_eq!(Some("plan.json".into()), args.plan_file);
+        assert_eq!((4, 3), (args.jobs, args.inputs.len()));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--at", "x=a", "in"]).unwrap_err().contains("x=a"));
+        assert!(parse(&["--at", "12", "in"]).is_err());
+        assert!(parse(&["--frobnicate", "in"]).is_err());
+        assert!(parse(&["-i", "-"]).is_err());
+        assert!(parse(&["in", "out", "more"]).is_err());
+        assert!(parse(&["-i", "-o", "dir", "in"]).is_err());
+        assert!(parse(&["-j", "0", "in"]).is_err());
+    }
+
+    #[test]
+    fn parses_anchors() {
+        let args = parse(&[
+            "--after",
+            "marker",
+            "a",
+            "--before-line",
+            "3",
+            "b",
+            "--after-regex",
+            "^fn ",
+            "c",
+            "in",
+        ])
+        .unwrap();
+        let positions: Vec<_> = args.plan.insertions.into_iter().map(|i| i.at).collect();
+        assert_eq!(
+            vec![
+                Position::After("marker".into()),
+                Position::BeforeLine(3),
+                Position::AfterRegex("^fn ".into()),
+            ],
+            positions
+        );
+        assert!(parse(&["--after-line", "0", "a", "in"]).is_err());
+        assert!(parse(&["--before-regex", "(", "a", "in"])
+            .unwrap_err()
+            .contains("`(`"));
+        assert!(parse(&["--after", "marker"]).is_err());
+    }
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(
+            Ok(Expression::Insert(Position::AfterLine(3), "text\n".into())),
+            parse_expression("3a\\text")
+        );
+        assert_eq!(
+            Ok(Expression::Insert(Position::BeforeLine(1), "text\n".into())),
+            parse_expression("1 i text")
+        );
+        assert_eq!(
+            Ok(Expression::Insert(Position::AfterLastLine, "end\n".into())),
+            parse_expression("$a\\end")
+        );
+        assert_eq!(
+            Ok(Expression::Replace("/usr".into(), "/opt".into())),
+            parse_expression("s|/usr|/opt|g")
+        );
+        assert_eq!(
+            Ok(Expression::Replace("a/b".into(), "c\\d".into())),
+            parse_expression("s/a\\/b/c\\d/g")
+        );
+        for bad in ["s/a/b/", "s//b/g", "s/a/b", "0a\\x", "$i\\x", "3d", ""] {
+            assert!(parse_expression(bad).is_err(), "{}", bad);
+        }
+        let args = parse(&["-e", "1i\\#!/bin/sh", "--expression", "s/foo/bar/g", "in"]).unwrap();
+        assert_eq!(1, args.plan.insertions.len());
+        assert_eq!(vec![("foo".into(), "bar".into())], args.replacements);
+    }
+
+    #[test]
+    fn parses_offsets() {
+        assert_eq!(Ok(500), parse_offset("0x1F4"));
+        assert_eq!(Ok(500), parse_offset("0o764"));
+        assert_eq!(Ok(5), parse_offset("0b101"));
+        assert_eq!(Ok(4096), parse_offset("4KiB"));
+        assert_eq!(Ok(1_000_000), parse_offset("1MB"));
+        assert_eq!(Ok(2048), parse_offset("2 kib"));
+        assert_eq!(Ok(12), parse_offset("12"));
+        assert!(parse_offset("4XB").is_err());
+        assert!(parse_offset("0xZZ").is_err());
+        assert!(parse_offset("KiB").is_err());
+        let err = parse(&["--at", "4QB=a", "in"]).unwrap_err();
+        assert!(err.contains("4QB=a") && err.contains("`qb`"));
+    }
+
+    #[test]
+    fn edits_in_place() {
+        let dir = scratch("in-place");
+        let input = dir.join("input.txt");
+        let payload = dir.join("payload.txt");
+        fs::write(&input, "hello world").unwrap();
+        fs::write(&payload, ", there").unwrap();
+        let args = Args {
+            plan: InsertionPlan::new().insert(
+                Position::After("hello".into()),
+                Source::File(payload.clone()),
+            ),
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..Args::default()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!("hello, there world", fs::read_to_string(&input).unwrap());
+
+        let report = report(
+            &args.plan,
+            &[],
+            &[5],
+            File::open(&input).unwrap(),
+            None,
+            false,
+        )
+        .unwrap();
+        assert!(report.ends_with("18 bytes in, 25 bytes out"));
+        let stats = stream(&args.plan, &[], &[0], &b"abc"[..], io::sink()).unwrap();
+        assert_eq!(
+            "{\"copied\":3,\"inserted\":7,\"written\":10,\"crc32\":\"b9ef26c4\"}",
+            stats.to_json(None)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions_in_place() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch("permissions");
+        let input = dir.join("script.sh");
+        fs::write(&input, "#!/bin/sh\necho hi\n").unwrap();
+        fs::set_permissions(&input, fs::Permissions::from_mode(0o750)).unwrap();
+        let args = Args {
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..parse(&["-e", "1a\\set -e", "in"]).unwrap()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!(
+            "#!/bin/sh\nset -e\necho hi\n",
+            fs::read_to_string(&input).unwrap()
+        );
+        let mode = fs::metadata(&input).unwrap().permissions().mode();
+        assert_eq!(0o750, mode & 0o777);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn applies_expressions() {
+        let dir = scratch("expressions");
+        let input = dir.join("input.txt");
+        fs::write(&input, "hello there world").unwrap();
+        let args = Args {
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..parse(&["-e", "s/there/big/g", "-e", "$a\\!", "in"]).unwrap()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!("hello big world\n!\n", fs::read_to_string(&input).unwrap());
+
+        fs::write(&input, "a\nb").unwrap();
+        let args = Args {
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..parse(&["-e", "2a\\c", "-e", "1a\\x", "in"]).unwrap()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!("a\nx\nb\nc\n", fs::read_to_string(&input).unwrap());
+
+        let replacements = [("o".to_string(), "00".to_string())];
+        let report = report(&args.plan, &replacements, &[0], &b"o"[..], None, true).unwrap();
+        assert!(report.contains("\"replacements\":[{\"replace\":\"o\",\"with\":\"00\"}]"));
+        assert!(report.ends_with("\"output_bytes\":null}"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn applies_plan_to_many_inputs() {
+        let dir = scratch("plan");
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        fs::write(dir.join("header.txt"), "// generated\n").unwrap();
+        fs::write(
+            dir.join("plan.json"),
+            r#"{"insertions":[{"at":{"offset":0},"source":{"file":"header.txt"}},
+                {"at":{"after_line":1},"source":{"text":"use std;\n"}}]}"#,
+        )
+        .unwrap();
+        for name in &["a.rs", "b.rs", ".hidden.rs"] {
+            fs::write(dir.join(name), format!("// {}\nfn main() {{}}\n", name)).unwrap();
+        }
+        let args = Args {
+            plan_file: Some(dir.join("plan.json")),
+            out_dir: Some(out.clone()),
+            jobs: 2,
+            inputs: vec![Some(dir.join("*.rs"))],
+            ..Args::default()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!(
+            "// generated\n// a.rs\nuse std;\nfn main() {}\n",
+            fs::read_to_string(out.join("a.rs")).unwrap()
+        );
+        assert!(out.join("b.rs").exists());
+        assert!(!out.join(".hidden.rs").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn inserts_plan_content_once() {
+        let dir = scratch("once");
+        let input = dir.join("page.html");
+        fs::write(&input, "<head></head>").unwrap();
+        fs::write(
+            dir.join("plan.json"),
+            r#"{"insertions":[{"at":{"after":"<head>"},"source":{"text":"<meta>"},"once":true}]}"#,
+        )
+        .unwrap();
+        let args = Args {
+            plan_file: Some(dir.join("plan.json")),
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..Args::default()
+        };
+        for _ in 0..2 {
+            assert_eq!(0, run(&args).unwrap());
+            assert_eq!("<head><meta></head>", fs::read_to_string(&input).unwrap());
+        }
+
+        let plan = load_plan(&dir.join("plan.json")).unwrap();
+        let origin = io::Cursor::new(b"<head></head>".to_vec());
+        let stats = splice(&plan, &[], origin, io::sink()).unwrap();
+        assert_eq!((13, 6, 19), (stats.copied, stats.inserted, stats.written));
+        let origin = io::Cursor::new(b"<head><meta></head>".to_vec());
+        let stats = splice(&plan, &[], origin, io::sink()).unwrap();
+        assert_eq!((19, 0, 19), (stats.copied, stats.inserted, stats.written));
+        let report = report(&plan, &[], &[6], &b"<head></head>"[..], None, false);
+        assert!(report.unwrap().ends_with("13 bytes in, unknown bytes out"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_plan_anchors() {
+        let dir = scratch("remove-anchors");
+        let input = dir.join("page.html");
+        fs::write(&input, "<nav><!-- nav --></nav>").unwrap();
+        fs::write(
+            dir.join("plan.json"),
+            r#"{"insertions":[{"at":{"after":"<!-- nav -->"},"source":{"text":"<a>"}}],
+                "remove_anchors":true}"#,
+        )
+        .unwrap();
+        let args = Args {
+            plan_file: Some(dir.join("plan.json")),
+            in_place: true,
+            inputs: vec![Some(input.clone())],
+            ..Args::default()
+        };
+        assert_eq!(0, run(&args).unwrap());
+        assert_eq!("<nav><a></nav>", fs::read_to_string(&input).unwrap());
+
+        let plan = load_plan(&dir.join("plan.json")).unwrap();
+        let report = report(
+            &plan,
+            &[],
+            &[17],
+            &b"<nav><!-- nav --></nav>"[..],
+            None,
+            false,
+        );
+        assert!(report.unwrap().ends_with("23 bytes in, unknown bytes out"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn honours_plan_final_line() {
+        let dir = scratch("final-line");
+        let input = dir.join("list.txt");
+        for &(final_line, expected) in &[
+            ("after", "a\nbc\n"),
+            ("terminate", "a\nb\nc\n"),
+            ("preserve", "a\nb\nc"),
+            ("before", "a\nc\nb"),
+        ] {
+            fs::write(&input, "a\nb").unwrap();
+            fs::write(
+                dir.join("plan.json"),
+                format!(
+                    r#"{{"insertions":[{{"at":{{"after_line":2}},"source":{{"text":"c\n"}}}}],
+                        "final_line":"{}"}}"#,
+                    final_line
+                ),
+            )
+            .unwrap();
+            let args = Args {
+                plan_file: Some(dir.join("plan.json")),
+                in_place: true,
+                inputs: vec![Some(input.clone())],
+                ..Args::default()
+            };
+            assert_eq!(0, run(&args).unwrap());
+            assert_eq!(
+                expected,
+                fs::read_to_string(&input).unwrap(),
+                "{}",
+                final_line
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_clashing_destinations() {
+        let dir = scratch("clash");
+        for sub in &["a", "b"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("x.txt"), *sub).unwrap();
+        }
+        let args = Args {
+            out_dir: Some(dir.join("out")),
+            inputs: vec![Some(dir.join("a/x.txt")), Some(dir.join("b/x.txt"))],
+            ..parse(&["-e", "1i\\top", "in"]).unwrap()
+        };
+        let err = run(&args).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(!dir.join("out").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_output_over_input() {
+        let dir = scratch("same-file");
+        let input = dir.join("s.txt");
+        fs::write(&input, "text").unwrap();
+        let args = Args {
+            inputs: vec![Some(input.clone())],
+            output: Some(dir.join(".").join("s.txt")),
+            ..parse(&["-e", "1i\\top", "in"]).unwrap()
+        };
+        assert_eq!(1, run(&args).unwrap());
+        assert_eq!("text", fs::read_to_string(&input).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn adds_headers_once() {
+        let dir = scratch("header");
+        fs::write(dir.join("LICENSE"), "Copyright 2020 Example\n").unwrap();
+        fs::write(dir.join("a.py"), "#!/usr/bin/env python\nprint()\n").unwrap();
+        fs::write(dir.join("b.rs"), "\u{FEFF}fn main() {}\n").unwrap();
+        let args = Args {
+            header: Some(dir.join("LICENSE")),
+            in_place: true,
+            inputs: vec![Some(dir.join("a.py")), Some(dir.join("b.rs"))],
+            ..Args::default()
+        };
+        for _ in 0..2 {
+            assert_eq!(0, run(&args).unwrap());
+            assert_eq!(
+                "#!/usr/bin/env python\n# Copyright 2020 Example\nprint()\n",
+                fs::read_to_string(dir.join("a.py")).unwrap()
+            );
+            assert_eq!(
+                "\u{FEFF}// Copyright 2020 Example\nfn main() {}\n",
+                fs::read_to_string(dir.join("b.rs")).unwrap()
+            );
+        }
+        assert_eq!(
+            Some(PathBuf::from("LICENSE")),
+            parse(&["--header", "LICENSE", "in"]).unwrap().header
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard(b"*.rs", b"main.rs"));
+        assert!(wildcard(b"a?c*", b"abc"));
+        assert!(!wildcard(b"*.rs", b"main.rsx"));
+        assert!(!wildcard(b"a?c", b"ac"));
+    }
+}
@@ -0,0 +1,229 @@
+use anchor::{self, Anchor};
+use operation::PlanBuilder;
+use std::{
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+/// the line beginning a block, unless configured otherwise
+pub const DEFAULT_BEGIN: &str = "# BEGIN MANAGED BLOCK";
+/// the line ending a block, unless configured otherwise
+pub const DEFAULT_END: &str = "# END MANAGED BLOCK";
+
+/// where a managed block was found in the origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Found {
+    /// the whole block, from the start of its begin line to the end of its end line
+    pub block: Range<usize>,
+    /// the contents, between the marker lines
+    pub contents: Range<usize>,
+}
+
+/// a block of lines between begin and end marker lines, maintained in a file
+/// like ansible's `blockinfile`
+///
+/// applying the block inserts it if it's absent, or replaces its contents if
+/// they're present; it can also be removed. the origin is scanned for the
+/// markers, then rewound and streamed to the target in a single pass.
+#[derive(Debug, Clone)]
+pub struct ManagedBlock {
+    begin: Vec<u8>,
+    end: Vec<u8>,
+    contents: Vec<u8>,
+    at: Anchor,
+}
+
+impl ManagedBlock {
+    /// a block with these contents, appended to the origin if it's absent
+    ///
+    /// a final newline is added to the contents if they lack one
+    pub fn new<C: Into<Vec<u8>>>(contents: C) -> ManagedBlock {
+        let mut contents = contents.into();
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            contents.push(b'\n');
+        }
+        ManagedBlock {
+            begin: DEFAULT_BEGIN.as_bytes().to_vec(),
+            end: DEFAULT_END.as_bytes().to_vec(),
+            contents,
+            at: Anchor::Offset(usize::MAX),
+        }
+    }
+
+    /// use custom marker lines, which are matched without their line endings
+    ///
+    /// panics if either marker is empty or contains a newline
+    pub fn markers(mut self, begin: &str, end: &str) -> Self {
+        assert!(
+            !begin.is_empty() && !end.is_empty() && !begin.contains('\n') && !end.contains('\n'),
+            "markers must be single, non-empty lines"
+        );
+        self.begin = begin.as_bytes().to_vec();
+        self.end = end.as_bytes().to_vec();
+        self
+    }
+
+    /// where the block is inserted if it's absent
+    pub fn at(mut self, anchor: Anchor) -> Self {
+        self.at = anchor;
+        self
+    }
+
+    /// find the block in the origin
+    ///
+    /// fails with `InvalidData` if a marker line is missing its partner
+    pub fn find<R: Read>(&self, origin: R) -> io::Result<Option<Found>> {
+        self.scan(origin).map(|(found, _)| found)
+    }
+
+    /// find the block, and the length of the origin if the block is absent
+    fn scan<R: Read>(&self, origin: R) -> io::Result<(Option<Found>, usize)> {
+        let mut origin = BufReader::new(origin);
+        let mut line = Vec::new();
+        let mut offset = 0;
+        let mut begin: Option<Range<usize>> = None;
+        loop {
+            line.clear();
+            let len = origin.read_until(b'\n', &mut line)?;
+            if len == 0 {
+                break;
+            }
+            let text = line.strip_suffix(b"\n").unwrap_or(&line);
+            let text = text.strip_suffix(b"\r").unwrap_or(text);
+            let range = offset..offset + len;
+            offset += len;
+            match begin {
+                None if text == &self.begin[..] => begin = Some(range),
+                None if text == &self.end[..] => {
+                    return Err(invalid("end marker without a begin marker"))
+                }
+                Some(ref begin) if text == &self.end[..] => {
+                    let found = Found {
+                        block: begin.start..range.end,
+                        contents: begin.end..range.start,
+                    };
+                    return Ok((Some(found), range.end));
+                }
+                _ => (),
+            }
+        }
+        match begin {
+            Some(_) => Err(invalid("begin marker without an end marker")),
+            None => Ok((None, offset)),
+        }
+    }
+
+    /// write the origin with the block inserted or its contents replaced
+    pub fn apply<R: Read + Seek, W: Write>(&self, mut origin: R, target: W) -> io::Result<()> {
+        let start = origin.stream_position()?;
+        let (found, len) = self.scan(&mut origin)?;
+        origin.seek(SeekFrom::Start(start))?;
+        let plan = match found {
+            Some(found) => PlanBuilder::new().replace(found.contents, self.contents.clone()),
+            None => {
+                let position =
+                    anchor::resolve(&mut origin, ::std::slice::from_ref(&self.at))?[0].min(len);
+                let mut block = Vec::new();
+                if position > 0 && !ends_line(&mut origin, start + position as u64 - 1)? {
+                    block.push(b'\n');
+                }
+                for part in [&self.begin[..], b"\n", &self.contents, &self.end, b"\n"] {
+                    block.extend_from_slice(part);
+                }
+                origin.seek(SeekFrom::Start(start))?;
+                PlanBuilder::new().insert(position, block)
+            }
+        };
+        plan.execute(origin, target)
+    }
+
+    /// write the origin without the block, marker lines and all
+    pub fn remove<R: Read + Seek, W: Write>(&self, mut origin: R, target: W) -> io::Result<()> {
+        let start = origin.stream_position()?;
+        let found = self.find(&mut origin)?;
+        origin.seek(SeekFrom::Start(start))?;
+        let mut plan = PlanBuilder::new();
+        if let Some(found) = found {
+            plan = plan.delete(found.block);
+        }
+        plan.execute(origin, target)
+    }
+}
+
+/// whether the origin byte at this index is a newline
+fn ends_line<R: Read + Seek>(origin: &mut R, index: u64) -> io::Result<bool> {
+    origin.seek(SeekFrom::Start(index))?;
+    let mut byte = [0];
+    origin.read_exact(&mut byte)?;
+    Ok(byte[0] == b'\n')
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn apply(block: &ManagedBlock, origin: &str) -> String {
+        let mut dest = Vec::new();
+        block.apply(Cursor::new(origin), &mut dest).unwrap();
+        String::from_utf8(dest).unwrap()
+    }
+
+    #[test]
+    fn inserts_then_replaces() {
+        let block = ManagedBlock::new("a = 1");
+        let once = apply(&block, "x = 0");
+        assert_eq!(
+            "x = 0\n# BEGIN MANAGED BLOCK\na = 1\n# END MANAGED BLOCK\n",
+            once
+        );
+        assert_eq!(once, apply(&block, &once));
+
+        let block = ManagedBlock::new("a = 2\nb = 3\n");
+        assert_eq!(
+            "x = 0\n# BEGIN MANAGED BLOCK\na = 2\nb = 3\n# END MANAGED BLOCK\n",
+            apply(&block, &once)
+        );
+    }
+
+    #[test]
+    fn inserts_at_anchor_with_custom_markers() {
+        let block = ManagedBlock::new("10.0.0.1 db")
+            .markers("# {", "# }")
+            .at(Anchor::AfterLine(1));
+        assert_eq!(
+            "127.0.0.1 localhost\r\n# {\n10.0.0.1 db\n# }\n::1 localhost\r\n",
+            apply(&block, "127.0.0.1 localhost\r\n::1 localhost\r\n")
+        );
+        assert_eq!(
+            "a\r\n# {\r\n10.0.0.1 db\n# }\r\nb",
+            apply(&block, "a\r\n# {\r\nold\r\n# }\r\nb")
+        );
+    }
+
+    #[test]
+    fn removes() {
+        let block = ManagedBlock::new("");
+        let mut dest = Vec::new();
+        block
+            .remove(
+                Cursor::new("a\n# BEGIN MANAGED BLOCK\nx\n# END MANAGED BLOCK\nb\n"),
+                &mut dest,
+            )
+            .unwrap();
+        assert_eq!(&b"a\nb\n"[..], &dest[..]);
+    }
+
+    #[test]
+    fn rejects_unpaired_markers() {
+        let block = ManagedBlock::new("x");
+        for origin in ["# BEGIN MANAGED BLOCK\nx\n", "x\n# END MANAGED BLOCK\n"] {
+            let err = block.apply(Cursor::new(origin), io::sink()).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+}
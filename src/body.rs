@@ -0,0 +1,249 @@
+//! spliced output as an http body
+//!
+//! the engine pushes its output to a writer, while an http body is polled for
+//! it, so the plan runs on a thread of its own and hands chunks across as
+//! they fill. the plan itself needn't be `Send`: it's built on that thread,
+//! by the closure passed to `Body::spawn`.
+
+use bytes::{Bytes, BytesMut};
+use http_body::{Frame, SizeHint};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+/// chunks are handed to the body once they're this long
+pub const CHUNK_LEN: usize = 16 * 1024;
+
+/// the writer blocks once this many chunks are waiting to be polled
+const QUEUED_CHUNKS: usize = 2;
+
+#[derive(Default)]
+struct State {
+    chunks: VecDeque<Bytes>,
+    /// the plan's result, once it's finished
+    finished: Option<io::Result<()>>,
+    waker: Option<Waker>,
+    dropped: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    polled: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// queue a chunk, or a result, and wake the body's task
+    fn send(&self, update: impl FnOnce(&mut State)) {
+        let mut state = self.lock();
+        update(&mut state);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// the target a spawned plan writes to, which hands its output to the body in
+/// chunks, blocking while the body falls behind
+///
+/// writes fail with `BrokenPipe` once the body has been dropped, such as when
+/// the client disconnects, so that the plan stops early
+pub struct BodyWriter {
+    shared: Arc<Shared>,
+    buffer: BytesMut,
+}
+
+impl BodyWriter {
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.shared.lock();
+        while state.chunks.len() >= QUEUED_CHUNKS && !state.dropped {
+            state = self
+                .shared
+                .polled
+                .wait(state)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        if state.dropped {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the body was dropped",
+            ));
+        }
+        drop(state);
+        let chunk = self.buffer.split().freeze();
+        self.shared.send(|state| state.chunks.push_back(chunk));
+        Ok(())
+    }
+}
+
+impl Write for BodyWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == CHUNK_LEN {
+            self.send_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// an http body whose content is written by a plan running on its own thread
+///
+/// an error from the plan ends the body with that error, after the output
+/// written before it
+pub struct Body {
+    shared: Arc<Shared>,
+    len: Option<u64>,
+}
+
+impl Body {
+    /// run the closure on a new thread, streaming what it writes as the body
+    pub fn spawn<F>(write: F) -> Body
+    where
+        F: 'static + Send + FnOnce(&mut BodyWriter) -> io::Result<()>,
+    {
+        let shared = Arc::new(Shared::default());
+        let mut writer = BodyWriter {
+            shared: shared.clone(),
+            buffer: BytesMut::with_capacity(CHUNK_LEN),
+        };
+        thread::spawn(move || {
+            // what was written before any error is still sent
+            let result = write(&mut writer);
+            let result = writer.send_buffer().and(result);
+            writer.shared.send(|state| state.finished = Some(result));
+        });
+        Body { shared, len: None }
+    }
+
+    /// declare the body's exact length, such as from `PlanBuilder::output_len`
+    pub fn exact_len(mut self, len: u64) -> Self {
+        self.len = Some(len);
+        self
+    }
+}
+
+impl http_body::Body for Body {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Frame<Bytes>>>> {
+        let mut state = self.shared.lock();
+        if let Some(chunk) = state.chunks.pop_front() {
+            self.shared.polled.notify_one();
+            return Poll::Ready(Some(Ok(Frame::data(chunk))));
+        }
+        match state.finished.take() {
+            Some(Err(err)) => {
+                state.finished = Some(Ok(()));
+                Poll::Ready(Some(Err(err)))
+            }
+            Some(Ok(())) => {
+                state.finished = Some(Ok(()));
+                Poll::Ready(None)
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.len
+            .map_or_else(SizeHint::default, SizeHint::with_exact)
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        self.shared.lock().dropped = true;
+        self.shared.polled.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body::Body as _;
+    use PlanBuilder;
+
+    /// poll the body to its end, spinning while it's pending
+    fn collect(mut body: Body) -> (Vec<u8>, Option<io::Error>) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut out = Vec::new();
+        loop {
+            match Pin::new(&mut body).poll_frame(&mut cx) {
+                Poll::Ready(Some(Ok(frame))) => out.extend_from_slice(&frame.into_data().unwrap()),
+                Poll::Ready(Some(Err(err))) => return (out, Some(err)),
+                Poll::Ready(None) => return (out, None),
+                Poll::Pending => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn streams_spliced_output() {
+        let origin = vec![b'o'; 3 * CHUNK_LEN + 10];
+        let expected = PlanBuilder::new()
+            .insert(CHUNK_LEN, "banner")
+            .execute_to_vec(&origin)
+            .unwrap();
+        let body = Body::spawn(move |writer| {
+            PlanBuilder::new()
+                .insert(CHUNK_LEN, "banner")
+                .execute(origin.as_slice(), writer)
+        })
+        .exact_len(expected.len() as u64);
+        assert_eq!(Some(expected.len() as u64), body.size_hint().exact());
+        let (out, err) = collect(body);
+        assert!(err.is_none());
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn ends_with_the_plan_error() {
+        let body = Body::spawn(|writer| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "broken"))
+        });
+        let (out, err) = collect(body);
+        assert_eq!(b"partial".to_vec(), out);
+        assert_eq!(io::ErrorKind::InvalidData, err.unwrap().kind());
+    }
+
+    #[test]
+    fn dropping_the_body_stops_the_plan() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let body = Body::spawn(move |writer| {
+            let result = io::copy(&mut io::repeat(0), writer).map(|_| ());
+            sender
+                .send(result.as_ref().map_err(|err| err.kind()).err())
+                .unwrap();
+            result
+        });
+        drop(body);
+        assert_eq!(Some(io::ErrorKind::BrokenPipe), receiver.recv().unwrap());
+    }
+}
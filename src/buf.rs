@@ -0,0 +1,174 @@
+use bytes::{Buf, Bytes, BytesMut};
+use std::collections::VecDeque;
+
+type Insertions = Vec<(usize, Bytes)>;
+
+/// bytes inserter splices `Bytes` without copying their contents
+///
+/// the output is a sequence of slices of the origin and the insertions, each
+/// sharing its underlying buffer. several insertions at the same index are
+/// all kept, in the order they were added.
+pub struct BytesInserter {
+    origin: Bytes,
+    insertions: Insertions,
+}
+
+impl BytesInserter {
+    /// create a new inserter with the specified origin
+    pub fn new<O: Into<Bytes>>(origin: O) -> BytesInserter {
+        BytesInserter {
+            origin: origin.into(),
+            insertions: Insertions::new(),
+        }
+    }
+
+    /// insert the remaining contents of the buffer at the given origin index
+    ///
+    /// a `Bytes` source is taken without copying; other buffers are copied once
+    pub fn insert<B: Buf>(mut self, position: usize, mut source: B) -> Self {
+        let len = source.remaining();
+        self.insertions.push((position, source.copy_to_bytes(len)));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    pub fn execute(mut self) -> Segments {
+        // a stable sort keeps same-index insertions in the order they were added
+        self.insertions.sort_by_key(|&(position, _)| position);
+        let mut segments = VecDeque::with_capacity(2 * self.insertions.len() + 1);
+        let mut copied = 0;
+        for (position, source) in self.insertions {
+            let position = position.min(self.origin.len());
+            if position > copied {
+                segments.push_back(self.origin.slice(copied..position));
+            }
+            if !source.is_empty() {
+                segments.push_back(source);
+            }
+            copied = position;
+        }
+        if copied < self.origin.len() {
+            segments.push_back(self.origin.slice(copied..));
+        }
+        Segments { segments }
+    }
+}
+
+/// the output of a `BytesInserter`, as a `Buf` over shared segments
+#[derive(Debug, Clone, Default)]
+pub struct Segments {
+    segments: VecDeque<Bytes>,
+}
+
+impl Segments {
+    /// the remaining segments, in order
+    pub fn segments(&self) -> impl Iterator<Item = &Bytes> {
+        self.segments.iter()
+    }
+
+    /// join the segments into a single `Bytes`
+    ///
+    /// this only copies if there is more than one segment
+    pub fn into_bytes(mut self) -> Bytes {
+        match self.segments.len() {
+            0 => Bytes::new(),
+            1 => self.segments.pop_front().unwrap_or_default(),
+            _ => {
+                let mut out = BytesMut::with_capacity(self.remaining());
+                for segment in self.segments {
+                    out.extend_from_slice(&segment);
+                }
+                out.freeze()
+            }
+        }
+    }
+}
+
+impl Buf for Segments {
+    fn remaining(&self) -> usize {
+        self.segments.iter().map(Bytes::len).sum()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        self.segments.front().map_or(&[], |segment| &segment[..])
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while cnt > 0 {
+            let front = self
+                .segments
+                .front_mut()
+                .expect("cannot advance past the end of the segments");
+            if cnt < front.len() {
+                front.advance(cnt);
+                return;
+            }
+            cnt -= front.len();
+            self.segments.pop_front();
+        }
+    }
+
+    fn copy_to_bytes(&mut self, len: usize) -> Bytes {
+        match self.segments.front_mut() {
+            Some(front) if len <= front.len() => {
+                let out = front.split_to(len);
+                if front.is_empty() {
+                    self.segments.pop_front();
+                }
+                out
+            }
+            _ => {
+                assert!(len <= self.remaining(), "not enough bytes remaining");
+                let mut out = BytesMut::with_capacity(len);
+                while out.len() < len {
+                    let n = self.chunk().len().min(len - out.len());
+                    out.extend_from_slice(&self.chunk()[..n]);
+                    self.advance(n);
+                }
+                out.freeze()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Inserter;
+
+    #[test]
+    fn splices_without_copying() {
+        let origin = Bytes::from_static(b"alpha charlie");
+        let insertion = Bytes::from_static(b"bravo ");
+        let out = BytesInserter::new(origin.clone())
+            .insert(6, insertion.clone())
+            .insert(100, &b"!"[..])
+            .execute();
+        let segments: Vec<&Bytes> = out.segments().collect();
+        assert_eq!(4, segments.len());
+        assert_eq!(origin.as_ptr(), segments[0].as_ptr());
+        assert_eq!(insertion.as_ptr(), segments[1].as_ptr());
+        assert_eq!(&b"alpha bravo charlie!"[..], &out.into_bytes()[..]);
+    }
+
+    #[test]
+    fn reads_as_buf() {
+        let mut out = BytesInserter::new(&b"ac"[..])
+            .insert(1, &b"b"[..])
+            .execute();
+        assert_eq!(3, out.remaining());
+        assert_eq!(&b"ab"[..], &out.copy_to_bytes(2)[..]);
+        assert_eq!(b'c', out.get_u8());
+        assert!(!out.has_remaining());
+    }
+
+    #[test]
+    fn buf_sources_for_inserter() {
+        let mut dest = Vec::new();
+        Inserter::new(&b"alpha charlie"[..], &mut dest)
+            .insert_buf(6, Bytes::from_static(b"bravo ").chain(&b""[..]))
+            .execute()
+            .unwrap();
+        assert_eq!(&b"alpha bravo charlie"[..], &dest[..]);
+    }
+}
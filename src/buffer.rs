@@ -0,0 +1,126 @@
+//! the buffers output is copied through
+//!
+//! a buffer is zeroed when it's allocated, unless the nightly-only `read_buf`
+//! feature is enabled. then it's left uninitialized, and readers which support
+//! `read_buf`, such as files and sockets, read into it directly, so a large
+//! buffer costs nothing to set up.
+
+use std::io::{self, Read};
+#[cfg(all(unix, feature = "unix"))]
+use std::{fs::File, os::unix::fs::FileExt};
+#[cfg(feature = "read_buf")]
+use std::{io::BorrowedBuf, mem::MaybeUninit};
+
+#[cfg(not(feature = "read_buf"))]
+pub(crate) struct CopyBuffer {
+    bytes: Box<[u8]>,
+}
+
+#[cfg(not(feature = "read_buf"))]
+impl CopyBuffer {
+    pub(crate) fn new(len: usize) -> CopyBuffer {
+        CopyBuffer {
+            bytes: vec![0; len].into_boxed_slice(),
+        }
+    }
+
+    /// read once from the reader, returning what was read
+    pub(crate) fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<&[u8]> {
+        let len = reader.read(&mut self.bytes)?;
+        Ok(&self.bytes[..len])
+    }
+
+    /// read exactly this many bytes from the file at the offset
+    #[cfg(all(unix, feature = "unix"))]
+    pub(crate) fn read_exact_at(
+        &mut self,
+        file: &File,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<&[u8]> {
+        let bytes = &mut self.bytes[..len];
+        file.read_exact_at(bytes, offset)?;
+        Ok(bytes)
+    }
+}
+
+#[cfg(feature = "read_buf")]
+pub(crate) struct CopyBuffer {
+    bytes: Box<[MaybeUninit<u8>]>,
+    /// whether every byte has been initialized by some earlier read, so that
+    /// a reader without `read_buf` needn't zero it again
+    init: bool,
+}
+
+#[cfg(feature = "read_buf")]
+impl CopyBuffer {
+    pub(crate) fn new(len: usize) -> CopyBuffer {
+        CopyBuffer {
+            bytes: Box::new_uninit_slice(len),
+            init: false,
+        }
+    }
+
+    /// read once from the reader, returning what was read
+    pub(crate) fn read_from<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<&[u8]> {
+        let mut buf = borrow(&mut self.bytes, self.init);
+        reader.read_buf(buf.unfilled())?;
+        self.init |= buf.is_init();
+        Ok(buf.into_filled())
+    }
+
+    /// read exactly this many bytes from the file at the offset
+    #[cfg(all(unix, feature = "unix"))]
+    pub(crate) fn read_exact_at(
+        &mut self,
+        file: &File,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<&[u8]> {
+        let mut buf = borrow(&mut self.bytes[..len], self.init);
+        file.read_buf_exact_at(buf.unfilled(), offset)?;
+        Ok(buf.into_filled())
+    }
+}
+
+/// lend out the bytes, marked as initialized if they are
+#[cfg(feature = "read_buf")]
+fn borrow(bytes: &mut [MaybeUninit<u8>], init: bool) -> BorrowedBuf<'_> {
+    let mut buf = BorrowedBuf::from(bytes);
+    if init {
+        // SAFETY: every byte was initialized by an earlier read
+        unsafe { buf.set_init() };
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Faulty;
+
+    #[test]
+    fn reads_once() {
+        let mut buffer = CopyBuffer::new(4);
+        let mut reader = &b"abcdef"[..];
+        assert_eq!(b"abcd", buffer.read_from(&mut reader).unwrap());
+        assert_eq!(b"ef", buffer.read_from(&mut reader).unwrap());
+        assert!(buffer.read_from(&mut reader).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_from_plain_readers() {
+        // a reader which only implements `read`
+        let mut reader = Faulty::new(&b"abcdef"[..], []);
+        let mut buffer = CopyBuffer::new(4);
+        let mut out = Vec::new();
+        loop {
+            let read = buffer.read_from(&mut reader).unwrap();
+            if read.is_empty() {
+                break;
+            }
+            out.extend_from_slice(read);
+        }
+        assert_eq!(b"abcdef".to_vec(), out);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read},
+    ops::Range,
+    rc::Rc,
+};
+
+/// how much of the origin is read at a time when reading ahead for a copy
+const READ_AHEAD: usize = 8 * 1024;
+
+/// the origin, recording the ranges of it which are copied elsewhere
+///
+/// copies are read as insertions. one positioned after its range has been
+/// recorded by the time it's needed; one positioned before its range reads
+/// ahead in the origin, which is buffered until the engine reaches it.
+pub(crate) struct Captures<R> {
+    reader: R,
+    /// origin index of the next byte to read from the reader
+    position: usize,
+    /// bytes read ahead, not yet passed on
+    ahead: VecDeque<u8>,
+    exhausted: bool,
+    ranges: Vec<Range<usize>>,
+    captured: Vec<Vec<u8>>,
+}
+
+impl<R: Read> Captures<R> {
+    pub(crate) fn new(reader: R) -> Captures<R> {
+        Captures {
+            reader,
+            position: 0,
+            ahead: VecDeque::new(),
+            exhausted: false,
+            ranges: Vec::new(),
+            captured: Vec::new(),
+        }
+    }
+
+    /// record this range of the origin, returning its index
+    pub(crate) fn capture(&mut self, range: Range<usize>) -> usize {
+        self.ranges.push(range);
+        self.captured.push(Vec::new());
+        self.ranges.len() - 1
+    }
+
+    fn read_origin(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        let read = self.position..self.position + bytes_read;
+        for (range, captured) in self.ranges.iter().zip(self.captured.iter_mut()) {
+            let start = read.start.max(range.start);
+            let end = read.end.min(range.end);
+            if start < end {
+                captured.extend_from_slice(&buf[start - read.start..end - read.start]);
+            }
+        }
+        self.position = read.end;
+        self.exhausted = bytes_read == 0;
+        Ok(bytes_read)
+    }
+
+    fn read_ahead(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_AHEAD];
+        let bytes_read = self.read_origin(&mut chunk)?;
+        self.ahead.extend(&chunk[..bytes_read]);
+        Ok(())
+    }
+}
+
+/// the origin as read by the engine, through shared captures
+pub(crate) struct Tap<R>(pub(crate) Rc<RefCell<Captures<R>>>);
+
+impl<R: Read> Read for Tap<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut captures = self.0.borrow_mut();
+        if captures.ahead.is_empty() {
+            return captures.read_origin(buf);
+        }
+        let len = buf.len().min(captures.ahead.len());
+        for (to, from) in buf.iter_mut().zip(captures.ahead.drain(..len)) {
+            *to = from;
+        }
+        Ok(len)
+    }
+}
+
+/// a captured range, read as an insertion
+pub(crate) struct Copied<R> {
+    captures: Rc<RefCell<Captures<R>>>,
+    idx: usize,
+    offset: usize,
+}
+
+impl<R> Copied<R> {
+    pub(crate) fn new(captures: Rc<RefCell<Captures<R>>>, idx: usize) -> Copied<R> {
+        Copied {
+            captures,
+            idx,
+            offset: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Copied<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut captures = self.captures.borrow_mut();
+        loop {
+            let captured = &captures.captured[self.idx];
+            if self.offset < captured.len() {
+                let len = buf.len().min(captured.len() - self.offset);
+                buf[..len].copy_from_slice(&captured[self.offset..self.offset + len]);
+                self.offset += len;
+                return Ok(len);
+            }
+            if captures.exhausted || captures.position >= captures.ranges[self.idx].end {
+                return Ok(0);
+            }
+            captures.read_ahead()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_ahead_for_forward_copies() {
+        let captures = Rc::new(RefCell::new(Captures::new(&b"0123456789"[..])));
+        let idx = captures.borrow_mut().capture(6..9);
+        let mut copied = Vec::new();
+        Copied::new(captures.clone(), idx)
+            .read_to_end(&mut copied)
+            .unwrap();
+        assert_eq!(b"678".to_vec(), copied);
+
+        let mut origin = Vec::new();
+        Tap(captures).read_to_end(&mut origin).unwrap();
+        assert_eq!(b"0123456789".to_vec(), origin);
+    }
+
+    #[test]
+    fn stops_short_at_end_of_origin() {
+        let captures = Rc::new(RefCell::new(Captures::new(&b"0123"[..])));
+        let idx = captures.borrow_mut().capture(2..10);
+        let mut copied = Vec::new();
+        Copied::new(captures, idx).read_to_end(&mut copied).unwrap();
+        assert_eq!(b"23".to_vec(), copied);
+    }
+}
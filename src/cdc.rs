@@ -0,0 +1,191 @@
+//! content-defined chunking, for insertions aligned to the chunks of
+//! deduplicating storage
+//!
+//! chunks are cut with FastCDC's gear hash and normalized chunking. the gear
+//! table is generated from splitmix64 seeded with zero, so boundaries agree with
+//! other chunkers only when they use the same table and sizes.
+
+#[cfg(feature = "arbitrary")]
+use arbitrary::Arbitrary;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+use inserter::BUFFER_SIZE;
+
+const GEAR: [u64; 256] = gear();
+
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut idx = 0;
+    while idx < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[idx] = z ^ (z >> 31);
+        idx += 1;
+    }
+    table
+}
+
+/// how the origin is divided into content-defined chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl Chunker {
+    /// chunks of about this size, from a quarter of it to four times it
+    pub fn new(avg_size: usize) -> Chunker {
+        Chunker {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size.saturating_mul(4),
+        }
+    }
+
+    /// never cut chunks shorter than this, except the last
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// always cut chunks which reach this size
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// the offset at which each chunk ends, the last being the end of the origin
+    pub fn boundaries<R: Read>(&self, mut origin: R) -> io::Result<Vec<usize>> {
+        let mut cutter = Cutter::new(self);
+        let mut boundaries = Vec::new();
+        let mut offset = 0;
+        let mut buffer = [0_u8; BUFFER_SIZE];
+        loop {
+            let n = match origin.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            for &byte in &buffer[..n] {
+                offset += 1;
+                if cutter.feed(byte) {
+                    boundaries.push(offset);
+                }
+            }
+        }
+        if boundaries.last().map_or(offset > 0, |&last| last < offset) {
+            boundaries.push(offset);
+        }
+        Ok(boundaries)
+    }
+}
+
+/// cuts chunks byte by byte
+#[derive(Debug, Clone)]
+pub(crate) struct Cutter {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    small_mask: u64,
+    large_mask: u64,
+    len: usize,
+    hash: u64,
+}
+
+impl Cutter {
+    pub(crate) fn new(chunker: &Chunker) -> Cutter {
+        let max_size = chunker.max_size.max(1);
+        let bits = usize::BITS - chunker.avg_size.leading_zeros();
+        // below the average size, cuts need a bit more than usual to match;
+        // above it, a bit less
+        let mask = |bits: u32| match bits.min(64) {
+            0 => 0,
+            bits => !0 << (64 - bits),
+        };
+        Cutter {
+            min_size: chunker.min_size.min(max_size),
+            avg_size: chunker.avg_size,
+            max_size,
+            small_mask: mask(bits),
+            large_mask: mask(bits.saturating_sub(2)),
+            len: 0,
+            hash: 0,
+        }
+    }
+
+    /// whether a chunk ends with this byte
+    pub(crate) fn feed(&mut self, byte: u8) -> bool {
+        self.len += 1;
+        self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+        let cut = self.len >= self.max_size
+            || self.len >= self.min_size
+                && self.hash
+                    & if self.len < self.avg_size {
+                        self.small_mask
+                    } else {
+                        self.large_mask
+                    }
+                    == 0;
+        if cut {
+            self.len = 0;
+            self.hash = 0;
+        }
+        cut
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cuts_within_bounds() {
+        let data = noise(1 << 18, 1);
+        let chunker = Chunker::new(4096);
+        let boundaries = chunker.boundaries(data.as_slice()).unwrap();
+        assert_eq!(Some(&data.len()), boundaries.last());
+        let mut start = 0;
+        for &end in &boundaries[..boundaries.len() - 1] {
+            assert!((1024..=16384).contains(&(end - start)));
+            start = end;
+        }
+        let average = data.len() / boundaries.len();
+        assert!((2048..=8192).contains(&average), "average {}", average);
+        assert_eq!(Vec::<usize>::new(), chunker.boundaries(&b""[..]).unwrap());
+    }
+
+    #[test]
+    fn boundaries_follow_content() {
+        let data = noise(1 << 16, 2);
+        let mut shifted = noise(100, 3);
+        shifted.extend_from_slice(&data);
+        let chunker = Chunker::new(1024).min_size(64).max_size(1 << 16);
+        let before = chunker.boundaries(data.as_slice()).unwrap();
+        let after = chunker.boundaries(shifted.as_slice()).unwrap();
+        let kept = before
+            .iter()
+            .filter(|&&boundary| after.contains(&(boundary + 100)))
+            .count();
+        assert!(kept + 2 >= before.len(), "{} of {}", kept, before.len());
+    }
+}
@@ -0,0 +1,236 @@
+/// a checksum which can be computed incrementally, for `Inserter::checksum`
+pub trait Checksum {
+    /// the width of the checksum field in bytes, from 1 to 8: plans with any
+    /// other width fail with `InvalidInput`
+    fn width(&self) -> usize;
+
+    /// feed more data into the checksum
+    fn update(&mut self, data: &[u8]);
+
+    /// the checksum of all data fed so far
+    fn value(&self) -> u64;
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0_u32; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xEDB8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// incremental CRC-32 (ISO-HDLC), as used by PNG, zip and gzip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    /// start a new checksum
+    pub fn new() -> Crc32 {
+        Crc32 { state: 0xFFFF_FFFF }
+    }
+
+    /// feed more data into the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state =
+                CRC32_TABLE[((self.state ^ u32::from(byte)) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    /// the checksum of all data fed so far
+    pub fn finish(&self) -> u32 {
+        self.state ^ 0xFFFF_FFFF
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Checksum for Crc32 {
+    fn width(&self) -> usize {
+        4
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        Crc32::update(self, data)
+    }
+
+    fn value(&self) -> u64 {
+        u64::from(self.finish())
+    }
+}
+
+/// compute the CRC-32 of a byte slice in one go
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// incremental CRC-16/CCITT-FALSE, as used by many firmware images and XMODEM-style protocols
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crc16 {
+    state: u16,
+}
+
+impl Crc16 {
+    /// start a new checksum
+    pub fn new() -> Crc16 {
+        Crc16 { state: 0xFFFF }
+    }
+
+    /// the checksum of all data fed so far
+    pub fn finish(&self) -> u16 {
+        self.state
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Crc16 {
+        Crc16::new()
+    }
+}
+
+impl Checksum for Crc16 {
+    fn width(&self) -> usize {
+        2
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state ^= u16::from(byte) << 8;
+            for _ in 0..8 {
+                self.state = if self.state & 0x8000 != 0 {
+                    (self.state << 1) ^ 0x1021
+                } else {
+                    self.state << 1
+                };
+            }
+        }
+    }
+
+    fn value(&self) -> u64 {
+        u64::from(self.state)
+    }
+}
+
+/// incremental Adler-32, as used by zlib
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+const ADLER_MOD: u32 = 65521;
+
+impl Adler32 {
+    /// start a new checksum
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    /// the checksum of all data fed so far
+    pub fn finish(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+impl Checksum for Adler32 {
+    fn width(&self) -> usize {
+        4
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        // the sums can't overflow a u32 within this many bytes
+        for chunk in data.chunks(5552) {
+            for &byte in chunk {
+                self.a += u32::from(byte);
+                self.b += self.a;
+            }
+            self.a %= ADLER_MOD;
+            self.b %= ADLER_MOD;
+        }
+    }
+
+    fn value(&self) -> u64 {
+        u64::from(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32(b""));
+    }
+
+    #[test]
+    fn incremental_matches_oneshot() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc32(b"123456789"), crc.finish());
+    }
+
+    #[test]
+    fn rejects_unsupported_widths() {
+        struct Wide(usize);
+        impl Checksum for Wide {
+            fn width(&self) -> usize {
+                self.0
+            }
+            fn update(&mut self, _data: &[u8]) {}
+            fn value(&self) -> u64 {
+                0
+            }
+        }
+        for width in [0, 9] {
+            let err = ::PlanBuilder::new()
+                .checksum(0, ::inserter::Endian::Big, 0..4, Wide(width))
+                .execute_to_vec(&[0; 16])
+                .unwrap_err();
+            assert_eq!(::std::io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn other_check_values() {
+        let mut crc = Crc16::new();
+        Checksum::update(&mut crc, b"123456789");
+        assert_eq!(0x29B1, crc.finish());
+        let mut adler = Adler32::new();
+        adler.update(b"Wikipedia");
+        assert_eq!(0x11E6_0398, adler.finish());
+        let mut adler = Adler32::new();
+        adler.update(&[0xFF; 100_000]);
+        assert_eq!(0x149A_302C_u64, Checksum::value(&adler));
+    }
+}
@@ -0,0 +1,255 @@
+use inserter::Inserter;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// default size of the chunks emitted by a `ChunkedInserter`
+pub const CHUNK_SIZE: usize = 8 * 1024;
+
+/// longest chunk-size or trailer line accepted from the origin
+const MAX_LINE: u64 = 8 * 1024;
+
+type Insertions<'i> = Vec<(usize, Box<dyn 'i + Read>)>;
+
+/// inserts into a `Transfer-Encoding: chunked` body at decoded body offsets
+///
+/// the origin is the body only, starting at the first chunk-size line; headers
+/// are left to the caller. the output is re-chunked, so chunk boundaries and
+/// extensions of the origin are not preserved, but its trailer fields are.
+pub struct ChunkedInserter<'i, R, W> {
+    origin: R,
+    target: W,
+    chunk_size: usize,
+    insertions: Insertions<'i>,
+}
+
+impl<'i, R, W> ChunkedInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new inserter from a chunked origin body to a chunked target body
+    pub fn new(origin: R, target: W) -> ChunkedInserter<'i, R, W> {
+        ChunkedInserter {
+            origin,
+            target,
+            chunk_size: CHUNK_SIZE,
+            insertions: Vec::new(),
+        }
+    }
+
+    /// set the largest chunk written to the output
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// insert the source document into the body at the given decoded origin index
+    pub fn insert<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
+        self.insertions.push((position, Box::new(source)));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    ///
+    /// fails with `InvalidData` on malformed framing and `UnexpectedEof` if the
+    /// origin ends before its last chunk
+    pub fn execute(self) -> io::Result<()> {
+        let mut decoder = Decoder {
+            origin: BufReader::new(self.origin),
+            remaining: 0,
+            state: State::Size,
+        };
+        let mut encoder = Encoder {
+            target: self.target,
+            buffer: Vec::with_capacity(self.chunk_size),
+            chunk_size: self.chunk_size,
+        };
+        let mut inserter = Inserter::new(&mut decoder, &mut encoder);
+        for (position, source) in self.insertions {
+            inserter = inserter.insert(position, source);
+        }
+        inserter.execute()?;
+        let trailers = match decoder.state {
+            State::Done(trailers) => trailers,
+            _ => return Err(truncated()),
+        };
+        encoder.finish(&trailers)
+    }
+}
+
+enum State {
+    /// expecting a chunk-size line
+    Size,
+    /// within the data of a chunk
+    Data,
+    /// past the last chunk, holding its trailer section
+    Done(Vec<u8>),
+}
+
+/// reads the decoded body of a chunked stream
+struct Decoder<R> {
+    origin: BufReader<R>,
+    remaining: u64,
+    state: State,
+}
+
+impl<R: Read> Decoder<R> {
+    fn line(&mut self, line: &mut Vec<u8>) -> io::Result<()> {
+        line.clear();
+        self.origin
+            .by_ref()
+            .take(MAX_LINE)
+            .read_until(b'\n', line)?;
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            Ok(())
+        } else if line.len() as u64 == MAX_LINE {
+            Err(invalid("chunk line too long"))
+        } else {
+            Err(truncated())
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut line = Vec::new();
+        self.line(&mut line)?;
+        let size = line.split(|&b| b == b';').next().unwrap_or_default();
+        let size = String::from_utf8_lossy(size);
+        let size = u64::from_str_radix(size.trim(), 16).map_err(|_| invalid("bad chunk size"))?;
+        if size > 0 {
+            self.remaining = size;
+            self.state = State::Data;
+            return Ok(());
+        }
+        let mut trailers = Vec::new();
+        loop {
+            self.line(&mut line)?;
+            if line.is_empty() {
+                break;
+            }
+            trailers.extend_from_slice(&line);
+            trailers.extend_from_slice(b"\r\n");
+        }
+        self.state = State::Done(trailers);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Done(_) => return Ok(0),
+                State::Size => self.next_chunk()?,
+                State::Data => break,
+            }
+        }
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let n = self.origin.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(truncated());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 {
+            let mut crlf = [0_u8; 2];
+            self.origin.read_exact(&mut crlf)?;
+            if &crlf != b"\r\n" {
+                return Err(invalid("chunk data not followed by CRLF"));
+            }
+            self.state = State::Size;
+        }
+        Ok(n)
+    }
+}
+
+/// writes its input as a chunked body
+struct Encoder<W> {
+    target: W,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    fn chunk(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            write!(self.target, "{:x}\r\n", self.buffer.len())?;
+            self.target.write_all(&self.buffer)?;
+            self.target.write_all(b"\r\n")?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, trailers: &[u8]) -> io::Result<()> {
+        self.chunk()?;
+        self.target.write_all(b"0\r\n")?;
+        self.target.write_all(trailers)?;
+        self.target.write_all(b"\r\n")?;
+        self.target.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == self.chunk_size {
+            self.chunk()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.chunk()?;
+        self.target.flush()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunked body")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(origin: &[u8], chunk_size: usize) -> io::Result<Vec<u8>> {
+        let mut dest = Vec::new();
+        ChunkedInserter::new(origin, &mut dest)
+            .chunk_size(chunk_size)
+            .insert(5, &b", there"[..])
+            .insert(100, &b"!"[..])
+            .execute()?;
+        Ok(dest)
+    }
+
+    #[test]
+    fn inserts_at_body_offsets() {
+        let origin = b"3\r\nhel\r\n4;ext=1\r\nlo w\r\n4\r\norld\r\n0\r\n\r\n";
+        let out = run(origin, CHUNK_SIZE).unwrap();
+        assert_eq!(&b"13\r\nhello, there world!\r\n0\r\n\r\n"[..], &out[..]);
+    }
+
+    #[test]
+    fn splits_chunks_and_keeps_trailers() {
+        let origin = b"B\r\nhello world\r\n0\r\nExpires: never\r\n\r\n";
+        let out = run(origin, 8).unwrap();
+        assert_eq!(
+            &b"8\r\nhello, t\r\n8\r\nhere wor\r\n3\r\nld!\r\n0\r\nExpires: never\r\n\r\n"[..],
+            &out[..]
+        );
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        let err = run(b"3\r\nhel\r\n", CHUNK_SIZE).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        let err = run(b"zz\r\nhel\r\n0\r\n\r\n", CHUNK_SIZE).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = run(b"3\r\nhello\r\n0\r\n\r\n", CHUNK_SIZE).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+/// the comment syntax of a language
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStyle {
+    /// comments run from this prefix to the end of the line, like `//`
+    Line(&'static str),
+    /// comments run between these delimiters, like `/*` and `*/`
+    Block(&'static str, &'static str),
+}
+
+impl CommentStyle {
+    /// the comment style of a language, by name or file extension, ignoring case
+    pub fn for_language(name: &str) -> Option<CommentStyle> {
+        Some(match &*name.to_ascii_lowercase() {
+            "rust" | "rs" | "c" | "h" | "cpp" | "c++" | "cc" | "hpp" | "java" | "javascript"
+            | "js" | "typescript" | "ts" | "go" | "swift" | "kotlin" | "kt" | "scala"
+            | "csharp" | "c#" | "cs" | "dart" | "proto" => CommentStyle::Line("//"),
+            "python" | "py" | "shell" | "sh" | "bash" | "zsh" | "ruby" | "rb" | "perl" | "pl"
+            | "r" | "yaml" | "yml" | "toml" | "make" | "makefile" | "mk" | "dockerfile"
+            | "cmake" | "nix" => CommentStyle::Line("#"),
+            "sql" | "lua" | "haskell" | "hs" | "ada" | "elm" => CommentStyle::Line("--"),
+            "lisp" | "clojure" | "clj" | "scheme" | "ini" => CommentStyle::Line(";"),
+            "tex" | "latex" | "erlang" | "erl" => CommentStyle::Line("%"),
+            "html" | "htm" | "xml" | "svg" | "markdown" | "md" | "vue" => {
+                CommentStyle::Block("<!--", "-->")
+            }
+            "css" | "scss" | "less" => CommentStyle::Block("/*", "*/"),
+            _ => return None,
+        })
+    }
+
+    /// the comment style of a file, by its extension or else its name
+    pub fn for_path<P: AsRef<Path>>(path: P) -> Option<CommentStyle> {
+        let path = path.as_ref();
+        path.extension()
+            .or_else(|| path.file_name())
+            .and_then(|name| name.to_str())
+            .and_then(CommentStyle::for_language)
+    }
+
+    /// the text as a comment of its own, ending with a newline
+    ///
+    /// every line of a line comment gets the prefix. a single line is
+    /// wrapped in block delimiters on the same line, while several lines
+    /// get the delimiters on lines of their own.
+    pub fn wrap(self, text: &str) -> String {
+        match self {
+            CommentStyle::Line(_) => self.body(text),
+            CommentStyle::Block(open, close) => {
+                let text = text.strip_suffix('\n').unwrap_or(text);
+                if text.contains('\n') {
+                    format!("{}\n{}\n{}\n", open, text, close)
+                } else {
+                    format!("{} {} {}\n", open, text, close)
+                }
+            }
+        }
+    }
+
+    /// the text as lines to go inside an existing comment, ending with a newline
+    ///
+    /// for line comments this is the same as `wrap`; block comment lines
+    /// are left as they are
+    pub fn body(self, text: &str) -> String {
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        let mut out = String::with_capacity(text.len() + text.len() / 8);
+        for line in text.split('\n') {
+            if let CommentStyle::Line(prefix) = self {
+                out.push_str(prefix);
+                if !line.is_empty() {
+                    out.push(' ');
+                }
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// the origin index at which lines can be added to the end of the
+    /// comment the origin starts with, if it starts with one
+    ///
+    /// leading blank lines are skipped. that's just past the last of a run
+    /// of line comments, or the start of the line closing a block comment.
+    /// a block comment closed on the line it opens isn't extended.
+    pub fn find_leading<R: Read>(self, origin: R) -> io::Result<Option<usize>> {
+        let mut origin = BufReader::new(origin);
+        let mut line = Vec::new();
+        let mut offset = 0;
+        let mut found = None;
+        let mut in_block = false;
+        loop {
+            line.clear();
+            let len = origin.read_until(b'\n', &mut line)?;
+            if len == 0 {
+                return Ok(found);
+            }
+            let text = line.trim_ascii_start();
+            match self {
+                CommentStyle::Line(prefix) => {
+                    if text.starts_with(prefix.as_bytes()) {
+                        found = Some(offset + len);
+                    } else if found.is_some() || !text.is_empty() {
+                        return Ok(found);
+                    }
+                }
+                CommentStyle::Block(open, close) => {
+                    if in_block {
+                        if contains(&line, close) {
+                            return Ok(Some(offset));
+                        }
+                    } else if let Some(rest) = text.strip_prefix(open.as_bytes()) {
+                        if contains(rest, close) {
+                            return Ok(None);
+                        }
+                        in_block = true;
+                    } else if !text.is_empty() {
+                        return Ok(None);
+                    }
+                }
+            }
+            offset += len;
+        }
+    }
+}
+
+fn contains(text: &[u8], needle: &str) -> bool {
+    text.windows(needle.len())
+        .any(|window| window == needle.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Inserter;
+
+    #[test]
+    fn looks_up_languages() {
+        assert_eq!(
+            Some(CommentStyle::Line("//")),
+            CommentStyle::for_language("Rust")
+        );
+        assert_eq!(
+            Some(CommentStyle::Line("#")),
+            CommentStyle::for_path("src/main.py")
+        );
+        assert_eq!(
+            Some(CommentStyle::Line("#")),
+            CommentStyle::for_path("Makefile")
+        );
+        assert_eq!(
+            Some(CommentStyle::Block("<!--", "-->")),
+            CommentStyle::for_path("index.html")
+        );
+        assert_eq!(None, CommentStyle::for_language("cobol"));
+    }
+
+    #[test]
+    fn wraps() {
+        let text = "generated\n\ndo not edit\n";
+        assert_eq!(
+            "// generated\n//\n// do not edit\n",
+            CommentStyle::Line("//").wrap(text)
+        );
+        assert_eq!(
+            "<!--\ngenerated\n\ndo not edit\n-->\n",
+            CommentStyle::Block("<!--", "-->").wrap(text)
+        );
+        assert_eq!(
+            "/* stamp */\n",
+            CommentStyle::Block("/*", "*/").wrap("stamp")
+        );
+    }
+
+    #[test]
+    fn extends_leading_comments() {
+        let rust = "\n// Copyright\n// MIT\nfn main() {}\n";
+        let style = CommentStyle::Line("//");
+        let position = style.find_leading(rust.as_bytes()).unwrap().unwrap();
+        let out = Inserter::new(rust.as_bytes(), Vec::new())
+            .insert(position, style.body("built 2020-01-01"))
+            .execute_to_string()
+            .unwrap();
+        assert_eq!(
+            "\n// Copyright\n// MIT\n// built 2020-01-01\nfn main() {}\n",
+            out
+        );
+        assert_eq!(None, style.find_leading(&b"fn main() {}"[..]).unwrap());
+
+        let css = CommentStyle::Block("/*", "*/");
+        assert_eq!(
+            Some(10),
+            css.find_leading(&b"/* a\n   b\n */\nbody {}"[..]).unwrap()
+        );
+        assert_eq!(None, css.find_leading(&b"/* a */\nbody {}"[..]).unwrap());
+    }
+}
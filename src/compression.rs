@@ -0,0 +1,293 @@
+#[cfg(feature = "flate2")]
+use flate2::{read::MultiGzDecoder, Compression, GzBuilder};
+use inserter::Inserter;
+use source::{InsertSource, IntoInsertSource};
+use std::io::{self, Read, Write};
+#[cfg(feature = "zstd")]
+use std::io::{BufRead, BufReader};
+#[cfg(feature = "zstd")]
+use zstd::stream::{read::Decoder, write::Encoder};
+
+type Insertions<'i> = Vec<(usize, Box<dyn 'i + Read>)>;
+
+/// apply the insertions, decompressing the origin and compressing the output
+fn splice<'i, R, W>(origin: R, target: W, insertions: Insertions<'i>) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut inserter = Inserter::new(origin, target);
+    for (position, source) in insertions {
+        inserter = inserter.insert(position, source);
+    }
+    inserter.execute()
+}
+
+/// a gzip-compressed insertion source, decompressed as the inserter reads it
+#[cfg(feature = "flate2")]
+pub fn gzip_source<R: Read>(source: R) -> MultiGzDecoder<R> {
+    MultiGzDecoder::new(source)
+}
+
+#[cfg(feature = "flate2")]
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for MultiGzDecoder<R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+/// a zstd-compressed insertion source, decompressed as the inserter reads it
+#[cfg(feature = "zstd")]
+pub fn zstd_source<R: Read>(source: R) -> io::Result<Decoder<'static, BufReader<R>>> {
+    Decoder::new(source)
+}
+
+#[cfg(feature = "zstd")]
+impl<'i, R: 'i + BufRead> IntoInsertSource<'i> for Decoder<'static, R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+/// inserts into a gzip stream at uncompressed offsets, recompressing the output
+///
+/// concatenated gzip members are decompressed as one stream. the file name,
+/// comment and modification time of the first member's header are carried over.
+#[cfg(feature = "flate2")]
+pub struct GzipInserter<'i, R, W> {
+    origin: R,
+    target: W,
+    level: Compression,
+    insertions: Insertions<'i>,
+}
+
+#[cfg(feature = "flate2")]
+impl<'i, R, W> GzipInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new inserter from a gzip-compressed origin to a target which will be gzip-compressed
+    pub fn new(origin: R, target: W) -> GzipInserter<'i, R, W> {
+        GzipInserter {
+            origin,
+            target,
+            level: Compression::default(),
+            insertions: Vec::new(),
+        }
+    }
+
+    /// set the compression level of the output, from 0 (none) to 9 (best)
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Compression::new(level.min(9));
+        self
+    }
+
+    /// insert the source document into the output document at the given uncompressed origin index
+    pub fn insert<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
+        self.insertions.push((position, Box::new(source)));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    pub fn execute(self) -> io::Result<()> {
+        let origin = MultiGzDecoder::new(self.origin);
+        let mut builder = GzBuilder::new();
+        if let Some(header) = origin.header() {
+            builder = builder.mtime(header.mtime());
+            if let Some(filename) = header.filename() {
+                builder = builder.filename(filename);
+            }
+            if let Some(comment) = header.comment() {
+                builder = builder.comment(comment);
+            }
+        }
+        let mut target = builder.write(self.target, self.level);
+        splice(origin, &mut target, self.insertions)?;
+        target.finish()?;
+        Ok(())
+    }
+}
+
+/// inserts into a zstd stream at uncompressed offsets, recompressing the output
+///
+/// concatenated zstd frames are decompressed as one stream.
+#[cfg(feature = "zstd")]
+pub struct ZstdInserter<'i, R, W> {
+    origin: R,
+    target: W,
+    level: i32,
+    insertions: Insertions<'i>,
+}
+
+#[cfg(feature = "zstd")]
+impl<'i, R, W> ZstdInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new inserter from a zstd-compressed origin to a target which will be zstd-compressed
+    pub fn new(origin: R, target: W) -> ZstdInserter<'i, R, W> {
+        ZstdInserter {
+            origin,
+            target,
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            insertions: Vec::new(),
+        }
+    }
+
+    /// set the compression level of the output
+    ///
+    /// levels run from 1 to 22, with negative levels trading ratio for speed;
+    /// 0 selects the library default
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// insert the source document into the output document at the given uncompressed origin index
+    pub fn insert<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
+        self.insertions.push((position, Box::new(source)));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    pub fn execute(self) -> io::Result<()> {
+        let origin = Decoder::new(self.origin)?;
+        let mut target = Encoder::new(self.target, self.level)?;
+        splice(origin, &mut target, self.insertions)?;
+        target.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "flate2")]
+    mod gzip {
+        use super::*;
+        use flate2::read::GzDecoder;
+
+        fn compress(data: &[u8]) -> Vec<u8> {
+            let mut encoder = GzBuilder::new()
+                .filename("log.txt")
+                .mtime(1_234)
+                .write(Vec::new(), Compression::fast());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        }
+
+        #[test]
+        fn inserts_at_uncompressed_offsets() {
+            let origin = compress(b"first line\nthird line\n");
+            let mut dest = Vec::new();
+            GzipInserter::new(origin.as_slice(), &mut dest)
+                .insert(11, &b"second line\n"[..])
+                .insert(1000, &b"appended\n"[..])
+                .execute()
+                .unwrap();
+
+            let mut decoder = GzDecoder::new(dest.as_slice());
+            let mut text = String::new();
+            decoder.read_to_string(&mut text).unwrap();
+            assert_eq!("first line\nsecond line\nthird line\nappended\n", text);
+            let header = decoder.header().unwrap();
+            assert_eq!(Some(&b"log.txt"[..]), header.filename());
+            assert_eq!(1_234, header.mtime());
+        }
+
+        #[test]
+        fn reads_concatenated_members() {
+            let mut origin = compress(b"one ");
+            origin.extend(compress(b"two"));
+            let mut dest = Vec::new();
+            GzipInserter::new(origin.as_slice(), &mut dest)
+                .level(9)
+                .insert(4, &b"and "[..])
+                .execute()
+                .unwrap();
+
+            let mut text = String::new();
+            GzDecoder::new(dest.as_slice())
+                .read_to_string(&mut text)
+                .unwrap();
+            assert_eq!("one and two", text);
+        }
+
+        #[test]
+        fn rejects_uncompressed_origin() {
+            let mut dest = Vec::new();
+            let result = GzipInserter::new(&b"plain text"[..], &mut dest)
+                .insert(0, &b"x"[..])
+                .execute();
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn decompresses_source() {
+            let block = compress(b"boilerplate ");
+            let mut dest = Vec::new();
+            Inserter::new(&b"head tail"[..], &mut dest)
+                .insert(5, gzip_source(block.as_slice()))
+                .execute()
+                .unwrap();
+            assert_eq!(b"head boilerplate tail".to_vec(), dest);
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    mod zstd {
+        use super::*;
+
+        fn decompress(data: &[u8]) -> Vec<u8> {
+            ::zstd::decode_all(data).unwrap()
+        }
+
+        #[test]
+        fn inserts_at_uncompressed_offsets() {
+            let origin = ::zstd::encode_all(&b"alpha gamma"[..], 1).unwrap();
+            let mut dest = Vec::new();
+            ZstdInserter::new(origin.as_slice(), &mut dest)
+                .level(19)
+                .insert(6, &b"beta "[..])
+                .insert(100, &b"!"[..])
+                .execute()
+                .unwrap();
+            assert_eq!(b"alpha beta gamma!".to_vec(), decompress(&dest));
+        }
+
+        #[test]
+        fn reads_concatenated_frames() {
+            let mut origin = ::zstd::encode_all(&b"one "[..], 3).unwrap();
+            origin.extend(::zstd::encode_all(&b"two"[..], 3).unwrap());
+            let mut dest = Vec::new();
+            ZstdInserter::new(origin.as_slice(), &mut dest)
+                .insert(4, &b"and "[..])
+                .execute()
+                .unwrap();
+            assert_eq!(b"one and two".to_vec(), decompress(&dest));
+        }
+
+        #[test]
+        fn rejects_uncompressed_origin() {
+            let mut dest = Vec::new();
+            let result = ZstdInserter::new(&b"plain text"[..], &mut dest)
+                .insert(0, &b"x"[..])
+                .execute();
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn decompresses_source() {
+            let block = ::zstd::encode_all(&b"boilerplate "[..], 3).unwrap();
+            let mut dest = Vec::new();
+            Inserter::new(&b"head tail"[..], &mut dest)
+                .insert(5, zstd_source(block.as_slice()).unwrap())
+                .execute()
+                .unwrap();
+            assert_eq!(b"head boilerplate tail".to_vec(), dest);
+        }
+    }
+}
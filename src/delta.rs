@@ -0,0 +1,241 @@
+//! deltas of the output against the origin, in librsync's format
+//!
+//! rather than writing the whole output, a delta copies the unchanged runs of
+//! the origin and carries only what's new: `rdiff patch origin delta output`
+//! rebuilds the output, as does `patch`. the runs are known from the plan, so
+//! there are no signatures or rolling checksums involved.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// the magic number starting every delta
+pub const MAGIC: [u8; 4] = [0x72, 0x73, 0x02, 0x36];
+
+const END: u8 = 0x00;
+/// literals of up to 64 bytes have their length as their opcode
+const LITERAL_N1: u8 = 0x41;
+const COPY_N1_N1: u8 = 0x45;
+const COPY_N8_N8: u8 = 0x54;
+
+/// literal bytes are sent once this many have accumulated
+const LITERAL_LIMIT: usize = 64 * 1024;
+
+/// the index among 1, 2, 4 and 8 bytes of the narrowest encoding of the value
+fn width(value: u64) -> u8 {
+    match value {
+        0..=0xff => 0,
+        0x100..=0xffff => 1,
+        0x1_0000..=0xffff_ffff => 2,
+        _ => 3,
+    }
+}
+
+fn write_int<W: Write>(target: &mut W, value: u64, width: u8) -> io::Result<()> {
+    target.write_all(&value.to_be_bytes()[8 - (1 << width)..])
+}
+
+/// encodes the output as copies from the origin and literal bytes, merging
+/// adjacent copies
+#[derive(Debug, Default)]
+pub(crate) struct Delta {
+    copy: Option<(u64, u64)>,
+    literal: Vec<u8>,
+}
+
+impl Delta {
+    /// start a delta, writing the magic number
+    pub(crate) fn new<W: Write>(target: &mut W) -> io::Result<Delta> {
+        target.write_all(&MAGIC)?;
+        Ok(Delta::default())
+    }
+
+    /// copy this run of the origin
+    pub(crate) fn copy<W: Write>(
+        &mut self,
+        target: &mut W,
+        position: usize,
+        len: usize,
+    ) -> io::Result<()> {
+        let (position, len) = (position as u64, len as u64);
+        self.flush_literal(target)?;
+        match self.copy {
+            Some((start, ref mut copied)) if start + *copied == position => *copied += len,
+            _ => {
+                self.flush_copy(target)?;
+                self.copy = Some((position, len));
+            }
+        }
+        Ok(())
+    }
+
+    /// send these bytes as they are
+    pub(crate) fn literal<W: Write>(&mut self, target: &mut W, bytes: &[u8]) -> io::Result<()> {
+        self.flush_copy(target)?;
+        self.literal.extend_from_slice(bytes);
+        if self.literal.len() >= LITERAL_LIMIT {
+            self.flush_literal(target)?;
+        }
+        Ok(())
+    }
+
+    /// write the last commands and the end of the delta
+    pub(crate) fn finish<W: Write>(mut self, target: &mut W) -> io::Result<()> {
+        self.flush_copy(target)?;
+        self.flush_literal(target)?;
+        target.write_all(&[END])
+    }
+
+    fn flush_copy<W: Write>(&mut self, target: &mut W) -> io::Result<()> {
+        if let Some((position, len)) = self.copy.take() {
+            let (position_width, len_width) = (width(position), width(len));
+            target.write_all(&[COPY_N1_N1 + 4 * position_width + len_width])?;
+            write_int(target, position, position_width)?;
+            write_int(target, len, len_width)?;
+        }
+        Ok(())
+    }
+
+    fn flush_literal<W: Write>(&mut self, target: &mut W) -> io::Result<()> {
+        if self.literal.is_empty() {
+            return Ok(());
+        }
+        let len = self.literal.len() as u64;
+        if len <= 64 {
+            target.write_all(&[len as u8])?;
+        } else {
+            let len_width = width(len);
+            target.write_all(&[LITERAL_N1 + len_width])?;
+            write_int(target, len, len_width)?;
+        }
+        target.write_all(&self.literal)?;
+        self.literal.clear();
+        Ok(())
+    }
+}
+
+fn read_int<R: Read>(delta: &mut R, width: u8) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    delta.read_exact(&mut bytes[8 - (1 << width)..])?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// rebuild the output by applying a delta to the origin it was made against
+///
+/// fails with `InvalidData` if the delta is malformed, and with
+/// `UnexpectedEof` if it's truncated or copies past the end of the origin
+pub fn patch<B, D, W>(mut origin: B, mut delta: D, mut target: W) -> io::Result<()>
+where
+    B: Read + Seek,
+    D: Read,
+    W: Write,
+{
+    let malformed = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut magic = [0; 4];
+    delta.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(malformed("not a delta"));
+    }
+    loop {
+        let mut opcode = [0];
+        delta.read_exact(&mut opcode)?;
+        match opcode[0] {
+            END => return target.flush(),
+            len @ 1..=64 => {
+                io::copy(&mut (&mut delta).take(u64::from(len)), &mut target)?;
+            }
+            op @ LITERAL_N1..=0x44 => {
+                let len = read_int(&mut delta, op - LITERAL_N1)?;
+                let copied = io::copy(&mut (&mut delta).take(len), &mut target)?;
+                if copied < len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+            op @ COPY_N1_N1..=COPY_N8_N8 => {
+                let position = read_int(&mut delta, (op - COPY_N1_N1) / 4)?;
+                let len = read_int(&mut delta, (op - COPY_N1_N1) % 4)?;
+                origin.seek(SeekFrom::Start(position))?;
+                let copied = io::copy(&mut (&mut origin).take(len), &mut target)?;
+                if copied < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "delta copies past the end of the origin",
+                    ));
+                }
+            }
+            _ => return Err(malformed("unknown delta command")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use {Inserter, Operation};
+
+    fn round_trip(origin: &[u8], delta: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        patch(Cursor::new(origin), delta, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn encodes_commands() {
+        let mut delta = Vec::new();
+        Inserter::new(&b"alpha charlie"[..], &mut delta)
+            .insert(6, "bravo ")
+            .execute_delta()
+            .unwrap();
+        let mut expected = MAGIC.to_vec();
+        expected.extend_from_slice(&[COPY_N1_N1, 0, 6, 6]);
+        expected.extend_from_slice(b"bravo ");
+        expected.extend_from_slice(&[COPY_N1_N1, 6, 7, END]);
+        assert_eq!(expected, delta);
+        assert_eq!(
+            b"alpha bravo charlie".to_vec(),
+            round_trip(b"alpha charlie", &delta)
+        );
+    }
+
+    #[test]
+    fn copies_only_unchanged_runs() {
+        let origin: Vec<u8> = (0..200_000_u32).map(|idx| (idx % 253) as u8).collect();
+        let mut delta = Vec::new();
+        Inserter::new(origin.as_slice(), &mut delta)
+            .insert(100_000, vec![1; 1000])
+            .overwrite(150_000, b"patched")
+            .operation(Operation::Delete(160_000..170_000))
+            .execute_delta()
+            .unwrap();
+        assert!(delta.len() < 1100, "{} byte delta", delta.len());
+
+        let expected = Inserter::new(origin.as_slice(), Vec::new())
+            .insert(100_000, vec![1; 1000])
+            .overwrite(150_000, b"patched")
+            .operation(Operation::Delete(160_000..170_000))
+            .execute_to_vec()
+            .unwrap();
+        assert_eq!(expected, round_trip(&origin, &delta));
+    }
+
+    #[test]
+    fn sends_replaced_text_as_literals() {
+        let mut delta = Vec::new();
+        Inserter::new(&b"one two one"[..], &mut delta)
+            .operation(Operation::ReplaceAll(b"one".to_vec(), b"1".to_vec()))
+            .execute_delta()
+            .unwrap();
+        assert_eq!(b"1 two 1".to_vec(), round_trip(b"one two one", &delta));
+    }
+
+    #[test]
+    fn rejects_malformed_deltas() {
+        let mut out = Vec::new();
+        let err = patch(Cursor::new(b"abc"), &b"rs\x02\x37\x00"[..], &mut out).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let delta = [0x72, 0x73, 0x02, 0x36, COPY_N1_N1, 1, 5, END];
+        let err = patch(Cursor::new(b"abc"), &delta[..], &mut out).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+        let err = patch(Cursor::new(b"abc"), &MAGIC[..], &mut out).unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+}
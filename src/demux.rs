@@ -0,0 +1,128 @@
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+
+/// a writer which routes ranges of its output to different writers
+///
+/// ranges are offsets into everything written to the demux; bytes outside
+/// every range go to the default writer. where ranges overlap, the one routed
+/// last wins.
+pub struct Demux<W> {
+    default: W,
+    routes: Vec<(Range<u64>, W)>,
+    position: u64,
+}
+
+impl<W: Write> Demux<W> {
+    /// create a demux sending everything to the default writer
+    pub fn new(default: W) -> Demux<W> {
+        Demux {
+            default,
+            routes: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// send the bytes in this range of the output to the writer
+    pub fn route(mut self, range: Range<u64>, writer: W) -> Self {
+        self.routes.push((range, writer));
+        self
+    }
+
+    /// how many bytes have been written so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// recover the default writer and the routed writers, in the order routed
+    pub fn into_inner(self) -> (W, Vec<W>) {
+        (
+            self.default,
+            self.routes.into_iter().map(|(_, writer)| writer).collect(),
+        )
+    }
+}
+
+impl<W: Write> Write for Demux<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let position = self.position;
+        let route = self
+            .routes
+            .iter()
+            .rposition(|(range, _)| range.contains(&position));
+        // the current writer's run ends where its range does, or where a
+        // route taking precedence over it begins
+        let later = route.map_or(0, |index| index + 1);
+        let end = self.routes[later..]
+            .iter()
+            .map(|(range, _)| range.start)
+            .filter(|&start| start > position)
+            .chain(route.map(|index| self.routes[index].0.end))
+            .min()
+            .unwrap_or(u64::MAX);
+        let len = buf
+            .len()
+            .min((end - position).min(usize::MAX as u64) as usize);
+        let writer = match route {
+            Some(index) => &mut self.routes[index].1,
+            None => &mut self.default,
+        };
+        let written = writer.write(&buf[..len])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.default.flush()?;
+        for (_, writer) in self.routes.iter_mut() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Inserter;
+
+    #[test]
+    fn splits_header_from_body() {
+        let mut demux = Demux::new(Vec::new()).route(0..8, Vec::new());
+        Inserter::new(&b"HEADbody"[..], &mut demux)
+            .insert(4, "ERxx")
+            .insert(8, "!")
+            .execute()
+            .unwrap();
+        let (body, routed) = demux.into_inner();
+        assert_eq!(b"HEADERxx".to_vec(), routed[0]);
+        assert_eq!(b"body!".to_vec(), body);
+    }
+
+    #[test]
+    fn gaps_go_to_default() {
+        let mut demux = Demux::new(Vec::new())
+            .route(2..4, Vec::new())
+            .route(6..8, Vec::new());
+        demux.write_all(b"0123456789").unwrap();
+        assert_eq!(10, demux.position());
+        let (default, routed) = demux.into_inner();
+        assert_eq!(b"014589".to_vec(), default);
+        assert_eq!(vec![b"23".to_vec(), b"67".to_vec()], routed);
+    }
+
+    #[test]
+    fn later_routes_win() {
+        let mut demux = Demux::new(Vec::new())
+            .route(0..10, Vec::new())
+            .route(3..5, Vec::new());
+        demux.write_all(b"0123456789").unwrap();
+        let (default, routed) = demux.into_inner();
+        assert!(default.is_empty());
+        assert_eq!(vec![b"01256789".to_vec(), b"34".to_vec()], routed);
+    }
+}
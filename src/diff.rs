@@ -0,0 +1,701 @@
+use operation::PlanBuilder;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+/// a line of a hunk, with its line ending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    /// a line in both the old and new file
+    Context(Vec<u8>),
+    /// a line only in the old file
+    Removed(Vec<u8>),
+    /// a line only in the new file
+    Added(Vec<u8>),
+}
+
+impl Line {
+    fn text_mut(&mut self) -> &mut Vec<u8> {
+        match *self {
+            Line::Context(ref mut text)
+            | Line::Removed(ref mut text)
+            | Line::Added(ref mut text) => text,
+        }
+    }
+}
+
+/// a hunk of a unified diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// the first old line, counting from 1, or the line after which the hunk
+    /// inserts if it has no old lines
+    pub old_start: usize,
+    /// how many old lines the hunk covers
+    pub old_len: usize,
+    /// the first new line, counting from 1
+    pub new_start: usize,
+    /// how many new lines the hunk covers
+    pub new_len: usize,
+    /// the lines of the hunk
+    pub lines: Vec<Line>,
+}
+
+impl Hunk {
+    /// the old lines, as they must appear in the origin
+    pub fn old_text(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        for line in &self.lines {
+            if let Line::Context(ref line) | Line::Removed(ref line) = *line {
+                text.extend_from_slice(line);
+            }
+        }
+        text
+    }
+
+    /// the new lines, which replace the old ones
+    pub fn new_text(&self) -> Vec<u8> {
+        let mut text = Vec::new();
+        for line in &self.lines {
+            if let Line::Context(ref line) | Line::Added(ref line) = *line {
+                text.extend_from_slice(line);
+            }
+        }
+        text
+    }
+
+    /// the old lines, each with its line ending
+    fn old_lines(&self) -> impl Iterator<Item = &[u8]> {
+        self.lines.iter().filter_map(|line| match *line {
+            Line::Context(ref line) | Line::Removed(ref line) => Some(&line[..]),
+            Line::Added(_) => None,
+        })
+    }
+
+    /// the line at which the old lines start, counting from 1
+    fn first_line(&self) -> usize {
+        if self.old_len == 0 {
+            self.old_start + 1
+        } else {
+            self.old_start
+        }
+    }
+}
+
+/// the changes a diff makes to a single file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilePatch {
+    /// the path of the old file, without any `a/` prefix, or `None` if the
+    /// file is created
+    pub old_path: Option<String>,
+    /// the path of the new file, without any `b/` prefix, or `None` if the
+    /// file is deleted
+    pub new_path: Option<String>,
+    /// the hunks, in the order of the lines they change
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// the new path, or the old path if the file is deleted
+    pub fn path(&self) -> Option<&str> {
+        self.new_path.as_deref().or(self.old_path.as_deref())
+    }
+
+    /// find the origin range of each hunk's old lines in a single pass,
+    /// checking that they match
+    ///
+    /// fails with `InvalidData` if the hunks overlap or are out of order, or
+    /// with `NotFound` naming the first hunk whose old lines don't match
+    pub fn locate<R: Read>(&self, origin: R) -> io::Result<Vec<Range<usize>>> {
+        Ok(self
+            .locate_within(origin, 0)?
+            .into_iter()
+            .map(|placement| placement.range)
+            .collect())
+    }
+
+    /// find each hunk's old lines up to `max_offset` lines from where the
+    /// diff puts them, in a single pass
+    ///
+    /// as with `patch`, a hunk which has moved is looked for nearest its line
+    /// first, and later hunks are expected to have moved as far. only as
+    /// many lines as the search needs are held in memory. hunks without old
+    /// lines can't be relocated.
+    pub fn locate_within<R: Read>(
+        &self,
+        origin: R,
+        max_offset: usize,
+    ) -> io::Result<Vec<Placement>> {
+        self.place(origin, max_offset, false)?
+            .into_iter()
+            .map(|placed| placed.map_err(|_| unreachable!("conflicts only arise when merging")))
+            .collect()
+    }
+
+    /// find each hunk as `locate_within` does, or if it can't be found and
+    /// this is a merge, the lines where it should have been
+    fn place<R: Read>(
+        &self,
+        origin: R,
+        max_offset: usize,
+        merge: bool,
+    ) -> io::Result<Vec<Result<Placement, Conflict>>> {
+        for (idx, pair) in self.hunks.windows(2).enumerate() {
+            if pair[1].first_line() < pair[0].first_line() + pair[0].old_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("hunk {} overlaps the one before it", idx + 2),
+                ));
+            }
+        }
+        let mut lines = Lines::new(origin);
+        let mut placements = Vec::with_capacity(self.hunks.len());
+        let mut drift = 0;
+        // the first line a hunk may start at, past the hunk before it
+        let mut earliest = 1;
+        for (idx, hunk) in self.hunks.iter().enumerate() {
+            let expected = hunk.first_line() as isize + drift;
+            let max_offset = if hunk.old_len == 0 {
+                0
+            } else {
+                max_offset as isize
+            };
+            let mut found = None;
+            // nearest first, earlier before later
+            let deltas =
+                (0..=2 * max_offset).map(|i| if i % 2 == 0 { i / 2 } else { -(i + 1) / 2 });
+            for delta in deltas {
+                let candidate = expected + delta;
+                if candidate < earliest as isize {
+                    continue;
+                }
+                if lines.matches(candidate as usize, hunk.old_lines())? {
+                    found = Some(candidate as usize);
+                    break;
+                }
+            }
+            let first = match found {
+                Some(first) => first,
+                None if merge => {
+                    let first = lines.clamp(expected.max(earliest as isize) as usize)?;
+                    let end = lines.clamp(first + hunk.old_len)?;
+                    placements.push(Err(Conflict {
+                        hunk: idx,
+                        lines: first..end,
+                        range: lines.offset(first)?..lines.offset(end)?,
+                        terminated: end == first || lines.terminated(end - 1)?,
+                    }));
+                    earliest = end;
+                    lines.discard_before(earliest);
+                    continue;
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "hunk {} doesn't match within {} lines of line {}",
+                            idx + 1,
+                            max_offset,
+                            hunk.first_line()
+                        ),
+                    ))
+                }
+            };
+            let end = first + hunk.old_len;
+            let moved = first as isize - hunk.first_line() as isize;
+            placements.push(Ok(Placement {
+                range: lines.offset(first)?..lines.offset(end)?,
+                moved,
+            }));
+            drift = moved;
+            earliest = end;
+            lines.discard_before(earliest);
+        }
+        Ok(placements)
+    }
+
+    /// a plan replacing each hunk's old lines with its new ones
+    pub fn plan<R: Read>(&self, origin: R) -> io::Result<PlanBuilder<'static>> {
+        let ranges = self.locate(origin)?;
+        Ok(self.plan_at(ranges))
+    }
+
+    fn plan_at<I: IntoIterator<Item = Range<usize>>>(&self, ranges: I) -> PlanBuilder<'static> {
+        let mut plan = PlanBuilder::new();
+        for (range, hunk) in ranges.into_iter().zip(&self.hunks) {
+            plan = plan.replace(range, hunk.new_text());
+        }
+        plan
+    }
+
+    /// write the origin with the patch applied
+    ///
+    /// the origin is checked from its current position, then rewound and
+    /// streamed to the target
+    pub fn apply<R: Read + Seek, W: Write>(&self, origin: R, target: W) -> io::Result<()> {
+        self.apply_within(origin, target, 0).map(drop)
+    }
+
+    /// write the origin with the patch applied, relocating hunks which have
+    /// moved by up to `max_offset` lines
+    ///
+    /// returns where each hunk was applied, so that moved hunks can be
+    /// reported
+    pub fn apply_within<R: Read + Seek, W: Write>(
+        &self,
+        mut origin: R,
+        target: W,
+        max_offset: usize,
+    ) -> io::Result<Vec<Placement>> {
+        let start = origin.stream_position()?;
+        let placements = self.locate_within(&mut origin, max_offset)?;
+        origin.seek(SeekFrom::Start(start))?;
+        self.plan_at(placements.iter().map(|placement| placement.range.clone()))
+            .execute(origin, target)?;
+        Ok(placements)
+    }
+
+    /// write the origin with every hunk that still matches applied, as
+    /// `apply_within` does, and conflict markers around the lines where
+    /// each other hunk should have applied
+    ///
+    /// a conflict shows the origin's lines, then the hunk's new lines:
+    ///
+    /// ```text
+    /// <<<<<<< origin
+    /// the lines as they are
+    /// =======
+    /// the lines as the patch would have them
+    /// >>>>>>> patch
+    /// ```
+    ///
+    /// returns the conflicts, which are also written if the patch couldn't
+    /// be applied at all.
+    pub fn merge<R: Read + Seek, W: Write>(
+        &self,
+        mut origin: R,
+        target: W,
+        max_offset: usize,
+    ) -> io::Result<Vec<Conflict>> {
+        let start = origin.stream_position()?;
+        let placed = self.place(&mut origin, max_offset, true)?;
+        origin.seek(SeekFrom::Start(start))?;
+        let mut plan = PlanBuilder::new();
+        let mut conflicts = Vec::new();
+        for (placed, hunk) in placed.into_iter().zip(&self.hunks) {
+            match placed {
+                Ok(placement) => plan = plan.replace(placement.range, hunk.new_text()),
+                Err(conflict) => {
+                    let mut theirs = if conflict.terminated {
+                        b"=======\n".to_vec()
+                    } else {
+                        b"\n=======\n".to_vec()
+                    };
+                    let new_text = hunk.new_text();
+                    theirs.extend_from_slice(&new_text);
+                    if !new_text.is_empty() && !new_text.ends_with(b"\n") {
+                        theirs.push(b'\n');
+                    }
+                    theirs.extend_from_slice(b">>>>>>> patch\n");
+                    plan = plan
+                        .insert(conflict.range.start, &b"<<<<<<< origin\n"[..])
+                        .insert(conflict.range.end, theirs);
+                    conflicts.push(conflict);
+                }
+            }
+        }
+        plan.execute(origin, target)?;
+        Ok(conflicts)
+    }
+}
+
+/// a hunk which couldn't be applied by `FilePatch::merge`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// the index of the hunk in the patch
+    pub hunk: usize,
+    /// the origin lines where the hunk should have applied, counting from 1
+    pub lines: Range<usize>,
+    /// the origin range of those lines
+    pub range: Range<usize>,
+    /// whether the lines end with a newline, or there are none
+    terminated: bool,
+}
+
+/// where a hunk was found in the origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    /// the origin range of the hunk's old lines
+    pub range: Range<usize>,
+    /// how many lines later than the diff said the hunk was found; negative
+    /// if earlier
+    pub moved: isize,
+}
+
+/// the lines of the origin, read as far as they're needed
+struct Lines<R> {
+    reader: BufReader<R>,
+    /// the origin index and content of each line from `first` onwards
+    window: VecDeque<(usize, Vec<u8>)>,
+    first: usize,
+    /// the origin index of the next line to be read
+    end: usize,
+    exhausted: bool,
+}
+
+impl<R: Read> Lines<R> {
+    fn new(origin: R) -> Lines<R> {
+        Lines {
+            reader: BufReader::new(origin),
+            window: VecDeque::new(),
+            first: 1,
+            end: 0,
+            exhausted: false,
+        }
+    }
+
+    /// read up to this line, counting from 1, returning whether it exists
+    fn fill(&mut self, number: usize) -> io::Result<bool> {
+        while !self.exhausted && self.first + self.window.len() <= number {
+            let mut line = Vec::new();
+            let len = self.reader.read_until(b'\n', &mut line)?;
+            if len == 0 {
+                self.exhausted = true;
+            } else {
+                self.window.push_back((self.end, line));
+                self.end += len;
+            }
+        }
+        Ok(number < self.first + self.window.len())
+    }
+
+    /// whether the lines from this one on are the expected ones
+    fn matches<'l, I: Iterator<Item = &'l [u8]>>(
+        &mut self,
+        first: usize,
+        expected: I,
+    ) -> io::Result<bool> {
+        for (number, expected) in (first..).zip(expected) {
+            if !self.fill(number)? || self.window[number - self.first].1 != expected {
+                return Ok(false);
+            }
+        }
+        // a hunk without old lines inserts at most just past the last line
+        Ok(self.fill(first)? || first == self.first + self.window.len())
+    }
+
+    /// the origin index at which this line starts, or the end of the origin
+    /// for the line after the last
+    fn offset(&mut self, number: usize) -> io::Result<usize> {
+        Ok(if self.fill(number)? {
+            self.window[number - self.first].0
+        } else {
+            self.end
+        })
+    }
+
+    /// this line, or the line after the last if there are fewer
+    fn clamp(&mut self, number: usize) -> io::Result<usize> {
+        if self.fill(number)? {
+            Ok(number)
+        } else {
+            Ok(self.first + self.window.len())
+        }
+    }
+
+    /// whether this line ends with a newline
+    fn terminated(&mut self, number: usize) -> io::Result<bool> {
+        Ok(self.fill(number)? && self.window[number - self.first].1.ends_with(b"\n"))
+    }
+
+    fn discard_before(&mut self, number: usize) {
+        while self.first < number && !self.window.is_empty() {
+            self.window.pop_front();
+            self.first += 1;
+        }
+    }
+}
+
+/// parse a unified diff, as produced by `git diff` or `diff -u`, into the
+/// changes it makes to each file
+///
+/// mode, rename, index and other extended header lines are ignored, as are
+/// any lines outside a file's headers and hunks. fails with `InvalidData` if
+/// a hunk header is malformed or a hunk is cut short.
+pub fn parse<R: Read>(diff: R) -> io::Result<Vec<FilePatch>> {
+    let mut diff = BufReader::new(diff);
+    let mut files: Vec<FilePatch> = Vec::new();
+    // whether the current file was started by a `diff --git` line, and has
+    // yet to see its `---` line
+    let mut git_header = false;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if diff.read_until(b'\n', &mut line)? == 0 {
+            return Ok(files);
+        }
+        let text = trim_newline(&line);
+        if let Some(paths) = text.strip_prefix(b"diff --git ") {
+            // `a/old b/new`, which is all a pure rename has to go on
+            let mut file = FilePatch::default();
+            if let Some(split) = paths.windows(3).position(|w| w == b" b/") {
+                file.old_path = parse_path(&paths[..split], b"a/");
+                file.new_path = parse_path(&paths[split + 1..], b"b/");
+            }
+            files.push(file);
+            git_header = true;
+        } else if let Some(path) = text.strip_prefix(b"--- ") {
+            if !git_header {
+                files.push(FilePatch::default());
+            }
+            git_header = false;
+            files.last_mut().expect("just pushed").old_path = parse_path(path, b"a/");
+        } else if let Some(path) = text.strip_prefix(b"+++ ") {
+            if let Some(file) = files.last_mut() {
+                file.new_path = parse_path(path, b"b/");
+            }
+        } else if text.starts_with(b"@@ ") {
+            let mut hunk = parse_hunk_header(text)?;
+            read_hunk(&mut diff, &mut hunk)?;
+            match files.last_mut() {
+                Some(file) => file.hunks.push(hunk),
+                None => return Err(invalid("hunk without a file header")),
+            }
+        }
+    }
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// the path in a `---` or `+++` line, without its prefix or any timestamp
+fn parse_path(path: &[u8], prefix: &[u8]) -> Option<String> {
+    let path = path.split(|&b| b == b'\t').next().unwrap_or(path);
+    if path == b"/dev/null" {
+        return None;
+    }
+    let path = path.strip_prefix(prefix).unwrap_or(path);
+    Some(String::from_utf8_lossy(path).into_owned())
+}
+
+/// parse `@@ -l,s +l,s @@`, where a missing length is 1
+fn parse_hunk_header(text: &[u8]) -> io::Result<Hunk> {
+    let malformed = || {
+        invalid(&format!(
+            "malformed hunk header {:?}",
+            String::from_utf8_lossy(text)
+        ))
+    };
+    let text = ::std::str::from_utf8(text).map_err(|_| malformed())?;
+    let mut parts = text.split(' ').skip(1);
+    let mut range = |sign: char| -> Option<(usize, usize)> {
+        let range = parts.next()?.strip_prefix(sign)?;
+        Some(match range.split_once(',') {
+            Some((start, len)) => (start.parse().ok()?, len.parse().ok()?),
+            None => (range.parse().ok()?, 1),
+        })
+    };
+    let (old_start, old_len) = range('-').ok_or_else(malformed)?;
+    let (new_start, new_len) = range('+').ok_or_else(malformed)?;
+    Ok(Hunk {
+        old_start,
+        old_len,
+        new_start,
+        new_len,
+        lines: Vec::new(),
+    })
+}
+
+/// read the lines of a hunk, and any "no newline" marker after its last line
+fn read_hunk<R: BufRead>(diff: &mut R, hunk: &mut Hunk) -> io::Result<()> {
+    let (mut old, mut new) = (hunk.old_len, hunk.new_len);
+    let mut line = Vec::new();
+    while old > 0 || new > 0 || diff.fill_buf()?.starts_with(b"\\") {
+        line.clear();
+        if diff.read_until(b'\n', &mut line)? == 0 {
+            return Err(invalid("hunk cut short"));
+        }
+        let (kind, text) = match line.split_first() {
+            Some((&b'\\', _)) => {
+                // "\ No newline at end of file" applies to the line before it
+                if let Some(last) = hunk.lines.last_mut() {
+                    let text = last.text_mut();
+                    let len = trim_newline(text).len();
+                    text.truncate(len);
+                }
+                continue;
+            }
+            // some tools strip the space from empty context lines
+            Some((&b'\n', _)) | Some((&b'\r', _)) => (b' ', &line[..]),
+            Some((&kind, text)) => (kind, text),
+            None => unreachable!("read_until read something"),
+        };
+        let text = text.to_vec();
+        match kind {
+            b' ' if old > 0 && new > 0 => {
+                old -= 1;
+                new -= 1;
+                hunk.lines.push(Line::Context(text));
+            }
+            b'-' if old > 0 => {
+                old -= 1;
+                hunk.lines.push(Line::Removed(text));
+            }
+            b'+' if new > 0 => {
+                new -= 1;
+                hunk.lines.push(Line::Added(text));
+            }
+            _ => return Err(invalid("hunk doesn't match its header")),
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const DIFF: &str = "\
+diff --git a/src/old.rs b/src/new.rs
+similarity index 90%
+rename from src/old.rs
+rename to src/new.rs
+old mode 100644
+new mode 100755
+index 1234567..89abcde
+--- a/src/old.rs
++++ b/src/new.rs
+@@ -1,3 +1,3 @@ fn main() {
+ one
+-two
++TWO
+ three
+@@ -6 +6,2 @@
+ six
++six and a half
+@@ -9,0 +11 @@
++ten
+diff --git a/gone.txt b/gone.txt
+deleted file mode 100644
+--- a/gone.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-bye
+\\ No newline at end of file
+diff --git a/x.md b/y.md
+similarity index 100%
+rename from x.md
+rename to y.md
+";
+
+    const ORIGIN: &str = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+
+    #[test]
+    fn parses_git_diffs() {
+        let files = parse(DIFF.as_bytes()).unwrap();
+        assert_eq!(3, files.len());
+        assert_eq!(Some("src/old.rs"), files[0].old_path.as_deref());
+        assert_eq!(Some("src/new.rs"), files[0].path());
+        assert_eq!(3, files[0].hunks.len());
+        assert_eq!((6, 1, 6, 2), {
+            let h = &files[0].hunks[1];
+            (h.old_start, h.old_len, h.new_start, h.new_len)
+        });
+        assert_eq!(b"one\ntwo\nthree\n".to_vec(), files[0].hunks[0].old_text());
+        assert_eq!(None, files[1].new_path);
+        assert_eq!(
+            vec![Line::Removed(b"bye".to_vec())],
+            files[1].hunks[0].lines
+        );
+    }
+
+    #[test]
+    fn applies_hunks() {
+        let files = parse(DIFF.as_bytes()).unwrap();
+        let mut dest = Vec::new();
+        files[0].apply(Cursor::new(ORIGIN), &mut dest).unwrap();
+        assert_eq!(
+            "one\nTWO\nthree\nfour\nfive\nsix\nsix and a half\nseven\neight\nnine\nten\n",
+            String::from_utf8(dest).unwrap()
+        );
+
+        let mut dest = Vec::new();
+        files[1].apply(Cursor::new("bye"), &mut dest).unwrap();
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let files = parse(DIFF.as_bytes()).unwrap();
+        let err = files[0]
+            .apply(Cursor::new(ORIGIN.replace("six", "6")), io::sink())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(err.to_string().contains("hunk 2"));
+
+        let err = parse(&b"--- a\n+++ b\n@@ -1,2 +1 @@\n-x\n"[..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn relocates_drifted_hunks() {
+        let files = parse(DIFF.as_bytes()).unwrap();
+        let drifted = format!(
+            "zero\n{}",
+            ORIGIN.replace("four\n", "four\nfour and a bit\n")
+        );
+        let err = files[0]
+            .apply(Cursor::new(&drifted), io::sink())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+        let mut dest = Vec::new();
+        let placements = files[0]
+            .apply_within(Cursor::new(&drifted), &mut dest, 1)
+            .unwrap();
+        assert_eq!(
+            vec![1, 2, 2],
+            placements.iter().map(|p| p.moved).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "zero\none\nTWO\nthree\nfour\nfour and a bit\nfive\nsix\nsix and a half\nseven\neight\nnine\nten\n",
+            String::from_utf8(dest).unwrap()
+        );
+    }
+
+    #[test]
+    fn merges_with_conflict_markers() {
+        let files = parse(DIFF.as_bytes()).unwrap();
+        let edited = ORIGIN.replace("two", "2");
+        let mut dest = Vec::new();
+        let conflicts = files[0].merge(Cursor::new(&edited), &mut dest, 0).unwrap();
+        assert_eq!(
+            vec![(0, 1..4)],
+            conflicts
+                .iter()
+                .map(|c| (c.hunk, c.lines.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "<<<<<<< origin\none\n2\nthree\n=======\none\nTWO\nthree\n>>>>>>> patch\n\
+             four\nfive\nsix\nsix and a half\nseven\neight\nnine\nten\n",
+            String::from_utf8(dest).unwrap()
+        );
+
+        let mut dest = Vec::new();
+        let conflicts = files[1].merge(Cursor::new("hello"), &mut dest, 0).unwrap();
+        assert_eq!(1, conflicts.len());
+        assert_eq!(
+            "<<<<<<< origin\nhello\n=======\n>>>>>>> patch\n",
+            String::from_utf8(dest).unwrap()
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! sha-256 digests of insertion content, checked as it's copied
+
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+/// the sha-256 digest of everything the reader produces
+pub fn sha256<R: Read>(mut reader: R) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut HashWriter(&mut hasher))?;
+    Ok(hasher.finalize().into())
+}
+
+/// a digest as lowercase hex
+pub fn to_hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// parse a digest from hex in either case, failing with `InvalidInput` if it
+/// isn't 64 hex digits
+pub fn from_hex(hex: &str) -> io::Result<[u8; 32]> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("malformed sha-256 digest {:?}", hex),
+        )
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = ::std::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(digest)
+}
+
+struct HashWriter<'h>(&'h mut Sha256);
+
+impl<'h> io::Write for HashWriter<'h> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// a reader which hashes what it reads, failing with `InvalidData` at the end
+/// of its inner reader if the digest isn't the expected one
+///
+/// everything before the end has already been read by then: a mismatch fails
+/// the execution, but the output written so far remains
+pub struct Verified<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: [u8; 32],
+}
+
+impl<R: Read> Verified<R> {
+    /// verify the reader's content against the expected digest
+    pub fn new(inner: R, expected: [u8; 32]) -> Verified<R> {
+        Verified {
+            inner,
+            hasher: Sha256::new(),
+            expected,
+        }
+    }
+}
+
+impl<R: Read> Read for Verified<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len > 0 {
+            self.hasher.update(&buf[..len]);
+        } else if !buf.is_empty() {
+            let digest: [u8; 32] = self.hasher.clone().finalize().into();
+            if digest != self.expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "content has sha-256 digest {}, not the expected {}",
+                        to_hex(&digest),
+                        to_hex(&self.expected)
+                    ),
+                ));
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::IntoInsertSource;
+    use Inserter;
+
+    const BRAVO: &str = "269a9f0889ac992c06c843402eeabab7d2275aca36888b94b0cdb391dcc139b2";
+
+    #[test]
+    fn round_trips_hex() {
+        let digest = sha256(&b"bravo "[..]).unwrap();
+        assert_eq!(digest, from_hex(&to_hex(&digest)).unwrap());
+        assert_eq!(digest, from_hex(&to_hex(&digest).to_uppercase()).unwrap());
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex(&"g".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn verifies_insertions() {
+        let digest = sha256(&b"bravo "[..]).unwrap();
+        assert_eq!(BRAVO, to_hex(&digest));
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, "bravo ".into_insert_source().expect_sha256(digest))
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("alpha bravo charlie", out);
+
+        let err = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, "delta ".into_insert_source().expect_sha256(digest))
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,262 @@
+use libc;
+use operation::PlanBuilder;
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    ops::{Deref, DerefMut},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    ptr::NonNull,
+    slice,
+};
+use util::fill;
+
+/// alignment of buffers, offsets and lengths for direct i/o
+pub const ALIGNMENT: usize = 4096;
+
+/// size of the buffers used for direct i/o, a multiple of `ALIGNMENT`
+pub const DIRECT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// open a file for reading, bypassing the page cache
+pub fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+/// create or truncate a file for writing, bypassing the page cache
+pub fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+impl<'i> PlanBuilder<'i> {
+    /// apply the plan from one file to another, bypassing the page cache
+    ///
+    /// both files are opened with `O_DIRECT` and accessed only in aligned
+    /// blocks. fails with `InvalidInput` if the filesystem doesn't support
+    /// direct i/o.
+    pub fn execute_direct(self, origin: &Path, target: &Path) -> io::Result<()> {
+        let origin = DirectReader::new(open(origin)?);
+        let mut target = DirectWriter::new(create(target)?);
+        self.execute(origin, &mut target)?;
+        target.finish()?.sync_all()
+    }
+}
+
+/// a zeroed heap buffer with a fixed alignment
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    fn new(size: usize) -> AlignedBuffer {
+        let layout = Layout::from_size_align(size, ALIGNMENT).expect("valid buffer layout");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        AlignedBuffer {
+            ptr: NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout)),
+            layout,
+        }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+/// reads a file from its start in aligned blocks
+pub struct DirectReader<R = File> {
+    file: R,
+    buffer: AlignedBuffer,
+    filled: usize,
+    consumed: usize,
+    eof: bool,
+}
+
+impl<R: Read> DirectReader<R> {
+    /// read the file from its start, which must be its current position
+    pub fn new(file: R) -> DirectReader<R> {
+        DirectReader {
+            file,
+            buffer: AlignedBuffer::new(DIRECT_BUFFER_SIZE),
+            filled: 0,
+            consumed: 0,
+            eof: false,
+        }
+    }
+}
+
+impl<R: Read> Read for DirectReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.filled {
+            if self.eof {
+                return Ok(0);
+            }
+            // a single read may be short anywhere, but only the end of the
+            // file leaves the whole buffer unfilled
+            let bytes_read = fill(&mut self.file, &mut self.buffer)?;
+            self.eof = bytes_read < self.buffer.len();
+            self.filled = bytes_read;
+            self.consumed = 0;
+        }
+        let len = buf.len().min(self.filled - self.consumed);
+        buf[..len].copy_from_slice(&self.buffer[self.consumed..self.consumed + len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
+
+/// writes a file from its start in aligned blocks
+///
+/// data is only written a whole buffer at a time, so `flush` does nothing:
+/// `finish` must be called to write out the rest.
+pub struct DirectWriter {
+    file: File,
+    buffer: AlignedBuffer,
+    len: usize,
+    written: u64,
+}
+
+impl DirectWriter {
+    /// write the file from its start, which must be its current position
+    pub fn new(file: File) -> DirectWriter {
+        DirectWriter {
+            file,
+            buffer: AlignedBuffer::new(DIRECT_BUFFER_SIZE),
+            len: 0,
+            written: 0,
+        }
+    }
+
+    /// write out the last partial block, returning the file
+    ///
+    /// the block is padded out to the alignment and the file then truncated
+    /// to the length actually written
+    pub fn finish(mut self) -> io::Result<File> {
+        if self.len > 0 {
+            let padded = self.len.div_ceil(ALIGNMENT) * ALIGNMENT;
+            for byte in self.buffer[self.len..padded].iter_mut() {
+                *byte = 0;
+            }
+            self.file.write_all(&self.buffer[..padded])?;
+            self.file.set_len(self.written + self.len as u64)?;
+        }
+        Ok(self.file)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&buf[..len]);
+        self.len += len;
+        if self.len == self.buffer.len() {
+            self.file.write_all(&self.buffer)?;
+            self.written += self.len as u64;
+            self.len = 0;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use testutil::{Fault, Faulty};
+
+    fn temp(name: &str) -> ::std::path::PathBuf {
+        env::temp_dir().join(format!("direct-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn buffers_are_aligned() {
+        let buffer = AlignedBuffer::new(DIRECT_BUFFER_SIZE);
+        assert_eq!(0, buffer.as_ptr() as usize % ALIGNMENT);
+        assert!(buffer.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn reads_past_short_and_interrupted_reads() {
+        let origin: Vec<u8> = (0..=255).cycle().take(DIRECT_BUFFER_SIZE + 5000).collect();
+        let faulty = Faulty::new(
+            origin.as_slice(),
+            [
+                Fault::Short(ALIGNMENT),
+                Fault::Interrupted,
+                Fault::Short(100),
+            ],
+        )
+        .cycle();
+        let mut read = Vec::new();
+        DirectReader::new(faulty).read_to_end(&mut read).unwrap();
+        assert_eq!(origin, read);
+    }
+
+    #[test]
+    fn round_trips_through_aligned_blocks() {
+        let (origin_path, target_path) = (temp("origin"), temp("target"));
+        let origin: Vec<u8> = (0..=255).cycle().take(DIRECT_BUFFER_SIZE + 5000).collect();
+        fs::write(&origin_path, &origin).unwrap();
+
+        // plain files stand in for ones opened with O_DIRECT, which tmpfs refuses
+        let mut target = DirectWriter::new(File::create(&target_path).unwrap());
+        PlanBuilder::new()
+            .insert(DIRECT_BUFFER_SIZE, "middle")
+            .execute(
+                DirectReader::new(File::open(&origin_path).unwrap()),
+                &mut target,
+            )
+            .unwrap();
+        target.finish().unwrap();
+
+        let mut expect = origin[..DIRECT_BUFFER_SIZE].to_vec();
+        expect.extend_from_slice(b"middle");
+        expect.extend_from_slice(&origin[DIRECT_BUFFER_SIZE..]);
+        assert_eq!(expect, fs::read(&target_path).unwrap());
+
+        // tmpfs refuses O_DIRECT, but any other filesystem must work
+        match open(&origin_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => (),
+            opened => {
+                opened.unwrap();
+                PlanBuilder::new()
+                    .insert(0, "head")
+                    .execute_direct(&origin_path, &target_path)
+                    .unwrap();
+                let mut expect = b"head".to_vec();
+                expect.extend_from_slice(&origin);
+                assert_eq!(expect, fs::read(&target_path).unwrap());
+            }
+        }
+        fs::remove_file(&origin_path).unwrap();
+        fs::remove_file(&target_path).unwrap();
+    }
+}
@@ -0,0 +1,645 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// section type of a note section
+pub const SHT_NOTE: u32 = 7;
+/// section type of a plain data section
+pub const SHT_PROGBITS: u32 = 1;
+
+const SHN_XINDEX: u64 = 0xFFFF;
+/// largest alignment kept for the inserted data or a segment moved past it
+const MAX_ALIGN: u64 = 64 * 1024;
+
+/// a non-allocated section to be added to an ELF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    name: String,
+    kind: u32,
+    align: u64,
+    data: Vec<u8>,
+}
+
+impl Section {
+    /// create a section of the given type holding raw data
+    ///
+    /// the section is not allocated: it is carried in the file but not loaded
+    /// into memory, so no segment needs to change to accommodate it
+    pub fn new(name: &str, kind: u32, data: Vec<u8>) -> Section {
+        Section {
+            name: name.to_string(),
+            kind,
+            align: 1,
+            data,
+        }
+    }
+
+    /// create a note section holding a single note
+    pub fn note(name: &str, note: &Note) -> Section {
+        Section {
+            name: name.to_string(),
+            kind: SHT_NOTE,
+            align: 4,
+            data: note.to_bytes(),
+        }
+    }
+
+    /// set the required file alignment of the section data
+    ///
+    /// the alignment must be a power of two no larger than 64 KiB; anything else
+    /// is rejected when the section is inserted
+    pub fn align(mut self, align: u64) -> Self {
+        self.align = align.max(1);
+        self
+    }
+}
+
+/// an ELF note: owner name, type, and descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub owner: String,
+    pub kind: u32,
+    pub desc: Vec<u8>,
+}
+
+impl Note {
+    /// encode this note in the four-byte aligned little-endian note format
+    ///
+    /// notes are encoded in the byte order of the file they're inserted into;
+    /// this form is for little-endian files, which is nearly all of them
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode(false)
+    }
+
+    fn encode(&self, big_endian: bool) -> Vec<u8> {
+        let word = |value: u32| {
+            if big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            }
+        };
+        let mut out = Vec::new();
+        out.extend_from_slice(&word(self.owner.len() as u32 + 1));
+        out.extend_from_slice(&word(self.desc.len() as u32));
+        out.extend_from_slice(&word(self.kind));
+        out.extend_from_slice(self.owner.as_bytes());
+        out.push(0);
+        pad_to(&mut out, 4);
+        out.extend_from_slice(&self.desc);
+        pad_to(&mut out, 4);
+        out
+    }
+}
+
+/// field widths and byte order of a particular ELF file
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    wide: bool,
+    big_endian: bool,
+}
+
+impl Layout {
+    fn get(self, buf: &[u8], at: usize, width: usize) -> u64 {
+        let bytes = &buf[at..at + width];
+        let mut value = 0_u64;
+        for idx in 0..width {
+            let byte = if self.big_endian {
+                bytes[idx]
+            } else {
+                bytes[width - 1 - idx]
+            };
+            value = (value << 8) | u64::from(byte);
+        }
+        value
+    }
+
+    fn put(self, buf: &mut [u8], at: usize, width: usize, value: u64) {
+        for idx in 0..width {
+            let byte = (value >> (8 * idx)) as u8;
+            if self.big_endian {
+                buf[at + width - 1 - idx] = byte;
+            } else {
+                buf[at + idx] = byte;
+            }
+        }
+    }
+
+    /// the width of an address or offset field
+    fn word(self) -> usize {
+        if self.wide {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn header_len(self) -> usize {
+        if self.wide {
+            64
+        } else {
+            52
+        }
+    }
+
+    /// the size of a program header entry, the least `e_phentsize` can be
+    fn segment_header_len(self) -> usize {
+        if self.wide {
+            56
+        } else {
+            32
+        }
+    }
+
+    /// the size of a section header entry, the least `e_shentsize` can be
+    fn section_header_len(self) -> usize {
+        if self.wide {
+            64
+        } else {
+            40
+        }
+    }
+
+    /// (e_phoff, e_shoff, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx)
+    fn header_fields(self) -> [usize; 7] {
+        if self.wide {
+            [32, 40, 54, 56, 58, 60, 62]
+        } else {
+            [28, 32, 42, 44, 46, 48, 50]
+        }
+    }
+
+    /// (sh_name, sh_type, sh_offset, sh_size, sh_addralign)
+    fn section_fields(self) -> [usize; 5] {
+        if self.wide {
+            [0, 4, 24, 32, 48]
+        } else {
+            [0, 4, 16, 20, 32]
+        }
+    }
+
+    /// (p_offset, p_filesz, p_align)
+    fn segment_fields(self) -> [usize; 3] {
+        if self.wide {
+            [8, 32, 48]
+        } else {
+            [4, 16, 28]
+        }
+    }
+}
+
+/// insert a non-allocated section into an ELF file
+///
+/// the section data and a grown copy of the section name string table are
+/// inserted just before the section header table, which is rewritten with the
+/// new entry appended. the ELF header, and any section or program headers
+/// pointing past the insertion point, are patched to match. loaded segments are
+/// left where they are; if one extends past the section header table the file
+/// is rejected as unsafe to grow.
+pub fn insert_section<R, W>(mut origin: R, mut target: W, section: &Section) -> io::Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    if !section.align.is_power_of_two() || section.align > MAX_ALIGN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "section alignment must be a power of two no larger than 64 KiB",
+        ));
+    }
+    origin.seek(SeekFrom::Start(0))?;
+    let mut ident = [0_u8; 16];
+    origin.read_exact(&mut ident)?;
+    if &ident[..4] != b"\x7FELF" {
+        return Err(invalid("not an ELF file"));
+    }
+    let layout = Layout {
+        wide: match ident[4] {
+            1 => false,
+            2 => true,
+            _ => return Err(invalid("unknown ELF class")),
+        },
+        big_endian: match ident[5] {
+            1 => false,
+            2 => true,
+            _ => return Err(invalid("unknown ELF byte order")),
+        },
+    };
+    let word = layout.word();
+
+    let mut header = vec![0_u8; layout.header_len()];
+    header[..16].copy_from_slice(&ident);
+    origin.read_exact(&mut header[16..])?;
+    let [phoff_at, shoff_at, phentsize_at, phnum_at, shentsize_at, shnum_at, shstrndx_at] =
+        layout.header_fields();
+    let phoff = layout.get(&header, phoff_at, word);
+    let shoff = layout.get(&header, shoff_at, word);
+    let phentsize = layout.get(&header, phentsize_at, 2) as usize;
+    let phnum = layout.get(&header, phnum_at, 2) as usize;
+    let shentsize = layout.get(&header, shentsize_at, 2) as usize;
+    let shnum = layout.get(&header, shnum_at, 2) as usize;
+    let shstrndx = layout.get(&header, shstrndx_at, 2) as usize;
+    if shoff == 0 || shnum == 0 || shstrndx as u64 >= SHN_XINDEX || shstrndx >= shnum {
+        return Err(invalid("ELF file has no usable section header table"));
+    }
+    if shentsize < layout.section_header_len() {
+        return Err(invalid("ELF section header entries are too small"));
+    }
+    if phnum > 0 && phentsize < layout.segment_header_len() {
+        return Err(invalid("ELF program header entries are too small"));
+    }
+    // nothing is read into memory beyond what the file holds
+    let file_len = origin.seek(SeekFrom::End(0))?;
+    let within = |offset: u64, len: u64| offset.checked_add(len).is_some_and(|end| end <= file_len);
+    let old_table_len = (shnum * shentsize) as u64;
+    if !within(shoff, old_table_len) || (phnum > 0 && !within(phoff, (phnum * phentsize) as u64)) {
+        return Err(invalid("ELF header table past the end of the file"));
+    }
+
+    let mut sections = vec![0_u8; shnum * shentsize];
+    origin.seek(SeekFrom::Start(shoff))?;
+    origin.read_exact(&mut sections)?;
+    let mut segments = vec![0_u8; phnum * phentsize];
+    if phnum > 0 {
+        origin.seek(SeekFrom::Start(phoff))?;
+        origin.read_exact(&mut segments)?;
+    }
+
+    let [sh_name, sh_type, sh_offset, sh_size, sh_addralign] = layout.section_fields();
+    let strtab_header = shstrndx * shentsize;
+    let strtab_offset = layout.get(&sections, strtab_header + sh_offset, word);
+    let strtab_size = layout.get(&sections, strtab_header + sh_size, word);
+    if !within(strtab_offset, strtab_size) {
+        return Err(invalid("section name table past the end of the file"));
+    }
+    let mut strtab = vec![0_u8; strtab_size as usize];
+    origin.seek(SeekFrom::Start(strtab_offset))?;
+    origin.read_exact(&mut strtab)?;
+
+    // segments after the insertion point move; their alignment must be preserved
+    let [p_offset, p_filesz, p_align] = layout.segment_fields();
+    let mut shift_align = word as u64;
+    for segment in segments.chunks(phentsize) {
+        let start = layout.get(segment, p_offset, word);
+        let end = start
+            .checked_add(layout.get(segment, p_filesz, word))
+            .ok_or_else(|| invalid("segment size out of range"))?;
+        if start < shoff && end > shoff {
+            return Err(invalid("a segment overlaps the section header table"));
+        }
+        let align = layout.get(segment, p_align, word);
+        if align > 1 && !align.is_power_of_two() {
+            return Err(invalid("segment alignment is not a power of two"));
+        }
+        if start >= shoff {
+            if align > MAX_ALIGN {
+                return Err(invalid(
+                    "segment after the section header table is too widely aligned",
+                ));
+            }
+            shift_align = shift_align.max(align);
+        }
+    }
+
+    // build the inserted block: section data, then the grown name table
+    let mut block = Vec::new();
+    let data_offset = align_up(shoff, section.align) - shoff;
+    block.resize(data_offset as usize, 0);
+    if section.kind == SHT_NOTE && layout.big_endian {
+        // notes are stored in the file's byte order
+        block.extend_from_slice(&swap_note_words(&section.data));
+    } else {
+        block.extend_from_slice(&section.data);
+    }
+    let strtab_new_offset = block.len() as u64;
+    let name_offset = strtab.len() as u64;
+    block.extend_from_slice(&strtab);
+    block.extend_from_slice(section.name.as_bytes());
+    block.push(0);
+    let strtab_new_size = block.len() as u64 - strtab_new_offset;
+    let table_padding =
+        align_up(shoff + block.len() as u64, word as u64) - shoff - block.len() as u64;
+    block.resize(block.len() + table_padding as usize, 0);
+    let delta = align_up(block.len() as u64, shift_align);
+    block.resize(delta as usize, 0);
+
+    let shift = |offset: u64| {
+        if offset >= shoff {
+            offset + delta
+        } else {
+            offset
+        }
+    };
+
+    // patch the existing tables, then append the new section header
+    for entry in sections.chunks_mut(shentsize) {
+        let offset = layout.get(entry, sh_offset, word);
+        layout.put(entry, sh_offset, word, shift(offset));
+    }
+    layout.put(
+        &mut sections,
+        strtab_header + sh_offset,
+        word,
+        shoff + strtab_new_offset,
+    );
+    layout.put(
+        &mut sections,
+        strtab_header + sh_size,
+        word,
+        strtab_new_size,
+    );
+    let mut entry = vec![0_u8; shentsize];
+    layout.put(&mut entry, sh_name, 4, name_offset);
+    layout.put(&mut entry, sh_type, 4, u64::from(section.kind));
+    layout.put(&mut entry, sh_offset, word, shoff + data_offset);
+    layout.put(&mut entry, sh_size, word, section.data.len() as u64);
+    layout.put(&mut entry, sh_addralign, word, section.align);
+    sections.extend_from_slice(&entry);
+
+    for segment in segments.chunks_mut(phentsize) {
+        let offset = layout.get(segment, p_offset, word);
+        layout.put(segment, p_offset, word, shift(offset));
+    }
+    let new_shnum = shnum + 1;
+    if new_shnum as u64 >= SHN_XINDEX {
+        return Err(invalid("too many sections"));
+    }
+    layout.put(&mut header, shoff_at, word, shoff + delta);
+    layout.put(&mut header, shnum_at, 2, new_shnum as u64);
+    layout.put(&mut header, phoff_at, word, shift(phoff));
+
+    // now stream the file, substituting the patched pieces as we pass them
+    let mut patches: Vec<(u64, u64, &[u8])> = vec![(0, header.len() as u64, &header)];
+    if phnum > 0 {
+        patches.push((phoff, segments.len() as u64, &segments));
+    }
+    patches.push((shoff, 0, &block));
+    patches.push((shoff, old_table_len, &sections));
+    patches.sort_by_key(|&(at, len, _)| (at, len));
+
+    origin.seek(SeekFrom::Start(0))?;
+    let mut position = 0_u64;
+    for (at, len, bytes) in patches {
+        if at < position {
+            return Err(invalid("overlapping ELF headers"));
+        }
+        copy_exact(&mut origin, &mut target, at - position)?;
+        io::copy(&mut origin.by_ref().take(len), &mut io::sink())?;
+        target.write_all(bytes)?;
+        position = at + len;
+    }
+    io::copy(&mut origin, &mut target)?;
+    Ok(())
+}
+
+/// re-encode the three header words of each little-endian note as big-endian
+fn swap_note_words(data: &[u8]) -> Vec<u8> {
+    let mut out = data.to_vec();
+    let mut at = 0;
+    while at + 12 <= out.len() {
+        let word = |idx: usize| {
+            u32::from_le_bytes([data[idx], data[idx + 1], data[idx + 2], data[idx + 3]])
+        };
+        let (namesz, descsz) = (word(at) as usize, word(at + 4) as usize);
+        for idx in 0..3 {
+            let value = word(at + 4 * idx);
+            out[at + 4 * idx..at + 4 * idx + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        at += 12 + align_up(namesz as u64, 4) as usize + align_up(descsz as u64, 4) as usize;
+    }
+    out
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+fn pad_to(buf: &mut Vec<u8>, align: usize) {
+    let len = align_up(buf.len() as u64, align as u64) as usize;
+    buf.resize(len, 0);
+}
+
+fn copy_exact<R: Read, W: Write>(origin: &mut R, target: &mut W, len: u64) -> io::Result<()> {
+    if io::copy(&mut origin.by_ref().take(len), target)? != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated ELF file",
+        ));
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// a minimal executable: header, one load segment covering the headers
+    /// and some code, a null section and a section name table
+    fn build(layout: Layout) -> Vec<u8> {
+        let word = layout.word();
+        let (phentsize, shentsize) = (layout.segment_header_len(), layout.section_header_len());
+        let ehsize = layout.header_len();
+        let code = b"\x90\x90\x90\x90";
+        let strtab = b"\0.shstrtab\0";
+        let code_at = ehsize + phentsize;
+        let strtab_at = code_at + code.len();
+        let shoff = align_up((strtab_at + strtab.len()) as u64, word as u64) as usize;
+
+        let mut elf = vec![0_u8; shoff + 2 * shentsize];
+        elf[..4].copy_from_slice(b"\x7FELF");
+        elf[4] = if layout.wide { 2 } else { 1 };
+        elf[5] = if layout.big_endian { 2 } else { 1 };
+        elf[6] = 1;
+        let [phoff_at, shoff_at, phentsize_at, phnum_at, shentsize_at, shnum_at, shstrndx_at] =
+            layout.header_fields();
+        layout.put(&mut elf, phoff_at, word, ehsize as u64);
+        layout.put(&mut elf, shoff_at, word, shoff as u64);
+        layout.put(&mut elf, phentsize_at, 2, phentsize as u64);
+        layout.put(&mut elf, phnum_at, 2, 1);
+        layout.put(&mut elf, shentsize_at, 2, shentsize as u64);
+        layout.put(&mut elf, shnum_at, 2, 2);
+        layout.put(&mut elf, shstrndx_at, 2, 1);
+
+        let [p_offset, p_filesz, p_align] = layout.segment_fields();
+        layout.put(&mut elf, ehsize, 4, 1);
+        layout.put(&mut elf, ehsize + p_offset, word, 0);
+        layout.put(&mut elf, ehsize + p_filesz, word, strtab_at as u64);
+        layout.put(&mut elf, ehsize + p_align, word, 0x1000);
+
+        elf[code_at..strtab_at].copy_from_slice(code);
+        elf[strtab_at..strtab_at + strtab.len()].copy_from_slice(strtab);
+
+        let [sh_name, sh_type, sh_offset, sh_size, _] = layout.section_fields();
+        let strtab_header = shoff + shentsize;
+        layout.put(&mut elf, strtab_header + sh_name, 4, 1);
+        layout.put(&mut elf, strtab_header + sh_type, 4, 3);
+        layout.put(&mut elf, strtab_header + sh_offset, word, strtab_at as u64);
+        layout.put(&mut elf, strtab_header + sh_size, word, strtab.len() as u64);
+        elf
+    }
+
+    /// look up a section by name, returning its contents
+    fn section<'e>(layout: Layout, elf: &'e [u8], name: &str) -> Option<&'e [u8]> {
+        let word = layout.word();
+        let [_, shoff_at, _, _, shentsize_at, shnum_at, shstrndx_at] = layout.header_fields();
+        let shoff = layout.get(elf, shoff_at, word) as usize;
+        let shentsize = layout.get(elf, shentsize_at, 2) as usize;
+        let shnum = layout.get(elf, shnum_at, 2) as usize;
+        let shstrndx = layout.get(elf, shstrndx_at, 2) as usize;
+        let [sh_name, _, sh_offset, sh_size, _] = layout.section_fields();
+        let contents = |idx: usize| {
+            let header = shoff + idx * shentsize;
+            let offset = layout.get(elf, header + sh_offset, word) as usize;
+            let size = layout.get(elf, header + sh_size, word) as usize;
+            &elf[offset..offset + size]
+        };
+        let strtab = contents(shstrndx);
+        (0..shnum)
+            .map(contents)
+            .zip(0..shnum)
+            .find_map(|(data, idx)| {
+                let at = layout.get(elf, shoff + idx * shentsize + sh_name, 4) as usize;
+                let end = at + strtab[at..].iter().position(|&b| b == 0)?;
+                if &strtab[at..end] == name.as_bytes() {
+                    Some(data)
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn note() -> Note {
+        Note {
+            owner: "build".to_string(),
+            kind: 1,
+            desc: b"v1.2.3".to_vec(),
+        }
+    }
+
+    #[test]
+    fn note_encoding() {
+        assert_eq!(
+            b"\x06\0\0\0\x06\0\0\0\x01\0\0\0build\0\0\0v1.2.3\0\0".to_vec(),
+            note().to_bytes()
+        );
+    }
+
+    #[test]
+    fn inserts_note_into_elf64() {
+        let layout = Layout {
+            wide: true,
+            big_endian: false,
+        };
+        let origin = build(layout);
+        let mut dest = Vec::new();
+        insert_section(
+            Cursor::new(&origin),
+            &mut dest,
+            &Section::note(".note.build", &note()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some(&note().to_bytes()[..]),
+            section(layout, &dest, ".note.build")
+        );
+        assert_eq!(
+            Some(&b"\0.shstrtab\0.note.build\0"[..]),
+            section(layout, &dest, ".shstrtab")
+        );
+        // the loaded segment is untouched
+        assert_eq!(&origin[64..64 + 56 + 4], &dest[64..64 + 56 + 4]);
+    }
+
+    #[test]
+    fn inserts_section_into_big_endian_elf32() {
+        let layout = Layout {
+            wide: false,
+            big_endian: true,
+        };
+        let mut dest = Vec::new();
+        insert_section(
+            Cursor::new(build(layout)),
+            &mut dest,
+            &Section::new(".meta", SHT_PROGBITS, b"hello".to_vec()),
+        )
+        .unwrap();
+        assert_eq!(Some(&b"hello"[..]), section(layout, &dest, ".meta"));
+        assert!(section(layout, &dest, ".shstrtab").is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let layout = Layout {
+            wide: true,
+            big_endian: false,
+        };
+        let [_, shoff_at, phentsize_at, _, shentsize_at, _, _] = layout.header_fields();
+        let shoff = layout.get(&build(layout), shoff_at, 8) as usize;
+        let [_, _, _, sh_size, _] = layout.section_fields();
+        let [p_offset, _, p_align] = layout.segment_fields();
+        let ehsize = layout.header_len();
+        // (field, width, value) written over a valid file
+        let corruptions = [
+            (phentsize_at, 2, 0),
+            (shentsize_at, 2, 0),
+            (shentsize_at, 2, 8),
+            (shoff_at, 8, u64::MAX - 8),
+            (shoff + 64 + sh_size, 8, u64::MAX),
+            (ehsize + p_offset, 8, u64::MAX),
+            (ehsize + p_align, 8, 3),
+        ];
+        for &(at, width, value) in corruptions.iter() {
+            let mut elf = build(layout);
+            layout.put(&mut elf, at, width, value);
+            let err = insert_section(
+                Cursor::new(elf),
+                &mut Vec::new(),
+                &Section::new(".x", SHT_PROGBITS, Vec::new()),
+            )
+            .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+
+    #[test]
+    fn rejects_wide_alignment() {
+        let layout = Layout {
+            wide: true,
+            big_endian: false,
+        };
+        let [_, shoff_at, _, _, _, _, _] = layout.header_fields();
+        let [p_offset, _, p_align] = layout.segment_fields();
+        let ehsize = layout.header_len();
+        let mut elf = build(layout);
+        // a segment past the section header table would have to move by 1 MiB
+        let shoff = layout.get(&elf, shoff_at, 8);
+        layout.put(&mut elf, ehsize + p_offset, 8, shoff);
+        layout.put(&mut elf, ehsize + p_align, 8, 1 << 20);
+        let section = Section::new(".x", SHT_PROGBITS, Vec::new());
+        let err = insert_section(Cursor::new(elf), &mut Vec::new(), &section).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        for &align in [3, 1 << 20].iter() {
+            let section = Section::new(".x", SHT_PROGBITS, Vec::new()).align(align);
+            let err =
+                insert_section(Cursor::new(build(layout)), &mut Vec::new(), &section).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn rejects_non_elf() {
+        let mut dest = Vec::new();
+        let err = insert_section(
+            Cursor::new(b"#!/bin/sh\necho not an executable\n".to_vec()),
+            &mut dest,
+            &Section::new(".x", SHT_PROGBITS, Vec::new()),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
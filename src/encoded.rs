@@ -0,0 +1,345 @@
+use base64::{value, ALPHABET};
+use inserter::Inserter;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// how much of the origin is examined to detect its line wrapping and case
+const PEEK_LEN: usize = 64 * 1024;
+
+type Insertions<'i> = Vec<(usize, Box<dyn 'i + Read>)>;
+
+/// a textual encoding of binary data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// standard base64 with padding
+    Base64,
+    /// two hex digits per byte
+    Hex,
+}
+
+/// inserts into base64 or hex encoded data at decoded offsets, re-encoding the output
+///
+/// whitespace in the origin is ignored while decoding. the output is wrapped
+/// at the width of the origin's first line, with the same line ending, and
+/// keeps a trailing newline if the origin has one; hex output keeps the case
+/// of the origin's digits.
+pub struct EncodedInserter<'i, R, W> {
+    origin: R,
+    target: W,
+    encoding: Encoding,
+    wrap: Option<usize>,
+    insertions: Insertions<'i>,
+}
+
+impl<'i, R, W> EncodedInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new inserter from an encoded origin to a target which will be encoded the same way
+    pub fn new(origin: R, target: W, encoding: Encoding) -> EncodedInserter<'i, R, W> {
+        EncodedInserter {
+            origin,
+            target,
+            encoding,
+            wrap: None,
+            insertions: Vec::new(),
+        }
+    }
+
+    /// wrap output lines at this many characters instead of matching the origin; 0 disables wrapping
+    pub fn wrap(mut self, width: usize) -> Self {
+        self.wrap = Some(width);
+        self
+    }
+
+    /// insert the source document into the output document at the given decoded origin index
+    pub fn insert<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
+        self.insertions.push((position, Box::new(source)));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    ///
+    /// fails with `InvalidData` if the origin isn't validly encoded
+    pub fn execute(self) -> io::Result<()> {
+        let mut origin = BufReader::with_capacity(PEEK_LEN, self.origin);
+        let layout = Layout::detect(origin.fill_buf()?);
+        let mut decoder = Decoder {
+            origin,
+            encoding: self.encoding,
+            acc: 0,
+            bits: 0,
+            padding: 0,
+            decoded: Vec::new(),
+            read: 0,
+            last: None,
+        };
+        let mut encoder = Encoder {
+            target: self.target,
+            encoding: self.encoding,
+            digits: if layout.upper {
+                b"0123456789ABCDEF"
+            } else {
+                b"0123456789abcdef"
+            },
+            wrap: match self.wrap {
+                Some(0) => None,
+                Some(width) => Some(width),
+                None => layout.wrap,
+            },
+            newline: layout.newline,
+            column: 0,
+            group: Vec::with_capacity(3),
+            encoded: Vec::new(),
+        };
+        let mut inserter = Inserter::new(&mut decoder, &mut encoder);
+        for (position, source) in self.insertions {
+            inserter = inserter.insert(position, source);
+        }
+        inserter.execute()?;
+        encoder.finish(decoder.last == Some(b'\n'))
+    }
+}
+
+/// the formatting of the origin, as far as it can be seen from its start
+struct Layout {
+    wrap: Option<usize>,
+    newline: &'static [u8],
+    upper: bool,
+}
+
+impl Layout {
+    fn detect(start: &[u8]) -> Layout {
+        let newline_at = start.iter().position(|&b| b == b'\n');
+        let crlf = newline_at.is_some_and(|at| at > 0 && start[at - 1] == b'\r');
+        let wrap = newline_at.and_then(|at| {
+            let rest = &start[at..];
+            if rest.iter().all(u8::is_ascii_whitespace) {
+                None
+            } else {
+                Some(at - crlf as usize)
+            }
+        });
+        Layout {
+            wrap: wrap.filter(|&width| width > 0),
+            newline: if crlf { b"\r\n" } else { b"\n" },
+            upper: start.iter().any(|b| (b'A'..=b'F').contains(b))
+                && !start.iter().any(|b| (b'a'..=b'f').contains(b)),
+        }
+    }
+}
+
+/// reads the decoded bytes of an encoded stream
+struct Decoder<R> {
+    origin: BufReader<R>,
+    encoding: Encoding,
+    acc: u32,
+    bits: u32,
+    padding: usize,
+    decoded: Vec<u8>,
+    read: usize,
+    last: Option<u8>,
+}
+
+impl<R: Read> Decoder<R> {
+    fn decode(&mut self, byte: u8) -> io::Result<()> {
+        if byte.is_ascii_whitespace() {
+            return Ok(());
+        }
+        let (digit, width) = match self.encoding {
+            Encoding::Base64 if byte == b'=' => {
+                self.padding += 1;
+                return Ok(());
+            }
+            Encoding::Base64 => (value(byte), 6),
+            Encoding::Hex => ((byte as char).to_digit(16).map(|d| d as u8), 4),
+        };
+        let digit = match digit {
+            Some(digit) if self.padding == 0 => digit,
+            Some(_) => return Err(invalid("data after base64 padding")),
+            None => return Err(invalid("invalid character in encoded data")),
+        };
+        self.acc = (self.acc << width) | u32::from(digit);
+        self.bits += width;
+        if self.bits >= 8 {
+            self.bits -= 8;
+            self.decoded.push((self.acc >> self.bits) as u8);
+            self.acc &= (1 << self.bits) - 1;
+        }
+        Ok(())
+    }
+
+    fn finish(&self) -> io::Result<()> {
+        let complete = match self.encoding {
+            Encoding::Base64 => self.bits < 6 && self.padding <= 2,
+            Encoding::Hex => self.bits == 0,
+        };
+        if complete {
+            Ok(())
+        } else {
+            Err(invalid("truncated encoded data"))
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.read == self.decoded.len() {
+            self.decoded.clear();
+            self.read = 0;
+            let chunk = self.origin.fill_buf()?.to_vec();
+            if chunk.is_empty() {
+                self.finish()?;
+                return Ok(0);
+            }
+            self.origin.consume(chunk.len());
+            for &byte in &chunk {
+                self.decode(byte)?;
+            }
+            self.last = chunk.last().cloned();
+        }
+        let len = buf.len().min(self.decoded.len() - self.read);
+        buf[..len].copy_from_slice(&self.decoded[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+/// writes its input encoded and wrapped
+struct Encoder<W> {
+    target: W,
+    encoding: Encoding,
+    digits: &'static [u8; 16],
+    wrap: Option<usize>,
+    newline: &'static [u8],
+    column: usize,
+    group: Vec<u8>,
+    encoded: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    fn emit(&mut self, character: u8) {
+        if self.wrap == Some(self.column) {
+            self.encoded.extend_from_slice(self.newline);
+            self.column = 0;
+        }
+        self.encoded.push(character);
+        self.column += 1;
+    }
+
+    fn emit_group(&mut self) {
+        let mut group = [0_u8; 3];
+        group[..self.group.len()].copy_from_slice(&self.group);
+        let bits = u32::from(group[0]) << 16 | u32::from(group[1]) << 8 | u32::from(group[2]);
+        for index in 0..4 {
+            if index <= self.group.len() {
+                self.emit(ALPHABET[(bits >> (18 - 6 * index) & 0x3F) as usize]);
+            } else {
+                self.emit(b'=');
+            }
+        }
+        self.group.clear();
+    }
+
+    fn finish(mut self, trailing_newline: bool) -> io::Result<()> {
+        if !self.group.is_empty() {
+            self.emit_group();
+        }
+        if trailing_newline && self.column > 0 {
+            self.encoded.extend_from_slice(self.newline);
+        }
+        self.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            match self.encoding {
+                Encoding::Base64 => {
+                    self.group.push(byte);
+                    if self.group.len() == 3 {
+                        self.emit_group();
+                    }
+                }
+                Encoding::Hex => {
+                    self.emit(self.digits[usize::from(byte >> 4)]);
+                    self.emit(self.digits[usize::from(byte & 0xF)]);
+                }
+            }
+        }
+        self.target.write_all(&self.encoded)?;
+        self.encoded.clear();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.target.write_all(&self.encoded)?;
+        self.encoded.clear();
+        self.target.flush()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(origin: &[u8], encoding: Encoding, wrap: Option<usize>) -> io::Result<String> {
+        let mut dest = Vec::new();
+        let mut inserter = EncodedInserter::new(origin, &mut dest, encoding);
+        if let Some(width) = wrap {
+            inserter = inserter.wrap(width);
+        }
+        inserter
+            .insert(3, &b"bar"[..])
+            .insert(100, &b"!"[..])
+            .execute()?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn inserts_into_base64() {
+        // "foobaz" -> "foobarbaz!"
+        assert_eq!(
+            "Zm9vYmFyYmF6IQ==",
+            run(b"Zm9vYmF6", Encoding::Base64, None).unwrap()
+        );
+        assert_eq!(
+            "Zm9v\nYmFy\nYmF6\nIQ==\n",
+            run(b"Zm9v\nYmF6\n", Encoding::Base64, None).unwrap()
+        );
+        assert_eq!(
+            "Zm9vYmFyYmF6\nIQ==",
+            run(b"Zm9vYmF6", Encoding::Base64, Some(12)).unwrap()
+        );
+    }
+
+    #[test]
+    fn inserts_into_hex() {
+        assert_eq!(
+            "666f6f6261726261\r\n7a21\r\n",
+            run(b"666f6f6261\r\n7a\r\n", Encoding::Hex, Some(16)).unwrap()
+        );
+        assert_eq!(
+            "666F6F62617262617A21",
+            run(b"666F6F62617A", Encoding::Hex, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_origins() {
+        for &(origin, encoding) in &[
+            (&b"Zm9v!"[..], Encoding::Base64),
+            (&b"Zm9=v"[..], Encoding::Base64),
+            (&b"Zm9vY"[..], Encoding::Base64),
+            (&b"abc"[..], Encoding::Hex),
+        ] {
+            let err = run(origin, encoding, None).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! aes-256-gcm encryption of output as it's written, and decryption of origins
+//! and sources as they're read
+//!
+//! the stream is the nonce prefix followed by chunks sealed with the STREAM
+//! construction: each holds `CHUNK_LEN` bytes of plaintext, except the last,
+//! which is shorter and flagged as last. truncating or reordering chunks fails
+//! decryption.
+
+use aes_gcm::{
+    aead::{
+        stream::{DecryptorBE32, EncryptorBE32},
+        KeyInit,
+    },
+    Aes256Gcm,
+};
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+/// bytes of plaintext in each chunk but the last
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// bytes each chunk grows by when it's sealed
+const TAG_LEN: usize = 16;
+
+/// an encryptor seals what's written to it, chunk by chunk, writing the
+/// ciphertext to its inner writer
+///
+/// the stream is incomplete until it's finished: dropping an encryptor leaves
+/// output which won't decrypt
+pub struct Encryptor<W> {
+    inner: W,
+    stream: Option<EncryptorBE32<Aes256Gcm>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Encryptor<W> {
+    /// encrypt everything written to `inner`, writing the nonce prefix immediately
+    ///
+    /// the nonce prefix must never be reused with the same key
+    pub fn new(mut inner: W, key: &[u8; 32], nonce: [u8; 7]) -> io::Result<Encryptor<W>> {
+        inner.write_all(&nonce)?;
+        Ok(Encryptor {
+            inner,
+            stream: Some(EncryptorBE32::from_aead(
+                Aes256Gcm::new(key.into()),
+                (&nonce).into(),
+            )),
+            buffer: Vec::with_capacity(CHUNK_LEN + TAG_LEN + 1),
+        })
+    }
+
+    /// seal the last chunk, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let stream = self.stream.take().expect("encryptor is only finished once");
+        stream
+            .encrypt_last_in_place(&[], &mut self.buffer)
+            .map_err(|_| exhausted())?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+fn exhausted() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "too many chunks to encrypt")
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a full chunk is only sealed once more follows it: until then, it
+        // may be the last
+        let len = buf.len().min(CHUNK_LEN + 1 - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() > CHUNK_LEN {
+            let rest = self.buffer.split_off(CHUNK_LEN);
+            self.stream
+                .as_mut()
+                .expect("encryptor is writable until it's finished")
+                .encrypt_next_in_place(&[], &mut self.buffer)
+                .map_err(|_| exhausted())?;
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+            self.buffer.extend_from_slice(&rest);
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// a decryptor opens the chunks its inner reader produces, reading as plaintext
+///
+/// reads fail with `InvalidData` when a chunk fails authentication, including
+/// when the stream has been truncated
+pub struct Decryptor<R> {
+    inner: R,
+    stream: Option<DecryptorBE32<Aes256Gcm>>,
+    sealed: Vec<u8>,
+    plain: Vec<u8>,
+    offset: usize,
+    /// whether a chunk failed authentication, so that nothing more is read
+    poisoned: bool,
+}
+
+impl<R: Read> Decryptor<R> {
+    /// decrypt the inner reader, reading its nonce prefix immediately
+    pub fn new(mut inner: R, key: &[u8; 32]) -> io::Result<Decryptor<R>> {
+        let mut nonce = [0; 7];
+        inner.read_exact(&mut nonce)?;
+        Ok(Decryptor {
+            inner,
+            stream: Some(DecryptorBE32::from_aead(
+                Aes256Gcm::new(key.into()),
+                (&nonce).into(),
+            )),
+            sealed: Vec::with_capacity(CHUNK_LEN + TAG_LEN + 1),
+            plain: Vec::new(),
+            offset: 0,
+            poisoned: false,
+        })
+    }
+
+    /// open the next chunk into the plaintext buffer
+    ///
+    /// a failed read can be retried, keeping what was read of the chunk, but
+    /// once a chunk fails authentication every later read fails too
+    fn open(&mut self) -> io::Result<()> {
+        if self.stream.is_none() {
+            return Ok(());
+        }
+        let want = CHUNK_LEN + TAG_LEN + 1 - self.sealed.len();
+        (&mut self.inner)
+            .take(want as u64)
+            .read_to_end(&mut self.sealed)?;
+        let mut stream = self.stream.take().expect("checked above");
+        let opened = if self.sealed.len() > CHUNK_LEN + TAG_LEN {
+            let rest = self.sealed.split_off(CHUNK_LEN + TAG_LEN);
+            self.plain = mem::replace(&mut self.sealed, rest);
+            let opened = stream.decrypt_next_in_place(&[], &mut self.plain);
+            self.stream = Some(stream);
+            opened
+        } else {
+            self.plain = mem::take(&mut self.sealed);
+            stream.decrypt_last_in_place(&[], &mut self.plain)
+        };
+        self.offset = 0;
+        if opened.is_err() {
+            self.poisoned = true;
+            self.stream = None;
+            self.plain.clear();
+            return Err(unauthentic());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.poisoned {
+            return Err(unauthentic());
+        }
+        while self.offset == self.plain.len() && self.stream.is_some() {
+            self.open()?;
+        }
+        let len = buf.len().min(self.plain.len() - self.offset);
+        buf[..len].copy_from_slice(&self.plain[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+fn unauthentic() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "chunk failed authentication")
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for Decryptor<R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{Fault, Faulty};
+    use Inserter;
+
+    const KEY: [u8; 32] = [3; 32];
+    const NONCE: [u8; 7] = *b"nonce!!";
+
+    fn encrypt(data: &[u8]) -> Vec<u8> {
+        let mut encryptor = Encryptor::new(Vec::new(), &KEY, NONCE).unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut plain = Vec::new();
+        Decryptor::new(data, &KEY)?.read_to_end(&mut plain)?;
+        Ok(plain)
+    }
+
+    #[test]
+    fn round_trips_across_chunks() {
+        for &len in &[0, 1, CHUNK_LEN - 1, CHUNK_LEN, CHUNK_LEN + 1, 3 * CHUNK_LEN] {
+            let data: Vec<u8> = (0..len).map(|idx| (idx % 251) as u8).collect();
+            let sealed = encrypt(&data);
+            let chunks = len.div_ceil(CHUNK_LEN).max(1);
+            assert_eq!(7 + len + chunks * TAG_LEN, sealed.len());
+            assert_eq!(data, decrypt(&sealed).unwrap());
+        }
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let data = vec![7; 2 * CHUNK_LEN];
+        let sealed = encrypt(&data);
+
+        let mut flipped = sealed.clone();
+        flipped[100] ^= 1;
+        let err = decrypt(&flipped).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        // the failure sticks rather than reading as the end of the stream
+        let mut decryptor = Decryptor::new(flipped.as_slice(), &KEY).unwrap();
+        for _ in 0..2 {
+            let err = decryptor.read(&mut [0; 16]).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        }
+
+        let truncated = &sealed[..7 + CHUNK_LEN + TAG_LEN];
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            decrypt(truncated).unwrap_err().kind()
+        );
+        assert!(Decryptor::new(&b"short"[..], &KEY).is_err());
+    }
+
+    #[test]
+    fn resumes_after_read_errors() {
+        let data = vec![5; CHUNK_LEN + 10];
+        let sealed = encrypt(&data);
+        let faults = [
+            Fault::Short(7),
+            Fault::Short(100),
+            Fault::Fail(io::ErrorKind::TimedOut),
+        ];
+        let mut decryptor = Decryptor::new(Faulty::new(sealed.as_slice(), faults), &KEY).unwrap();
+        let err = decryptor.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        let mut plain = Vec::new();
+        decryptor.read_to_end(&mut plain).unwrap();
+        assert_eq!(data, plain);
+    }
+
+    #[test]
+    fn encrypts_output() {
+        let mut out = Vec::new();
+        Inserter::new(&b"alpha charlie"[..], &mut out)
+            .insert(6, "bravo ")
+            .execute_encrypted(&KEY, NONCE)
+            .unwrap();
+        assert_eq!(b"alpha bravo charlie".to_vec(), decrypt(&out).unwrap());
+    }
+
+    #[test]
+    fn decrypts_origin_and_sources() {
+        let origin = encrypt(b"alpha charlie");
+        let source = encrypt(b"bravo ");
+        let out = Inserter::new(Decryptor::new(origin.as_slice(), &KEY).unwrap(), Vec::new())
+            .insert(6, Decryptor::new(source.as_slice(), &KEY).unwrap())
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("alpha bravo charlie", out);
+    }
+}
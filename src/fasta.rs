@@ -0,0 +1,203 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// insert `sequence` into the named record of a FASTA file at the given
+/// sequence coordinate
+///
+/// coordinates count residues from zero, ignoring the header and line breaks.
+/// the record's sequence lines are re-wrapped to its original line width from
+/// the insertion point onward. positions past the end of the record append to
+/// it. fails with `NotFound` if no record has the given name.
+pub fn insert_sequence<R, W>(
+    origin: R,
+    mut target: W,
+    record: &str,
+    position: usize,
+    sequence: &[u8],
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let sequence: Vec<u8> = sequence
+        .iter()
+        .cloned()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    let mut origin = BufReader::new(origin);
+    let mut rewrap: Option<Rewrap> = None;
+    let mut found = false;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if origin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        if line.starts_with(b">") {
+            if let Some(rewrap) = rewrap.take() {
+                rewrap.finish(&mut target)?;
+            }
+            target.write_all(&line)?;
+            if !found && record_name(&line) == record.as_bytes() {
+                found = true;
+                let newline: &[u8] = if line.ends_with(b"\r\n") {
+                    b"\r\n"
+                } else {
+                    b"\n"
+                };
+                rewrap = Some(Rewrap::new(position, &sequence, newline));
+            }
+            continue;
+        }
+
+        match rewrap {
+            Some(ref mut rewrap) => rewrap.push(&mut target, trim(&line))?,
+            None => target.write_all(&line)?,
+        }
+    }
+
+    if let Some(rewrap) = rewrap.take() {
+        rewrap.finish(&mut target)?;
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no FASTA record named `{}`", record),
+        ))
+    }
+}
+
+fn record_name(header: &[u8]) -> &[u8] {
+    let header = &header[1..];
+    let end = header
+        .iter()
+        .position(|b| b.is_ascii_whitespace())
+        .unwrap_or(header.len());
+    &header[..end]
+}
+
+fn trim(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |idx| idx + 1);
+    &line[..end]
+}
+
+/// re-wraps the residues of a single record, splicing in the insertion
+struct Rewrap<'s> {
+    width: Option<usize>,
+    newline: &'s [u8],
+    carry: Vec<u8>,
+    seen: usize,
+    position: usize,
+    insertion: Option<&'s [u8]>,
+}
+
+impl<'s> Rewrap<'s> {
+    fn new(position: usize, insertion: &'s [u8], newline: &'s [u8]) -> Rewrap<'s> {
+        Rewrap {
+            width: None,
+            newline,
+            carry: Vec::new(),
+            seen: 0,
+            position,
+            insertion: Some(insertion),
+        }
+    }
+
+    fn push<W: Write>(&mut self, target: &mut W, residues: &[u8]) -> io::Result<()> {
+        if residues.is_empty() {
+            return Ok(());
+        }
+        if self.width.is_none() {
+            self.width = Some(residues.len());
+        }
+        let split = self.position.saturating_sub(self.seen);
+        self.seen += residues.len();
+        if split <= residues.len() {
+            if let Some(insertion) = self.insertion.take() {
+                self.emit(target, &residues[..split])?;
+                self.emit(target, insertion)?;
+                return self.emit(target, &residues[split..]);
+            }
+        }
+        self.emit(target, residues)
+    }
+
+    fn emit<W: Write>(&mut self, target: &mut W, residues: &[u8]) -> io::Result<()> {
+        self.carry.extend_from_slice(residues);
+        let width = self.width.unwrap_or(self.carry.len()).max(1);
+        let mut start = 0;
+        while self.carry.len() - start >= width {
+            target.write_all(&self.carry[start..start + width])?;
+            target.write_all(self.newline)?;
+            start += width;
+        }
+        self.carry.drain(..start);
+        Ok(())
+    }
+
+    fn finish<W: Write>(mut self, target: &mut W) -> io::Result<()> {
+        if let Some(insertion) = self.insertion.take() {
+            self.emit(target, insertion)?;
+        }
+        if !self.carry.is_empty() {
+            target.write_all(&self.carry)?;
+            target.write_all(self.newline)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = ">one first record\nAAAA\nAAAA\nAA\n>two\nCCCC\nCC\n";
+
+    fn run(record: &str, position: usize, sequence: &str) -> io::Result<String> {
+        let mut dest = Vec::new();
+        insert_sequence(
+            ORIGIN.as_bytes(),
+            &mut dest,
+            record,
+            position,
+            sequence.as_bytes(),
+        )?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn inserts_and_rewraps() {
+        let out = run("one", 5, "GGG").unwrap();
+        assert_eq!(
+            ">one first record\nAAAA\nAGGG\nAAAA\nA\n>two\nCCCC\nCC\n",
+            out
+        );
+    }
+
+    #[test]
+    fn inserts_at_line_boundary() {
+        let out = run("two", 4, "TTTT").unwrap();
+        assert_eq!(
+            ">one first record\nAAAA\nAAAA\nAA\n>two\nCCCC\nTTTT\nCC\n",
+            out
+        );
+    }
+
+    #[test]
+    fn appends_past_end_of_record() {
+        let out = run("one", 100, "TT").unwrap();
+        assert_eq!(">one first record\nAAAA\nAAAA\nAATT\n>two\nCCCC\nCC\n", out);
+    }
+
+    #[test]
+    fn missing_record() {
+        let err = run("three", 0, "A").unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
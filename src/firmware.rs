@@ -0,0 +1,378 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// the most data bytes written per generated record
+pub const RECORD_LEN: usize = 16;
+
+/// insert data at a memory address into an Intel HEX or Motorola S-record file
+///
+/// the format is detected from the first record. the new records go just before
+/// the first data record with a higher address, so sorted files stay sorted, or
+/// else before the end of file record. Intel HEX extended address records are
+/// emitted as needed and the address in effect is restored afterwards; S-record
+/// count records are updated. fails if the data overlaps an existing record.
+pub fn insert_data<R, W>(origin: R, target: W, address: u32, data: &[u8]) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    if data.is_empty() || u64::from(address) + data.len() as u64 > 1 << 32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "data must be non-empty and fit in a 32-bit address space",
+        ));
+    }
+    let mut lines = BufReader::new(origin);
+    let first = lines
+        .fill_buf()?
+        .iter()
+        .cloned()
+        .find(|b| !b.is_ascii_whitespace());
+    let mut splice = Splice {
+        lines,
+        target,
+        address,
+        data,
+        eol: b"\n".to_vec(),
+        done: false,
+    };
+    match first {
+        Some(b'S') => splice.srec(),
+        _ => splice.intel_hex(),
+    }
+}
+
+struct Splice<'d, R, W> {
+    lines: BufReader<R>,
+    target: W,
+    address: u32,
+    data: &'d [u8],
+    eol: Vec<u8>,
+    done: bool,
+}
+
+impl<'d, R: Read, W: Write> Splice<'d, R, W> {
+    /// the next line, without its ending; `None` at the end of the stream
+    fn next_line(&mut self, line: &mut Vec<u8>) -> io::Result<Option<usize>> {
+        line.clear();
+        if self.lines.read_until(b'\n', line)? == 0 {
+            return Ok(None);
+        }
+        let text_len = line
+            .iter()
+            .rposition(|&b| b != b'\n' && b != b'\r')
+            .map_or(0, |idx| idx + 1);
+        if text_len < line.len() {
+            self.eol = line[text_len..].to_vec();
+        }
+        Ok(Some(text_len))
+    }
+
+    /// data chunks of at most `RECORD_LEN` bytes which don't cross a 64k boundary
+    fn chunks(&self) -> Vec<(u32, &'d [u8])> {
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < self.data.len() {
+            let address = self.address + offset as u32;
+            let to_boundary = 0x1_0000 - (address & 0xFFFF) as usize;
+            let len = RECORD_LEN.min(to_boundary).min(self.data.len() - offset);
+            chunks.push((address, &self.data[offset..offset + len]));
+            offset += len;
+        }
+        chunks
+    }
+
+    fn check_overlap(&self, start: u64, len: usize) -> io::Result<()> {
+        let ours = u64::from(self.address)..u64::from(self.address) + self.data.len() as u64;
+        if start < ours.end && ours.start < start + len as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("data overlaps the existing record at {:#x}", start),
+            ));
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        self.target.write_all(record)?;
+        self.target.write_all(&self.eol)
+    }
+
+    fn intel_hex(&mut self) -> io::Result<()> {
+        let mut line = Vec::new();
+        // the upper address bits in effect, the record setting them, and whether it was linear
+        let mut base = 0_u32;
+        let mut base_record: Option<Vec<u8>> = None;
+        let mut linear = true;
+
+        while let Some(text_len) = self.next_line(&mut line)? {
+            if text_len == 0 {
+                self.target.write_all(&line)?;
+                continue;
+            }
+            let (kind, offset, payload) = decode_intel_hex(&line[..text_len])?;
+            match kind {
+                0 => {
+                    let start = u64::from(base) + u64::from(offset);
+                    self.check_overlap(start, payload.len())?;
+                    if !self.done && start > u64::from(self.address) {
+                        self.write_intel_hex(base, linear, &base_record)?;
+                    }
+                }
+                1 if !self.done => self.write_intel_hex(base, linear, &base_record)?,
+                2 | 4 if payload.len() == 2 => {
+                    let value = u32::from(u16::from_be_bytes([payload[0], payload[1]]));
+                    linear = kind == 4;
+                    base = if linear { value << 16 } else { value << 4 };
+                    base_record = Some(line[..text_len].to_vec());
+                }
+                2 | 4 => return Err(invalid("malformed extended address record")),
+                _ => {}
+            }
+            self.target.write_all(&line)?;
+        }
+        if !self.done {
+            // no end of file record: supply one
+            self.write_intel_hex(base, linear, &base_record)?;
+            self.write_record(b":00000001FF")?;
+        }
+        Ok(())
+    }
+
+    fn write_intel_hex(
+        &mut self,
+        base: u32,
+        linear: bool,
+        base_record: &Option<Vec<u8>>,
+    ) -> io::Result<()> {
+        let mut emitted = if linear { Some(base) } else { None };
+        for (address, chunk) in self.chunks() {
+            let upper = address & 0xFFFF_0000;
+            if emitted != Some(upper) {
+                let upper_bytes = ((upper >> 16) as u16).to_be_bytes();
+                self.write_record(&encode_intel_hex(4, 0, &upper_bytes))?;
+                emitted = Some(upper);
+            }
+            self.write_record(&encode_intel_hex(0, address as u16, chunk))?;
+        }
+        if emitted != Some(base) || !linear {
+            let restore = match *base_record {
+                Some(ref record) => record.clone(),
+                None => encode_intel_hex(4, 0, &[0, 0]),
+            };
+            self.write_record(&restore)?;
+        }
+        self.done = true;
+        Ok(())
+    }
+
+    fn srec(&mut self) -> io::Result<()> {
+        let mut line = Vec::new();
+        let mut width = 2;
+        let mut inserted = 0;
+
+        while let Some(text_len) = self.next_line(&mut line)? {
+            if text_len == 0 {
+                self.target.write_all(&line)?;
+                continue;
+            }
+            let (kind, bytes) = decode_srec(&line[..text_len])?;
+            match kind {
+                b'1'..=b'3' => {
+                    width = usize::from(kind - b'0') + 1;
+                    if bytes.len() < width {
+                        return Err(invalid("truncated S-record address"));
+                    }
+                    let start = bytes[..width]
+                        .iter()
+                        .fold(0_u64, |acc, &b| acc << 8 | u64::from(b));
+                    self.check_overlap(start, bytes.len() - width)?;
+                    if !self.done && start > u64::from(self.address) {
+                        inserted = self.write_srec(width)?;
+                    }
+                }
+                b'5' | b'6' => {
+                    if !self.done {
+                        inserted = self.write_srec(width)?;
+                    }
+                    let count = bytes.iter().fold(0_u64, |acc, &b| acc << 8 | u64::from(b));
+                    let count = count + inserted as u64;
+                    let record = if count <= 0xFFFF {
+                        encode_srec(b'5', &(count as u16).to_be_bytes())
+                    } else {
+                        encode_srec(b'6', &(count as u32).to_be_bytes()[1..])
+                    };
+                    self.write_record(&record)?;
+                    continue;
+                }
+                b'7'..=b'9' if !self.done => inserted = self.write_srec(width)?,
+                _ => {}
+            }
+            self.target.write_all(&line)?;
+        }
+        if !self.done {
+            self.write_srec(width)?;
+        }
+        Ok(())
+    }
+
+    /// write the data as S-records, returning how many were written
+    fn write_srec(&mut self, file_width: usize) -> io::Result<usize> {
+        let last = self.address as usize + self.data.len() - 1;
+        let needed = match last {
+            0..=0xFFFF => 2,
+            0x1_0000..=0xFF_FFFF => 3,
+            _ => 4,
+        };
+        let width = needed.max(file_width);
+        let kind = b'0' + width as u8 - 1;
+        let chunks = self.chunks();
+        for &(address, chunk) in &chunks {
+            let mut bytes = address.to_be_bytes()[4 - width..].to_vec();
+            bytes.extend_from_slice(chunk);
+            self.write_record(&encode_srec(kind, &bytes))?;
+        }
+        self.done = true;
+        Ok(chunks.len())
+    }
+}
+
+/// record type, address offset and data of an Intel HEX record
+fn decode_intel_hex(text: &[u8]) -> io::Result<(u8, u16, Vec<u8>)> {
+    if text[0] != b':' {
+        return Err(invalid("Intel HEX records start with `:`"));
+    }
+    let bytes = decode_hex(&text[1..])?;
+    if bytes.len() < 5 || bytes.len() != usize::from(bytes[0]) + 5 {
+        return Err(invalid("Intel HEX record length mismatch"));
+    }
+    if bytes.iter().fold(0_u8, |acc, &b| acc.wrapping_add(b)) != 0 {
+        return Err(invalid("Intel HEX record checksum mismatch"));
+    }
+    let offset = u16::from_be_bytes([bytes[1], bytes[2]]);
+    Ok((bytes[3], offset, bytes[4..bytes.len() - 1].to_vec()))
+}
+
+fn encode_intel_hex(kind: u8, offset: u16, data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0_u8, |acc, &b| acc.wrapping_add(b));
+    bytes.push(sum.wrapping_neg());
+    let mut out = b":".to_vec();
+    out.extend(encode_hex(&bytes));
+    out
+}
+
+/// record type digit and the address and data bytes of an S-record
+fn decode_srec(text: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+    if text.len() < 2 || text[0] != b'S' || !text[1].is_ascii_digit() {
+        return Err(invalid("S-records start with `S` and a type digit"));
+    }
+    let bytes = decode_hex(&text[2..])?;
+    if bytes.len() < 2 || bytes.len() != usize::from(bytes[0]) + 1 {
+        return Err(invalid("S-record length mismatch"));
+    }
+    if bytes.iter().fold(0_u8, |acc, &b| acc.wrapping_add(b)) != 0xFF {
+        return Err(invalid("S-record checksum mismatch"));
+    }
+    Ok((text[1], bytes[1..bytes.len() - 1].to_vec()))
+}
+
+fn encode_srec(kind: u8, bytes: &[u8]) -> Vec<u8> {
+    let mut framed = vec![bytes.len() as u8 + 1];
+    framed.extend_from_slice(bytes);
+    let sum = framed.iter().fold(0_u8, |acc, &b| acc.wrapping_add(b));
+    framed.push(!sum);
+    let mut out = vec![b'S', kind];
+    out.extend(encode_hex(&framed));
+    out
+}
+
+fn decode_hex(text: &[u8]) -> io::Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err(invalid("odd number of hex digits"));
+    }
+    text.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid("malformed hex digits"))
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|b| format!("{:02X}", b).into_bytes())
+        .collect()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(origin: &str, address: u32, data: &[u8]) -> io::Result<String> {
+        let mut dest = Vec::new();
+        insert_data(origin.as_bytes(), &mut dest, address, data)?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn encodes_known_records() {
+        assert_eq!(
+            b":0B0010006164647265737320676170A7".to_vec(),
+            encode_intel_hex(0, 0x10, b"address gap")
+        );
+        assert_eq!(b"S5030003F9".to_vec(), encode_srec(b'5', &[0, 3]));
+    }
+
+    #[test]
+    fn intel_hex_in_address_order() {
+        let origin = ":0100000001FE\r\n:0100200002DD\r\n:00000001FF\r\n";
+        let out = run(origin, 0x10, &[0xAA, 0xBB]).unwrap();
+        assert_eq!(
+            ":0100000001FE\r\n:02001000AABB89\r\n:0100200002DD\r\n:00000001FF\r\n",
+            out
+        );
+    }
+
+    #[test]
+    fn intel_hex_extended_address() {
+        let origin = ":0100000001FE\n:00000001FF\n";
+        let out = run(origin, 0x1_FFFF, &[1, 2]).unwrap();
+        let expected = [
+            ":0100000001FE",
+            ":020000040001F9",
+            ":01FFFF000100",
+            ":020000040002F8",
+            ":0100000002FD",
+            ":020000040000FA",
+            ":00000001FF",
+        ];
+        assert_eq!(expected.join("\n") + "\n", out);
+    }
+
+    #[test]
+    fn srec_updates_count() {
+        let origin = "S00600004844521B\nS104000001FA\nS5030001FB\nS9030000FC\n";
+        let out = run(origin, 0x20, &[5]).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!("S104002005D6", lines[2]);
+        assert_eq!("S5030002FA", lines[3]);
+        assert_eq!("S9030000FC", lines[4]);
+    }
+
+    #[test]
+    fn rejects_overlap_and_bad_checksums() {
+        let origin = ":0200000001020B\n:00000001FF\n";
+        assert!(run(origin, 1, &[9]).is_err());
+        assert!(run(":0100000001FF\n", 0x10, &[9]).is_err());
+    }
+}
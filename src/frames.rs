@@ -0,0 +1,252 @@
+use std::io::{self, Read, Write};
+use util::fill;
+
+/// how the length of each frame is encoded ahead of its payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+    /// a big-endian unsigned integer of the given width in bytes, 1 to 8
+    BigEndian(usize),
+    /// a little-endian unsigned integer of the given width in bytes, 1 to 8
+    LittleEndian(usize),
+    /// a protobuf-style LEB128 varint
+    Varint,
+}
+
+impl Prefix {
+    /// the prefix for a payload of the given length
+    pub fn encode(self, len: usize) -> io::Result<Vec<u8>> {
+        let len = len as u64;
+        match self {
+            Prefix::Varint => {
+                let mut out = Vec::with_capacity(10);
+                let mut value = len;
+                while value >= 0x80 {
+                    out.push(value as u8 | 0x80);
+                    value >>= 7;
+                }
+                out.push(value as u8);
+                Ok(out)
+            }
+            Prefix::BigEndian(width) | Prefix::LittleEndian(width) => {
+                let width = checked_width(width)?;
+                if width < 8 && len >> (8 * width) != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "frame too long for its length prefix",
+                    ));
+                }
+                Ok(match self {
+                    Prefix::BigEndian(_) => len.to_be_bytes()[8 - width..].to_vec(),
+                    _ => len.to_le_bytes()[..width].to_vec(),
+                })
+            }
+        }
+    }
+
+    /// read a prefix, returning `None` on a clean end of stream
+    fn read<R: Read>(self, origin: &mut R, raw: &mut Vec<u8>) -> io::Result<Option<u64>> {
+        raw.clear();
+        match self {
+            Prefix::Varint => {
+                let mut value = 0_u64;
+                for shift in (0..64).step_by(7) {
+                    let mut byte = [0_u8];
+                    if fill(origin, &mut byte)? == 0 {
+                        return if raw.is_empty() {
+                            Ok(None)
+                        } else {
+                            Err(truncated())
+                        };
+                    }
+                    raw.push(byte[0]);
+                    value |= u64::from(byte[0] & 0x7F) << shift;
+                    if byte[0] & 0x80 == 0 {
+                        return Ok(Some(value));
+                    }
+                }
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "varint length prefix too long",
+                ))
+            }
+            Prefix::BigEndian(width) | Prefix::LittleEndian(width) => {
+                let width = checked_width(width)?;
+                raw.resize(width, 0);
+                match fill(origin, raw)? {
+                    0 => return Ok(None),
+                    n if n < width => return Err(truncated()),
+                    _ => {}
+                }
+                let mut bytes = [0_u8; 8];
+                Ok(Some(match self {
+                    Prefix::BigEndian(_) => {
+                        bytes[8 - width..].copy_from_slice(raw);
+                        u64::from_be_bytes(bytes)
+                    }
+                    _ => {
+                        bytes[..width].copy_from_slice(raw);
+                        u64::from_le_bytes(bytes)
+                    }
+                }))
+            }
+        }
+    }
+}
+
+/// a test applied to the payload of each existing frame
+pub type Predicate<'p> = Box<dyn 'p + Fn(&[u8]) -> bool>;
+
+/// where to place a new frame in a stream of frames
+pub enum Placement<'p> {
+    /// before the frame with this index; past the last frame appends
+    Index(usize),
+    /// after the first frame whose payload satisfies the predicate
+    After(Predicate<'p>),
+}
+
+/// insert a frame into a stream of length-prefixed frames
+///
+/// frames are streamed through untouched, except that an `After` placement must
+/// buffer each payload to test it. fails with `NotFound` if the predicate
+/// matches no frame, and with `UnexpectedEof` if the last frame is truncated.
+pub fn insert_frame<R, W>(
+    mut origin: R,
+    mut target: W,
+    prefix: Prefix,
+    placement: Placement,
+    payload: &[u8],
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut frame = prefix.encode(payload.len())?;
+    frame.extend_from_slice(payload);
+
+    let mut raw = Vec::new();
+    let mut existing = Vec::new();
+    let mut index = 0;
+    loop {
+        if let Placement::Index(at) = placement {
+            if at == index {
+                target.write_all(&frame)?;
+                io::copy(&mut origin, &mut target)?;
+                return Ok(());
+            }
+        }
+        let len = match prefix.read(&mut origin, &mut raw)? {
+            Some(len) => len,
+            None => break,
+        };
+        target.write_all(&raw)?;
+        match placement {
+            Placement::Index(_) => {
+                if io::copy(&mut origin.by_ref().take(len), &mut target)? != len {
+                    return Err(truncated());
+                }
+            }
+            Placement::After(ref predicate) => {
+                existing.clear();
+                if origin.by_ref().take(len).read_to_end(&mut existing)? as u64 != len {
+                    return Err(truncated());
+                }
+                target.write_all(&existing)?;
+                if predicate(&existing) {
+                    target.write_all(&frame)?;
+                    io::copy(&mut origin, &mut target)?;
+                    return Ok(());
+                }
+            }
+        }
+        index += 1;
+    }
+
+    match placement {
+        Placement::Index(_) => target.write_all(&frame),
+        Placement::After(_) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no frame matched the predicate",
+        )),
+    }
+}
+
+fn checked_width(width: usize) -> io::Result<usize> {
+    if width == 0 || width > 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "length prefix width must be 1 to 8 bytes",
+        ));
+    }
+    Ok(width)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(prefix: Prefix, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for payload in payloads {
+            out.extend(prefix.encode(payload.len()).unwrap());
+            out.extend_from_slice(payload);
+        }
+        out
+    }
+
+    fn run(prefix: Prefix, placement: Placement) -> io::Result<Vec<u8>> {
+        let origin = stream(prefix, &[b"alpha", b"bravo"]);
+        let mut dest = Vec::new();
+        insert_frame(origin.as_slice(), &mut dest, prefix, placement, b"new")?;
+        Ok(dest)
+    }
+
+    #[test]
+    fn encodes_prefixes() {
+        assert_eq!(vec![0, 5], Prefix::BigEndian(2).encode(5).unwrap());
+        assert_eq!(vec![5, 0, 0], Prefix::LittleEndian(3).encode(5).unwrap());
+        assert_eq!(vec![0xAC, 0x02], Prefix::Varint.encode(300).unwrap());
+        assert!(Prefix::BigEndian(1).encode(256).is_err());
+        assert!(Prefix::BigEndian(9).encode(1).is_err());
+    }
+
+    #[test]
+    fn inserts_at_index() {
+        for &prefix in &[
+            Prefix::BigEndian(4),
+            Prefix::LittleEndian(2),
+            Prefix::Varint,
+        ] {
+            let out = run(prefix, Placement::Index(1)).unwrap();
+            assert_eq!(stream(prefix, &[b"alpha", b"new", b"bravo"]), out);
+            let out = run(prefix, Placement::Index(7)).unwrap();
+            assert_eq!(stream(prefix, &[b"alpha", b"bravo", b"new"]), out);
+        }
+    }
+
+    #[test]
+    fn inserts_after_match() {
+        let prefix = Prefix::Varint;
+        let out = run(prefix, Placement::After(Box::new(|p| p == b"alpha"))).unwrap();
+        assert_eq!(stream(prefix, &[b"alpha", b"new", b"bravo"]), out);
+        let err = run(prefix, Placement::After(Box::new(|_| false))).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut dest = Vec::new();
+        let err = insert_frame(
+            &b"\x00\x09abc"[..],
+            &mut dest,
+            Prefix::BigEndian(2),
+            Placement::Index(5),
+            b"x",
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+}
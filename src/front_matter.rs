@@ -0,0 +1,123 @@
+use inserter::Inserter;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// the fence styles used by static site generators for front matter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrontMatter {
+    /// `---` fenced YAML
+    Yaml,
+    /// `+++` fenced TOML
+    Toml,
+}
+
+impl FrontMatter {
+    /// the line which opens and closes a block of this kind
+    pub fn delimiter(self) -> &'static str {
+        match self {
+            FrontMatter::Yaml => "---",
+            FrontMatter::Toml => "+++",
+        }
+    }
+}
+
+/// insert `content` into the front matter of the origin document
+///
+/// if the document already opens with a front matter fence of the given kind,
+/// the content is inserted just after that opening line. otherwise a new fenced
+/// block is created at the top of the document, after any byte order mark.
+/// generated lines follow the line ending of the document's first line.
+pub fn insert_front_matter<R, W>(
+    origin: R,
+    target: W,
+    kind: FrontMatter,
+    content: &str,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut origin = BufReader::new(origin);
+    let mut first_line = Vec::new();
+    origin.read_until(b'\n', &mut first_line)?;
+
+    let bom_len = if first_line.starts_with(BOM) {
+        BOM.len()
+    } else {
+        0
+    };
+    let newline: &[u8] = if first_line.ends_with(b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    let fenced = trim_line_end(&first_line[bom_len..]) == kind.delimiter().as_bytes();
+
+    let mut insertion = Vec::with_capacity(content.len() + 2 * (kind.delimiter().len() + 2));
+    if !fenced {
+        insertion.extend_from_slice(kind.delimiter().as_bytes());
+        insertion.extend_from_slice(newline);
+    }
+    insertion.extend_from_slice(content.as_bytes());
+    if !content.is_empty() && !content.ends_with('\n') {
+        insertion.extend_from_slice(newline);
+    }
+    if !fenced {
+        insertion.extend_from_slice(kind.delimiter().as_bytes());
+        insertion.extend_from_slice(newline);
+    }
+
+    let position = if fenced { first_line.len() } else { bom_len };
+    Inserter::new(first_line.as_slice().chain(origin), target)
+        .insert(position, insertion.as_slice())
+        .execute()
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    while end > 0 && (line[end - 1] == b'\n' || line[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(origin: &str, kind: FrontMatter, content: &str) -> String {
+        let mut dest = Vec::new();
+        insert_front_matter(origin.as_bytes(), &mut dest, kind, content)
+            .expect("manipulating strings should never fail");
+        String::from_utf8(dest).unwrap()
+    }
+
+    #[test]
+    fn extends_existing_block() {
+        let out = run(
+            "---\ntitle: hi\n---\nbody\n",
+            FrontMatter::Yaml,
+            "draft: true",
+        );
+        assert_eq!("---\ndraft: true\ntitle: hi\n---\nbody\n", out);
+    }
+
+    #[test]
+    fn creates_missing_block() {
+        let out = run("# heading\r\n", FrontMatter::Toml, "draft = true\n");
+        assert_eq!("+++\r\ndraft = true\n+++\r\n# heading\r\n", out);
+    }
+
+    #[test]
+    fn preserves_byte_order_mark() {
+        let out = run("\u{feff}body", FrontMatter::Yaml, "a: b");
+        assert_eq!("\u{feff}---\na: b\n---\nbody", out);
+    }
+
+    #[test]
+    fn empty_document() {
+        let out = run("", FrontMatter::Yaml, "a: b");
+        assert_eq!("---\na: b\n---\n", out);
+    }
+}
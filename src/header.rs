@@ -0,0 +1,174 @@
+use comment::CommentStyle;
+use operation::PlanBuilder;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// the length of the preamble which must stay at the top of a file: a byte
+/// order mark, then a shebang line, an encoding declaration like
+/// `# -*- coding: latin-1 -*-`, or both, or an xml declaration
+pub fn preamble_len<R: Read>(origin: R) -> io::Result<usize> {
+    let head = read_head(&mut BufReader::new(origin))?;
+    Ok(preamble(&head).0)
+}
+
+/// the first two lines of the origin, which hold any preamble
+fn read_head<R: BufRead>(origin: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    for _ in 0..2 {
+        origin.read_until(b'\n', &mut head)?;
+    }
+    Ok(head)
+}
+
+/// the preamble length within the head, and whether it ends with a newline
+fn preamble(head: &[u8]) -> (usize, bool) {
+    let mut len = if head.starts_with(BOM) { BOM.len() } else { 0 };
+    let mut terminated = true;
+    for number in 1..=2 {
+        let rest = &head[len..];
+        let line = match rest.iter().position(|&b| b == b'\n') {
+            Some(newline) => &rest[..=newline],
+            None => rest,
+        };
+        let shebang = number == 1 && line.starts_with(b"#!");
+        let xml = number == 1 && line.starts_with(b"<?xml");
+        if line.is_empty() || !(shebang || xml || declares_encoding(line)) {
+            break;
+        }
+        len += line.len();
+        terminated = line.ends_with(b"\n");
+        if !shebang {
+            break;
+        }
+    }
+    (len, terminated)
+}
+
+/// whether the line is a python-style encoding declaration
+fn declares_encoding(line: &[u8]) -> bool {
+    line.starts_with(b"#")
+        && line
+            .windows(7)
+            .any(|window| window == b"coding:" || window == b"coding=")
+}
+
+/// a header, such as a license notice, inserted at the top of files after
+/// their preamble unless it's already there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    text: String,
+    style: Option<CommentStyle>,
+}
+
+impl Header {
+    /// a header of this text, inserted as it is
+    pub fn new<S: Into<String>>(text: S) -> Header {
+        Header {
+            text: text.into(),
+            style: None,
+        }
+    }
+
+    /// wrap the text in comments of this style, if any
+    ///
+    /// use `CommentStyle::for_path` to comment each file in its own language
+    pub fn comment(mut self, style: Option<CommentStyle>) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// the header as it's inserted
+    pub fn text(&self) -> String {
+        match self.style {
+            Some(style) => style.wrap(&self.text),
+            None => self.text.clone(),
+        }
+    }
+
+    /// where the header goes in the origin and what's inserted there, or
+    /// `None` if the header already follows the preamble
+    pub fn insertion<R: Read>(&self, origin: R) -> io::Result<Option<(usize, String)>> {
+        let mut origin = BufReader::new(origin);
+        let head = read_head(&mut origin)?;
+        let (position, terminated) = preamble(&head);
+        let text = self.text();
+        if !terminated {
+            return Ok(Some((position, format!("\n{}", text))));
+        }
+        let mut following = Vec::with_capacity(text.len());
+        (&head[position..])
+            .chain(origin)
+            .take(text.len() as u64)
+            .read_to_end(&mut following)?;
+        if following == text.as_bytes() {
+            Ok(None)
+        } else {
+            Ok(Some((position, text)))
+        }
+    }
+
+    /// write the origin with the header inserted, returning whether it was
+    ///
+    /// the origin is scanned from its current position, then rewound
+    pub fn apply<R: Read + Seek, W: Write>(&self, mut origin: R, target: W) -> io::Result<bool> {
+        let start = origin.stream_position()?;
+        let insertion = self.insertion(&mut origin)?;
+        origin.seek(SeekFrom::Start(start))?;
+        let mut plan = PlanBuilder::new();
+        if let Some((position, ref text)) = insertion {
+            plan = plan.insert(position, text.as_bytes());
+        }
+        plan.execute(origin, target)?;
+        Ok(insertion.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn skips_preambles() {
+        for (origin, len) in [
+            (&b"fn main() {}\n"[..], 0),
+            (b"\xEF\xBB\xBF# a comment\n", 3),
+            (b"#!/usr/bin/env python\nimport os\n", 22),
+            (
+                b"#!/usr/bin/env python\n# -*- coding: latin-1 -*-\nx = 1\n",
+                48,
+            ),
+            (b"# vim: set fileencoding=utf-8 :\n#!not a shebang\n", 32),
+            (b"<?xml version=\"1.0\"?>\n<svg/>", 22),
+            (b"#!/bin/sh", 9),
+        ] {
+            assert_eq!(len, preamble_len(origin).unwrap());
+        }
+    }
+
+    #[test]
+    fn inserts_once_after_preamble() {
+        let header = Header::new("Copyright 2020 Example\nSPDX-License-Identifier: MIT")
+            .comment(CommentStyle::for_path("tool.py"));
+        let origin = "#!/usr/bin/env python\nprint()\n";
+        let mut once = Vec::new();
+        assert!(header.apply(Cursor::new(origin), &mut once).unwrap());
+        assert_eq!(
+            "#!/usr/bin/env python\n# Copyright 2020 Example\n# SPDX-License-Identifier: MIT\nprint()\n",
+            String::from_utf8(once.clone()).unwrap()
+        );
+        let mut twice = Vec::new();
+        assert!(!header.apply(Cursor::new(&once), &mut twice).unwrap());
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn separates_unterminated_preamble() {
+        let header = Header::new("// x\n");
+        assert_eq!(
+            Some((9, "\n// x\n".to_string())),
+            header.insertion(&b"#!/bin/sh"[..]).unwrap()
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    io::{self, Read},
+    time::Duration,
+};
+use ureq::{self, Agent, BodyReader};
+
+/// how long a fetch may take in total, unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the body of a url, fetched when first read
+///
+/// nothing is requested until the output reaches the insertion, and the body
+/// is streamed rather than downloaded up front. error statuses are reported as
+/// errors.
+pub struct Url {
+    url: String,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    body: Option<BodyReader<'static>>,
+}
+
+impl Url {
+    /// fetch the url with a get request when first read
+    pub fn new<S: Into<String>>(url: S) -> Url {
+        Url {
+            url: url.into(),
+            timeout: Some(DEFAULT_TIMEOUT),
+            connect_timeout: None,
+            body: None,
+        }
+    }
+
+    /// limit how long the whole fetch may take, or `None` to wait indefinitely
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// limit how long connecting may take
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    fn error(&self, error: ureq::Error) -> io::Error {
+        let error = error.into_io();
+        io::Error::new(error.kind(), format!("fetching {}: {}", self.url, error))
+    }
+
+    fn fetch(&mut self) -> io::Result<&mut BodyReader<'static>> {
+        if self.body.is_none() {
+            let agent: Agent = Agent::config_builder()
+                .timeout_global(self.timeout)
+                .timeout_connect(self.connect_timeout)
+                .build()
+                .into();
+            let response = agent.get(&self.url).call().map_err(|e| self.error(e))?;
+            self.body = Some(response.into_body().into_reader());
+        }
+        Ok(self.body.as_mut().expect("just fetched"))
+    }
+}
+
+impl Read for Url {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.fetch()?
+            .read(buf)
+            .map_err(|e| io::Error::new(e.kind(), format!("reading {}: {}", self.url, e)))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for Url {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, net::TcpListener, thread};
+    use Inserter;
+
+    /// serve a single response on a local port
+    fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request);
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        format!("http://{}/fragment", address)
+    }
+
+    #[test]
+    fn streams_body() {
+        let url = serve("HTTP/1.1 200 OK\r\nContent-Length: 6\r\nConnection: close\r\n\r\nbravo ");
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, Url::new(url))
+            .execute_to_vec()
+            .unwrap();
+        assert_eq!(b"alpha bravo charlie".to_vec(), out);
+    }
+
+    #[test]
+    fn reports_error_status() {
+        let url = serve("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        let err = Inserter::new(&b"ab"[..], Vec::new())
+            .insert(1, Url::new(url.clone()))
+            .execute_to_vec()
+            .unwrap_err();
+        assert!(err.to_string().contains(&url));
+    }
+
+    #[test]
+    fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let err = Url::new(url)
+            .timeout(Some(Duration::from_millis(100)))
+            .read(&mut [0; 16])
+            .unwrap_err();
+        assert!(err.to_string().contains("fetching"));
+        drop(listener);
+    }
+}
@@ -0,0 +1,271 @@
+use std::io::{self, Read, Write};
+use util::fill;
+
+/// largest tag size representable by the 28-bit syncsafe size field
+pub const MAX_TAG_SIZE: usize = (1 << 28) - 1;
+
+const HEADER_LEN: usize = 10;
+const UNSYNCHRONISATION: u8 = 0x80;
+const EXTENDED_HEADER: u8 = 0x40;
+const FOOTER: u8 = 0x10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Body {
+    Text(String),
+    Raw(Vec<u8>),
+}
+
+/// a frame to be added to an ID3v2 tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    id: [u8; 4],
+    body: Body,
+}
+
+impl Frame {
+    /// create a frame with the given id and raw, already encoded contents
+    pub fn new(id: [u8; 4], data: Vec<u8>) -> io::Result<Frame> {
+        Frame::checked(id, Body::Raw(data))
+    }
+
+    /// create a text information frame such as `TIT2` (title) or `TPE1` (artist)
+    ///
+    /// the text is encoded as utf-8 in v2.4 tags and utf-16 in v2.3 tags
+    pub fn text(id: [u8; 4], text: &str) -> io::Result<Frame> {
+        if id[0] != b'T' || &id == b"TXXX" {
+            return Err(invalid_input(
+                "text frame ids start with `T` and aren't `TXXX`",
+            ));
+        }
+        Frame::checked(id, Body::Text(text.to_string()))
+    }
+
+    fn checked(id: [u8; 4], body: Body) -> io::Result<Frame> {
+        if !id
+            .iter()
+            .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        {
+            return Err(invalid_input(
+                "frame ids are four uppercase letters or digits",
+            ));
+        }
+        Ok(Frame { id, body })
+    }
+
+    /// the framed contents for a tag of the given major version
+    fn to_bytes(&self, version: u8) -> io::Result<Vec<u8>> {
+        let data = match self.body {
+            Body::Raw(ref data) => data.clone(),
+            Body::Text(ref text) if version >= 4 => {
+                let mut data = vec![3];
+                data.extend_from_slice(text.as_bytes());
+                data
+            }
+            Body::Text(ref text) => {
+                let mut data = vec![1, 0xFF, 0xFE];
+                data.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                data
+            }
+        };
+        let mut out = Vec::with_capacity(data.len() + HEADER_LEN);
+        out.extend_from_slice(&self.id);
+        if version >= 4 {
+            out.extend_from_slice(&syncsafe(data.len())?);
+        } else {
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        }
+        out.extend_from_slice(&[0, 0]);
+        out.extend(data);
+        Ok(out)
+    }
+}
+
+/// add frames to the ID3v2 tag at the start of an MP3 stream
+///
+/// if the stream has no tag, a new v2.4 tag is prepended. otherwise the frames
+/// are appended to the existing v2.3 or v2.4 tag, using up its padding where
+/// there's enough of it so the tag size doesn't change. the existing tag is
+/// buffered; the audio frames after it are streamed through untouched.
+pub fn insert_frames<R, W>(mut origin: R, mut target: W, frames: &[Frame]) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut header = [0_u8; HEADER_LEN];
+    let filled = fill(&mut origin, &mut header)?;
+
+    if filled < HEADER_LEN || &header[..3] != b"ID3" {
+        let mut body = Vec::new();
+        for frame in frames {
+            body.extend(frame.to_bytes(4)?);
+        }
+        target.write_all(b"ID3\x04\0\0")?;
+        target.write_all(&syncsafe(body.len())?)?;
+        target.write_all(&body)?;
+        target.write_all(&header[..filled])?;
+        io::copy(&mut origin, &mut target)?;
+        return Ok(());
+    }
+
+    let version = header[3];
+    let flags = header[5];
+    if version != 3 && version != 4 {
+        return Err(invalid_data("only ID3v2.3 and v2.4 tags are supported"));
+    }
+    if flags & UNSYNCHRONISATION != 0 {
+        return Err(invalid_data("unsynchronised tags are not supported"));
+    }
+    let size = parse_syncsafe(&header[6..])?;
+    let mut tag = vec![0_u8; size];
+    origin.read_exact(&mut tag)?;
+    let footer = version >= 4 && flags & FOOTER != 0;
+    if footer {
+        origin.read_exact(&mut [0_u8; HEADER_LEN])?;
+    }
+
+    let frames_end = frames_end(&tag, version, flags)?;
+    let mut added = Vec::new();
+    for frame in frames {
+        added.extend(frame.to_bytes(version)?);
+    }
+    let padding = size - frames_end;
+    let new_size = if added.len() <= padding {
+        size
+    } else {
+        frames_end + added.len()
+    };
+
+    header[6..].copy_from_slice(&syncsafe(new_size)?);
+    target.write_all(&header)?;
+    target.write_all(&tag[..frames_end])?;
+    target.write_all(&added)?;
+    target.write_all(&vec![0; new_size - frames_end - added.len()])?;
+    if footer {
+        header[..3].copy_from_slice(b"3DI");
+        target.write_all(&header)?;
+    }
+    io::copy(&mut origin, &mut target)?;
+    Ok(())
+}
+
+/// the offset within the tag body at which the padding begins
+fn frames_end(tag: &[u8], version: u8, flags: u8) -> io::Result<usize> {
+    let mut offset = 0;
+    if flags & EXTENDED_HEADER != 0 {
+        if tag.len() < 4 {
+            return Err(invalid_data("truncated extended header"));
+        }
+        offset = if version >= 4 {
+            parse_syncsafe(&tag[..4])?
+        } else {
+            // the v2.3 extended header size excludes the size field itself
+            u32::from_be_bytes([tag[0], tag[1], tag[2], tag[3]]) as usize + 4
+        };
+    }
+    while offset + HEADER_LEN <= tag.len() && tag[offset] != 0 {
+        let raw = &tag[offset + 4..offset + 8];
+        let len = if version >= 4 {
+            parse_syncsafe(raw)?
+        } else {
+            u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]) as usize
+        };
+        offset += HEADER_LEN + len;
+    }
+    if offset > tag.len() {
+        return Err(invalid_data("ID3 frame overruns its tag"));
+    }
+    Ok(offset)
+}
+
+fn syncsafe(value: usize) -> io::Result<[u8; 4]> {
+    if value > MAX_TAG_SIZE {
+        return Err(invalid_input("ID3 tag would exceed 256 MiB"));
+    }
+    Ok([
+        (value >> 21) as u8 & 0x7F,
+        (value >> 14) as u8 & 0x7F,
+        (value >> 7) as u8 & 0x7F,
+        value as u8 & 0x7F,
+    ])
+}
+
+fn parse_syncsafe(raw: &[u8]) -> io::Result<usize> {
+    if raw.iter().any(|&b| b & 0x80 != 0) {
+        return Err(invalid_data("malformed syncsafe integer"));
+    }
+    Ok(raw.iter().fold(0, |acc, &b| (acc << 7) | b as usize))
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: &[u8] = b"\xFF\xFBaudio frames";
+
+    fn run(origin: &[u8]) -> Vec<u8> {
+        let mut dest = Vec::new();
+        let frames = [Frame::text(*b"TIT2", "Song").unwrap()];
+        insert_frames(origin, &mut dest, &frames).unwrap();
+        dest
+    }
+
+    #[test]
+    fn prepends_new_tag() {
+        let out = run(AUDIO);
+        assert_eq!(
+            b"ID3\x04\0\0\0\0\0\x0fTIT2\0\0\0\x05\0\0\x03Song",
+            &out[..25]
+        );
+        assert_eq!(AUDIO, &out[25..]);
+    }
+
+    #[test]
+    fn syncsafe_round_trip() {
+        assert_eq!([0, 0, 2, 1], syncsafe(257).unwrap());
+        assert_eq!(257, parse_syncsafe(&[0, 0, 2, 1]).unwrap());
+        assert!(syncsafe(MAX_TAG_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn uses_existing_padding() {
+        let mut origin = b"ID3\x04\0\0\0\0\0\x30".to_vec();
+        origin.extend(Frame::text(*b"TPE1", "Band").unwrap().to_bytes(4).unwrap());
+        origin.resize(HEADER_LEN + 0x30, 0);
+        origin.extend_from_slice(AUDIO);
+        let out = run(&origin);
+        assert_eq!(origin.len(), out.len());
+        assert_eq!(&origin[..25], &out[..25]);
+        assert_eq!(b"TIT2", &out[25..29]);
+        assert!(out.ends_with(AUDIO));
+    }
+
+    #[test]
+    fn grows_v23_tag() {
+        let mut origin = b"ID3\x03\0\0\0\0\0\x0e".to_vec();
+        origin.extend_from_slice(b"TPE1\0\0\0\x04\0\0\0abc");
+        origin.extend_from_slice(AUDIO);
+        let out = run(&origin);
+        // text frames in v2.3 are utf-16 with a byte order mark
+        assert_eq!(b"\0\0\0\x23", &out[6..10]);
+        assert_eq!(b"TIT2\0\0\0\x0b\0\0\x01\xff\xfeS\0", &out[24..39]);
+        assert!(out.ends_with(AUDIO));
+    }
+
+    #[test]
+    fn rejects_unsupported_tags() {
+        let mut dest = Vec::new();
+        let v22 = b"ID3\x02\0\0\0\0\0\0";
+        assert!(insert_frames(&v22[..], &mut dest, &[]).is_err());
+        let unsync = b"ID3\x04\0\x80\0\0\0\0";
+        assert!(insert_frames(&unsync[..], &mut dest, &[]).is_err());
+        assert!(Frame::text(*b"APIC", "x").is_err());
+    }
+}
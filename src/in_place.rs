@@ -0,0 +1,188 @@
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    borrow::Cow,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+/// size of the blocks in which the tail of the file is shifted
+pub const BLOCK_SIZE: usize = 64 * 1024;
+
+/// inserts into a file in place, with neither a temporary file nor a second copy
+///
+/// the file is extended and each run of bytes following an insertion point is
+/// shifted towards the new end, working backwards in blocks so nothing is
+/// overwritten before it's been moved. the insertions are then written into
+/// the gaps. sources of unknown length are read into memory first, and any
+/// other source must produce exactly its reported length. if this fails
+/// partway the file is left partly shifted, so keep a backup of anything
+/// which can't be regenerated.
+pub struct InPlaceInserter<'i, F> {
+    file: F,
+    block_size: usize,
+    insertions: Vec<(usize, InsertSource<'i>)>,
+}
+
+impl<'i, F> InPlaceInserter<'i, F>
+where
+    F: Read + Write + Seek,
+{
+    /// create a new inserter editing the given file
+    pub fn new(file: F) -> InPlaceInserter<'i, F> {
+        InPlaceInserter {
+            file,
+            block_size: BLOCK_SIZE,
+            insertions: Vec::new(),
+        }
+    }
+
+    /// set the size of the blocks in which the tail is shifted
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// insert the source at the given index into the file as it is now
+    ///
+    /// several insertions at the same index are all kept, in the order they
+    /// were added. insertions past the end of the file are appended.
+    pub fn insert<I: IntoInsertSource<'i>>(mut self, position: usize, source: I) -> Self {
+        self.insertions
+            .push((position, source.into_insert_source()));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    pub fn execute(mut self) -> io::Result<()> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        // a stable sort keeps same-index insertions in the order they were added
+        self.insertions.sort_by_key(|&(position, _)| position);
+        let mut sized = Vec::with_capacity(self.insertions.len());
+        for (position, source) in self.insertions {
+            let position = (position as u64).min(len);
+            let source = match source.len_hint() {
+                Some(_) => source,
+                None => {
+                    let mut bytes = Vec::new();
+                    source.into_reader().read_to_end(&mut bytes)?;
+                    InsertSource::Bytes(Cow::Owned(bytes))
+                }
+            };
+            let size = source.len_hint().expect("every source has a length by now");
+            sized.push((position, size, source));
+        }
+
+        // shift each run of the file by the size of the insertions before it,
+        // starting from the end so that nothing is overwritten before it's moved
+        let mut buffer = vec![0; self.block_size];
+        let mut shift: u64 = sized.iter().map(|&(_, size, _)| size).sum();
+        let mut end = len;
+        for &(position, size, _) in sized.iter().rev() {
+            shift_run(&mut self.file, position, end, shift, &mut buffer)?;
+            end = position;
+            shift -= size;
+        }
+
+        let mut inserted = 0;
+        for (position, size, source) in sized {
+            self.file.seek(SeekFrom::Start(position + inserted))?;
+            let written = io::copy(&mut source.into_reader().take(size), &mut self.file)?;
+            if written < size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "insertion shorter than its reported length",
+                ));
+            }
+            inserted += size;
+        }
+        self.file.flush()
+    }
+}
+
+/// move the bytes in `start..end` forward by `shift`, last block first
+fn shift_run<F: Read + Write + Seek>(
+    file: &mut F,
+    start: u64,
+    mut end: u64,
+    shift: u64,
+    buffer: &mut [u8],
+) -> io::Result<()> {
+    if shift == 0 {
+        return Ok(());
+    }
+    while end > start {
+        let len = (buffer.len() as u64).min(end - start);
+        let from = end - len;
+        let block = &mut buffer[..len as usize];
+        file.seek(SeekFrom::Start(from))?;
+        file.read_exact(block)?;
+        file.seek(SeekFrom::Start(from + shift))?;
+        file.write_all(block)?;
+        end = from;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Cursor};
+
+    #[test]
+    fn shifts_in_small_blocks() {
+        let mut file = Cursor::new(b"alpha charlie echo".to_vec());
+        InPlaceInserter::new(&mut file)
+            .block_size(3)
+            .insert(14, "delta ")
+            .insert(6, "bravo ")
+            .insert(100, "!")
+            .insert(0, "> ")
+            .execute()
+            .unwrap();
+        assert_eq!(
+            &b"> alpha bravo charlie delta echo!"[..],
+            &file.into_inner()[..]
+        );
+    }
+
+    #[test]
+    fn sources_of_unknown_length() {
+        let mut file = Cursor::new(b"ad".to_vec());
+        InPlaceInserter::new(&mut file)
+            .insert(1, InsertSource::reader(&b"b"[..]))
+            .insert(1, InsertSource::reader(&b"c"[..]))
+            .execute()
+            .unwrap();
+        assert_eq!(b"abcd".to_vec(), file.into_inner());
+
+        let mut file = Cursor::new(b"ad".to_vec());
+        let err = InPlaceInserter::new(&mut file)
+            .insert(1, InsertSource::Reader(Box::new(&b"b"[..]), Some(2)))
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn edits_a_file() {
+        let path = ::std::env::temp_dir().join(format!("in-place-{}", ::std::process::id()));
+        let origin: Vec<u8> = (0..=255).cycle().take(200_000).collect();
+        fs::write(&path, &origin).unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        InPlaceInserter::new(file)
+            .insert(150_000, vec![b'x'; 70_000])
+            .insert(10, "ten")
+            .execute()
+            .unwrap();
+        let mut expect = origin[..10].to_vec();
+        expect.extend_from_slice(b"ten");
+        expect.extend_from_slice(&origin[10..150_000]);
+        expect.extend(vec![b'x'; 70_000]);
+        expect.extend_from_slice(&origin[150_000..]);
+        assert_eq!(expect, fs::read(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,50 @@
+use buffer::CopyBuffer;
+#[cfg(feature = "bytes")]
+use bytes::Buf;
+use capture::{Captures, Copied, Tap};
+use checksum::Checksum;
+use delta::Delta;
+#[cfg(feature = "minisign")]
+use minisign::{SecretKey, Signature};
+use operation::{Operation, PlanBuilder, Settings, Transform};
+use reader::{Concat, Spliced};
+use scan::Finder;
+use source::{InsertSource, IntoInsertSource};
 use std::{
-    collections::BTreeMap,
+    cell::RefCell,
+    collections::{btree_map, BTreeMap, VecDeque},
+    fmt,
     io::{self, Read, Write},
+    iter::Peekable,
+    ops::Range,
+    rc::Rc,
 };
+use step::Stepper;
+use string_inserter;
+use throttle::Throttle;
 
 /// size of the internal buffer used to copy data from readers to writers
 pub const BUFFER_SIZE: usize = 1024;
 
-type Insertions<'i> = BTreeMap<usize, Box<'i + Read>>;
+type Insertions<'i> = BTreeMap<usize, InsertSource<'i>>;
+type Overwrites = BTreeMap<usize, Patch>;
+
+/// byte order of a field patched by a fixup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    /// most significant byte first
+    Big,
+    /// least significant byte first
+    Little,
+}
 
 /// inserter keeps track of origin reader, target writer, and all points of insertion
+///
+/// it's a convenience over `PlanBuilder` for the common case of a single
+/// origin and target known up front
 pub struct Inserter<'i, R, W> {
     origin: R,
-    insertions: Insertions<'i>,
+    plan: PlanBuilder<'i>,
     target: W,
 }
 
@@ -24,82 +57,1023 @@ where
     pub fn new(origin: R, target: W) -> Inserter<'i, R, W> {
         Inserter {
             origin,
-            insertions: BTreeMap::new(),
+            plan: PlanBuilder::new(),
             target,
         }
     }
 
+    /// add an arbitrary operation
+    pub fn operation(mut self, operation: Operation<'i>) -> Self {
+        self.plan = self.plan.push(operation);
+        self
+    }
+
     /// insert the source document into the output document at the given origin index
-    pub fn insert<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
-        self.insertions.insert(position, Box::new(source));
+    ///
+    /// several insertions at the same index are all kept, in the order they
+    /// were added
+    pub fn insert<I: IntoInsertSource<'i>>(self, position: usize, source: I) -> Self {
+        self.operation(Operation::Insert(position, source.into_insert_source()))
+    }
+
+    /// insert the remaining contents of the buffer at the given origin index
+    #[cfg(feature = "bytes")]
+    pub fn insert_buf<B: 'i + Buf>(self, position: usize, source: B) -> Self {
+        self.insert(position, InsertSource::reader(source.reader()))
+    }
+
+    /// insert the value, formatted with `Display`, at the given origin index
+    ///
+    /// the value is formatted straight into the output when its index is
+    /// reached, rather than into a string up front
+    pub fn insert_display<T: 'i + fmt::Display>(self, position: usize, value: T) -> Self {
+        self.insert(position, InsertSource::display(value))
+    }
+
+    /// replace the origin bytes starting at the given origin index with `bytes`
+    ///
+    /// unlike an insertion, this doesn't change the length of the output: it's
+    /// meant for patching fixed-width fields such as sizes and offsets. an
+    /// insertion within the overwritten range lands between the overwritten
+    /// bytes. overwritten bytes past the end of the origin are appended.
+    /// overlapping overwrites cause `execute` to fail.
+    pub fn overwrite(self, position: usize, bytes: &[u8]) -> Self {
+        self.operation(Operation::Overwrite(position, bytes.to_vec()))
+    }
+
+    /// adjust the u32 at the given origin index by the size of the insertions in `range`
+    ///
+    /// `range` is in origin indices and includes its end, so an insertion at the
+    /// very end of a region counts as growing it. the field is patched as it
+    /// passes through; if it precedes insertions in its range, output is held
+    /// in memory until they've been measured. fails on execution if the field
+    /// would overflow, lies past the end of the origin, overlaps an overwrite,
+    /// or has an insertion within it.
+    pub fn fixup(self, position: usize, endian: Endian, range: Range<usize>) -> Self {
+        self.operation(Operation::Fixup(position, endian, range))
+    }
+
+    /// overwrite the field at the given origin index with a checksum of `region`
+    ///
+    /// `region` is in origin indices, covering the output bytes which come from
+    /// it including insertions within it or at its end, just as for `fixup`. if
+    /// the field lies within its own region it's checksummed as zeros. if it
+    /// precedes its region, output is held in memory until the region is done.
+    /// fails on execution if the region contains any other fixup or checksum
+    /// field, or if an insertion lands within the field.
+    pub fn checksum<C: 'i + Checksum>(
+        self,
+        position: usize,
+        endian: Endian,
+        region: Range<usize>,
+        checksum: C,
+    ) -> Self {
+        self.operation(Operation::Checksum(
+            position,
+            endian,
+            region,
+            Box::new(checksum),
+        ))
+    }
+
+    /// limit the output to this many bytes per second, counting insertions
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.plan = self.plan.rate_limit(bytes_per_second);
+        self
+    }
+
+    /// fail rather than produce more than this many bytes of output
+    pub fn max_output(mut self, bytes: u64) -> Self {
+        self.plan = self.plan.max_output(bytes);
         self
     }
 
+    /// the queued operations
+    pub fn plan(&self) -> &PlanBuilder<'i> {
+        &self.plan
+    }
+
+    /// the queued insertions, in the order they were added
+    pub fn insertions(&self) -> impl Iterator<Item = (usize, &InsertSource<'i>)> {
+        self.plan.insertions()
+    }
+
+    /// remove and return every queued operation at the given origin index
+    pub fn remove_at(&mut self, position: usize) -> Vec<Operation<'i>> {
+        self.plan.remove_at(position)
+    }
+
+    /// remove every queued operation
+    pub fn clear(&mut self) {
+        self.plan.clear()
+    }
+
+    /// a stepper to drive this inserter one bounded step at a time
+    ///
+    /// only insertions can be made this way: fails with `InvalidInput` if any
+    /// other operation is queued
+    pub fn into_stepper(self) -> io::Result<Stepper<'i, R, W>> {
+        let mut spliced = Spliced::new(self.origin);
+        for operation in self.plan.into_operations() {
+            match operation {
+                Operation::Insert(position, source) => {
+                    spliced = spliced.insert_at(position, source.into_reader())
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "only insertions can be stepped",
+                    ))
+                }
+            }
+        }
+        Ok(Stepper::new(spliced, self.target))
+    }
+
     /// execute this inserter, consuming it
-    pub fn execute(mut self) -> io::Result<()> {
-        let mut input_index: usize = 0;
-        let mut buffer = [0_u8; BUFFER_SIZE];
-        for (&insert_idx, mut to_insert) in self.insertions.iter_mut() {
-            // if we haven't yet reached this insertion index, copy bytes
-            // from the origin until we have
-            while input_index < insert_idx {
-                let remaining_bytes = insert_idx - input_index;
-                let mut source = self.origin.by_ref().take(remaining_bytes as u64);
-                match source.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(bytes_read) => {
-                        let written = &buffer[..bytes_read];
-                        input_index += bytes_read;
-                        self.target.write_all(written)?;
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                        // try again
-                    }
-                    Err(e) => return Err(e),
+    pub fn execute(self) -> io::Result<()> {
+        self.plan.execute(self.origin, self.target)
+    }
+
+    /// execute this inserter, returning a detached signature of its output
+    #[cfg(feature = "minisign")]
+    pub fn execute_signed(self, key: &SecretKey, trusted_comment: &str) -> io::Result<Signature> {
+        self.plan
+            .execute_signed(self.origin, self.target, key, trusted_comment)
+    }
+
+    /// execute this inserter, writing to the target on a thread of its own
+    pub fn execute_pipelined(self) -> io::Result<()>
+    where
+        W: Send,
+    {
+        self.plan.execute_pipelined(self.origin, self.target)
+    }
+
+    /// execute this inserter, writing a delta against the origin rather than the output
+    pub fn execute_delta(self) -> io::Result<()> {
+        self.plan.execute_delta(self.origin, self.target)
+    }
+
+    /// execute this inserter, encrypting its output
+    ///
+    /// the nonce prefix must never be reused with the same key
+    #[cfg(feature = "encryption")]
+    pub fn execute_encrypted(self, key: &[u8; 32], nonce: [u8; 7]) -> io::Result<()> {
+        self.plan
+            .execute_encrypted(self.origin, self.target, key, nonce)
+    }
+}
+
+impl<'i, R: Read, W: Write> Inserter<'i, Concat<R>, W> {
+    /// create a new inserter whose origin is several readers, one after another
+    ///
+    /// positions are indices into the concatenated origin
+    pub fn new_multi<I: IntoIterator<Item = R>>(origins: I, target: W) -> Self {
+        Inserter::new(Concat::new(origins), target)
+    }
+}
+
+impl<'i, R: Read> Inserter<'i, R, Vec<u8>> {
+    /// execute this inserter, consuming it and returning its target with the output appended
+    ///
+    /// space is reserved up front for every insertion whose length is known
+    pub fn execute_to_vec(self) -> io::Result<Vec<u8>> {
+        let Inserter {
+            origin,
+            plan,
+            mut target,
+        } = self;
+        let inserted: u64 = plan
+            .insertions()
+            .filter_map(|(_, source)| source.len_hint())
+            .sum();
+        target.reserve(inserted as usize);
+        plan.execute(origin, &mut target)?;
+        Ok(target)
+    }
+
+    /// as `execute_to_vec`, but checking that the result is utf-8
+    pub fn execute_to_string(self) -> Result<String, string_inserter::Error> {
+        String::from_utf8(self.execute_to_vec()?).map_err(|e| e.into())
+    }
+}
+
+impl<'i, R, W> fmt::Debug for Inserter<'i, R, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inserter")
+            .field("plan", &self.plan)
+            .finish_non_exhaustive()
+    }
+}
+
+/// apply the operations in a single pass from the origin to the target
+pub(crate) fn apply<'i, R: Read, W: Write>(
+    operations: Vec<Operation<'i>>,
+    settings: Settings,
+    mut origin: R,
+    mut target: W,
+) -> io::Result<()> {
+    let captures = Rc::new(RefCell::new(Captures::new(&mut origin)));
+    let mut insertions = Insertions::new();
+    let mut overwrites = Overwrites::new();
+    let mut fixups = Vec::new();
+    let mut checksums = Vec::new();
+    let mut transforms = Vec::new();
+    let mut replacers = Vec::new();
+    for operation in operations {
+        match operation {
+            Operation::Insert(position, source) => {
+                add_insertion(&mut insertions, position, source)?
+            }
+            Operation::Delete(range) => {
+                if range.start < range.end {
+                    add_patch(&mut overwrites, range.start, Patch::Delete(range.len()))?;
+                }
+            }
+            Operation::Replace(range, source) => {
+                if range.start < range.end {
+                    add_patch(&mut overwrites, range.start, Patch::Delete(range.len()))?;
+                }
+                add_insertion(&mut insertions, range.start, source)?;
+            }
+            Operation::Overwrite(position, bytes) => {
+                add_patch(&mut overwrites, position, Patch::Bytes(bytes))?
+            }
+            Operation::Transform(range, transform) => {
+                let patch =
+                    Patch::Transform(transforms.len(), range.end.saturating_sub(range.start));
+                add_patch(&mut overwrites, range.start, patch)?;
+                transforms.push((range, transform));
+            }
+            Operation::Copy(range, position) => {
+                let idx = captures.borrow_mut().capture(range);
+                let copied = Copied::new(captures.clone(), idx);
+                add_insertion(&mut insertions, position, InsertSource::reader(copied))?;
+            }
+            Operation::ReplaceAll(pattern, replacement) => {
+                if pattern.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "empty search pattern",
+                    ));
                 }
+                replacers.push(Replacer {
+                    finder: Finder::new(&pattern),
+                    replacement,
+                });
             }
+            Operation::Fixup(position, endian, range) => {
+                add_patch(&mut overwrites, position, Patch::Fixup(fixups.len()))?;
+                fixups.push(Fixup {
+                    endian,
+                    range,
+                    original: None,
+                    delta: 0,
+                    pending: 0,
+                });
+            }
+            Operation::Checksum(position, endian, region, checksum) => {
+                if !(1..=8).contains(&checksum.width()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("checksum width {} isn't 1 to 8 bytes", checksum.width()),
+                    ));
+                }
+                let patch = Patch::Checksum(checksums.len(), checksum.width());
+                add_patch(&mut overwrites, position, patch)?;
+                checksums.push(Summed {
+                    endian,
+                    region,
+                    checksum,
+                    pending: 0,
+                });
+            }
+        }
+    }
 
-            // now that we've reached the insertion index (or the origin has
-            // run out of bytes), copy over the data at this insertion point
-            // note that this doesn't affect the input index
-            loop {
-                match to_insert.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(bytes_read) => {
-                        let written = &buffer[..bytes_read];
-                        self.target.write_all(written)?;
+    if !replacers.is_empty() && (!fixups.is_empty() || !checksums.is_empty()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "search and replace with fixups or checksums",
+        ));
+    }
+
+    let mut previous_end = 0;
+    for (&position, patch) in overwrites.iter() {
+        if position < previous_end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "overlapping overwrites",
+            ));
+        }
+        previous_end = position + patch.len();
+        let own = match *patch {
+            Patch::Bytes(_) | Patch::Delete(_) => continue,
+            Patch::Checksum(idx, _) => Some(idx),
+            _ => None,
+        };
+        if previous_end > position + 1
+            && insertions
+                .range(position + 1..previous_end)
+                .next()
+                .is_some()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "insertion within a field or transformed range",
+            ));
+        }
+        if let Patch::Transform(idx, _) = *patch {
+            let range = &transforms[idx].0;
+            let straddles = |outer: &Range<usize>| {
+                overlap(range, outer) > 0 && !(outer.start <= range.start && range.end <= outer.end)
+            };
+            if fixups.iter().any(|fixup| straddles(&fixup.range))
+                || checksums.iter().any(|summed| straddles(&summed.region))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "transform straddling a fixup range or checksum region",
+                ));
+            }
+            continue;
+        }
+        let covered = checksums.iter().enumerate().any(|(idx, summed)| {
+            Some(idx) != own && position < summed.region.end && summed.region.start < previous_end
+        });
+        if covered {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "fixup or checksum field within another checksum region",
+            ));
+        }
+    }
+    for fixup in fixups.iter_mut() {
+        fixup.pending = insertions
+            .keys()
+            .filter(|&&position| fixup.contains(position))
+            .count()
+            + transforms
+                .iter()
+                .filter(|(range, _)| fixup.spans(range))
+                .count();
+        let deleted: usize = overwrites
+            .iter()
+            .filter_map(|(&position, patch)| match *patch {
+                Patch::Delete(len) => Some(overlap(&fixup.range, &(position..position + len))),
+                _ => None,
+            })
+            .sum();
+        fixup.delta = -(deleted as i64);
+    }
+    for summed in checksums.iter_mut() {
+        summed.pending = insertions
+            .keys()
+            .filter(|&&position| summed.contains(position))
+            .count();
+    }
+
+    let mut buffer = CopyBuffer::new(BUFFER_SIZE);
+    let delta = if settings.delta {
+        Some(Delta::new(&mut target)?)
+    } else {
+        None
+    };
+    let mut output = Output {
+        target: &mut target,
+        held: Vec::new(),
+        slots: VecDeque::new(),
+        fixups,
+        checksums,
+        transforms,
+        replacers,
+        next_origin: None,
+        throttle: settings.rate_limit.map(Throttle::new),
+        quota: settings.max_output.map(|limit| (limit, 0)),
+        delta,
+    };
+    let mut origin = Origin {
+        reader: Tap(captures),
+        position: 0,
+        exhausted: false,
+        overwrites: overwrites.iter().peekable(),
+    };
+    for (&insert_idx, to_insert) in insertions.iter_mut() {
+        // if we haven't yet reached this insertion index, copy bytes
+        // from the origin until we have
+        origin.copy_until(insert_idx, &mut output, &mut buffer)?;
+        // search and replace never matches across an insertion, even an empty one
+        output.release()?;
+
+        // now that we've reached the insertion index (or the origin has
+        // run out of bytes), copy over the data at this insertion point
+        // note that this doesn't affect the input index
+        let mut inserted = 0;
+        match *to_insert {
+            InsertSource::Bytes(ref bytes) => {
+                // in-memory sources skip the copy through the buffer
+                output.write_all(bytes, Source::Insertion(insert_idx))?;
+                inserted = bytes.len();
+            }
+            InsertSource::Reader(ref mut reader, _) => loop {
+                match buffer.read_from(reader) {
+                    Ok([]) => break,
+                    Ok(written) => {
+                        output.write_all(written, Source::Insertion(insert_idx))?;
+                        inserted += written.len();
                     }
                     Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
                         // try again
                     }
                     Err(e) => return Err(e),
                 }
+            },
+            InsertSource::Display(ref value) => {
+                let mut formatting = Formatting {
+                    output: &mut output,
+                    position: insert_idx,
+                    written: 0,
+                    error: None,
+                };
+                if fmt::write(&mut formatting, format_args!("{}", value)).is_err() {
+                    return Err(formatting
+                        .error
+                        .unwrap_or_else(|| io::Error::other("formatter error")));
+                }
+                inserted = formatting.written;
+            }
+            InsertSource::Refused(ref message) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message.clone()))
+            }
+        }
+
+        output.inserted(insert_idx, inserted);
+        output.settle(origin.progress())?;
+    }
+
+    // we've added all inserts
+    // now finish copying over any remaining bytes from the origin
+    origin.copy_until(usize::MAX, &mut output, &mut buffer)?;
+    output.release()?;
+    output.settle(usize::MAX)?;
+    match output.delta.take() {
+        Some(delta) => delta.finish(&mut output.target),
+        None => Ok(()),
+    }
+}
+
+fn add_insertion<'i>(
+    insertions: &mut Insertions<'i>,
+    position: usize,
+    source: InsertSource<'i>,
+) -> io::Result<()> {
+    if let InsertSource::Refused(message) = source {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+    }
+    let source = match insertions.remove(&position) {
+        Some(previous) => previous.chain(source),
+        None => source,
+    };
+    insertions.insert(position, source);
+    Ok(())
+}
+
+fn add_patch(overwrites: &mut Overwrites, position: usize, patch: Patch) -> io::Result<()> {
+    match overwrites.entry(position) {
+        btree_map::Entry::Vacant(entry) => {
+            entry.insert(patch);
+            Ok(())
+        }
+        btree_map::Entry::Occupied(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "overlapping overwrites",
+        )),
+    }
+}
+
+/// the number of indices in both ranges
+pub(crate) fn overlap(a: &Range<usize>, b: &Range<usize>) -> usize {
+    a.end.min(b.end).saturating_sub(a.start.max(b.start))
+}
+
+/// a change to a run of origin bytes
+enum Patch {
+    Bytes(Vec<u8>),
+    Delete(usize),
+    Transform(usize, usize),
+    Fixup(usize),
+    Checksum(usize, usize),
+}
+
+impl Patch {
+    fn len(&self) -> usize {
+        match *self {
+            Patch::Bytes(ref bytes) => bytes.len(),
+            Patch::Delete(len) | Patch::Transform(_, len) => len,
+            Patch::Fixup(_) => 4,
+            Patch::Checksum(_, width) => width,
+        }
+    }
+}
+
+/// a u32 field to be adjusted by the size of the insertions within a range
+struct Fixup {
+    endian: Endian,
+    range: Range<usize>,
+    original: Option<u32>,
+    delta: i64,
+    pending: usize,
+}
+
+impl Fixup {
+    fn contains(&self, position: usize) -> bool {
+        self.range.start <= position && position <= self.range.end
+    }
+
+    fn spans(&self, range: &Range<usize>) -> bool {
+        self.range.start <= range.start && range.end <= self.range.end
+    }
+
+    /// the patched field, once it's been read and every insertion in range measured
+    fn value(&self) -> io::Result<Option<[u8; 4]>> {
+        let original = match self.original {
+            Some(original) if self.pending == 0 => original,
+            _ => return Ok(None),
+        };
+        let value = i64::from(original) + self.delta;
+        if value < 0 || value > i64::from(u32::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fixup value out of range",
+            ));
+        }
+        Ok(Some(match self.endian {
+            Endian::Big => (value as u32).to_be_bytes(),
+            Endian::Little => (value as u32).to_le_bytes(),
+        }))
+    }
+}
+
+/// a checksum being computed over a region of the output
+struct Summed<'i> {
+    endian: Endian,
+    region: Range<usize>,
+    checksum: Box<dyn 'i + Checksum>,
+    pending: usize,
+}
+
+impl<'i> Summed<'i> {
+    fn contains(&self, position: usize) -> bool {
+        self.region.start <= position && position <= self.region.end
+    }
+
+    fn feed(&mut self, bytes: &[u8], source: Source) {
+        match source {
+            Source::Insertion(position) => {
+                if self.contains(position) {
+                    self.checksum.update(bytes);
+                }
+            }
+            Source::Transformed(start, end) => {
+                if self.region.start <= start && end <= self.region.end {
+                    self.checksum.update(bytes);
+                }
+            }
+            Source::Origin(position) => {
+                let start = position.max(self.region.start);
+                let end = (position + bytes.len()).min(self.region.end);
+                if start < end {
+                    self.checksum
+                        .update(&bytes[start - position..end - position]);
+                }
+            }
+        }
+    }
+
+    /// the checksum field, once the origin has progressed past the region
+    fn value(&self, progress: usize) -> Option<Vec<u8>> {
+        if self.pending > 0 || progress < self.region.end {
+            return None;
+        }
+        let width = self.checksum.width();
+        let value = self.checksum.value();
+        Some(match self.endian {
+            Endian::Big => value.to_be_bytes()[8 - width..].to_vec(),
+            Endian::Little => value.to_le_bytes()[..width].to_vec(),
+        })
+    }
+}
+
+/// replaces a pattern in the origin as it's written
+struct Replacer {
+    finder: Finder,
+    replacement: Vec<u8>,
+}
+
+impl Replacer {
+    fn feed(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(bytes.len());
+        for &byte in bytes {
+            if self.finder.feed_through(byte, &mut out) {
+                out.extend_from_slice(&self.replacement);
+            }
+        }
+        out
+    }
+}
+
+/// where output bytes come from, in origin indices
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Origin(usize),
+    Insertion(usize),
+    Transformed(usize, usize),
+}
+
+/// count output against the quota, failing with `QuotaExceeded` if it would
+/// cross it
+fn charge(quota: &mut Option<(u64, u64)>, len: usize, source: Source) -> io::Result<()> {
+    let (limit, produced) = match *quota {
+        Some((limit, ref mut produced)) => (limit, produced),
+        None => return Ok(()),
+    };
+    if *produced + len as u64 > limit {
+        let culprit = match source {
+            Source::Origin(position) => format!("origin index {}", position),
+            Source::Insertion(position) => format!("the insertion at {}", position),
+            Source::Transformed(start, end) => format!("the transform of {}..{}", start, end),
+        };
+        return Err(io::Error::new(
+            io::ErrorKind::QuotaExceeded,
+            format!("output exceeds its {} byte limit at {}", limit, culprit),
+        ));
+    }
+    *produced += len as u64;
+    Ok(())
+}
+
+/// a field in the output whose value isn't known yet
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+    Fixup(usize),
+    Checksum(usize),
+}
+
+/// the target writer, holding output back while fields are unresolved
+struct Output<'i, W> {
+    target: W,
+    held: Vec<u8>,
+    /// offsets into `held` of unresolved fields
+    slots: VecDeque<(usize, Slot)>,
+    fixups: Vec<Fixup>,
+    checksums: Vec<Summed<'i>>,
+    transforms: Vec<(Range<usize>, Transform<'i>)>,
+    replacers: Vec<Replacer>,
+    /// the origin index following the last origin bytes searched
+    next_origin: Option<usize>,
+    throttle: Option<Throttle>,
+    /// the most output allowed, and how much has been produced
+    quota: Option<(u64, u64)>,
+    /// when writing a delta, the commands not yet written
+    delta: Option<Delta>,
+}
+
+impl<'i, W: Write> Output<'i, W> {
+    fn write_all(&mut self, bytes: &[u8], source: Source) -> io::Result<()> {
+        if self.replacers.is_empty() {
+            return self.emit(bytes, source);
+        }
+        match source {
+            Source::Origin(position) => {
+                if self.next_origin != Some(position) {
+                    self.release()?;
+                }
+                self.next_origin = Some(position + bytes.len());
+                let mut replaced = bytes.to_vec();
+                for replacer in self.replacers.iter_mut() {
+                    replaced = replacer.feed(&replaced);
+                }
+                self.emit(&replaced, source)
+            }
+            _ => {
+                self.release()?;
+                self.emit(bytes, source)
+            }
+        }
+    }
+
+    /// write out any origin bytes held back as partial matches
+    fn release(&mut self) -> io::Result<()> {
+        let position = match self.next_origin.take() {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        for idx in 0..self.replacers.len() {
+            let mut released = self.replacers[idx].finder.pending().to_vec();
+            self.replacers[idx].finder.reset();
+            for replacer in self.replacers[idx + 1..].iter_mut() {
+                released = replacer.feed(&released);
+            }
+            self.emit(&released, Source::Origin(position))?;
+        }
+        Ok(())
+    }
+
+    /// write origin bytes which pass through unchanged, as a copy if writing a delta
+    fn copy(&mut self, bytes: &[u8], position: usize) -> io::Result<()> {
+        match self.delta {
+            Some(ref mut delta) if self.replacers.is_empty() && self.slots.is_empty() => {
+                charge(&mut self.quota, bytes.len(), Source::Origin(position))?;
+                for summed in self.checksums.iter_mut() {
+                    summed.feed(bytes, Source::Origin(position));
+                }
+                delta.copy(&mut self.target, position, bytes.len())
+            }
+            _ => self.write_all(bytes, Source::Origin(position)),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8], source: Source) -> io::Result<()> {
+        charge(&mut self.quota, bytes.len(), source)?;
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.take(bytes.len());
+        }
+        for summed in self.checksums.iter_mut() {
+            summed.feed(bytes, source);
+        }
+        if self.slots.is_empty() {
+            match self.delta {
+                Some(ref mut delta) => delta.literal(&mut self.target, bytes),
+                None => self.target.write_all(bytes),
+            }
+        } else {
+            self.held.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    /// leave space for a field at the given origin index, to be filled in once it's resolved
+    fn reserve(&mut self, slot: Slot, position: usize, width: usize) -> io::Result<()> {
+        charge(&mut self.quota, width, Source::Origin(position))?;
+        let placeholder = vec![0; width];
+        if let Some(ref mut throttle) = self.throttle {
+            throttle.take(width);
+        }
+        for summed in self.checksums.iter_mut() {
+            summed.feed(&placeholder, Source::Origin(position));
+        }
+        self.slots.push_back((self.held.len(), slot));
+        self.held.extend(placeholder);
+        Ok(())
+    }
+
+    /// write the transformed origin bytes, accounting for them in every fixup spanning them
+    fn transform(&mut self, idx: usize, original: &[u8]) -> io::Result<()> {
+        let (range, transformed) = {
+            let (ref range, ref mut transform) = self.transforms[idx];
+            (range.clone(), transform(original))
+        };
+        for fixup in self.fixups.iter_mut() {
+            if fixup.spans(&range) {
+                fixup.delta += transformed.len() as i64 - original.len() as i64;
+                fixup.pending -= 1;
+            }
+        }
+        self.write_all(&transformed, Source::Transformed(range.start, range.end))
+    }
+
+    /// account for a completed insertion in every fixup and checksum covering it
+    fn inserted(&mut self, position: usize, len: usize) {
+        for fixup in self.fixups.iter_mut() {
+            if fixup.contains(position) {
+                fixup.delta += len as i64;
+                fixup.pending -= 1;
+            }
+        }
+        for summed in self.checksums.iter_mut() {
+            if summed.contains(position) {
+                summed.pending -= 1;
+            }
+        }
+    }
+
+    /// fill in every resolved field, then write out whatever no longer needs holding
+    ///
+    /// `progress` is the origin index up to which everything has been written
+    fn settle(&mut self, progress: usize) -> io::Result<()> {
+        if self.slots.is_empty() {
+            return Ok(());
+        }
+        let mut unresolved = VecDeque::with_capacity(self.slots.len());
+        for (offset, slot) in self.slots.drain(..) {
+            let value = match slot {
+                Slot::Fixup(idx) => self.fixups[idx].value()?.map(|bytes| bytes.to_vec()),
+                Slot::Checksum(idx) => self.checksums[idx].value(progress),
+            };
+            match value {
+                Some(bytes) => self.held[offset..offset + bytes.len()].copy_from_slice(&bytes),
+                None => unresolved.push_back((offset, slot)),
+            }
+        }
+        let flushable = unresolved
+            .front()
+            .map_or(self.held.len(), |&(offset, _)| offset);
+        match self.delta {
+            Some(ref mut delta) => delta.literal(&mut self.target, &self.held[..flushable])?,
+            None => self.target.write_all(&self.held[..flushable])?,
+        }
+        self.held.drain(..flushable);
+        self.slots = unresolved
+            .into_iter()
+            .map(|(offset, slot)| (offset - flushable, slot))
+            .collect();
+        Ok(())
+    }
+}
+
+/// writes a formatted value to the output as an insertion at this index,
+/// keeping any io error which `fmt::Write` can't carry
+struct Formatting<'o, 'i, W> {
+    output: &'o mut Output<'i, W>,
+    position: usize,
+    written: usize,
+    error: Option<io::Error>,
+}
+
+impl<'o, 'i, W: Write> fmt::Write for Formatting<'o, 'i, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self
+            .output
+            .write_all(s.as_bytes(), Source::Insertion(self.position))
+        {
+            Ok(()) => {
+                self.written += s.len();
+                Ok(())
+            }
+            Err(e) => {
+                self.error = Some(e);
+                Err(fmt::Error)
             }
         }
+    }
+}
+
+/// tracks progress through the origin, applying overwrites along the way
+struct Origin<'o, R> {
+    reader: R,
+    position: usize,
+    exhausted: bool,
+    overwrites: Peekable<btree_map::Iter<'o, usize, Patch>>,
+}
+
+impl<'o, R: Read> Origin<'o, R> {
+    /// the origin index up to which everything has been written
+    fn progress(&self) -> usize {
+        if self.exhausted {
+            usize::MAX
+        } else {
+            self.position
+        }
+    }
 
-        // we've added all inserts
-        // now finish copying over any remaining bytes from the origin
-        loop {
-            match self.origin.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(bytes_read) => {
-                    let written = &buffer[..bytes_read];
-                    self.target.write_all(written)?;
+    /// copy bytes from the origin to the target until reaching the given
+    /// origin index, or until both the origin and any overwrites past its end
+    /// have run out
+    fn copy_until<W: Write>(
+        &mut self,
+        until: usize,
+        target: &mut Output<W>,
+        buffer: &mut CopyBuffer,
+    ) -> io::Result<()> {
+        while self.position < until {
+            let next_overwrite = self
+                .overwrites
+                .peek()
+                .map(|(&start, patch)| (start, patch.len()));
+            match next_overwrite {
+                Some((start, len)) if start <= self.position => {
+                    let (_, patch) = self.overwrites.peek().expect("peeked above");
+                    // insertions within fields were rejected up front, so
+                    // fixup and checksum fields are handled here in one go
+                    let slot = match **patch {
+                        Patch::Bytes(_) | Patch::Delete(_) => {
+                            // we're within an overwrite or deletion: discard
+                            // origin bytes, write any replacements
+                            let replacement = match **patch {
+                                Patch::Bytes(ref bytes) => Some(bytes),
+                                _ => None,
+                            };
+                            let stop = until.min(start + len);
+                            self.skip(stop - self.position)?;
+                            if let Some(bytes) = replacement {
+                                target.write_all(
+                                    &bytes[self.position - start..stop - start],
+                                    Source::Origin(self.position),
+                                )?;
+                            }
+                            self.position = stop;
+                            if stop == start + len {
+                                self.overwrites.next();
+                            }
+                            continue;
+                        }
+                        Patch::Transform(idx, _) => {
+                            let mut original = Vec::with_capacity(len);
+                            if !self.exhausted {
+                                self.reader
+                                    .by_ref()
+                                    .take(len as u64)
+                                    .read_to_end(&mut original)?;
+                                self.exhausted = original.len() < len;
+                            }
+                            target.transform(idx, &original)?;
+                            self.position = start + len;
+                            self.overwrites.next();
+                            target.settle(self.progress())?;
+                            continue;
+                        }
+                        Patch::Fixup(idx) => {
+                            let mut field = [0_u8; 4];
+                            if self.exhausted {
+                                return Err(fixup_past_end());
+                            }
+                            self.reader.read_exact(&mut field).map_err(|e| {
+                                if e.kind() == io::ErrorKind::UnexpectedEof {
+                                    fixup_past_end()
+                                } else {
+                                    e
+                                }
+                            })?;
+                            let fixup = &mut target.fixups[idx];
+                            fixup.original = Some(match fixup.endian {
+                                Endian::Big => u32::from_be_bytes(field),
+                                Endian::Little => u32::from_le_bytes(field),
+                            });
+                            Slot::Fixup(idx)
+                        }
+                        Patch::Checksum(idx, _) => {
+                            self.skip(len)?;
+                            Slot::Checksum(idx)
+                        }
+                    };
+                    target.reserve(slot, start, len)?;
+                    self.position = start + len;
+                    self.overwrites.next();
+                    target.settle(self.progress())?;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                    // try again
+                next_overwrite => {
+                    let stop = next_overwrite.map_or(until, |(start, _)| until.min(start));
+                    if self.exhausted {
+                        // past the end of the origin, overwrites follow directly
+                        match next_overwrite {
+                            Some((start, _)) if start < until => self.position = start,
+                            _ => break,
+                        }
+                        continue;
+                    }
+                    let remaining_bytes = stop - self.position;
+                    let mut source = self.reader.by_ref().take(remaining_bytes as u64);
+                    match buffer.read_from(&mut source) {
+                        Ok([]) => self.exhausted = true,
+                        Ok(read) => {
+                            target.copy(read, self.position)?;
+                            self.position += read.len();
+                            target.settle(self.position)?;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                            // try again
+                        }
+                        Err(e) => return Err(e),
+                    }
                 }
-                Err(e) => return Err(e),
             }
         }
+        Ok(())
+    }
 
+    /// discard origin bytes which are being overwritten
+    fn skip(&mut self, len: usize) -> io::Result<()> {
+        if !self.exhausted {
+            let len = len as u64;
+            let skipped = io::copy(&mut self.reader.by_ref().take(len), &mut io::sink())?;
+            self.exhausted = skipped < len;
+        }
         Ok(())
     }
 }
 
+fn fixup_past_end() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "fixup field past the end of the origin",
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use checksum::{crc32, Adler32, Crc16, Crc32};
     use std::io::Cursor;
 
     #[test]
@@ -164,4 +1138,223 @@ mod tests {
 
         assert_eq!(&(0..10).collect::<Vec<u8>>(), &dest);
     }
+
+    #[test]
+    fn inspect_queued_insertions() {
+        let mut dest = Vec::new();
+        let mut inserter = Inserter::new(&b"ac"[..], &mut dest)
+            .insert(1, "b")
+            .insert(2, "!")
+            .overwrite(0, b"A");
+        assert_eq!(
+            vec![1, 2],
+            inserter
+                .insertions()
+                .map(|(position, _)| position)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(1, inserter.remove_at(2).len());
+        assert!(format!("{:?}", inserter).starts_with("Inserter { plan: PlanBuilder"));
+        inserter.execute().unwrap();
+        assert_eq!(b"Abc".to_vec(), dest);
+
+        let mut inserter = Inserter::new(&b"ac"[..], Vec::new()).insert(1, "b");
+        inserter.clear();
+        assert!(inserter.plan().is_empty());
+    }
+
+    #[test]
+    fn formats_display_sources() {
+        struct Failing;
+        impl fmt::Display for Failing {
+            fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert_display(6, 42)
+            .insert(6, " ")
+            .insert_display(13, 'x')
+            .operation(Operation::ReplaceAll(b"a".to_vec(), b"A".to_vec()))
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("AlphA 42 chArliex", out);
+
+        let err = Inserter::new(&b"alpha"[..], Vec::new())
+            .insert_display(2, Failing)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+    }
+
+    #[test]
+    fn execute_to_vec_and_string() {
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, "bravo ")
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("alpha bravo charlie", out);
+
+        let out = Inserter::new(&[0xff_u8][..], b"head ".to_vec())
+            .insert(0, "x")
+            .execute_to_vec()
+            .unwrap();
+        assert_eq!(b"head x\xff".to_vec(), out);
+        let err = Inserter::new(&[0xff_u8][..], Vec::new()).execute_to_string();
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn overwrite_in_place() {
+        let origin: Vec<u8> = (0..10).collect();
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .overwrite(2, &[20, 30])
+            .insert(3, &[99][..])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        assert_eq!(&vec![0, 1, 20, 99, 30, 4, 5, 6, 7, 8, 9], &dest);
+    }
+
+    #[test]
+    fn overwrite_past_end() {
+        let origin: Vec<u8> = (0..4).collect();
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .overwrite(3, &[30, 40])
+            .overwrite(8, &[80])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        assert_eq!(&vec![0, 1, 2, 30, 40, 80], &dest);
+    }
+
+    #[test]
+    fn overlapping_overwrites_fail() {
+        let mut dest = Vec::new();
+        let err = Inserter::new(&[0_u8; 8][..], Cursor::new(&mut dest))
+            .overwrite(0, &[1, 2, 3])
+            .overwrite(2, &[4])
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn fixup_before_its_range() {
+        // a little-endian length header followed by the data it describes
+        let origin = vec![3, 0, 0, 0, b'a', b'b', b'c', b'!'];
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .fixup(0, Endian::Little, 4..7)
+            .insert(5, &b"XY"[..])
+            .insert(7, &b"Z"[..])
+            .insert(8, &b"not counted"[..])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        assert_eq!(b"\x06\0\0\0aXYbcZ!not counted".to_vec(), dest);
+    }
+
+    #[test]
+    fn fixup_after_its_range() {
+        let origin = b"abc\0\0\0\x03".to_vec();
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .fixup(3, Endian::Big, 0..3)
+            .insert(0, &b"12"[..])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        assert_eq!(b"12abc\0\0\0\x05".to_vec(), dest);
+    }
+
+    #[test]
+    fn invalid_fixups_fail() {
+        let origin = [0_u8; 6];
+        let mut dest = Vec::new();
+        let err = Inserter::new(&origin[..], Cursor::new(&mut dest))
+            .fixup(4, Endian::Big, 0..1)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+
+        let err = Inserter::new(&origin[..], Cursor::new(&mut dest))
+            .fixup(0, Endian::Big, 0..6)
+            .insert(2, &b"x"[..])
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let err = Inserter::new(&[0xFF_u8; 4][..], Cursor::new(&mut dest))
+            .fixup(0, Endian::Big, 4..4)
+            .insert(4, &b"x"[..])
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn checksum_after_its_region() {
+        // a png-style chunk: length, type, data, crc of type and data
+        let mut origin = b"\0\0\0\x03tEXtabc".to_vec();
+        origin.extend_from_slice(&crc32(b"tEXtabc").to_be_bytes());
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .fixup(0, Endian::Big, 8..11)
+            .checksum(11, Endian::Big, 4..11, Crc32::new())
+            .insert(11, &b"def"[..])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        let mut expect = b"\0\0\0\x06tEXtabcdef".to_vec();
+        expect.extend_from_slice(&crc32(b"tEXtabcdef").to_be_bytes());
+        assert_eq!(expect, dest);
+    }
+
+    #[test]
+    fn checksum_before_its_region() {
+        let origin = b"\0\0payload".to_vec();
+        let mut dest = Vec::new();
+
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .checksum(0, Endian::Little, 2..9, Crc16::new())
+            .insert(2, &b"new "[..])
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+
+        let mut crc = Crc16::new();
+        Checksum::update(&mut crc, b"new payload");
+        assert_eq!(&crc.finish().to_le_bytes(), &dest[..2]);
+        assert_eq!(b"new payload", &dest[2..]);
+    }
+
+    #[test]
+    fn checksum_fields_within_regions() {
+        // a field within its own region counts as zeros
+        let origin = b"ab\xff\xff\xff\xffcd".to_vec();
+        let mut dest = Vec::new();
+        Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .checksum(2, Endian::Big, 0..8, Adler32::new())
+            .execute()
+            .expect("manipulating u8 lists should never fail");
+        let mut adler = Adler32::new();
+        adler.update(b"ab\0\0\0\0cd");
+        assert_eq!(&adler.finish().to_be_bytes(), &dest[2..6]);
+
+        // but another field within it can't be checksummed before it's known
+        let err = Inserter::new(origin.as_slice(), Cursor::new(&mut dest))
+            .checksum(0, Endian::Big, 0..8, Crc16::new())
+            .fixup(4, Endian::Big, 0..8)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
 }
@@ -0,0 +1,194 @@
+use std::{collections::VecDeque, ops::Range};
+
+type Source<'i, T> = Box<dyn 'i + Iterator<Item = T>>;
+
+/// item inserter splices iterators of any item type at origin indices
+///
+/// it mirrors `Inserter` for streams of items rather than bytes: the origin
+/// and every source are consumed lazily as the output iterator advances.
+/// unlike `Inserter`, several insertions at the same index are all kept, in
+/// the order they were added.
+pub struct ItemInserter<'i, T, I> {
+    origin: I,
+    insertions: Vec<(usize, Source<'i, T>)>,
+    deletions: Vec<Range<usize>>,
+}
+
+impl<'i, T, I> ItemInserter<'i, T, I>
+where
+    I: Iterator<Item = T>,
+{
+    /// create a new inserter with the specified origin iterator
+    pub fn new<O: IntoIterator<IntoIter = I, Item = T>>(origin: O) -> ItemInserter<'i, T, I> {
+        ItemInserter {
+            origin: origin.into_iter(),
+            insertions: Vec::new(),
+            deletions: Vec::new(),
+        }
+    }
+
+    /// insert the source items into the output at the given origin index
+    pub fn insert<S>(mut self, position: usize, source: S) -> Self
+    where
+        S: IntoIterator<Item = T>,
+        S::IntoIter: 'i,
+    {
+        self.insertions
+            .push((position, Box::new(source.into_iter())));
+        self
+    }
+
+    /// drop the origin items in the given range of origin indices
+    ///
+    /// overlapping deletions are merged. insertions within a deleted range are
+    /// still made.
+    pub fn delete(mut self, range: Range<usize>) -> Self {
+        if range.start < range.end {
+            self.deletions.push(range);
+        }
+        self
+    }
+
+    /// replace the origin items in the given range with the source items
+    pub fn replace<S>(self, range: Range<usize>, source: S) -> Self
+    where
+        S: IntoIterator<Item = T>,
+        S::IntoIter: 'i,
+    {
+        let start = range.start;
+        self.delete(range).insert(start, source)
+    }
+
+    /// execute this inserter, consuming it and returning the output items
+    pub fn execute(mut self) -> Items<'i, T, I> {
+        // a stable sort keeps same-index insertions in the order they were added
+        self.insertions.sort_by_key(|&(position, _)| position);
+        self.deletions.sort_by_key(|range| range.start);
+        let mut deletions: VecDeque<Range<usize>> = VecDeque::new();
+        for range in self.deletions {
+            match deletions.back_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => deletions.push_back(range),
+            }
+        }
+        Items {
+            origin: self.origin,
+            index: 0,
+            exhausted: false,
+            insertions: self.insertions.into_iter().collect(),
+            deletions,
+            current: None,
+        }
+    }
+}
+
+/// the output of an `ItemInserter`
+pub struct Items<'i, T, I> {
+    origin: I,
+    index: usize,
+    exhausted: bool,
+    insertions: VecDeque<(usize, Source<'i, T>)>,
+    deletions: VecDeque<Range<usize>>,
+    current: Option<Source<'i, T>>,
+}
+
+impl<'i, T, I> Items<'i, T, I> {
+    fn deleted(&mut self, index: usize) -> bool {
+        while self.deletions.front().is_some_and(|r| r.end <= index) {
+            self.deletions.pop_front();
+        }
+        self.deletions.front().is_some_and(|r| r.contains(&index))
+    }
+}
+
+impl<'i, T, I> Iterator for Items<'i, T, I>
+where
+    I: Iterator<Item = T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(ref mut current) = self.current {
+                if let Some(item) = current.next() {
+                    return Some(item);
+                }
+            }
+            self.current = None;
+
+            let due = self
+                .insertions
+                .front()
+                .is_some_and(|&(position, _)| self.exhausted || position <= self.index);
+            if due {
+                self.current = self.insertions.pop_front().map(|(_, source)| source);
+                continue;
+            }
+            if self.exhausted {
+                return None;
+            }
+
+            match self.origin.next() {
+                Some(item) => {
+                    let index = self.index;
+                    self.index += 1;
+                    if !self.deleted(index) {
+                        return Some(item);
+                    }
+                }
+                None => self.exhausted = true,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_items() {
+        let out: Vec<u32> = ItemInserter::new(vec![1, 2, 5])
+            .insert(2, 3..5)
+            .insert(0, Some(0))
+            .insert(9, vec![6, 7])
+            .execute()
+            .collect();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5, 6, 7], out);
+    }
+
+    #[test]
+    fn keeps_insertions_at_same_index_in_order() {
+        let out: String = ItemInserter::new("ad".chars())
+            .insert(1, "b".chars())
+            .insert(1, "c".chars())
+            .execute()
+            .collect();
+        assert_eq!("abcd", out);
+    }
+
+    #[test]
+    fn deletes_and_replaces() {
+        let words = vec!["the", "quick", "brown", "fox", "jumps"];
+        let out: Vec<&str> = ItemInserter::new(words)
+            .delete(1..2)
+            .replace(2..4, vec!["cat", "sleeps"])
+            .delete(3..6)
+            .execute()
+            .collect();
+        assert_eq!(vec!["the", "cat", "sleeps"], out);
+    }
+
+    #[test]
+    fn is_lazy() {
+        let mut out = ItemInserter::new(0..)
+            .insert(2, vec![100])
+            .delete(4..6)
+            .execute();
+        assert_eq!(
+            vec![0, 1, 100, 2, 3, 6],
+            out.by_ref().take(6).collect::<Vec<_>>()
+        );
+        assert_eq!(Some(7), out.next());
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{self, Read, Write};
+use util::fill;
+
+/// largest payload a segment can hold, as its length field counts itself
+pub const MAX_SEGMENT_LEN: usize = 0xFFFF - 2;
+
+const SOI: [u8; 2] = [0xFF, 0xD8];
+const APP0: u8 = 0xE0;
+const APP1: u8 = 0xE1;
+const COM: u8 = 0xFE;
+
+/// a marker segment to be inserted into a JPEG stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    marker: u8,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    /// create an application (`APPn`) or comment segment with the given payload
+    pub fn new(marker: u8, data: Vec<u8>) -> io::Result<Segment> {
+        if !(APP0..=APP0 + 15).contains(&marker) && marker != COM {
+            return Err(invalid_input("only APPn and COM segments may be inserted"));
+        }
+        if data.len() > MAX_SEGMENT_LEN {
+            return Err(invalid_input("JPEG segment payload too long"));
+        }
+        Ok(Segment { marker, data })
+    }
+
+    /// create an `APP1` segment holding exif data, i.e. a tiff structure
+    pub fn exif(tiff: &[u8]) -> io::Result<Segment> {
+        let mut data = b"Exif\0\0".to_vec();
+        data.extend_from_slice(tiff);
+        Segment::new(APP1, data)
+    }
+
+    /// create an `APP1` segment holding an xmp packet
+    pub fn xmp(packet: &str) -> io::Result<Segment> {
+        let mut data = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        data.extend_from_slice(packet.as_bytes());
+        Segment::new(APP1, data)
+    }
+
+    /// the framed segment: marker, big-endian length, payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 4);
+        out.extend_from_slice(&[0xFF, self.marker]);
+        out.extend_from_slice(&(self.data.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(&self.data);
+        out
+    }
+}
+
+/// insert a segment into a JPEG stream directly after the start of image marker
+///
+/// a leading JFIF `APP0` segment must stay first, so if one is present the new
+/// segment goes right after it instead. everything else is streamed untouched.
+pub fn insert_segment<R, W>(mut origin: R, mut target: W, segment: &Segment) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut soi = [0_u8; 2];
+    origin.read_exact(&mut soi)?;
+    if soi != SOI {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing JPEG start of image marker",
+        ));
+    }
+    target.write_all(&soi)?;
+
+    let mut header = [0_u8; 4];
+    let filled = fill(&mut origin, &mut header)?;
+    if filled == header.len() && header[..2] == [0xFF, APP0] {
+        let len = u64::from(u16::from_be_bytes([header[2], header[3]]));
+        if len < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed JPEG segment length",
+            ));
+        }
+        target.write_all(&header)?;
+        if io::copy(&mut origin.by_ref().take(len - 2), &mut target)? != len - 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated JPEG segment",
+            ));
+        }
+        target.write_all(&segment.to_bytes())?;
+    } else {
+        target.write_all(&segment.to_bytes())?;
+        target.write_all(&header[..filled])?;
+    }
+    io::copy(&mut origin, &mut target)?;
+    Ok(())
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REST: &[u8] = b"\xFF\xDB\x00\x03q\xFF\xD9";
+
+    fn run(origin: &[u8]) -> io::Result<Vec<u8>> {
+        let mut dest = Vec::new();
+        insert_segment(origin, &mut dest, &Segment::exif(b"II*\0").unwrap())?;
+        Ok(dest)
+    }
+
+    #[test]
+    fn inserts_after_soi() {
+        let mut origin = SOI.to_vec();
+        origin.extend_from_slice(REST);
+        let out = run(&origin).unwrap();
+        assert_eq!(b"\xFF\xD8\xFF\xE1\x00\x0cExif\0\0II*\0", &out[..16]);
+        assert_eq!(REST, &out[16..]);
+    }
+
+    #[test]
+    fn keeps_jfif_first() {
+        let mut origin = SOI.to_vec();
+        origin.extend_from_slice(b"\xFF\xE0\x00\x07JFIF\0");
+        origin.extend_from_slice(REST);
+        let out = run(&origin).unwrap();
+        assert_eq!(&origin[..11], &out[..11]);
+        assert_eq!(b"\xFF\xE1", &out[11..13]);
+        assert!(out.ends_with(REST));
+    }
+
+    #[test]
+    fn limits_segment_length() {
+        assert!(Segment::new(APP1, vec![0; MAX_SEGMENT_LEN]).is_ok());
+        assert!(Segment::new(APP1, vec![0; MAX_SEGMENT_LEN + 1]).is_err());
+        assert!(Segment::new(0xDB, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_non_jpeg() {
+        let err = run(b"GIF89a").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
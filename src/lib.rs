@@ -1,5 +1,165 @@
+#![cfg_attr(
+    feature = "read_buf",
+    feature(read_buf, core_io_borrowed_buf, borrowed_buf_init)
+)]
+#![cfg_attr(
+    all(unix, feature = "read_buf", feature = "unix"),
+    feature(read_buf_at)
+)]
+
+#[cfg(feature = "aes-gcm")]
+extern crate aes_gcm;
+#[cfg(feature = "arbitrary")]
+extern crate arbitrary;
+#[cfg(feature = "blake2")]
+extern crate blake2;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "cap-std")]
+extern crate cap_std;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "ed25519-dalek")]
+extern crate ed25519_dalek;
+#[cfg(feature = "flate2")]
+extern crate flate2;
+#[cfg(feature = "tower")]
+extern crate http as http_types;
+#[cfg(feature = "http-body")]
+extern crate http_body;
+#[cfg(all(unix, feature = "unix"))]
+extern crate libc;
+#[cfg(feature = "object_store")]
+extern crate object_store;
+#[cfg(feature = "regex")]
+extern crate regex;
+#[cfg(feature = "ropey")]
+extern crate ropey;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "sha2")]
+extern crate sha2;
+#[cfg(feature = "object_store")]
+extern crate tokio;
+#[cfg(feature = "tower")]
+extern crate tower_layer;
+#[cfg(feature = "tower")]
+extern crate tower_service;
+#[cfg(feature = "ureq")]
+extern crate ureq;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
 pub mod inserter;
 pub use inserter::Inserter;
 
+pub mod operation;
+pub use operation::{Operation, PlanBuilder};
+
+pub mod reader;
+pub use reader::ReadInsertExt;
+
+pub mod source;
+pub use source::IntoInsertSource;
+
+pub mod step;
+pub use step::Step;
+
 pub mod string_inserter;
 pub use string_inserter::StringInserter;
+
+pub mod utf16_inserter;
+pub use utf16_inserter::Utf16Inserter;
+
+pub mod item_inserter;
+pub use item_inserter::ItemInserter;
+
+pub mod vec_inserter;
+pub use vec_inserter::VecInserter;
+
+pub mod template;
+pub use template::TemplateInserter;
+
+#[cfg(all(unix, feature = "unix"))]
+pub mod advise;
+pub mod anchor;
+pub mod block;
+#[cfg(feature = "http-body")]
+pub mod body;
+#[cfg(feature = "bytes")]
+pub mod buf;
+pub mod cdc;
+pub mod checksum;
+pub mod comment;
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+pub mod compression;
+pub mod delta;
+pub mod demux;
+pub mod diff;
+#[cfg(feature = "sha2")]
+pub mod digest;
+#[cfg(all(target_os = "linux", feature = "unix"))]
+pub mod direct;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+#[cfg(feature = "ureq")]
+pub mod http;
+pub mod in_place;
+#[cfg(feature = "sha2")]
+pub mod manifest;
+pub mod marker;
+#[cfg(feature = "tower")]
+pub mod middleware;
+#[cfg(feature = "minisign")]
+pub mod minisign;
+#[cfg(all(unix, feature = "unix"))]
+pub mod parallel;
+pub mod process;
+#[cfg(feature = "cap-std")]
+pub mod sandbox;
+pub mod segment;
+pub mod sparse;
+#[cfg(feature = "object_store")]
+pub mod upload;
+
+pub mod chunked;
+#[cfg(feature = "elf")]
+pub mod elf;
+pub mod encoded;
+pub mod fasta;
+pub mod firmware;
+pub mod frames;
+pub mod front_matter;
+pub mod header;
+pub mod id3;
+pub mod jpeg;
+#[cfg(feature = "chrono")]
+pub mod logfile;
+pub mod mp4;
+pub mod multipart;
+pub mod pdf;
+pub mod pem;
+pub mod plan;
+#[cfg(feature = "png")]
+pub mod png;
+pub mod po;
+pub mod protobuf;
+pub mod record;
+pub mod riff;
+#[cfg(feature = "ropey")]
+pub mod rope;
+pub mod srt;
+pub mod tar;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod zip;
+
+mod base64;
+mod buffer;
+mod capture;
+mod pipeline;
+mod scan;
+mod throttle;
+mod util;
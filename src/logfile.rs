@@ -0,0 +1,114 @@
+use chrono::NaiveDateTime;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// insert `content` before the first line of a log whose leading timestamp is
+/// at or after `instant`
+///
+/// timestamps are parsed from the start of each line with a `chrono` format
+/// string such as `"%Y-%m-%d %H:%M:%S"`. lines which don't begin with a
+/// timestamp, such as wrapped stack traces, are never chosen as the anchor. if
+/// no line qualifies the content is appended. a newline is added to the content
+/// if it doesn't already end with one. once the content is written, the rest of
+/// the origin is copied without further parsing.
+pub fn insert_before_timestamp<R, W>(
+    origin: R,
+    mut target: W,
+    format: &str,
+    instant: NaiveDateTime,
+    content: &[u8],
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut origin = BufReader::new(origin);
+    let mut line = Vec::new();
+    let mut terminated = true;
+
+    loop {
+        line.clear();
+        if origin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        if leading_timestamp(&line, format).is_some_and(|ts| ts >= instant) {
+            write_content(&mut target, content)?;
+            target.write_all(&line)?;
+            io::copy(&mut origin, &mut target)?;
+            return Ok(());
+        }
+        terminated = line.ends_with(b"\n");
+        target.write_all(&line)?;
+    }
+
+    if !terminated {
+        target.write_all(b"\n")?;
+    }
+    write_content(&mut target, content)
+}
+
+fn leading_timestamp(line: &[u8], format: &str) -> Option<NaiveDateTime> {
+    let line = std::str::from_utf8(line).ok()?;
+    NaiveDateTime::parse_and_remainder(line, format)
+        .ok()
+        .map(|(ts, _)| ts)
+}
+
+fn write_content<W: Write>(target: &mut W, content: &[u8]) -> io::Result<()> {
+    target.write_all(content)?;
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        target.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+    const ORIGIN: &str = "2024-01-01 10:00:00 start\n\
+                          2024-01-01 10:05:00 boom\n  at frame one\n\
+                          2024-01-01 10:10:00 recovered\n";
+
+    fn run(origin: &str, instant: &str, content: &str) -> String {
+        let instant = NaiveDateTime::parse_from_str(instant, FORMAT).unwrap();
+        let mut dest = Vec::new();
+        insert_before_timestamp(
+            origin.as_bytes(),
+            &mut dest,
+            FORMAT,
+            instant,
+            content.as_bytes(),
+        )
+        .unwrap();
+        String::from_utf8(dest).unwrap()
+    }
+
+    #[test]
+    fn inserts_before_first_later_line() {
+        let out = run(
+            ORIGIN,
+            "2024-01-01 10:07:00",
+            "2024-01-01 10:07:00 synthetic",
+        );
+        assert_eq!(
+            "2024-01-01 10:00:00 start\n\
+             2024-01-01 10:05:00 boom\n  at frame one\n\
+             2024-01-01 10:07:00 synthetic\n\
+             2024-01-01 10:10:00 recovered\n",
+            out
+        );
+    }
+
+    #[test]
+    fn equal_timestamp_goes_first() {
+        let out = run(ORIGIN, "2024-01-01 10:00:00", "x\n");
+        assert!(out.starts_with("x\n2024-01-01 10:00:00 start\n"));
+    }
+
+    #[test]
+    fn appends_when_all_earlier() {
+        let out = run("2024-01-01 10:00:00 only", "2025-01-01 00:00:00", "late");
+        assert_eq!("2024-01-01 10:00:00 only\nlate\n", out);
+    }
+}
@@ -0,0 +1,259 @@
+//! manifests of where each run of the output came from
+//!
+//! supply-chain tooling needs to prove exactly what was spliced into a shipped
+//! artifact. alongside the output, a plan can list each of its segments, with
+//! its offset, length and sha-256 digest, and the digest of the whole, so that
+//! every byte can be traced to the origin or to one of the plan's insertions.
+
+use digest::to_hex;
+use segment::{Piece, Segment};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// a segment of the output, where it landed and what it contained
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// where the bytes came from. an insertion's length is always known
+    pub segment: Segment,
+    /// where the segment starts in the output
+    pub offset: u64,
+    pub len: u64,
+    pub sha256: [u8; 32],
+}
+
+/// every segment of an output, in order, and the digest of the whole
+///
+/// displayed, it's a line per segment of offset, length, digest and source,
+/// such as `6 4 <hex> origin 2..6` or `10 6 <hex> insertion 0`, followed by a
+/// line for the whole output, `0 <len> <hex> output`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+    pub len: u64,
+    pub sha256: [u8; 32],
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in self.entries.iter() {
+            write!(
+                f,
+                "{} {} {} ",
+                entry.offset,
+                entry.len,
+                to_hex(&entry.sha256)
+            )?;
+            match entry.segment {
+                Segment::Origin(ref range) => writeln!(f, "origin {}..{}", range.start, range.end)?,
+                Segment::Insertion { id, .. } => writeln!(f, "insertion {}", id)?,
+            }
+        }
+        writeln!(f, "0 {} {} output", self.len, to_hex(&self.sha256))
+    }
+}
+
+/// the target, hashing the segment being written and the output as a whole
+struct Hashed<W> {
+    inner: W,
+    segment: Sha256,
+    segment_len: u64,
+    whole: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.segment.update(&buf[..written]);
+        self.whole.update(&buf[..written]);
+        self.segment_len += written as u64;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W> Hashed<W> {
+    /// end the segment being written, recording it unless it's an empty run
+    /// of the origin
+    fn finish_segment(&mut self, segment: Segment, entries: &mut Vec<Entry>) {
+        let len = std::mem::replace(&mut self.segment_len, 0);
+        let sha256 = std::mem::take(&mut self.segment).finalize().into();
+        let segment = match segment {
+            Segment::Origin(_) if len == 0 => return,
+            Segment::Insertion { id, .. } => Segment::Insertion { id, len: Some(len) },
+            segment => segment,
+        };
+        entries.push(Entry {
+            segment,
+            offset: self.len - len,
+            len,
+            sha256,
+        });
+    }
+}
+
+/// write the laid out pieces to the target, listing the segments written
+///
+/// insertions come with their index in the plan's operations. adjacent runs
+/// of the origin are one segment, as they are in `PlanBuilder::segments`
+pub(crate) fn write<R: Read, W: Write>(
+    pieces: Vec<Piece<(usize, Box<dyn Read + '_>)>>,
+    mut origin: R,
+    target: W,
+) -> io::Result<Manifest> {
+    let mut target = Hashed {
+        inner: target,
+        segment: Sha256::new(),
+        segment_len: 0,
+        whole: Sha256::new(),
+        len: 0,
+    };
+    let mut entries = Vec::new();
+    let mut position = 0;
+    // the run of the origin being written, if any
+    let mut run: Option<usize> = None;
+    for piece in pieces {
+        match piece {
+            Piece::Origin(end) => {
+                let start = *run.get_or_insert(position);
+                let copied = io::copy(
+                    &mut origin.by_ref().take((end - position) as u64),
+                    &mut target,
+                )?;
+                position += copied as usize;
+                if position < end {
+                    // the origin has ended
+                    target.finish_segment(Segment::Origin(start..position), &mut entries);
+                    run = None;
+                    position = end;
+                }
+            }
+            Piece::Skip(end) => {
+                if let Some(start) = run.take() {
+                    target.finish_segment(Segment::Origin(start..position), &mut entries);
+                }
+                io::copy(
+                    &mut origin.by_ref().take((end - position) as u64),
+                    &mut io::sink(),
+                )?;
+                position = end;
+            }
+            Piece::Insertion((id, mut reader)) => {
+                if let Some(start) = run.take() {
+                    target.finish_segment(Segment::Origin(start..position), &mut entries);
+                }
+                io::copy(&mut reader, &mut target)?;
+                target.finish_segment(Segment::Insertion { id, len: None }, &mut entries);
+            }
+        }
+    }
+    if let Some(start) = run {
+        target.finish_segment(Segment::Origin(start..position), &mut entries);
+    }
+    target.flush()?;
+    Ok(Manifest {
+        entries,
+        len: target.len,
+        sha256: target.whole.finalize().into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digest::sha256;
+    use PlanBuilder;
+
+    fn plan() -> PlanBuilder<'static> {
+        PlanBuilder::new()
+            .insert(6, "bravo ")
+            .delete(0..2)
+            .replace(8..9, "H")
+            .insert(100, "!")
+    }
+
+    #[test]
+    fn lists_every_segment() {
+        let origin = b"alpha charlie";
+        let mut out = Vec::new();
+        let manifest = plan().execute_with_manifest(&origin[..], &mut out).unwrap();
+        assert_eq!(plan().execute_to_vec(origin).unwrap(), out);
+        assert_eq!(out.len() as u64, manifest.len);
+        assert_eq!(sha256(out.as_slice()).unwrap(), manifest.sha256);
+
+        let segments: Vec<_> = manifest.entries.iter().map(|e| e.segment.clone()).collect();
+        assert_eq!(plan().segments(origin.len()).unwrap(), segments);
+        for entry in manifest.entries.iter() {
+            let bytes = &out[entry.offset as usize..(entry.offset + entry.len) as usize];
+            assert_eq!(sha256(bytes).unwrap(), entry.sha256);
+            if let Segment::Origin(ref range) = entry.segment {
+                assert_eq!(&origin[range.clone()], bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn measures_insertions_as_written() {
+        let mut out = Vec::new();
+        let manifest = PlanBuilder::new()
+            .insert(1, ::source::InsertSource::reader(&b"unsized"[..]))
+            .delete(2..3)
+            .execute_with_manifest(&b"abc"[..], &mut out)
+            .unwrap();
+        assert_eq!(b"aunsizedb".to_vec(), out);
+        assert_eq!(
+            vec![
+                Segment::Origin(0..1),
+                Segment::Insertion {
+                    id: 0,
+                    len: Some(7)
+                },
+                Segment::Origin(1..2),
+            ],
+            manifest
+                .entries
+                .iter()
+                .map(|e| e.segment.clone())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn displays_a_line_per_segment() {
+        let manifest = PlanBuilder::new()
+            .insert(2, "cd")
+            .execute_with_manifest(&b"ab"[..], io::sink())
+            .unwrap();
+        let text = manifest.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(
+            format!("0 2 {} origin 0..2", to_hex(&sha256(&b"ab"[..]).unwrap())),
+            lines[0]
+        );
+        assert_eq!(
+            format!("2 2 {} insertion 0", to_hex(&sha256(&b"cd"[..]).unwrap())),
+            lines[1]
+        );
+        assert_eq!(
+            format!("0 4 {} output", to_hex(&sha256(&b"abcd"[..]).unwrap())),
+            lines[2]
+        );
+    }
+
+    #[test]
+    fn rejects_other_operations() {
+        let err = PlanBuilder::new()
+            .overwrite(0, b"x")
+            .execute_with_manifest(&b"ab"[..], io::sink())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -0,0 +1,250 @@
+use operation::PlanBuilder;
+use scan::Finder;
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+use template::MAX_NAME_LEN;
+
+use inserter::BUFFER_SIZE;
+
+/// the origin ranges of every named marker, by name
+pub type Markers = HashMap<String, Vec<Range<usize>>>;
+
+/// find every marker between the delimiters in a single pass over the origin
+///
+/// the name of a marker is the text between its delimiters, trimmed of
+/// whitespace. fails with `InvalidData` if a marker is unterminated or its
+/// name is too long.
+pub fn scan<R: Read>(mut origin: R, open: &[u8], close: &[u8]) -> io::Result<Markers> {
+    let mut markers = Markers::new();
+    let mut opener = Finder::new(open);
+    let mut closer = Finder::new(close);
+    // the origin index at which the current marker starts, and its name so far
+    let mut current: Option<(usize, Vec<u8>)> = None;
+    let mut position = 0;
+    let mut buffer = [0_u8; BUFFER_SIZE];
+    loop {
+        let bytes_read = match origin.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &byte in &buffer[..bytes_read] {
+            position += 1;
+            match current {
+                None => {
+                    if opener.feed(byte) {
+                        current = Some((position - open.len(), Vec::new()));
+                    }
+                }
+                Some((start, ref mut name)) => {
+                    name.push(byte);
+                    if name.len() > MAX_NAME_LEN + close.len() {
+                        return Err(invalid("marker name too long"));
+                    }
+                    if closer.feed(byte) {
+                        name.truncate(name.len() - close.len());
+                        let name = String::from_utf8_lossy(name).trim().to_string();
+                        markers.entry(name).or_default().push(start..position);
+                        current = None;
+                    }
+                }
+            }
+        }
+    }
+    if current.is_some() {
+        return Err(invalid("unterminated marker"));
+    }
+    Ok(markers)
+}
+
+/// marker inserter pre-scans the origin for named markers like
+/// `<!-- INSERT:nav -->` and inserts the sources registered under each name
+/// just after its markers
+pub struct MarkerInserter<'i, R, W> {
+    origin: R,
+    open: Vec<u8>,
+    close: Vec<u8>,
+    insertions: Vec<(String, InsertSource<'i>)>,
+    remove_markers: bool,
+    target: W,
+}
+
+impl<'i, R, W> MarkerInserter<'i, R, W>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    /// create a new marker inserter with the default `<!-- INSERT:` `-->` delimiters
+    ///
+    /// the origin is read from its current position
+    pub fn new(origin: R, target: W) -> MarkerInserter<'i, R, W> {
+        MarkerInserter {
+            origin,
+            open: b"<!-- INSERT:".to_vec(),
+            close: b"-->".to_vec(),
+            insertions: Vec::new(),
+            remove_markers: false,
+            target,
+        }
+    }
+
+    /// use custom marker delimiters
+    ///
+    /// panics if either delimiter is empty
+    pub fn delimiters(mut self, open: &str, close: &str) -> Self {
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "marker delimiters must not be empty"
+        );
+        self.open = open.as_bytes().to_vec();
+        self.close = close.as_bytes().to_vec();
+        self
+    }
+
+    /// remove every marker from the output, so that the insertions take its place
+    pub fn remove_markers(mut self, remove: bool) -> Self {
+        self.remove_markers = remove;
+        self
+    }
+
+    /// insert the source after every marker with this name
+    ///
+    /// several sources for the same name are inserted in the order they were
+    /// registered. only in-memory sources can be inserted at more than one
+    /// marker.
+    pub fn insert<I: IntoInsertSource<'i>>(mut self, name: &str, source: I) -> Self {
+        self.insertions
+            .push((name.trim().to_string(), source.into_insert_source()));
+        self
+    }
+
+    /// execute this marker inserter, consuming it
+    ///
+    /// fails with `NotFound` if a registered name has no marker, or with
+    /// `InvalidData` if a reader is registered for a name with several markers
+    pub fn execute(mut self) -> io::Result<()> {
+        let start = self.origin.stream_position()?;
+        let markers = scan(&mut self.origin, &self.open, &self.close)?;
+        self.origin.seek(SeekFrom::Start(start))?;
+
+        let mut plan = PlanBuilder::new();
+        for (name, source) in self.insertions {
+            let ranges = match markers.get(&name) {
+                Some(ranges) => ranges,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("marker `{}` not found", name),
+                    ))
+                }
+            };
+            match source {
+                InsertSource::Bytes(bytes) => {
+                    for range in ranges {
+                        plan = plan.insert(range.end, InsertSource::Bytes(bytes.clone()));
+                    }
+                }
+                source if ranges.len() == 1 => plan = plan.insert(ranges[0].end, source),
+                _ => {
+                    return Err(invalid(&format!(
+                        "reader registered for marker `{}`, which occurs more than once",
+                        name
+                    )))
+                }
+            }
+        }
+        if self.remove_markers {
+            for range in markers.into_values().flatten() {
+                plan = plan.delete(range);
+            }
+        }
+        plan.execute(self.origin, self.target)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn scans_markers() {
+        let markers = scan(&b"a [[x]] b [[ y ]] c [[x]]"[..], b"[[", b"]]").unwrap();
+        assert_eq!(&[2..7, 20..25][..], &markers["x"][..]);
+        assert_eq!(Some(&(10..17)), markers["y"].first());
+        let err = scan(&b"a [[x"[..], b"[[", b"]]").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn inserts_by_name() {
+        let origin = "<nav><!-- INSERT:nav --></nav>\n<!-- INSERT: footer -->\n<!-- INSERT:nav -->";
+        let mut dest = Vec::new();
+        MarkerInserter::new(Cursor::new(origin), &mut dest)
+            .insert("nav", "home")
+            .insert("footer", InsertSource::reader(&b"(c)"[..]))
+            .insert("nav", "|about")
+            .execute()
+            .unwrap();
+        assert_eq!(
+            "<nav><!-- INSERT:nav -->home|about</nav>\n<!-- INSERT: footer -->(c)\n<!-- INSERT:nav -->home|about",
+            String::from_utf8(dest).unwrap()
+        );
+    }
+
+    #[test]
+    fn removes_markers() {
+        let origin = "<ul><!-- INSERT:items --></ul><!-- INSERT:unused -->";
+        let mut dest = Vec::new();
+        MarkerInserter::new(Cursor::new(origin), &mut dest)
+            .insert("items", "<li>a</li>")
+            .insert("items", "<li>b</li>")
+            .remove_markers(true)
+            .execute()
+            .unwrap();
+        assert_eq!(
+            "<ul><li>a</li><li>b</li></ul>",
+            String::from_utf8(dest).unwrap()
+        );
+    }
+
+    #[test]
+    fn custom_delimiters_spanning_buffers() {
+        let prefix = "-".repeat(BUFFER_SIZE - 3);
+        let mut dest = Vec::new();
+        MarkerInserter::new(Cursor::new(format!("{}{{%slot%}}-", prefix)), &mut dest)
+            .delimiters("{%", "%}")
+            .insert("slot", "!")
+            .execute()
+            .unwrap();
+        assert_eq!(
+            format!("{}{{%slot%}}!-", prefix),
+            String::from_utf8(dest).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_repeated_markers() {
+        let err = MarkerInserter::new(Cursor::new("none here"), io::sink())
+            .insert("nav", "x")
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+
+        let err = MarkerInserter::new(Cursor::new("[[a]][[a]]"), io::sink())
+            .delimiters("[[", "]]")
+            .insert("a", InsertSource::reader(&b"x"[..]))
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,376 @@
+//! tower middleware splicing content into response bodies
+//!
+//! the layer works with any tower-based server, axum among them: a response
+//! whose body it splices is still a `Response` of a `Body`, so it's still an
+//! `IntoResponse`. bodies are spliced as they stream, never buffered whole,
+//! and only up to the first match; after that they pass straight through.
+
+use bytes::{Buf, Bytes};
+use http_body::{Body, Frame, SizeHint};
+use http_types::{header, response::Parts, Response};
+use scan::Finder;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower_layer::Layer;
+use tower_service::Service;
+
+type Predicate = Arc<dyn Fn(&Parts) -> bool + Send + Sync>;
+
+/// whether a response is html which hasn't been compressed
+pub fn is_html(parts: &Parts) -> bool {
+    let html = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().starts_with("text/html"));
+    html && !parts.headers.contains_key(header::CONTENT_ENCODING)
+}
+
+struct Config {
+    pattern: Vec<u8>,
+    /// whether the content goes after the pattern, rather than before it
+    after: bool,
+    content: Bytes,
+    predicate: Predicate,
+}
+
+/// a layer splicing content into the bodies of responses which match a
+/// predicate, next to the first occurrence of a pattern
+///
+/// by default only uncompressed html is spliced. a spliced response loses its
+/// `Content-Length`, as the length is no longer known up front; a body without
+/// the pattern passes through unchanged.
+#[derive(Clone)]
+pub struct SpliceLayer {
+    config: Arc<Config>,
+}
+
+impl SpliceLayer {
+    fn new(pattern: &[u8], after: bool, content: Bytes) -> SpliceLayer {
+        SpliceLayer {
+            config: Arc::new(Config {
+                pattern: pattern.to_vec(),
+                after,
+                content,
+                predicate: Arc::new(is_html),
+            }),
+        }
+    }
+
+    /// splice the content in before the pattern, such as a script before
+    /// `</body>`
+    ///
+    /// an empty pattern puts the content at the start of the body
+    pub fn before<C: Into<Bytes>>(pattern: &[u8], content: C) -> SpliceLayer {
+        SpliceLayer::new(pattern, false, content.into())
+    }
+
+    /// splice the content in after the pattern, such as a banner after `<body>`
+    pub fn after<C: Into<Bytes>>(pattern: &[u8], content: C) -> SpliceLayer {
+        SpliceLayer::new(pattern, true, content.into())
+    }
+
+    /// splice only responses for which the predicate holds, instead of `is_html`
+    pub fn when<P>(self, predicate: P) -> Self
+    where
+        P: 'static + Send + Sync + Fn(&Parts) -> bool,
+    {
+        let config = Config {
+            pattern: self.config.pattern.clone(),
+            after: self.config.after,
+            content: self.config.content.clone(),
+            predicate: Arc::new(predicate),
+        };
+        SpliceLayer {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> Layer<S> for SpliceLayer {
+    type Service = Splice<S>;
+
+    fn layer(&self, inner: S) -> Splice<S> {
+        Splice {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// a service whose responses are spliced, made by `SpliceLayer`
+#[derive(Clone)]
+pub struct Splice<S> {
+    inner: S,
+    config: Arc<Config>,
+}
+
+impl<S, Request, B> Service<Request> for Splice<S>
+where
+    S: Service<Request, Response = Response<B>>,
+    B: Body,
+{
+    type Response = Response<SplicedBody<B>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        ResponseFuture {
+            inner: Box::pin(self.inner.call(request)),
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// the response future of `Splice`
+pub struct ResponseFuture<F> {
+    inner: Pin<Box<F>>,
+    config: Arc<Config>,
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: Body,
+{
+    type Output = Result<Response<SplicedBody<B>>, E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = match self.inner.as_mut().poll(cx) {
+            Poll::Ready(response) => response?,
+            Poll::Pending => return Poll::Pending,
+        };
+        let (mut parts, body) = response.into_parts();
+        let splicing = if (self.config.predicate)(&parts) {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Some(self.config.clone())
+        } else {
+            None
+        };
+        Poll::Ready(Ok(Response::from_parts(
+            parts,
+            SplicedBody::new(body, splicing),
+        )))
+    }
+}
+
+/// a response body with content spliced in next to the first match of a
+/// pattern, or passed through as it is
+pub struct SplicedBody<B> {
+    inner: Pin<Box<B>>,
+    /// the finder and what it's looking for, until the content is spliced in
+    splicing: Option<(Finder, Arc<Config>)>,
+    /// a frame to send before polling the inner body again
+    pending: Option<Frame<Bytes>>,
+}
+
+impl<B: Body> SplicedBody<B> {
+    fn new(inner: B, config: Option<Arc<Config>>) -> SplicedBody<B> {
+        let mut body = SplicedBody {
+            inner: Box::pin(inner),
+            splicing: None,
+            pending: None,
+        };
+        match config {
+            Some(config) if config.pattern.is_empty() => {
+                body.pending = Some(Frame::data(config.content.clone()))
+            }
+            Some(config) => body.splicing = Some((Finder::new(&config.pattern), config)),
+            None => {}
+        }
+        body
+    }
+
+    /// pass the chunk through the finder, splicing the content in at a match
+    fn splice(&mut self, chunk: Bytes) -> Bytes {
+        let (ref mut finder, ref config) = match self.splicing {
+            Some(ref mut splicing) => splicing,
+            None => return chunk,
+        };
+        let mut out = Vec::with_capacity(chunk.len() + config.content.len());
+        for (idx, &byte) in chunk.iter().enumerate() {
+            if finder.feed_through(byte, &mut out) {
+                if config.after {
+                    out.extend_from_slice(&config.pattern);
+                    out.extend_from_slice(&config.content);
+                } else {
+                    out.extend_from_slice(&config.content);
+                    out.extend_from_slice(&config.pattern);
+                }
+                out.extend_from_slice(&chunk[idx + 1..]);
+                self.splicing = None;
+                break;
+            }
+        }
+        out.into()
+    }
+
+    /// the bytes held back as a partial match, once the body has no more data
+    fn held(&mut self) -> Option<Bytes> {
+        let (finder, _) = self.splicing.take()?;
+        let held = finder.pending();
+        (!held.is_empty()).then(|| Bytes::copy_from_slice(held))
+    }
+}
+
+impl<B: Body> Body for SplicedBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, B::Error>>> {
+        if let Some(frame) = self.pending.take() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        let frame = match self.inner.as_mut().poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => frame,
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+            Poll::Ready(None) => return Poll::Ready(self.held().map(|held| Ok(Frame::data(held)))),
+            Poll::Pending => return Poll::Pending,
+        };
+        let frame = match frame.into_data() {
+            Ok(mut data) => Frame::data(self.splice(data.copy_to_bytes(data.remaining()))),
+            Err(frame) => {
+                // trailers end the data, so whatever's held back goes first
+                let frame = frame.map_data(|mut data| data.copy_to_bytes(data.remaining()));
+                match self.held() {
+                    Some(held) => {
+                        self.pending = Some(frame);
+                        Frame::data(held)
+                    }
+                    None => frame,
+                }
+            }
+        };
+        Poll::Ready(Some(Ok(frame)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.splicing.is_none() && self.pending.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match (&self.splicing, &self.pending) {
+            (None, None) => self.inner.size_hint(),
+            _ => SizeHint::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_types::{HeaderValue, Request};
+    use std::{collections::VecDeque, convert::Infallible, future, task::Waker};
+
+    /// a body of several chunks
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Body for Chunks {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+            Poll::Ready(
+                self.0
+                    .pop_front()
+                    .map(|chunk| Ok(Frame::data(chunk.into()))),
+            )
+        }
+
+        fn is_end_stream(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    /// responds with the chunks, as the given content type
+    struct Respond(&'static str, Vec<&'static [u8]>);
+
+    impl Service<Request<()>> for Respond {
+        type Response = Response<Chunks>;
+        type Error = Infallible;
+        type Future = future::Ready<Result<Response<Chunks>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<()>) -> Self::Future {
+            let len: usize = self.1.iter().map(|chunk| chunk.len()).sum();
+            let mut response = Response::new(Chunks(self.1.iter().cloned().collect()));
+            let headers = response.headers_mut();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(self.0));
+            headers.insert(header::CONTENT_LENGTH, len.into());
+            future::ready(Ok(response))
+        }
+    }
+
+    fn respond(layer: &SpliceLayer, service: Respond) -> (Parts, Vec<u8>) {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut service = layer.layer(service);
+        let mut future = service.call(Request::new(()));
+        let response = match Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(response) => response.unwrap(),
+            Poll::Pending => panic!("the response is ready"),
+        };
+        let (parts, mut body) = response.into_parts();
+        let mut out = Vec::new();
+        while let Poll::Ready(Some(frame)) = Pin::new(&mut body).poll_frame(&mut cx) {
+            out.extend_from_slice(&frame.unwrap().into_data().unwrap());
+        }
+        assert!(body.is_end_stream());
+        (parts, out)
+    }
+
+    #[test]
+    fn splices_across_chunks() {
+        let page: Vec<&[u8]> = vec![b"<html><body>hi</bo", b"dy></html>"];
+        let layer = SpliceLayer::before(b"</body>", "<script></script>");
+        let (parts, out) = respond(&layer, Respond("text/html; charset=utf-8", page.clone()));
+        assert_eq!(
+            b"<html><body>hi<script></script></body></html>".to_vec(),
+            out
+        );
+        assert!(!parts.headers.contains_key(header::CONTENT_LENGTH));
+
+        let layer = SpliceLayer::after(b"<body>", "<p>banner</p>");
+        let (_, out) = respond(&layer, Respond("text/html", page));
+        assert_eq!(b"<html><body><p>banner</p>hi</body></html>".to_vec(), out);
+    }
+
+    #[test]
+    fn passes_other_responses_through() {
+        let layer = SpliceLayer::before(b"}", "injected");
+        let (parts, out) = respond(&layer, Respond("application/json", vec![b"{}"]));
+        assert_eq!(b"{}".to_vec(), out);
+        assert!(parts.headers.contains_key(header::CONTENT_LENGTH));
+
+        let layer = layer.when(|parts| parts.status.is_success());
+        let (_, out) = respond(&layer, Respond("application/json", vec![b"{}"]));
+        assert_eq!(b"{injected}".to_vec(), out);
+    }
+
+    #[test]
+    fn keeps_partial_matches() {
+        let layer = SpliceLayer::before(b"</body>", "x");
+        let (_, out) = respond(&layer, Respond("text/html", vec![b"<p>", b"</bo"]));
+        assert_eq!(b"<p></bo".to_vec(), out);
+
+        let layer = SpliceLayer::before(b"", "<!-- spliced -->");
+        let (_, out) = respond(&layer, Respond("text/html", vec![b"<p>"]));
+        assert_eq!(b"<!-- spliced --><p>".to_vec(), out);
+    }
+}
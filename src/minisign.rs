@@ -0,0 +1,207 @@
+//! minisign detached signatures of output, computed as it's written
+//!
+//! signatures are of the prehashed kind: the output is hashed with blake2b as
+//! it passes through, so signing never needs it read back. they verify with
+//! `minisign -V` against the key's public key.
+
+use base64;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signer as _, SigningKey};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+/// an ed25519 secret key with its minisign key id
+pub struct SecretKey {
+    key: SigningKey,
+    id: [u8; 8],
+}
+
+impl SecretKey {
+    /// a key from its 32-byte ed25519 seed and 8-byte key id
+    pub fn from_seed(seed: [u8; 32], id: [u8; 8]) -> SecretKey {
+        SecretKey {
+            key: SigningKey::from_bytes(&seed),
+            id,
+        }
+    }
+
+    /// the public key, as the contents of a minisign `.pub` file
+    pub fn public_key(&self) -> String {
+        let mut key = b"Ed".to_vec();
+        key.extend_from_slice(&self.id);
+        key.extend_from_slice(self.key.verifying_key().as_bytes());
+        format!(
+            "untrusted comment: minisign public key {}\n{}\n",
+            key_id(&self.id),
+            base64::encode(&key)
+        )
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("id", &key_id(&self.id))
+            .finish_non_exhaustive()
+    }
+}
+
+/// key ids are shown as hex of their little-endian value
+fn key_id(id: &[u8; 8]) -> String {
+    format!("{:016X}", u64::from_le_bytes(*id))
+}
+
+/// a detached signature, displayed as the contents of a minisign `.minisig` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    id: [u8; 8],
+    signature: [u8; 64],
+    trusted_comment: String,
+    global: [u8; 64],
+}
+
+impl Signature {
+    /// the ed25519 signature of the output's blake2b-512 hash
+    pub fn signature(&self) -> &[u8; 64] {
+        &self.signature
+    }
+
+    /// the comment signed along with the signature
+    pub fn trusted_comment(&self) -> &str {
+        &self.trusted_comment
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut signature = b"ED".to_vec();
+        signature.extend_from_slice(&self.id);
+        signature.extend_from_slice(&self.signature);
+        writeln!(
+            f,
+            "untrusted comment: signature from minisign secret key {}",
+            key_id(&self.id)
+        )?;
+        writeln!(f, "{}", base64::encode(&signature))?;
+        writeln!(f, "trusted comment: {}", self.trusted_comment)?;
+        writeln!(f, "{}", base64::encode(&self.global))
+    }
+}
+
+/// signer passes writes through to its inner writer, hashing everything
+/// written to sign it once it's finished
+pub struct Signer<'k, W> {
+    inner: W,
+    hasher: Blake2b512,
+    key: &'k SecretKey,
+}
+
+impl<'k, W: Write> Signer<'k, W> {
+    /// sign everything written to `inner` with the key
+    pub fn new(inner: W, key: &'k SecretKey) -> Signer<'k, W> {
+        Signer {
+            inner,
+            hasher: Blake2b512::new(),
+            key,
+        }
+    }
+
+    /// sign what's been written, along with the trusted comment, returning
+    /// the inner writer and the signature
+    ///
+    /// fails with `InvalidInput` if the comment contains a newline
+    pub fn finish(mut self, trusted_comment: &str) -> io::Result<(W, Signature)> {
+        if trusted_comment.contains(['\r', '\n']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "trusted comment contains a newline",
+            ));
+        }
+        self.inner.flush()?;
+        let hash = self.hasher.finalize();
+        let signature = self.key.key.sign(&hash).to_bytes();
+        let mut global = signature.to_vec();
+        global.extend_from_slice(trusted_comment.as_bytes());
+        let global = self.key.key.sign(&global).to_bytes();
+        Ok((
+            self.inner,
+            Signature {
+                id: self.key.id,
+                signature,
+                trusted_comment: trusted_comment.to_string(),
+                global,
+            },
+        ))
+    }
+}
+
+impl<'k, W: Write> Write for Signer<'k, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature as Ed25519, Verifier};
+    use Inserter;
+
+    fn key() -> SecretKey {
+        SecretKey::from_seed([7; 32], *b"\x01\x02\x03\x04\x05\x06\x07\x08")
+    }
+
+    #[test]
+    fn signs_output_in_one_pass() {
+        let key = key();
+        let mut out = Vec::new();
+        let signature = Inserter::new(&b"alpha charlie"[..], &mut out)
+            .insert(6, "bravo ")
+            .execute_signed(&key, "file:out.txt")
+            .unwrap();
+        assert_eq!(b"alpha bravo charlie", &out[..]);
+
+        let public = key.key.verifying_key();
+        let hash = Blake2b512::digest(&out);
+        let detached = Ed25519::from_bytes(signature.signature());
+        assert!(public.verify(&hash, &detached).is_ok());
+        let mut global = signature.signature().to_vec();
+        global.extend_from_slice(b"file:out.txt");
+        assert!(public
+            .verify(&global, &Ed25519::from_bytes(&signature.global))
+            .is_ok());
+        assert!(public
+            .verify(&Blake2b512::digest(b"alpha"), &detached)
+            .is_err());
+    }
+
+    #[test]
+    fn formats_minisign_files() {
+        let key = key();
+        let (_, signature) = Signer::new(io::sink(), &key).finish("t").unwrap();
+        let text = signature.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(4, lines.len());
+        assert_eq!(
+            "untrusted comment: signature from minisign secret key 0807060504030201",
+            lines[0]
+        );
+        assert_eq!(
+            b"ED\x01\x02\x03\x04\x05\x06\x07\x08",
+            &base64::decode(lines[1].as_bytes()).unwrap()[..10]
+        );
+        assert_eq!("trusted comment: t", lines[2]);
+        assert!(key
+            .public_key()
+            .starts_with("untrusted comment: minisign public key 0807060504030201\nRWQBAgMEBQYH"));
+        assert!(Signer::new(io::sink(), &key).finish("a\nb").is_err());
+    }
+}
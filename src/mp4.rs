@@ -0,0 +1,347 @@
+use inserter::Inserter;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// boxes whose payload is made up entirely of child boxes
+const CONTAINERS: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta", b"edts", b"dinf", b"mvex", b"moof",
+    b"traf", b"meta",
+];
+
+/// a box (also known as an atom) to be inserted into an MP4 file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Atom {
+    kind: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Atom {
+    /// create a box with the given four-character type and raw payload
+    pub fn new(kind: [u8; 4], data: Vec<u8>) -> Atom {
+        Atom { kind, data }
+    }
+
+    /// create a container box whose payload is the given children
+    pub fn container(kind: [u8; 4], children: &[Atom]) -> Atom {
+        Atom::new(kind, children.iter().flat_map(Atom::to_bytes).collect())
+    }
+
+    /// the framed box: size, type and payload
+    ///
+    /// a 64-bit size is used if the box doesn't fit in 32 bits
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 16);
+        let len = self.data.len() as u64 + 8;
+        if len <= u64::from(u32::MAX) {
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+            out.extend_from_slice(&self.kind);
+        } else {
+            out.extend_from_slice(&1_u32.to_be_bytes());
+            out.extend_from_slice(&self.kind);
+            out.extend_from_slice(&(len + 8).to_be_bytes());
+        }
+        out.extend_from_slice(&self.data);
+        out
+    }
+}
+
+/// what to do with the chunk offset tables when media data moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkOffsets {
+    /// shift every `stco` and `co64` entry which points past the insertion
+    Adjust,
+    /// leave the tables alone, e.g. for fragmented files without them
+    Keep,
+}
+
+/// insert a box at the end of the container identified by `parent`
+///
+/// `parent` is a path of box types from the top level, e.g. `[*b"moov", *b"udta"]`;
+/// an empty path appends a top-level box. the size of every enclosing box is
+/// patched to account for the new box. when inserting ahead of `mdat`, as is
+/// usual for `moov` at the start of a file, the chunk offsets must be adjusted
+/// or the file will no longer play. fails with `NotFound` if the parent is missing.
+pub fn insert_box<R, W>(
+    mut origin: R,
+    target: W,
+    parent: &[[u8; 4]],
+    atom: &Atom,
+    offsets: ChunkOffsets,
+) -> io::Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let file_len = origin.seek(SeekFrom::End(0))?;
+    let bytes = atom.to_bytes();
+    let added = bytes.len() as u64;
+
+    let mut ancestors = Vec::with_capacity(parent.len());
+    let (mut start, mut end) = (0, file_len);
+    for kind in parent {
+        let found = children(&mut origin, start, end)?
+            .into_iter()
+            .find(|header| &header.kind == kind)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no `{}` box", String::from_utf8_lossy(kind)),
+                )
+            })?;
+        start = found.payload_start();
+        end = found.end;
+        ancestors.push(found);
+    }
+    let position = end;
+
+    let mut patches = Vec::new();
+    for header in &ancestors {
+        if header.to_eof {
+            continue;
+        }
+        let size = header.end - header.offset + added;
+        if header.large {
+            patches.push((header.offset + 8, size.to_be_bytes().to_vec()));
+        } else if size <= u64::from(u32::MAX) {
+            patches.push((header.offset, (size as u32).to_be_bytes().to_vec()));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "`{}` box would exceed 4 GiB",
+                    String::from_utf8_lossy(&header.kind)
+                ),
+            ));
+        }
+    }
+
+    if offsets == ChunkOffsets::Adjust {
+        let mut tables = Vec::new();
+        find_offset_tables(&mut origin, 0, file_len, &mut tables)?;
+        for table in tables {
+            patches.push(shift_offsets(&mut origin, &table, position, added)?);
+        }
+    }
+
+    origin.seek(SeekFrom::Start(0))?;
+    let mut inserter = Inserter::new(origin, target);
+    for (at, patch) in &patches {
+        inserter = inserter.overwrite(*at as usize, patch);
+    }
+    inserter
+        .insert(position as usize, bytes.as_slice())
+        .execute()
+}
+
+#[derive(Debug)]
+struct Header {
+    kind: [u8; 4],
+    offset: u64,
+    header_len: u64,
+    end: u64,
+    large: bool,
+    to_eof: bool,
+}
+
+impl Header {
+    fn payload_start(&self) -> u64 {
+        // `meta` is a full box: version and flags precede its children
+        let full = if &self.kind == b"meta" { 4 } else { 0 };
+        self.offset + self.header_len + full
+    }
+}
+
+/// the headers of the boxes laid out between `start` and `end`
+fn children<R: Read + Seek>(origin: &mut R, start: u64, end: u64) -> io::Result<Vec<Header>> {
+    let mut headers = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        if end - offset < 8 {
+            return Err(invalid("truncated box header"));
+        }
+        let mut raw = [0_u8; 8];
+        origin.seek(SeekFrom::Start(offset))?;
+        origin.read_exact(&mut raw)?;
+        let size = u64::from(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]));
+        let mut header = Header {
+            kind: [raw[4], raw[5], raw[6], raw[7]],
+            offset,
+            header_len: 8,
+            end: offset + size,
+            large: false,
+            to_eof: false,
+        };
+        if size == 1 {
+            let mut large = [0_u8; 8];
+            origin.read_exact(&mut large)?;
+            header.header_len = 16;
+            header.end = offset
+                .checked_add(u64::from_be_bytes(large))
+                .ok_or_else(|| invalid("box size out of bounds"))?;
+            header.large = true;
+        } else if size == 0 {
+            header.end = end;
+            header.to_eof = true;
+        }
+        if header.end < offset + header.header_len || header.end > end {
+            return Err(invalid("box size out of bounds"));
+        }
+        offset = header.end;
+        headers.push(header);
+    }
+    Ok(headers)
+}
+
+fn find_offset_tables<R: Read + Seek>(
+    origin: &mut R,
+    start: u64,
+    end: u64,
+    tables: &mut Vec<Header>,
+) -> io::Result<()> {
+    for header in children(origin, start, end)? {
+        if &header.kind == b"stco" || &header.kind == b"co64" {
+            tables.push(header);
+        } else if CONTAINERS.contains(&&header.kind) {
+            find_offset_tables(origin, header.payload_start(), header.end, tables)?;
+        }
+    }
+    Ok(())
+}
+
+/// the rewritten entries of a chunk offset table, and where they go
+fn shift_offsets<R: Read + Seek>(
+    origin: &mut R,
+    table: &Header,
+    position: u64,
+    added: u64,
+) -> io::Result<(u64, Vec<u8>)> {
+    let width = if &table.kind == b"co64" { 8 } else { 4 };
+    let entries_at = table.offset + table.header_len + 8;
+    let mut count = [0_u8; 4];
+    origin.seek(SeekFrom::Start(entries_at - 4))?;
+    origin.read_exact(&mut count)?;
+    let count = u64::from(u32::from_be_bytes(count));
+    if entries_at + count * width > table.end {
+        return Err(invalid("chunk offset table overruns its box"));
+    }
+
+    let mut entries = vec![0_u8; (count * width) as usize];
+    origin.read_exact(&mut entries)?;
+    for entry in entries.chunks_mut(width as usize) {
+        if width == 8 {
+            let mut raw = [0_u8; 8];
+            raw.copy_from_slice(entry);
+            let offset = u64::from_be_bytes(raw);
+            if offset >= position {
+                entry.copy_from_slice(&(offset + added).to_be_bytes());
+            }
+        } else {
+            let offset = u64::from(u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]));
+            if offset >= position {
+                let shifted = offset + added;
+                if shifted > u64::from(u32::MAX) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "shifted chunk offset doesn't fit in `stco`",
+                    ));
+                }
+                entry.copy_from_slice(&(shifted as u32).to_be_bytes());
+            }
+        }
+    }
+    Ok((entries_at, entries))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// ftyp, moov with a single track's stco, then mdat
+    fn movie() -> Vec<u8> {
+        let ftyp = Atom::new(*b"ftyp", b"isom\0\0\0\0".to_vec());
+        let stco = |offset: u32| {
+            let mut data = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            data.extend_from_slice(&offset.to_be_bytes());
+            Atom::new(*b"stco", data)
+        };
+        let build = |offset| {
+            let stbl = Atom::container(*b"stbl", &[stco(offset)]);
+            let minf = Atom::container(*b"minf", &[stbl]);
+            let mdia = Atom::container(*b"mdia", &[minf]);
+            let trak = Atom::container(*b"trak", &[mdia]);
+            let moov = Atom::container(*b"moov", &[trak]);
+            let mut out = ftyp.to_bytes();
+            out.extend(moov.to_bytes());
+            out
+        };
+        // the mdat payload starts just after its own header
+        let mdat_payload = build(0).len() as u32 + 8;
+        let mut out = build(mdat_payload);
+        out.extend(Atom::new(*b"mdat", b"media".to_vec()).to_bytes());
+        out
+    }
+
+    fn run(parent: &[[u8; 4]], offsets: ChunkOffsets) -> io::Result<Vec<u8>> {
+        let mut dest = Vec::new();
+        let udta = Atom::container(*b"udta", &[Atom::new(*b"\xa9nam", b"title".to_vec())]);
+        insert_box(Cursor::new(movie()), &mut dest, parent, &udta, offsets)?;
+        Ok(dest)
+    }
+
+    fn chunk_offset(mp4: &[u8]) -> usize {
+        let at = mp4.windows(4).position(|w| w == b"stco").unwrap();
+        u32::from_be_bytes([mp4[at + 12], mp4[at + 13], mp4[at + 14], mp4[at + 15]]) as usize
+    }
+
+    #[test]
+    fn fixture_is_consistent() {
+        let mp4 = movie();
+        assert_eq!(b"media", &mp4[chunk_offset(&mp4)..]);
+    }
+
+    #[test]
+    fn inserts_into_moov_and_adjusts_offsets() {
+        let out = run(&[*b"moov"], ChunkOffsets::Adjust).unwrap();
+        assert_eq!(b"media", &out[chunk_offset(&out)..]);
+        let mut cursor = Cursor::new(&out);
+        let top = children(&mut cursor, 0, out.len() as u64).unwrap();
+        let kinds: Vec<_> = top.iter().map(|h| h.kind).collect();
+        assert_eq!(vec![*b"ftyp", *b"moov", *b"mdat"], kinds);
+        let moov = children(&mut cursor, top[1].payload_start(), top[1].end).unwrap();
+        let kinds: Vec<_> = moov.iter().map(|h| h.kind).collect();
+        assert_eq!(vec![*b"trak", *b"udta"], kinds);
+    }
+
+    #[test]
+    fn keeps_offsets_when_asked() {
+        let out = run(&[*b"moov"], ChunkOffsets::Keep).unwrap();
+        assert_eq!(chunk_offset(&movie()), chunk_offset(&out));
+    }
+
+    #[test]
+    fn appending_at_top_level_moves_nothing() {
+        let out = run(&[], ChunkOffsets::Adjust).unwrap();
+        assert_eq!(b"media", &out[chunk_offset(&out)..chunk_offset(&out) + 5]);
+        assert_eq!(&movie()[..], &out[..movie().len()]);
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes() {
+        let mut mp4 = movie();
+        mp4.extend_from_slice(&[0, 0, 0, 1]);
+        mp4.extend_from_slice(b"free");
+        mp4.extend_from_slice(&u64::MAX.to_be_bytes());
+        let err = children(&mut Cursor::new(&mp4), 0, mp4.len() as u64).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn missing_parent() {
+        let err = run(&[*b"moov", *b"udta"], ChunkOffsets::Adjust).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
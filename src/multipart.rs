@@ -0,0 +1,307 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+/// longest line examined for a boundary delimiter; longer lines are body data
+const MAX_LINE: u64 = 1024;
+
+/// largest header section of an existing part that will be parsed
+pub const MAX_HEADERS_LEN: usize = 64 * 1024;
+
+/// the header fields of a body part
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// the value of the first field with this name, compared case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// all fields, in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    fn parse(section: &[u8]) -> Headers {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for line in String::from_utf8_lossy(section).lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                if let Some(last) = fields.last_mut() {
+                    last.1.push(' ');
+                    last.1.push_str(line.trim());
+                }
+            } else if let Some(colon) = line.find(':') {
+                fields.push((
+                    line[..colon].trim().to_string(),
+                    line[colon + 1..].trim().to_string(),
+                ));
+            }
+        }
+        Headers { fields }
+    }
+}
+
+/// a body part to insert
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Part {
+    headers: Headers,
+    body: Vec<u8>,
+}
+
+impl Part {
+    /// a part with the given body and no header fields
+    pub fn new(body: &[u8]) -> Part {
+        Part {
+            headers: Headers::default(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// add a header field
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .fields
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// the encapsulation of this part: delimiter, header fields and body
+    fn to_bytes(&self, boundary: &str) -> Vec<u8> {
+        let mut out = format!("--{}\r\n", boundary).into_bytes();
+        for (name, value) in self.headers.iter() {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+        out.extend_from_slice(&self.body);
+        out.extend_from_slice(b"\r\n");
+        out
+    }
+}
+
+/// a test applied to the header fields of each existing part
+pub type Predicate<'p> = Box<dyn 'p + Fn(&Headers) -> bool>;
+
+/// where to place a new part in a multipart body
+pub enum Placement<'p> {
+    /// before the part with this index; past the last part appends
+    Index(usize),
+    /// before the first part whose headers satisfy the predicate
+    Before(Predicate<'p>),
+    /// after the first part whose headers satisfy the predicate
+    After(Predicate<'p>),
+}
+
+/// insert a body part into a multipart body with the given boundary
+///
+/// the origin is the body only, without the enclosing entity's headers. the
+/// origin is streamed through line by line; only the header sections of
+/// existing parts are buffered. fails with `InvalidInput` if the boundary is
+/// invalid or appears in the new part, `NotFound` if no part satisfies the
+/// predicate, and `UnexpectedEof` if the origin has no close delimiter.
+pub fn insert_part<R, W>(
+    origin: R,
+    mut target: W,
+    boundary: &str,
+    placement: Placement,
+    part: &Part,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    if boundary.is_empty() || boundary.len() > 70 || boundary.ends_with(' ') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "boundary must be 1 to 70 characters, not ending in a space",
+        ));
+    }
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let new = part.to_bytes(boundary);
+    if new[delimiter.len()..]
+        .windows(delimiter.len())
+        .any(|w| w == &delimiter[..])
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "new part contains the boundary delimiter",
+        ));
+    }
+
+    let mut origin = BufReader::new(origin);
+    let mut line = Vec::new();
+    let mut line_start = true;
+    let mut index = 0;
+    let mut inserted = false;
+    let mut after_this = false;
+    loop {
+        line.clear();
+        origin
+            .by_ref()
+            .take(MAX_LINE)
+            .read_until(b'\n', &mut line)?;
+        if line.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "multipart body has no close delimiter",
+            ));
+        }
+        let at_start = line_start;
+        line_start = line.ends_with(b"\n");
+        if !at_start || !line.starts_with(&delimiter) {
+            target.write_all(&line)?;
+            continue;
+        }
+        let close = line[delimiter.len()..].starts_with(b"--");
+        let tail = &line[delimiter.len() + if close { 2 } else { 0 }..];
+        if !tail.iter().all(|b| b" \t\r\n".contains(b)) {
+            // a body line which merely starts with the delimiter
+            target.write_all(&line)?;
+            continue;
+        }
+
+        let mut headers = Vec::new();
+        if !close {
+            if !line_start {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated boundary delimiter",
+                ));
+            }
+            read_headers(&mut origin, &mut headers)?;
+        }
+        let fields = Headers::parse(&headers);
+        let before = match placement {
+            Placement::Index(at) => close || at == index,
+            Placement::Before(ref predicate) => !close && predicate(&fields),
+            Placement::After(_) => after_this,
+        };
+        if before {
+            target.write_all(&new)?;
+            inserted = true;
+        }
+        target.write_all(&line)?;
+        target.write_all(&headers)?;
+        if inserted || close {
+            io::copy(&mut origin, &mut target)?;
+            break;
+        }
+        if let Placement::After(ref predicate) = placement {
+            after_this = predicate(&fields);
+        }
+        index += 1;
+    }
+
+    if inserted {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no part matched the predicate",
+        ))
+    }
+}
+
+/// read a part's header section, including the blank line which ends it
+fn read_headers<R: BufRead>(origin: &mut R, headers: &mut Vec<u8>) -> io::Result<()> {
+    loop {
+        let start = headers.len();
+        let limit = (MAX_HEADERS_LEN - start) as u64;
+        origin.by_ref().take(limit).read_until(b'\n', headers)?;
+        match &headers[start..] {
+            b"\r\n" | b"\n" => return Ok(()),
+            line if line.ends_with(b"\n") => {}
+            _ if headers.len() == MAX_HEADERS_LEN => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "part headers too long",
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated part headers",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"a\"\r\n\
+        \r\n\
+        alpha\r\n\
+        --xyz\r\n\
+        Content-Disposition: form-data; name=\"b\"\r\n\
+        \r\n\
+        bravo\r\n\
+        --xyz--\r\n\
+        epilogue";
+
+    fn run(placement: Placement) -> io::Result<String> {
+        let part = Part::new(b"new").header("Content-Type", "text/plain");
+        let mut dest = Vec::new();
+        insert_part(BODY, &mut dest, "xyz", placement, &part)?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    fn named(name: &'static str) -> Predicate<'static> {
+        Box::new(move |h: &Headers| {
+            h.get("content-disposition")
+                .is_some_and(|d| d.contains(&format!("name=\"{}\"", name)))
+        })
+    }
+
+    const NEW: &str = "--xyz\r\nContent-Type: text/plain\r\n\r\nnew\r\n";
+
+    #[test]
+    fn inserts_at_index() {
+        let body = String::from_utf8(BODY.to_vec()).unwrap();
+        let out = run(Placement::Index(0)).unwrap();
+        assert_eq!(
+            body.replacen("--xyz\r\n", &format!("{}--xyz\r\n", NEW), 1),
+            out
+        );
+        let out = run(Placement::Index(9)).unwrap();
+        assert_eq!(body.replace("--xyz--", &format!("{}--xyz--", NEW)), out);
+    }
+
+    #[test]
+    fn inserts_around_matching_part() {
+        let body = String::from_utf8(BODY.to_vec()).unwrap();
+        let expected = body.replace(
+            "--xyz\r\nContent-Disposition: form-data; name=\"b\"",
+            &format!("{}--xyz\r\nContent-Disposition: form-data; name=\"b\"", NEW),
+        );
+        assert_eq!(expected, run(Placement::Before(named("b"))).unwrap());
+        assert_eq!(expected, run(Placement::After(named("a"))).unwrap());
+        let err = run(Placement::After(named("c"))).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut dest = Vec::new();
+        let part = Part::new(b"oops\r\n--xyz--");
+        let err = insert_part(BODY, &mut dest, "xyz", Placement::Index(0), &part).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let part = Part::new(b"fine");
+        let err = insert_part(
+            &b"--xyz\r\n\r\nunterminated"[..],
+            &mut dest,
+            "xyz",
+            Placement::Index(5),
+            &part,
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    }
+}
@@ -0,0 +1,1054 @@
+#[cfg(feature = "arbitrary")]
+use arbitrary::{Arbitrary, Unstructured};
+use checksum::Checksum;
+#[cfg(feature = "encryption")]
+use encryption::Encryptor;
+use inserter::{self, overlap, Endian};
+#[cfg(feature = "sha2")]
+use manifest::{self, Manifest};
+#[cfg(feature = "minisign")]
+use minisign::{SecretKey, Signature, Signer};
+use pipeline;
+use reader::Chained;
+use segment::{self, Piece, Segment};
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Range,
+};
+
+/// a function rewriting a range of origin bytes, for `Operation::Transform`
+pub type Transform<'i> = Box<dyn 'i + FnMut(&[u8]) -> Vec<u8>>;
+
+/// what a reversal reverses the order of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+pub enum Reverse {
+    /// every byte
+    Bytes,
+    /// every `\n`-terminated line, keeping the bytes of each in order
+    ///
+    /// an unterminated last line becomes the terminated first line, and the
+    /// new last line is left unterminated
+    Lines,
+}
+
+impl Reverse {
+    /// the bytes in reverse order
+    pub fn apply(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Reverse::Bytes => bytes.iter().rev().cloned().collect(),
+            Reverse::Lines => {
+                let terminated = bytes.last().is_none_or(|&b| b == b'\n');
+                let mut out = Vec::with_capacity(bytes.len());
+                for line in bytes.split_inclusive(|&b| b == b'\n').rev() {
+                    out.extend_from_slice(line);
+                    if !line.ends_with(b"\n") {
+                        out.push(b'\n');
+                    }
+                }
+                if !terminated {
+                    out.pop();
+                }
+                out
+            }
+        }
+    }
+}
+
+/// a single edit to a stream, in origin indices
+///
+/// every operation refers to the origin as it was before any of them were
+/// applied, so they can be given in any order. several insertions at the same
+/// index are all kept, in the order they were added. deleted, overwritten,
+/// transformed and field ranges mustn't overlap one another.
+pub enum Operation<'i> {
+    /// insert the source at this origin index
+    Insert(usize, InsertSource<'i>),
+    /// drop the origin bytes in this range
+    ///
+    /// insertions within the range are still made
+    Delete(Range<usize>),
+    /// drop the origin bytes in this range and insert the source in their place
+    Replace(Range<usize>, InsertSource<'i>),
+    /// replace the origin bytes starting at this index with these bytes
+    Overwrite(usize, Vec<u8>),
+    /// replace the origin bytes in this range with the output of the function
+    ///
+    /// the range is buffered in memory. it may not contain insertions, and may
+    /// not straddle the range of a fixup or the region of a checksum.
+    Transform(Range<usize>, Transform<'i>),
+    /// insert a copy of the origin bytes in this range at this index
+    ///
+    /// the copy is of the origin as it was, whatever else happens to the
+    /// range. copying from later in the origin buffers everything in between.
+    Copy(Range<usize>, usize),
+    /// replace every occurrence of the pattern in the origin
+    ///
+    /// occurrences are found in the origin as it's copied, after overwrites,
+    /// and never span an insertion, deletion or transform. several are applied
+    /// in the order they were added, each to the output of the last. the
+    /// pattern mustn't be empty, and can't be combined with fixups or checksums.
+    ReplaceAll(Vec<u8>, Vec<u8>),
+    /// adjust the u32 at this index by the change in size of the range
+    Fixup(usize, Endian, Range<usize>),
+    /// overwrite the field at this index with a checksum of the region
+    Checksum(usize, Endian, Range<usize>, Box<dyn 'i + Checksum>),
+}
+
+impl<'i> Operation<'i> {
+    /// the origin index at which this operation takes effect
+    pub fn position(&self) -> usize {
+        match *self {
+            Operation::Insert(position, _)
+            | Operation::Copy(_, position)
+            | Operation::Overwrite(position, _)
+            | Operation::Fixup(position, _, _)
+            | Operation::Checksum(position, _, _, _) => position,
+            Operation::ReplaceAll(_, _) => 0,
+            Operation::Delete(ref range)
+            | Operation::Replace(ref range, _)
+            | Operation::Transform(ref range, _) => range.start,
+        }
+    }
+
+    /// the source this operation inserts, if any
+    pub fn source(&self) -> Option<&InsertSource<'i>> {
+        match *self {
+            Operation::Insert(_, ref source) | Operation::Replace(_, ref source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// an operation on a short origin: indices are below 256, so that plans
+/// generated for an origin of that length mostly land within it
+///
+/// transforms reverse their range; fixups and checksums are never generated
+#[cfg(feature = "arbitrary")]
+impl<'a, 'i> Arbitrary<'a> for Operation<'i> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.choose_index(7)? {
+            0 => Operation::Insert(arbitrary_index(u)?, u.arbitrary()?),
+            1 => Operation::Delete(arbitrary_range(u)?),
+            2 => Operation::Replace(arbitrary_range(u)?, u.arbitrary()?),
+            3 => Operation::Overwrite(arbitrary_index(u)?, u.arbitrary()?),
+            4 => {
+                let reverse = Reverse::arbitrary(u)?;
+                Operation::Transform(
+                    arbitrary_range(u)?,
+                    Box::new(move |bytes| reverse.apply(bytes)),
+                )
+            }
+            5 => Operation::Copy(arbitrary_range(u)?, arbitrary_index(u)?),
+            _ => {
+                let mut pattern = Vec::<u8>::arbitrary(u)?;
+                if pattern.is_empty() {
+                    pattern.push(u.arbitrary()?);
+                }
+                Operation::ReplaceAll(pattern, u.arbitrary()?)
+            }
+        })
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_index(u: &mut Unstructured) -> arbitrary::Result<usize> {
+    Ok(u8::arbitrary(u)? as usize)
+}
+
+#[cfg(feature = "arbitrary")]
+fn arbitrary_range(u: &mut Unstructured) -> arbitrary::Result<Range<usize>> {
+    let start = arbitrary_index(u)?;
+    Ok(start..start + arbitrary_index(u)?)
+}
+
+impl<'i> fmt::Debug for Operation<'i> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operation::Insert(position, ref source) => f
+                .debug_tuple("Insert")
+                .field(&position)
+                .field(source)
+                .finish(),
+            Operation::Delete(ref range) => f.debug_tuple("Delete").field(range).finish(),
+            Operation::Replace(ref range, ref source) => {
+                f.debug_tuple("Replace").field(range).field(source).finish()
+            }
+            Operation::Overwrite(position, ref bytes) => f
+                .debug_tuple("Overwrite")
+                .field(&position)
+                .field(bytes)
+                .finish(),
+            Operation::Transform(ref range, _) => f
+                .debug_tuple("Transform")
+                .field(range)
+                .finish_non_exhaustive(),
+            Operation::Copy(ref range, position) => {
+                f.debug_tuple("Copy").field(range).field(&position).finish()
+            }
+            Operation::ReplaceAll(ref pattern, ref replacement) => f
+                .debug_tuple("ReplaceAll")
+                .field(&String::from_utf8_lossy(pattern))
+                .field(&String::from_utf8_lossy(replacement))
+                .finish(),
+            Operation::Fixup(position, endian, ref range) => f
+                .debug_tuple("Fixup")
+                .field(&position)
+                .field(&endian)
+                .field(range)
+                .finish(),
+            Operation::Checksum(position, endian, ref region, ref checksum) => f
+                .debug_struct("Checksum")
+                .field("position", &position)
+                .field("endian", &endian)
+                .field("region", region)
+                .field("width", &checksum.width())
+                .finish(),
+        }
+    }
+}
+
+/// how a plan is executed, as opposed to what it does
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Settings {
+    pub(crate) rate_limit: Option<u64>,
+    pub(crate) max_output: Option<u64>,
+    pub(crate) delta: bool,
+}
+
+/// plan builder collects operations to be applied to a stream in one pass
+#[derive(Debug, Default)]
+pub struct PlanBuilder<'i> {
+    operations: Vec<Operation<'i>>,
+    settings: Settings,
+}
+
+impl<'i> PlanBuilder<'i> {
+    /// create a new, empty plan
+    pub fn new() -> PlanBuilder<'i> {
+        PlanBuilder::default()
+    }
+
+    /// add an operation to the plan
+    pub fn push(mut self, operation: Operation<'i>) -> Self {
+        self.operations.push(operation);
+        self
+    }
+
+    /// insert the source at the given origin index
+    pub fn insert<I: IntoInsertSource<'i>>(self, position: usize, source: I) -> Self {
+        self.push(Operation::Insert(position, source.into_insert_source()))
+    }
+
+    /// insert the value, formatted with `Display` when its index is reached,
+    /// at the given origin index
+    pub fn insert_display<T: 'i + fmt::Display>(self, position: usize, value: T) -> Self {
+        self.insert(position, InsertSource::display(value))
+    }
+
+    /// drop the origin bytes in the given range
+    pub fn delete(self, range: Range<usize>) -> Self {
+        self.push(Operation::Delete(range))
+    }
+
+    /// replace the origin bytes in the given range with the source
+    pub fn replace<I: IntoInsertSource<'i>>(self, range: Range<usize>, source: I) -> Self {
+        self.push(Operation::Replace(range, source.into_insert_source()))
+    }
+
+    /// replace the origin bytes starting at the given origin index with `bytes`
+    pub fn overwrite(self, position: usize, bytes: &[u8]) -> Self {
+        self.push(Operation::Overwrite(position, bytes.to_vec()))
+    }
+
+    /// replace the origin bytes in the given range with the output of `transform`
+    pub fn transform<F>(self, range: Range<usize>, transform: F) -> Self
+    where
+        F: 'i + FnMut(&[u8]) -> Vec<u8>,
+    {
+        self.push(Operation::Transform(range, Box::new(transform)))
+    }
+
+    /// write the origin bytes in the given range in reverse order
+    pub fn reverse(self, range: Range<usize>, reverse: Reverse) -> Self {
+        self.transform(range, move |bytes| reverse.apply(bytes))
+    }
+
+    /// insert a copy of the origin bytes in `range` at the given origin index
+    pub fn copy(self, range: Range<usize>, position: usize) -> Self {
+        self.push(Operation::Copy(range, position))
+    }
+
+    /// move the origin bytes in `range` to the given origin index
+    ///
+    /// this is a copy and a deletion of the range, so moving the range to the
+    /// index of any other operation puts it after whatever that inserts
+    pub fn move_range(self, range: Range<usize>, position: usize) -> Self {
+        self.copy(range.clone(), position).delete(range)
+    }
+
+    /// exchange the origin bytes in two ranges
+    ///
+    /// the ranges may be of different lengths, but mustn't overlap: since each
+    /// is deleted, overlapping ranges fail on execution just as overlapping
+    /// deletions do
+    pub fn swap(self, a: Range<usize>, b: Range<usize>) -> Self {
+        self.copy(b.clone(), a.start)
+            .copy(a.clone(), b.start)
+            .delete(a)
+            .delete(b)
+    }
+
+    /// replace every occurrence of `pattern` in the origin with `replacement`
+    pub fn replace_all(self, pattern: &[u8], replacement: &[u8]) -> Self {
+        self.push(Operation::ReplaceAll(
+            pattern.to_vec(),
+            replacement.to_vec(),
+        ))
+    }
+
+    /// adjust the u32 at the given origin index by the change in size of `range`
+    pub fn fixup(self, position: usize, endian: Endian, range: Range<usize>) -> Self {
+        self.push(Operation::Fixup(position, endian, range))
+    }
+
+    /// overwrite the field at the given origin index with a checksum of `region`
+    pub fn checksum<C: 'i + Checksum>(
+        self,
+        position: usize,
+        endian: Endian,
+        region: Range<usize>,
+        checksum: C,
+    ) -> Self {
+        self.push(Operation::Checksum(
+            position,
+            endian,
+            region,
+            Box::new(checksum),
+        ))
+    }
+
+    /// limit the output to this many bytes per second
+    ///
+    /// every byte written counts, whether it comes from the origin or an
+    /// insertion. output may run a tenth of a second ahead of the rate.
+    pub fn rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.settings.rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// fail rather than produce more than this many bytes of output
+    ///
+    /// the error is `QuotaExceeded`, naming the origin index, insertion or
+    /// transform whose output would have crossed the limit; nothing past the
+    /// limit is written. a delta's output counts as the output it rebuilds
+    pub fn max_output(mut self, bytes: u64) -> Self {
+        self.settings.max_output = Some(bytes);
+        self
+    }
+
+    /// how the plan is to be executed
+    #[cfg(all(unix, feature = "unix"))]
+    pub(crate) fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// the operations in the plan, in the order they were added
+    pub fn operations(&self) -> &[Operation<'i>] {
+        &self.operations
+    }
+
+    /// the insertions in the plan, including replacements, in the order they were added
+    pub fn insertions(&self) -> impl Iterator<Item = (usize, &InsertSource<'i>)> {
+        self.operations.iter().filter_map(|operation| {
+            operation
+                .source()
+                .map(|source| (operation.position(), source))
+        })
+    }
+
+    /// remove and return every operation taking effect at the given origin index
+    pub fn remove_at(&mut self, position: usize) -> Vec<Operation<'i>> {
+        let (removed, kept) = self
+            .operations
+            .drain(..)
+            .partition(|operation| operation.position() == position);
+        self.operations = kept;
+        removed
+    }
+
+    /// remove every operation from the plan
+    pub fn clear(&mut self) {
+        self.operations.clear()
+    }
+
+    /// the number of operations in the plan
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// whether the plan has no operations
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// the operations in the plan, in the order they were added
+    pub fn into_operations(self) -> Vec<Operation<'i>> {
+        self.operations
+    }
+
+    /// the exact length of the output for an origin of the given length
+    ///
+    /// this can't be known up front if the length of any insertion is unknown,
+    /// or if the plan has a transform. deleted ranges which overlap, and so
+    /// fail on execution, may also give `None`
+    pub fn output_len(&self, origin_len: usize) -> Option<usize> {
+        let origin = 0..origin_len;
+        let mut len = origin_len;
+        for operation in self.operations.iter() {
+            match *operation {
+                Operation::Insert(_, ref source) => len += source.len_hint()? as usize,
+                Operation::Delete(ref range) => len = len.checked_sub(overlap(range, &origin))?,
+                Operation::Replace(ref range, ref source) => {
+                    len =
+                        (len + source.len_hint()? as usize).checked_sub(overlap(range, &origin))?
+                }
+                Operation::Overwrite(position, ref bytes) => {
+                    // overwritten bytes past the end of the origin are appended
+                    len += (position + bytes.len()).saturating_sub(position.max(origin_len))
+                }
+                Operation::Transform(_, _) => return None,
+                Operation::Copy(ref range, _) => len += overlap(range, &origin),
+                Operation::ReplaceAll(ref pattern, ref replacement) => {
+                    if pattern.len() != replacement.len() {
+                        return None;
+                    }
+                }
+                Operation::Fixup(_, _, _) | Operation::Checksum(_, _, _, _) => {}
+            }
+        }
+        Some(len)
+    }
+
+    /// apply the plan, streaming the origin to the target
+    pub fn execute<R: Read, W: Write>(self, origin: R, target: W) -> io::Result<()> {
+        inserter::apply(self.operations, self.settings, origin, target)
+    }
+
+    /// apply the plan, writing to the target on a thread of its own
+    ///
+    /// the origin is read and the output produced on this thread, and handed
+    /// over in buffers, so that reading from a slow origin such as a socket
+    /// overlaps with writing to a slow target such as a disk. output is written
+    /// in the same order, and an error from either side ends both
+    pub fn execute_pipelined<R: Read, W: Write + Send>(
+        self,
+        origin: R,
+        target: W,
+    ) -> io::Result<()> {
+        pipeline::run(target, |pipe| self.execute(origin, pipe))
+    }
+
+    /// apply the plan, writing a delta which rebuilds the output from the origin
+    ///
+    /// see the `delta` module for the format
+    pub fn execute_delta<R: Read, W: Write>(mut self, origin: R, target: W) -> io::Result<()> {
+        self.settings.delta = true;
+        self.execute(origin, target)
+    }
+
+    /// apply the plan, signing the output with the key as it's written
+    ///
+    /// the trusted comment is signed along with it, and mustn't contain a newline
+    #[cfg(feature = "minisign")]
+    pub fn execute_signed<R: Read, W: Write>(
+        self,
+        origin: R,
+        target: W,
+        key: &SecretKey,
+        trusted_comment: &str,
+    ) -> io::Result<Signature> {
+        let mut signer = Signer::new(target, key);
+        self.execute(origin, &mut signer)?;
+        Ok(signer.finish(trusted_comment)?.1)
+    }
+
+    /// apply the plan, encrypting the output as it's written
+    ///
+    /// the nonce prefix must never be reused with the same key
+    #[cfg(feature = "encryption")]
+    pub fn execute_encrypted<R: Read, W: Write>(
+        self,
+        origin: R,
+        target: W,
+        key: &[u8; 32],
+        nonce: [u8; 7],
+    ) -> io::Result<()> {
+        let mut encryptor = Encryptor::new(target, key, nonce)?;
+        self.execute(origin, &mut encryptor)?;
+        encryptor.finish()?;
+        Ok(())
+    }
+
+    /// apply the plan to a seekable origin, sizing the target file up front
+    ///
+    /// the origin is measured from where it is to its end. if the length of the
+    /// output can then be known, the target is set to its final length before
+    /// anything is written to it, and with the `unix` feature its blocks are
+    /// allocated too, so a full disk fails early and the file isn't fragmented.
+    /// output is written from the target's current position, and the target is
+    /// cut off where the output ends, whether or not its length was known.
+    pub fn execute_presized<R: Read + Seek>(
+        self,
+        mut origin: R,
+        target: &mut File,
+    ) -> io::Result<()> {
+        let start = origin.stream_position()?;
+        let end = origin.seek(SeekFrom::End(0))?;
+        origin.seek(SeekFrom::Start(start))?;
+        if let Some(len) = self.output_len((end - start) as usize) {
+            let offset = target.stream_position()?;
+            target.set_len(offset + len as u64)?;
+            allocate(target, offset, len as u64)?;
+        }
+        let mut target = BufWriter::new(target);
+        self.execute(origin, &mut target)?;
+        let target = target.into_inner()?;
+        let end = target.stream_position()?;
+        target.set_len(end)
+    }
+
+    /// compile the plan into a reader of its output, which reads each insertion
+    /// and run of the origin in turn when it's reached
+    ///
+    /// only insertions, deletions and replacements can be chained: plans with
+    /// any other operation, a rate limit or an output quota fail with
+    /// `InvalidInput`, as do overlapping deletions
+    pub fn into_chained_reader<R: Read>(self, origin: R) -> io::Result<Chained<'i, R>> {
+        if self.settings.rate_limit.is_some() || self.settings.max_output.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limits and quotas can't be chained",
+            ));
+        }
+        let pieces = self.lay_out()?;
+        let mut sources: Vec<_> = self
+            .operations
+            .into_iter()
+            .map(|operation| match operation {
+                Operation::Insert(_, source) | Operation::Replace(_, source) => {
+                    Some(source.into_reader())
+                }
+                _ => None,
+            })
+            .collect();
+        let pieces = pieces.into_iter().map(|piece| match piece {
+            Piece::Origin(end) => Piece::Origin(end),
+            Piece::Skip(end) => Piece::Skip(end),
+            Piece::Insertion(id) => {
+                Piece::Insertion(sources[id].take().expect("each source is laid out once"))
+            }
+        });
+        Ok(Chained::new(origin, pieces.collect()))
+    }
+
+    /// map the output for an origin of this length, rather than producing it
+    ///
+    /// insertions are identified by their index in `operations`. the same
+    /// plans can be mapped as can be chained, other than rate limits, which
+    /// are left to the caller
+    pub fn segments(&self, origin_len: usize) -> io::Result<Vec<Segment>> {
+        let pieces = self.lay_out()?.into_iter().map(|piece| match piece {
+            Piece::Origin(end) => Piece::Origin(end),
+            Piece::Skip(end) => Piece::Skip(end),
+            Piece::Insertion(id) => {
+                let len = self.operations[id]
+                    .source()
+                    .and_then(|source| source.len_hint());
+                Piece::Insertion((id, len))
+            }
+        });
+        Ok(segment::segments(pieces.collect(), origin_len))
+    }
+
+    /// apply the plan, returning a manifest of every segment of the output
+    /// with its source, offset, length and sha-256 digest
+    ///
+    /// as with `into_chained_reader`, only insertions, deletions and
+    /// replacements are supported, without a rate limit or quota: anything
+    /// else fails with `InvalidInput` before the target is touched
+    #[cfg(feature = "sha2")]
+    pub fn execute_with_manifest<R: Read, W: Write>(
+        self,
+        origin: R,
+        target: W,
+    ) -> io::Result<Manifest> {
+        if self.settings.rate_limit.is_some() || self.settings.max_output.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limits and quotas can't be applied with a manifest",
+            ));
+        }
+        let pieces = self.lay_out()?;
+        let mut sources: Vec<_> = self
+            .operations
+            .into_iter()
+            .map(|operation| match operation {
+                Operation::Insert(_, source) | Operation::Replace(_, source) => {
+                    Some(source.into_reader())
+                }
+                _ => None,
+            })
+            .collect();
+        let pieces = pieces.into_iter().map(|piece| match piece {
+            Piece::Origin(end) => Piece::Origin(end),
+            Piece::Skip(end) => Piece::Skip(end),
+            Piece::Insertion(id) => Piece::Insertion((
+                id,
+                sources[id].take().expect("each source is laid out once"),
+            )),
+        });
+        manifest::write(pieces.collect(), origin, target)
+    }
+
+    /// the insertions, by operation index, and runs of the origin around the
+    /// deletions, failing with `InvalidInput` for any other operation
+    fn lay_out(&self) -> io::Result<Vec<Piece<usize>>> {
+        let mut insertions = Vec::new();
+        let mut deletions = Vec::new();
+        for (id, operation) in self.operations.iter().enumerate() {
+            match *operation {
+                Operation::Insert(position, _) => insertions.push((position, id)),
+                Operation::Delete(ref range) => deletions.push(range.clone()),
+                Operation::Replace(ref range, _) => {
+                    insertions.push((range.start, id));
+                    deletions.push(range.clone());
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "only insertions, deletions and replacements can be chained or mapped",
+                    ))
+                }
+            }
+        }
+        // sorting is stable, so insertions at one index keep their order
+        insertions.sort_by_key(|&(position, _)| position);
+        segment::lay_out(insertions, deletions)
+    }
+
+    /// apply the plan to an in-memory origin, returning the output
+    ///
+    /// the output is allocated at its exact length when that's known up front
+    pub fn execute_to_vec(self, origin: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.execute_into_vec(origin, &mut out)?;
+        Ok(out)
+    }
+
+    /// apply the plan to an in-memory origin, appending the output to `out`
+    ///
+    /// exactly the output's length is reserved when that's known up front, so
+    /// a buffer reused across calls stops reallocating once it's large enough
+    pub fn execute_into_vec(self, origin: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        out.reserve_exact(self.output_len(origin.len()).unwrap_or(origin.len()));
+        self.execute(origin, out)
+    }
+}
+
+/// reserve disk blocks for part of a file
+#[cfg(all(unix, feature = "unix"))]
+fn allocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    match unsafe {
+        ::libc::posix_fallocate(
+            file.as_raw_fd(),
+            offset as ::libc::off_t,
+            len as ::libc::off_t,
+        )
+    } {
+        0 => Ok(()),
+        // some filesystems can't allocate ahead; the length is set regardless
+        ::libc::EOPNOTSUPP | ::libc::EINVAL => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(not(all(unix, feature = "unix")))]
+fn allocate(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+impl<'i> From<Vec<Operation<'i>>> for PlanBuilder<'i> {
+    fn from(operations: Vec<Operation<'i>>) -> PlanBuilder<'i> {
+        PlanBuilder {
+            operations,
+            settings: Settings::default(),
+        }
+    }
+}
+
+/// a plan of arbitrary operations, with the default settings
+#[cfg(feature = "arbitrary")]
+impl<'a, 'i> Arbitrary<'a> for PlanBuilder<'i> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(PlanBuilder::from(Vec::<Operation>::arbitrary(u)?))
+    }
+}
+
+impl<'i> Extend<Operation<'i>> for PlanBuilder<'i> {
+    fn extend<T: IntoIterator<Item = Operation<'i>>>(&mut self, iter: T) {
+        self.operations.extend(iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use checksum::{crc32, Crc32};
+
+    fn execute(plan: PlanBuilder, origin: &[u8]) -> io::Result<Vec<u8>> {
+        plan.execute_to_vec(origin)
+    }
+
+    #[test]
+    fn deletes_and_replaces() {
+        let plan = PlanBuilder::new()
+            .delete(0..6)
+            .insert(12, &b"charlie "[..])
+            .replace(12..15, &b"delta"[..])
+            .insert(20, &b"!"[..]);
+        assert_eq!(
+            b"bravo charlie delta echo!".to_vec(),
+            execute(plan, b"alpha bravo xxx echo").unwrap()
+        );
+    }
+
+    #[test]
+    fn insertions_at_same_index_keep_order() {
+        let plan = PlanBuilder::new().insert(1, &b"b"[..]).insert(1, &b"c"[..]);
+        assert_eq!(b"abcd".to_vec(), execute(plan, b"ad").unwrap());
+    }
+
+    #[test]
+    fn transforms_and_fixes_up() {
+        // a big-endian length header followed by the text it describes
+        let plan = PlanBuilder::new()
+            .fixup(0, Endian::Big, 4..9)
+            .transform(4..9, |bytes| bytes.to_ascii_uppercase().repeat(2))
+            .delete(9..10);
+        assert_eq!(
+            b"\0\0\0\x0aHELLOHELLO".to_vec(),
+            execute(plan, b"\0\0\0\x05hello!").unwrap()
+        );
+    }
+
+    #[test]
+    fn checksums_transformed_output() {
+        let mut origin = b"\0\0\0\x03tEXtabc".to_vec();
+        origin.extend_from_slice(&[0; 4]);
+        let plan = PlanBuilder::from(vec![Operation::Delete(7..8)])
+            .fixup(0, Endian::Big, 8..11)
+            .transform(8..11, |bytes| bytes.iter().rev().cloned().collect())
+            .checksum(11, Endian::Big, 4..11, Crc32::new());
+        let mut expect = b"\0\0\0\x03tEXcba".to_vec();
+        expect.extend_from_slice(&crc32(b"tEXcba").to_be_bytes());
+        assert_eq!(expect, execute(plan, &origin).unwrap());
+    }
+
+    #[test]
+    fn inspect_and_remove() {
+        let mut plan = PlanBuilder::new()
+            .insert(6, "bravo ")
+            .delete(0..6)
+            .replace(6..9, "delta")
+            .insert(0, InsertSource::reader(&b"> "[..]));
+        let positions: Vec<_> = plan
+            .insertions()
+            .map(|(position, source)| (position, source.len_hint()))
+            .collect();
+        assert_eq!(vec![(6, Some(6)), (6, Some(5)), (0, None)], positions);
+        assert_eq!(
+            "[Insert(6, Bytes(\"bravo \")), Delete(0..6)]",
+            format!("{:?}", &plan.operations()[..2])
+        );
+
+        let removed = plan.remove_at(0);
+        assert_eq!(2, removed.len());
+        assert_eq!(
+            b"alpha bravo delta echo".to_vec(),
+            execute(plan, b"alpha xxx echo").unwrap()
+        );
+    }
+
+    #[test]
+    fn exact_output_len() {
+        let origin = b"alpha bravo xxx";
+        let plan = PlanBuilder::new()
+            .insert(0, "> ")
+            .delete(0..6)
+            .replace(12..15, "charlie")
+            .overwrite(15, b"!!");
+        assert_eq!(Some(17), plan.output_len(origin.len()));
+        let out = plan.execute_to_vec(origin).unwrap();
+        assert_eq!(b"> bravo charlie!!".to_vec(), out);
+        assert_eq!(out.len(), out.capacity());
+
+        let plan = PlanBuilder::new().insert(0, InsertSource::reader(&b"?"[..]));
+        assert_eq!(None, plan.output_len(origin.len()));
+    }
+
+    #[test]
+    fn reverses_ranges() {
+        let plan = PlanBuilder::new()
+            .reverse(0..4, Reverse::Bytes)
+            .reverse(5..19, Reverse::Lines);
+        assert_eq!(
+            b"\x04\x03\x02\x01|three\ntwo\none\n|".to_vec(),
+            execute(plan, b"\x01\x02\x03\x04|one\ntwo\nthree\n|").unwrap()
+        );
+        assert_eq!(b"c\nb\na".to_vec(), Reverse::Lines.apply(b"a\nb\nc"));
+        assert_eq!(b"\nb\na\n".to_vec(), Reverse::Lines.apply(b"a\nb\n\n"));
+        assert!(Reverse::Lines.apply(b"").is_empty());
+    }
+
+    #[test]
+    fn copies_ranges_of_the_origin() {
+        let origin = b"head|body|";
+        let plan = PlanBuilder::new().copy(0..5, 10).copy(5..10, 0);
+        assert_eq!(Some(20), plan.output_len(origin.len()));
+        assert_eq!(
+            b"body|head|body|head|".to_vec(),
+            execute(plan, origin).unwrap()
+        );
+
+        // the copy is of the origin, whatever else happens to the range
+        let plan = PlanBuilder::new()
+            .copy(0..4, 10)
+            .transform(0..4, |bytes| bytes.to_ascii_uppercase());
+        assert_eq!(b"HEAD|body|head".to_vec(), execute(plan, origin).unwrap());
+    }
+
+    #[test]
+    fn moves_ranges() {
+        let origin = b"one two three ";
+        let plan = PlanBuilder::new().move_range(0..4, 14).move_range(8..14, 0);
+        assert_eq!(Some(origin.len()), plan.output_len(origin.len()));
+        assert_eq!(b"three two one ".to_vec(), execute(plan, origin).unwrap());
+
+        // moving a range onto itself leaves it in place
+        let plan = PlanBuilder::new().move_range(4..8, 6);
+        assert_eq!(origin.to_vec(), execute(plan, origin).unwrap());
+    }
+
+    #[test]
+    fn swaps_ranges() {
+        let origin = b"[ab][cde]";
+        let plan = PlanBuilder::new().swap(5..8, 1..3);
+        assert_eq!(b"[cde][ab]".to_vec(), execute(plan, origin).unwrap());
+
+        for (a, b) in [(1..3, 2..8), (1..3, 1..2)] {
+            let err = execute(PlanBuilder::new().swap(a, b), origin).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn replaces_all_across_buffers() {
+        let mut origin = vec![b'.'; inserter::BUFFER_SIZE - 2];
+        origin.extend_from_slice(b"cat cat");
+        let plan = PlanBuilder::new()
+            .replace_all(b"cat", b"dog")
+            .insert(0, "cat:");
+        assert_eq!(Some(origin.len() + 4), plan.output_len(origin.len()));
+        let out = execute(plan, &origin).unwrap();
+        assert_eq!(b"cat:", &out[..4]);
+        assert_eq!(b"dog dog", &out[out.len() - 7..]);
+    }
+
+    #[test]
+    fn replaces_all_around_other_operations() {
+        let plan = PlanBuilder::new()
+            .replace_all(b"ab", b"b")
+            .replace_all(b"bb", b"X")
+            .insert(2, "|")
+            .delete(6..7)
+            .overwrite(8, b"b");
+        assert_eq!(b"b|Xxb".to_vec(), execute(plan, b"ababbxbaa").unwrap());
+
+        for plan in [
+            PlanBuilder::new().replace_all(b"", b"x"),
+            PlanBuilder::new()
+                .replace_all(b"a", b"bb")
+                .fixup(0, Endian::Big, 4..8),
+        ] {
+            let err = execute(plan, b"abcdefgh").unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn copies_count_towards_fixups() {
+        let plan = PlanBuilder::new()
+            .fixup(0, Endian::Big, 4..6)
+            .copy(4..6, 6)
+            .copy(4..5, 4);
+        assert_eq!(
+            b"\0\0\0\x05aababc".to_vec(),
+            execute(plan, b"\0\0\0\x02abc").unwrap()
+        );
+    }
+
+    #[test]
+    fn allocates_known_output_exactly() {
+        let out = PlanBuilder::new()
+            .insert(6, "bravo ")
+            .delete(0..6)
+            .execute_to_vec(b"alpha charlie")
+            .unwrap();
+        assert_eq!(b"bravo charlie".to_vec(), out);
+        assert_eq!(out.len(), out.capacity());
+    }
+
+    #[test]
+    fn appends_into_reused_buffers() {
+        let mut out = b"prefix ".to_vec();
+        PlanBuilder::new()
+            .insert(6, "bravo ")
+            .execute_into_vec(b"alpha charlie", &mut out)
+            .unwrap();
+        assert_eq!(b"prefix alpha bravo charlie".to_vec(), out);
+        assert_eq!(out.len(), out.capacity());
+
+        out.clear();
+        let capacity = out.capacity();
+        PlanBuilder::new()
+            .insert(0, "x")
+            .execute_into_vec(b"short", &mut out)
+            .unwrap();
+        assert_eq!(b"xshort".to_vec(), out);
+        assert_eq!(capacity, out.capacity());
+    }
+
+    #[test]
+    fn presizes_target() {
+        let dir = ::std::env::temp_dir();
+        let path = dir.join(format!("presized-{}", ::std::process::id()));
+        let mut target = File::create(&path).unwrap();
+        target.write_all(b"a much longer existing file").unwrap();
+        target.seek(SeekFrom::Start(2)).unwrap();
+        let mut origin = io::Cursor::new(b"xxalpha charlie");
+        origin.set_position(2);
+        PlanBuilder::new()
+            .insert(6, "bravo ")
+            .execute_presized(origin, &mut target)
+            .unwrap();
+        assert_eq!(
+            &b"a alpha bravo charlie"[..],
+            &::std::fs::read(&path).unwrap()[..]
+        );
+
+        // the output's length isn't known up front, and a longer target is cut short
+        let mut target = ::std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap();
+        PlanBuilder::new()
+            .replace_all(b"alpha", b"a")
+            .execute_presized(io::Cursor::new(b"alpha"), &mut target)
+            .unwrap();
+        assert_eq!(&b"a"[..], &::std::fs::read(&path).unwrap()[..]);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rate_limits_all_output() {
+        let start = ::std::time::Instant::now();
+        let out = PlanBuilder::new()
+            .insert(0, vec![b'x'; 20_000])
+            .rate_limit(100_000)
+            .execute_to_vec(&[b'o'; 10_000])
+            .unwrap();
+        assert_eq!(30_000, out.len());
+        assert!(start.elapsed() >= ::std::time::Duration::from_millis(150));
+    }
+
+    #[test]
+    fn enforces_output_quota() {
+        let plan = || {
+            PlanBuilder::new()
+                .insert(2, "inserted")
+                .transform(6..8, |bytes| bytes.repeat(4))
+        };
+        let out = plan().max_output(22).execute_to_vec(b"origin..").unwrap();
+        assert_eq!(22, out.len());
+
+        let mut out = Vec::new();
+        let err = plan()
+            .max_output(9)
+            .execute(&b"origin.."[..], &mut out)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::QuotaExceeded, err.kind());
+        assert!(err.to_string().contains("the insertion at 2"), "{}", err);
+        assert_eq!(b"or".to_vec(), out);
+
+        let err = plan()
+            .max_output(21)
+            .execute_to_vec(b"origin..")
+            .unwrap_err();
+        assert!(err.to_string().contains("the transform of 6..8"), "{}", err);
+        let err = plan()
+            .max_output(22)
+            .execute_to_vec(b"origin...")
+            .unwrap_err();
+        assert!(err.to_string().contains("origin index 8"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_plans_fail() {
+        let origin = [0_u8; 8];
+        for plan in [
+            PlanBuilder::new().delete(0..4).overwrite(2, b"x"),
+            PlanBuilder::new()
+                .transform(0..4, |bytes| bytes.to_vec())
+                .insert(2, &b"x"[..]),
+            PlanBuilder::new()
+                .fixup(0, Endian::Big, 4..6)
+                .transform(5..8, |bytes| bytes.to_vec()),
+        ] {
+            let err = execute(plan, &origin).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_plans_keep_their_output_length() {
+        let origin: Vec<u8> = (0..=255).collect();
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..500 {
+            let data: Vec<u8> = (0..256)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            let plan = PlanBuilder::arbitrary(&mut Unstructured::new(&data)).unwrap();
+            let expected = plan.output_len(origin.len());
+            if let Ok(out) = execute(plan, &origin) {
+                if let Some(len) = expected {
+                    assert_eq!(len, out.len());
+                }
+            }
+        }
+    }
+}
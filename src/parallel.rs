@@ -0,0 +1,254 @@
+//! positioned writes of the output, in parallel
+//!
+//! once every segment's length is known, so is every segment's offset in the
+//! output, and the runs of the origin can be copied by several threads at
+//! once with `read_at` and `write_at`. insertions are written meanwhile by the
+//! calling thread, so their sources needn't be `Send`.
+
+use buffer::CopyBuffer;
+use operation::PlanBuilder;
+use segment::Segment;
+use source::InsertSource;
+use std::{
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+/// runs of the origin are copied in pieces of at most this length, so that
+/// one long run is shared between threads
+const JOB_LEN: u64 = 8 * 1024 * 1024;
+
+/// the buffer each thread copies through
+const BUFFER_LEN: usize = 1024 * 1024;
+
+/// a piece of the origin to copy: where from, where to, and its length
+type Job = (u64, u64, u64);
+
+impl<'i> PlanBuilder<'i> {
+    /// apply the plan from one file to another, copying runs of the origin on
+    /// this many threads at once
+    ///
+    /// the plan may only insert, delete and replace, and every insertion's
+    /// length must be known up front: anything else fails with `InvalidInput`
+    /// before the target is touched, as does an output larger than its quota
+    /// with `QuotaExceeded`. the target is set to the output's
+    /// length, and written from its start.
+    pub fn execute_parallel(self, origin: &File, target: &File, threads: usize) -> io::Result<()> {
+        let origin_len = origin.metadata()?.len() as usize;
+        let segments = self.segments(origin_len)?;
+        if self.settings().rate_limit.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "rate limits can't be applied to parallel writes",
+            ));
+        }
+        let mut jobs = Vec::new();
+        let mut insertions = Vec::new();
+        let mut offset = 0;
+        for segment in segments.iter() {
+            let len = segment.len().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "insertion of unknown length in a parallel write",
+                )
+            })?;
+            match *segment {
+                Segment::Origin(ref range) => {
+                    let mut start = range.start as u64;
+                    while start < range.end as u64 {
+                        let len = JOB_LEN.min(range.end as u64 - start);
+                        jobs.push((start, offset + start - range.start as u64, len));
+                        start += len;
+                    }
+                }
+                Segment::Insertion { id, .. } => insertions.push((id, offset, len)),
+            }
+            offset += len;
+        }
+        match self.settings().max_output {
+            Some(limit) if offset > limit => {
+                return Err(io::Error::new(
+                    io::ErrorKind::QuotaExceeded,
+                    format!("{} byte output exceeds its {} byte limit", offset, limit),
+                ))
+            }
+            _ => {}
+        }
+        target.set_len(offset)?;
+
+        let mut sources: Vec<Option<InsertSource>> = self
+            .into_operations()
+            .into_iter()
+            .map(|operation| match operation {
+                ::Operation::Insert(_, source) | ::Operation::Replace(_, source) => Some(source),
+                _ => None,
+            })
+            .collect();
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let error = Mutex::new(None);
+        let fail = |err: io::Error| {
+            failed.store(true, Ordering::Relaxed);
+            error
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .get_or_insert(err);
+        };
+        thread::scope(|scope| {
+            for _ in 0..threads.max(1) {
+                scope.spawn(|| {
+                    let mut buffer = CopyBuffer::new(BUFFER_LEN);
+                    while !failed.load(Ordering::Relaxed) {
+                        let job = match jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            Some(&job) => job,
+                            None => break,
+                        };
+                        if let Err(err) = copy(origin, target, job, &mut buffer) {
+                            fail(err);
+                        }
+                    }
+                });
+            }
+            let mut buffer = CopyBuffer::new(BUFFER_LEN);
+            for (id, offset, len) in insertions {
+                if failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let source = sources[id].take().expect("each source is laid out once");
+                if let Err(err) = write_insertion(source, target, offset, len, &mut buffer) {
+                    fail(err);
+                }
+            }
+        });
+        match error
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// copy a piece of the origin to its place in the target
+fn copy(origin: &File, target: &File, job: Job, buffer: &mut CopyBuffer) -> io::Result<()> {
+    let (from, to, len) = job;
+    let mut copied = 0;
+    while copied < len {
+        let chunk_len = (len - copied).min(BUFFER_LEN as u64) as usize;
+        let chunk = buffer.read_exact_at(origin, chunk_len, from + copied)?;
+        target.write_all_at(chunk, to + copied)?;
+        copied += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// write an insertion at its offset, failing with `InvalidData` if it isn't
+/// the length it was said to be
+fn write_insertion(
+    source: InsertSource,
+    target: &File,
+    offset: u64,
+    len: u64,
+    buffer: &mut CopyBuffer,
+) -> io::Result<()> {
+    let mut reader = source.into_reader();
+    let mut written = 0;
+    loop {
+        let read = match buffer.read_from(&mut reader) {
+            Ok(read) => read,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if read.is_empty() {
+            break;
+        }
+        if written + read.len() as u64 > len {
+            break;
+        }
+        target.write_all_at(read, offset + written)?;
+        written += read.len() as u64;
+    }
+    if written != len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("insertion isn't the {} bytes it was said to be", len),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs,
+        io::{Cursor, Write},
+        path::PathBuf,
+    };
+
+    fn temp(name: &str, contents: &[u8]) -> (PathBuf, File) {
+        let path = ::std::env::temp_dir().join(format!("{}-{}", name, ::std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
+
+    fn plan() -> PlanBuilder<'static> {
+        PlanBuilder::new()
+            .insert(0, "header")
+            .delete(100_000..200_000)
+            .replace(500_000..500_010, vec![7; 3000])
+            .insert(
+                900_000,
+                InsertSource::Reader(Box::new(Cursor::new(vec![9; 50_000])), Some(50_000)),
+            )
+            .insert(2_000_000, "trailer")
+    }
+
+    #[test]
+    fn writes_segments_in_place() {
+        let contents: Vec<u8> = (0..1_000_000_u32).map(|idx| (idx % 251) as u8).collect();
+        let (origin_path, origin) = temp("parallel-origin", &contents);
+        let (target_path, target) =
+            temp("parallel-target", b"previous contents, longer than nothing");
+        plan().execute_parallel(&origin, &target, 4).unwrap();
+        assert_eq!(
+            plan().execute_to_vec(&contents).unwrap(),
+            fs::read(&target_path).unwrap()
+        );
+        fs::remove_file(origin_path).unwrap();
+        fs::remove_file(target_path).unwrap();
+    }
+
+    #[test]
+    fn needs_a_known_layout() {
+        let (origin_path, origin) = temp("parallel-unknown-origin", b"abc");
+        let (target_path, mut target) = temp("parallel-unknown-target", b"");
+        target.write_all(b"untouched").unwrap();
+        let err = PlanBuilder::new()
+            .insert(1, InsertSource::reader(&b"?"[..]))
+            .execute_parallel(&origin, &target, 2)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!(b"untouched".to_vec(), fs::read(&target_path).unwrap());
+
+        let err = PlanBuilder::new()
+            .insert(1, InsertSource::Reader(Box::new(&b"short"[..]), Some(10)))
+            .execute_parallel(&origin, &target, 2)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        fs::remove_file(origin_path).unwrap();
+        fs::remove_file(target_path).unwrap();
+    }
+}
@@ -0,0 +1,582 @@
+use inserter::Inserter;
+use std::{
+    convert::TryFrom,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+};
+
+/// how far from the end of the file to look for `startxref`
+const TAIL_LEN: u64 = 1024;
+/// how much to read of a trailer dictionary, or of the start of a
+/// cross-reference stream, looking for its end
+const DICT_LEN: u64 = 64 * 1024;
+/// the length of each row of a cross-reference table
+const ROW_LEN: u64 = 20;
+
+/// an indirect object to be written in an incremental update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    number: u32,
+    generation: u16,
+    body: Vec<u8>,
+}
+
+impl Object {
+    /// create an object with the given number and generation 0
+    ///
+    /// the body is the raw object syntax between `obj` and `endobj`, e.g. a
+    /// dictionary or a dictionary followed by a stream. reusing the number of an
+    /// existing object replaces it.
+    pub fn new(number: u32, body: Vec<u8>) -> Object {
+        Object {
+            number,
+            generation: 0,
+            body,
+        }
+    }
+
+    /// set the generation number of this object
+    pub fn generation(mut self, generation: u16) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} obj\n", self.number, self.generation).into_bytes();
+        out.extend_from_slice(&self.body);
+        out.extend_from_slice(b"\nendobj\n");
+        out
+    }
+}
+
+/// the parts of the latest trailer which an update has to carry forward
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    size: u32,
+    root: String,
+    info: Option<String>,
+    id: Option<String>,
+    startxref: u64,
+    stream: bool,
+}
+
+impl Trailer {
+    /// one greater than the highest object number in use
+    ///
+    /// new objects should be numbered from here up
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// whether the latest cross-reference section is a stream rather than a table
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+}
+
+/// read the latest trailer of a PDF file
+pub fn read_trailer<R: Read + Seek>(origin: &mut R) -> io::Result<Trailer> {
+    let len = origin.seek(SeekFrom::End(0))?;
+    let mut tail = Vec::new();
+    origin.seek(SeekFrom::Start(len.saturating_sub(TAIL_LEN)))?;
+    origin.read_to_end(&mut tail)?;
+    let at = rfind(&tail, b"startxref").ok_or_else(|| invalid("missing startxref"))?;
+    let startxref = parse_int(&tail[at + 9..])?;
+    if startxref >= len {
+        return Err(invalid("startxref out of bounds"));
+    }
+
+    origin.seek(SeekFrom::Start(startxref))?;
+    let mut reader = BufReader::new(origin);
+    let stream = !reader.fill_buf()?.starts_with(b"xref");
+    let mut section = if stream {
+        Vec::new()
+    } else {
+        skip_table(&mut reader)?
+    };
+    reader.take(DICT_LEN).read_to_end(&mut section)?;
+    let dict = dictionary(&section).ok_or_else(|| invalid("missing trailer dictionary"))?;
+
+    if value(dict, b"/Encrypt").is_some() {
+        return Err(invalid("encrypted PDFs are not supported"));
+    }
+    let size = value(dict, b"/Size").ok_or_else(|| invalid("trailer lacks /Size"))?;
+    let size = parse_int(size)?;
+    if size > u64::from(u32::MAX) {
+        return Err(invalid("trailer /Size out of range"));
+    }
+    let reference = |key: &[u8]| value(dict, key).map(|v| String::from_utf8_lossy(v).into_owned());
+    Ok(Trailer {
+        size: size as u32,
+        root: reference(b"/Root").ok_or_else(|| invalid("trailer lacks /Root"))?,
+        info: reference(b"/Info"),
+        id: reference(b"/ID"),
+        startxref,
+        stream,
+    })
+}
+
+/// read past a cross-reference table to its trailer, returning what follows
+/// the `trailer` keyword on its line
+///
+/// the rows are skipped unread, counted by the header of each subsection
+fn skip_table<R: BufRead + Seek>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let malformed = || invalid("malformed cross-reference table");
+    if read_line(reader)?.ok_or_else(malformed)?.trim_ascii() != b"xref" {
+        return Err(malformed());
+    }
+    loop {
+        let line = read_line(reader)?.ok_or_else(malformed)?;
+        let line = line.trim_ascii();
+        if let Some(rest) = line.strip_prefix(b"trailer") {
+            return Ok(rest.to_vec());
+        }
+        let mut fields = line.split(u8::is_ascii_whitespace);
+        let count = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(count), None) => {
+                parse_int(start)?;
+                parse_int(count)?
+            }
+            _ => return Err(malformed()),
+        };
+        let rows = count
+            .checked_mul(ROW_LEN)
+            .and_then(|len| i64::try_from(len).ok())
+            .ok_or_else(malformed)?;
+        reader.seek_relative(rows)?;
+    }
+}
+
+/// read a line ending in LF, CR or CRLF, or `None` at the end of the file
+///
+/// fails with `InvalidData` if the line is implausibly long for the
+/// structure around a cross-reference table
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    loop {
+        let byte = match reader.fill_buf()?.first() {
+            Some(&byte) => byte,
+            None if line.is_empty() => return Ok(None),
+            None => return Ok(Some(line)),
+        };
+        reader.consume(1);
+        match byte {
+            b'\n' => return Ok(Some(line)),
+            b'\r' => {
+                if reader.fill_buf()?.first() == Some(&b'\n') {
+                    reader.consume(1);
+                }
+                return Ok(Some(line));
+            }
+            _ if line.len() == TAIL_LEN as usize => return Err(invalid("line too long")),
+            _ => line.push(byte),
+        }
+    }
+}
+
+/// append an incremental update holding the given objects to a PDF file
+///
+/// the original bytes are left untouched; the update adds the objects, a
+/// cross-reference section for them and a trailer pointing back at the previous
+/// one through `/Prev`. the section is a table or a stream to match the previous
+/// section. number new objects from `read_trailer(..).size()` upwards.
+pub fn append_update<R, W>(mut origin: R, target: W, objects: &[Object]) -> io::Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let trailer = read_trailer(&mut origin)?;
+    let len = origin.seek(SeekFrom::End(0))?;
+    let mut last = [0_u8];
+    origin.seek(SeekFrom::End(-1))?;
+    origin.read_exact(&mut last)?;
+
+    let mut update = Vec::new();
+    if last[0] != b'\n' && last[0] != b'\r' {
+        update.push(b'\n');
+    }
+    let mut entries = Vec::with_capacity(objects.len() + 1);
+    for object in objects {
+        entries.push((object.number, object.generation, len + update.len() as u64));
+        update.extend(object.to_bytes());
+    }
+    let mut size = objects
+        .iter()
+        .map(|o| u64::from(o.number) + 1)
+        .fold(u64::from(trailer.size), u64::max);
+    let xref_at = len + update.len() as u64;
+    if trailer.stream {
+        // the stream describes itself too, taking the next free number
+        entries.push((size as u32, 0, xref_at));
+        size += 1;
+    }
+    entries.sort_by_key(|e| e.0);
+
+    let mut dict = format!(
+        "/Size {} /Prev {} /Root {}",
+        size, trailer.startxref, trailer.root
+    );
+    if let Some(ref info) = trailer.info {
+        dict.push_str(&format!(" /Info {}", info));
+    }
+    if let Some(ref id) = trailer.id {
+        dict.push_str(&format!(" /ID {}", id));
+    }
+
+    if trailer.stream {
+        let width = if xref_at > u64::from(u32::MAX) { 8 } else { 4 };
+        let mut data = Vec::new();
+        for &(_, generation, offset) in &entries {
+            data.push(1);
+            data.extend_from_slice(&offset.to_be_bytes()[8 - width..]);
+            data.extend_from_slice(&generation.to_be_bytes());
+        }
+        let index: Vec<String> = runs(&entries)
+            .map(|run| format!("{} {}", run[0].0, run.len()))
+            .collect();
+        let mut body = format!(
+            "<< /Type /XRef {} /W [1 {} 2] /Index [{}] /Length {} >>\nstream\n",
+            dict,
+            width,
+            index.join(" "),
+            data.len()
+        )
+        .into_bytes();
+        body.extend(data);
+        body.extend_from_slice(b"\nendstream");
+        update.extend(Object::new(size as u32 - 1, body).to_bytes());
+    } else {
+        update.extend_from_slice(b"xref\n");
+        for run in runs(&entries) {
+            update.extend(format!("{} {}\n", run[0].0, run.len()).into_bytes());
+            for &(_, generation, offset) in run {
+                update.extend(format!("{:010} {:05} n\r\n", offset, generation).into_bytes());
+            }
+        }
+        update.extend(format!("trailer\n<< {} >>\n", dict).into_bytes());
+    }
+    update.extend(format!("startxref\n{}\n%%EOF\n", xref_at).into_bytes());
+
+    origin.seek(SeekFrom::Start(0))?;
+    Inserter::new(origin, target)
+        .insert(len as usize, update.as_slice())
+        .execute()
+}
+
+/// sorted entries split into runs of consecutive object numbers
+fn runs(entries: &[(u32, u16, u64)]) -> impl Iterator<Item = &[(u32, u16, u64)]> {
+    entries.chunk_by(|a, b| b.0 == a.0 + 1)
+}
+
+/// the contents of the first balanced `<< >>` dictionary in the data
+fn dictionary(data: &[u8]) -> Option<&[u8]> {
+    let start = find(data, b"<<")?;
+    let end = skip_dictionary(data, start)?;
+    Some(&data[start + 2..end - 2])
+}
+
+/// the index just past the dictionary starting at `start`
+fn skip_dictionary(data: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < data.len() {
+        if data[idx..].starts_with(b"<<") {
+            depth += 1;
+            idx += 2;
+        } else if data[idx..].starts_with(b">>") {
+            depth -= 1;
+            idx += 2;
+            if depth == 0 {
+                return Some(idx);
+            }
+        } else if data[idx] == b'<' {
+            // hex string
+            idx += data[idx..].iter().position(|&b| b == b'>')? + 1;
+        } else if data[idx] == b'(' {
+            idx = skip_literal(data, idx)?;
+        } else {
+            idx += 1;
+        }
+    }
+    None
+}
+
+/// the index just past the object starting at or after `start`
+fn skip_object(data: &[u8], start: usize) -> Option<usize> {
+    let idx = skip_space(data, start);
+    let rest = &data[idx..];
+    if rest.starts_with(b"<<") {
+        skip_dictionary(data, idx)
+    } else if rest.starts_with(b"<") {
+        Some(idx + rest.iter().position(|&b| b == b'>')? + 1)
+    } else if rest.starts_with(b"(") {
+        skip_literal(data, idx)
+    } else if rest.starts_with(b"/") {
+        Some(skip_regular(data, idx + 1))
+    } else if rest.starts_with(b"[") {
+        let mut idx = idx + 1;
+        loop {
+            idx = skip_space(data, idx);
+            match data.get(idx)? {
+                b']' => return Some(idx + 1),
+                _ => idx = skip_object(data, idx)?,
+            }
+        }
+    } else {
+        // a number, keyword or reference, up to the next delimiter
+        let mut idx = skip_regular(data, idx);
+        loop {
+            let next = skip_space(data, idx);
+            match data.get(next) {
+                Some(&byte) if !is_delimiter(byte) => idx = skip_regular(data, next),
+                _ => break,
+            }
+        }
+        Some(idx).filter(|&end| end > start)
+    }
+}
+
+fn skip_space(data: &[u8], start: usize) -> usize {
+    start
+        + data[start..]
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .unwrap_or(data.len() - start)
+}
+
+fn skip_regular(data: &[u8], start: usize) -> usize {
+    start
+        + data[start..]
+            .iter()
+            .position(|&b| b.is_ascii_whitespace() || is_delimiter(b))
+            .unwrap_or(data.len() - start)
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    b"()<>[]{}/%".contains(&byte)
+}
+
+/// the index just past the literal string starting at `start`
+fn skip_literal(data: &[u8], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    let mut idx = start;
+    while idx < data.len() {
+        match data[idx] {
+            b'\\' => idx += 1,
+            b'(' => depth += 1,
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(idx + 1);
+                }
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+    None
+}
+
+/// the raw value following a top-level key in dictionary contents
+///
+/// keys of nested dictionaries, and names within values, don't count. only
+/// the value forms a trailer uses are understood: integers, references and
+/// arrays
+fn value<'d>(dict: &'d [u8], key: &[u8]) -> Option<&'d [u8]> {
+    let mut idx = 0;
+    let at = loop {
+        idx = skip_space(dict, idx);
+        if dict.get(idx) != Some(&b'/') {
+            return None;
+        }
+        let end = skip_regular(dict, idx + 1);
+        if &dict[idx..end] == key {
+            break end;
+        }
+        idx = skip_object(dict, end)?;
+    };
+    let rest = &dict[at..];
+    let start = rest.iter().position(|b| !b.is_ascii_whitespace())?;
+    let rest = &rest[start..];
+    if rest[0] == b'[' {
+        let end = rest.iter().position(|&b| b == b']')?;
+        return Some(&rest[..=end]);
+    }
+    // an integer, possibly followed by a generation and `R`
+    let tokens: Vec<&[u8]> = rest
+        .split(|b| b.is_ascii_whitespace() || *b == b'/' || *b == b'>')
+        .filter(|t| !t.is_empty())
+        .take(3)
+        .collect();
+    let is_ref = tokens.len() == 3 && tokens[2] == b"R";
+    let end = if is_ref {
+        find(rest, b"R")? + 1
+    } else {
+        rest.iter()
+            .position(|b| !b.is_ascii_digit())
+            .unwrap_or(rest.len())
+    };
+    Some(&rest[..end])
+}
+
+fn parse_int(data: &[u8]) -> io::Result<u64> {
+    let digits: Vec<u8> = data
+        .iter()
+        .cloned()
+        .skip_while(u8::is_ascii_whitespace)
+        .take_while(u8::is_ascii_digit)
+        .collect();
+    String::from_utf8_lossy(&digits)
+        .parse()
+        .map_err(|_| invalid("malformed integer"))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn document() -> Vec<u8> {
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let catalog = pdf.len();
+        pdf.extend_from_slice(b"1 0 obj\n<< /Type /Catalog /Pages 2 0 R >>\nendobj\n");
+        let pages = pdf.len();
+        pdf.extend_from_slice(b"2 0 obj\n<< /Type /Pages /Kids [] /Count 0 >>\nendobj\n");
+        let xref = pdf.len();
+        pdf.extend(
+            format!(
+                "xref\n0 3\n0000000000 65535 f\r\n{:010} 00000 n\r\n{:010} 00000 n\r\n",
+                catalog, pages
+            )
+            .into_bytes(),
+        );
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size 3 /Root 1 0 R /ID [<abc>(x)] >>\nstartxref\n{}\n%%EOF\n",
+                xref
+            )
+            .into_bytes(),
+        );
+        pdf
+    }
+
+    fn object_at(pdf: &[u8], offset: usize) -> &[u8] {
+        &pdf[offset..offset + 7]
+    }
+
+    #[test]
+    fn reads_trailer() {
+        let trailer = read_trailer(&mut Cursor::new(document())).unwrap();
+        assert_eq!(3, trailer.size());
+        assert_eq!("1 0 R", trailer.root);
+        assert_eq!(Some("[<abc>(x)]".to_string()), trailer.id);
+        assert!(!trailer.is_stream());
+    }
+
+    #[test]
+    fn appends_table_update() {
+        let origin = document();
+        let mut dest = Vec::new();
+        let objects = [
+            Object::new(3, b"<< /Producer (splice) >>".to_vec()),
+            Object::new(2, b"<< /Type /Pages /Kids [] /Count 0 >>".to_vec()),
+        ];
+        append_update(Cursor::new(origin.clone()), &mut dest, &objects).unwrap();
+        assert!(dest.starts_with(&origin));
+
+        let trailer = read_trailer(&mut Cursor::new(dest.clone())).unwrap();
+        assert_eq!(4, trailer.size());
+        assert_eq!("1 0 R", trailer.root);
+        let section = &dest[trailer.startxref as usize..];
+        assert!(section.starts_with(b"xref\n2 2\n"));
+        let second = parse_int(&section[9..]).unwrap() as usize;
+        let third = parse_int(&section[29..]).unwrap() as usize;
+        assert_eq!(b"2 0 obj", object_at(&dest, second));
+        assert_eq!(b"3 0 obj", object_at(&dest, third));
+        let prev = format!(
+            "/Prev {}",
+            read_trailer(&mut Cursor::new(origin)).unwrap().startxref
+        );
+        assert!(find(section, prev.as_bytes()).is_some());
+    }
+
+    #[test]
+    fn appends_stream_update() {
+        let mut origin = b"%PDF-1.5\n1 0 obj\n<< /Type /Catalog >>\nendobj\n".to_vec();
+        let xref = origin.len();
+        origin.extend_from_slice(
+            b"2 0 obj\n<< /Type /XRef /Size 3 /W [1 4 2] /Root 1 0 R /Length 0 >>\nstream\n\nendstream\nendobj\n",
+        );
+        origin.extend(format!("startxref\n{}\n%%EOF", xref).into_bytes());
+        let mut dest = Vec::new();
+        append_update(
+            Cursor::new(origin),
+            &mut dest,
+            &[Object::new(3, b"(hello)".to_vec())],
+        )
+        .unwrap();
+
+        let trailer = read_trailer(&mut Cursor::new(dest.clone())).unwrap();
+        assert!(trailer.is_stream());
+        assert_eq!(5, trailer.size());
+        assert_eq!(b"4 0 obj", object_at(&dest, trailer.startxref as usize));
+        assert!(find(&dest, b"/Index [3 2]").is_some());
+        assert!(find(&dest, format!("/Prev {}", xref).as_bytes()).is_some());
+    }
+
+    #[test]
+    fn skips_long_tables() {
+        // a subsection of free entries, longer than any fixed window
+        let rows = 100_000;
+        let mut free = format!("3 {}\n", rows);
+        free.push_str(&"0000000000 00000 f\r\n".repeat(rows));
+        let pdf = String::from_utf8(document())
+            .unwrap()
+            .replace("trailer\n", &format!("{}trailer\n", free))
+            .into_bytes();
+        let trailer = read_trailer(&mut Cursor::new(pdf)).unwrap();
+        assert_eq!("1 0 R", trailer.root);
+
+        let pdf = String::from_utf8(document())
+            .unwrap()
+            .replace("trailer\n", "3 x\ntrailer\n");
+        let err = read_trailer(&mut Cursor::new(pdf.into_bytes())).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn reads_only_top_level_keys() {
+        let pdf = String::from_utf8(document()).unwrap().replace(
+            "<< /Size 3",
+            "<< /Extra << /Size 99 /Root 9 0 R >> /Names [/Size (/Root)] /Size 3",
+        );
+        let trailer = read_trailer(&mut Cursor::new(pdf.into_bytes())).unwrap();
+        assert_eq!(3, trailer.size());
+        assert_eq!("1 0 R", trailer.root);
+
+        let dict = b"/Info << /Encrypt 5 0 R >> /Type /Size";
+        assert_eq!(None, value(dict, b"/Encrypt"));
+        assert_eq!(None, value(dict, b"/Size"));
+    }
+
+    #[test]
+    fn rejects_encrypted() {
+        let pdf = document();
+        let pdf = String::from_utf8(pdf)
+            .unwrap()
+            .replace("/Size 3", "/Size 3 /Encrypt 9 0 R");
+        let err = read_trailer(&mut Cursor::new(pdf.into_bytes())).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
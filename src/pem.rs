@@ -0,0 +1,285 @@
+use base64;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+const BEGIN: &[u8] = b"-----BEGIN ";
+const END: &[u8] = b"-----END ";
+const DASHES: &[u8] = b"-----";
+
+/// a single validated PEM block, such as a certificate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PemBlock {
+    label: String,
+    text: Vec<u8>,
+}
+
+impl PemBlock {
+    /// parse and validate exactly one PEM block
+    ///
+    /// the BEGIN and END labels must agree and the body must be valid base64.
+    /// surrounding whitespace is discarded and a trailing newline is ensured.
+    pub fn parse(text: &[u8]) -> io::Result<PemBlock> {
+        let text = trim(text);
+        let mut lines = text.split(|&b| b == b'\n').map(trim);
+        let label = lines
+            .next()
+            .and_then(|line| fence_label(line, BEGIN))
+            .ok_or_else(|| invalid("PEM block must start with a BEGIN line"))?;
+        let end = lines
+            .next_back()
+            .and_then(|line| fence_label(line, END))
+            .ok_or_else(|| invalid("PEM block must end with an END line"))?;
+        if label != end {
+            return Err(invalid("PEM BEGIN and END labels differ"));
+        }
+        let mut text = text.to_vec();
+        text.push(b'\n');
+        let block = PemBlock {
+            label: String::from_utf8_lossy(label).into_owned(),
+            text,
+        };
+        block.der()?;
+        Ok(block)
+    }
+
+    /// the label of this block, such as `CERTIFICATE`
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// the complete text of this block, including the BEGIN and END lines
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// the decoded contents of this block
+    ///
+    /// fingerprints can be computed by hashing this with the digest of your choice
+    pub fn der(&self) -> io::Result<Vec<u8>> {
+        let body: Vec<u8> = self
+            .text
+            .split(|&b| b == b'\n')
+            .map(trim)
+            .filter(|line| !line.starts_with(DASHES) && !line.contains(&b':'))
+            .flat_map(|line| line.iter().cloned())
+            .collect();
+        base64::decode(&body).ok_or_else(|| invalid("PEM body is not valid base64"))
+    }
+
+    /// true if the decoded contents contain the given bytes
+    ///
+    /// this is a cheap way to select a certificate by a distinctive part of its
+    /// subject, such as the common name, without parsing ASN.1
+    pub fn der_contains(&self, needle: &[u8]) -> bool {
+        self.der()
+            .map(|der| needle.is_empty() || der.windows(needle.len()).any(|w| w == needle))
+            .unwrap_or(false)
+    }
+}
+
+/// where to place a new block in a bundle
+pub enum Placement<'p> {
+    /// before every existing block
+    Start,
+    /// after every existing block
+    End,
+    /// before the first block, and any explanatory text preceding it, which satisfies the predicate
+    Before(Box<dyn 'p + Fn(&PemBlock) -> bool>),
+    /// after the first block which satisfies the predicate
+    After(Box<dyn 'p + Fn(&PemBlock) -> bool>),
+}
+
+/// insert a PEM block into a bundle of PEM blocks
+///
+/// every block in the origin is validated as it passes through; a malformed
+/// bundle fails rather than being silently spliced. fails with `NotFound` if a
+/// `Before` or `After` predicate matches no block.
+pub fn insert_block<R, W>(
+    origin: R,
+    mut target: W,
+    block: &PemBlock,
+    placement: Placement,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut origin = BufReader::new(origin);
+    let mut inserted = false;
+    if let Placement::Start = placement {
+        target.write_all(block.as_bytes())?;
+        inserted = true;
+    }
+
+    // text between blocks is held back until we know where the next block goes
+    let mut preamble = Vec::new();
+    let mut current: Option<Vec<u8>> = None;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if origin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let begin = fence_label(trim(&line), BEGIN).is_some();
+        match current {
+            Some(_) if begin => return Err(invalid("PEM BEGIN line inside a block")),
+            Some(ref mut text) => text.extend_from_slice(&line),
+            None if begin => current = Some(line.clone()),
+            None => preamble.extend_from_slice(&line),
+        }
+        if fence_label(trim(&line), END).is_none() {
+            continue;
+        }
+        let text = match current.take() {
+            Some(text) => text,
+            None => return Err(invalid("PEM END line without BEGIN")),
+        };
+        let existing = PemBlock::parse(&text)?;
+
+        let before = !inserted
+            && match placement {
+                Placement::Before(ref predicate) => predicate(&existing),
+                _ => false,
+            };
+        if before {
+            target.write_all(block.as_bytes())?;
+            inserted = true;
+        }
+        target.write_all(&preamble)?;
+        preamble.clear();
+        target.write_all(&text)?;
+
+        let after = !inserted
+            && match placement {
+                Placement::After(ref predicate) => predicate(&existing),
+                _ => false,
+            };
+        if after {
+            if !text.ends_with(b"\n") {
+                target.write_all(b"\n")?;
+            }
+            target.write_all(block.as_bytes())?;
+            inserted = true;
+        }
+    }
+
+    if current.is_some() {
+        return Err(invalid("unterminated PEM block"));
+    }
+    target.write_all(&preamble)?;
+    if let Placement::End = placement {
+        if !preamble.is_empty() && !preamble.ends_with(b"\n") {
+            target.write_all(b"\n")?;
+        }
+        target.write_all(block.as_bytes())?;
+        inserted = true;
+    }
+    if inserted {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no PEM block matched the placement",
+        ))
+    }
+}
+
+fn fence_label<'l>(line: &'l [u8], prefix: &[u8]) -> Option<&'l [u8]> {
+    if line.starts_with(prefix)
+        && line.ends_with(DASHES)
+        && line.len() >= prefix.len() + DASHES.len()
+    {
+        Some(&line[prefix.len()..line.len() - DASHES.len()])
+    } else {
+        None
+    }
+}
+
+fn trim(line: &[u8]) -> &[u8] {
+    let start = line
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |idx| idx + 1);
+    &line[start..end]
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bodies decode to "alpha", "bravo" and "charlie"
+    const ALPHA: &str = "-----BEGIN CERTIFICATE-----\nYWxwaGE=\n-----END CERTIFICATE-----\n";
+    const BRAVO: &str = "-----BEGIN CERTIFICATE-----\nYnJhdm8=\n-----END CERTIFICATE-----\n";
+    const CHARLIE: &str = "-----BEGIN CERTIFICATE-----\nY2hhcmxpZQ==\n-----END CERTIFICATE-----\n";
+
+    fn run(origin: &str, placement: Placement) -> io::Result<String> {
+        let block = PemBlock::parse(CHARLIE.as_bytes())?;
+        let mut dest = Vec::new();
+        insert_block(origin.as_bytes(), &mut dest, &block, placement)?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn parses_block() {
+        let block = PemBlock::parse(ALPHA.as_bytes()).unwrap();
+        assert_eq!("CERTIFICATE", block.label());
+        assert_eq!(b"alpha".to_vec(), block.der().unwrap());
+        assert!(block.der_contains(b"lph"));
+    }
+
+    #[test]
+    fn rejects_mismatched_labels() {
+        let err = PemBlock::parse(b"-----BEGIN A-----\nYQ==\n-----END B-----").unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn inserts_before_match_and_its_comment() {
+        let origin = format!("# alpha\n{}# bravo\n{}", ALPHA, BRAVO);
+        let out = run(
+            &origin,
+            Placement::Before(Box::new(|block| block.der_contains(b"bravo"))),
+        )
+        .unwrap();
+        assert_eq!(
+            format!("# alpha\n{}{}# bravo\n{}", ALPHA, CHARLIE, BRAVO),
+            out
+        );
+    }
+
+    #[test]
+    fn inserts_after_match() {
+        let origin = format!("{}{}", ALPHA, BRAVO);
+        let out = run(
+            &origin,
+            Placement::After(Box::new(|block| block.der_contains(b"alpha"))),
+        )
+        .unwrap();
+        assert_eq!(format!("{}{}{}", ALPHA, CHARLIE, BRAVO), out);
+    }
+
+    #[test]
+    fn appends_at_end() {
+        let out = run(ALPHA, Placement::End).unwrap();
+        assert_eq!(format!("{}{}", ALPHA, CHARLIE), out);
+    }
+
+    #[test]
+    fn rejects_truncated_bundle() {
+        let err = run("-----BEGIN CERTIFICATE-----\nYQ==\n", Placement::End).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn unmatched_predicate() {
+        let err = run(ALPHA, Placement::After(Box::new(|_| false))).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
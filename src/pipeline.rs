@@ -0,0 +1,132 @@
+use std::{
+    io::{self, Write},
+    mem,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+};
+
+/// output is handed to the writing thread in buffers of this length
+pub(crate) const BUFFER_LEN: usize = 64 * 1024;
+
+/// buffers in flight between the threads: one being filled, one being written
+const BUFFERS: usize = 2;
+
+/// a writer which hands full buffers to another thread to be written, and
+/// takes written ones back to fill again
+pub(crate) struct Pipe {
+    buffer: Vec<u8>,
+    full: SyncSender<Vec<u8>>,
+    empty: Receiver<Vec<u8>>,
+}
+
+impl Pipe {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        // the writing thread only stops taking buffers when it's failed
+        let broken = || io::Error::new(io::ErrorKind::BrokenPipe, "the writing thread stopped");
+        let next = self.empty.recv().map_err(|_| broken())?;
+        let full = mem::replace(&mut self.buffer, next);
+        self.full.send(full).map_err(|_| broken())
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BUFFER_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == BUFFER_LEN {
+            self.send()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+/// run `produce` on this thread, writing what it writes to the target on
+/// another, so that each can work while the other waits
+///
+/// if the target fails, `produce` sees `BrokenPipe` but the target's error is
+/// the one returned
+pub(crate) fn run<W, F>(target: W, produce: F) -> io::Result<()>
+where
+    W: Write + Send,
+    F: FnOnce(&mut Pipe) -> io::Result<()>,
+{
+    let (full, filled) = mpsc::sync_channel::<Vec<u8>>(BUFFERS);
+    let (emptied, empty) = mpsc::sync_channel(BUFFERS);
+    for _ in 1..BUFFERS {
+        emptied
+            .send(Vec::with_capacity(BUFFER_LEN))
+            .expect("the channel has room for every buffer");
+    }
+    let mut pipe = Pipe {
+        buffer: Vec::with_capacity(BUFFER_LEN),
+        full,
+        empty,
+    };
+    thread::scope(|scope| {
+        let writing = scope.spawn(move || {
+            let mut target = target;
+            for mut buffer in filled {
+                target.write_all(&buffer)?;
+                buffer.clear();
+                // the producer may have finished with its buffers already
+                let _ = emptied.send(buffer);
+            }
+            target.flush()
+        });
+        let produced = produce(&mut pipe).and_then(|()| pipe.send());
+        drop(pipe);
+        let written = writing
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        written.and(produced)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Faulty;
+
+    #[test]
+    fn writes_everything_in_order() {
+        let data: Vec<u8> = (0..5 * BUFFER_LEN + 7).map(|idx| idx as u8).collect();
+        let mut out = Vec::new();
+        run(&mut out, |pipe| {
+            for chunk in data.chunks(1000) {
+                pipe.write_all(chunk)?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn reports_the_target_error() {
+        let target = Faulty::new(Vec::new(), []).fail_at(10, io::ErrorKind::StorageFull);
+        let err = run(target, |pipe| loop {
+            pipe.write_all(&[0; 1000])?;
+        })
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::StorageFull, err.kind());
+    }
+
+    #[test]
+    fn reports_the_producer_error() {
+        let mut out = Vec::new();
+        let err = run(&mut out, |pipe| {
+            pipe.write_all(b"partial")?;
+            Err(io::Error::new(io::ErrorKind::InvalidData, "broken"))
+        })
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(out.is_empty());
+    }
+}
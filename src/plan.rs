@@ -0,0 +1,746 @@
+use anchor::{self, Anchor};
+#[cfg(feature = "arbitrary")]
+use arbitrary::Arbitrary;
+use cdc::Chunker;
+#[cfg(feature = "sha2")]
+use digest::{self, Verified};
+use inserter::Inserter;
+use operation::{Operation, PlanBuilder};
+#[cfg(feature = "regex")]
+use regex::bytes::Regex;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    cell::Cell,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// where a planned insertion goes, in a form which can be stored
+///
+/// this mirrors `Anchor`, except that patterns are text and regexes are
+/// compiled when the plan is applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Position {
+    /// this origin index
+    Offset(usize),
+    /// the first multiple of the alignment at or after this origin index
+    Aligned { after: usize, alignment: usize },
+    /// the start of the first occurrence of this text
+    Before(String),
+    /// just past the first occurrence of this text
+    After(String),
+    /// the start of this line, counting from 1
+    BeforeLine(usize),
+    /// just past the newline ending this line, counting from 1
+    AfterLine(usize),
+    /// just past the end of the final line
+    AfterLastLine,
+    /// just past this occurrence of the delimiter byte, counting from 1
+    AfterDelimiter { delimiter: u8, count: usize },
+    /// the start of the first match of this regex within a line
+    BeforeRegex(String),
+    /// just past the first match of this regex within a line
+    AfterRegex(String),
+    /// the first boundary between content-defined chunks at or after this
+    /// origin index
+    ChunkBoundary { after: usize, chunker: Chunker },
+}
+
+impl Position {
+    /// the anchor for this position
+    ///
+    /// fails with `InvalidInput` if a regex doesn't compile, or if the crate
+    /// was built without the `regex` feature
+    pub fn to_anchor(&self) -> io::Result<Anchor> {
+        Ok(match *self {
+            Position::Offset(offset) => Anchor::Offset(offset),
+            Position::Aligned { after, alignment } => Anchor::Aligned(after, alignment),
+            Position::Before(ref text) => Anchor::Before(text.as_bytes().to_vec()),
+            Position::After(ref text) => Anchor::After(text.as_bytes().to_vec()),
+            Position::BeforeLine(n) => Anchor::BeforeLine(n),
+            Position::AfterLine(n) => Anchor::AfterLine(n),
+            Position::AfterLastLine => Anchor::AfterLastLine,
+            Position::AfterDelimiter { delimiter, count } => {
+                Anchor::AfterDelimiter(delimiter, count)
+            }
+            Position::ChunkBoundary { after, chunker } => Anchor::ChunkBoundary(after, chunker),
+            #[cfg(feature = "regex")]
+            Position::BeforeRegex(ref re) => Anchor::BeforeRegex(compile(re)?),
+            #[cfg(feature = "regex")]
+            Position::AfterRegex(ref re) => Anchor::AfterRegex(compile(re)?),
+            #[cfg(not(feature = "regex"))]
+            Position::BeforeRegex(_) | Position::AfterRegex(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "regex positions need the `regex` feature",
+                ))
+            }
+        })
+    }
+}
+
+#[cfg(feature = "regex")]
+fn compile(re: &str) -> io::Result<Regex> {
+    Regex::new(re).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
+
+/// what a planned insertion inserts
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Source {
+    /// the contents of this file, opened when the plan is applied
+    File(PathBuf),
+    /// this text
+    Text(String),
+}
+
+impl Source {
+    /// open a reader over the content
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        Ok(match *self {
+            Source::File(ref path) => Box::new(BufReader::new(File::open(path)?)),
+            Source::Text(ref text) => Box::new(io::Cursor::new(text.clone().into_bytes())),
+        })
+    }
+
+    /// the length of the content in bytes
+    pub fn size(&self) -> io::Result<u64> {
+        match *self {
+            Source::File(ref path) => Ok(fs::metadata(path)?.len()),
+            Source::Text(ref text) => Ok(text.len() as u64),
+        }
+    }
+
+    /// the sha-256 digest of the content as hex, for `PlannedInsertion::sha256`
+    #[cfg(feature = "sha2")]
+    pub fn sha256(&self) -> io::Result<String> {
+        Ok(digest::to_hex(&digest::sha256(self.open()?)?))
+    }
+}
+
+/// a single insertion in a plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PlannedInsertion {
+    /// where the content goes
+    pub at: Position,
+    /// the content
+    pub source: Source,
+    /// whether the insertion is skipped if its content is already in the
+    /// origin just before or just after its position, so that applying the
+    /// plan to its own output changes nothing
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "::std::ops::Not::not")
+    )]
+    pub once: bool,
+    /// the sha-256 digest of the content as hex, if it's to be checked: the
+    /// plan fails with `InvalidData` once a mismatching source has been read
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub sha256: Option<String>,
+}
+
+impl PlannedInsertion {
+    /// open a reader over the content, verifying it against its digest if
+    /// there is one
+    ///
+    /// fails with `InvalidInput` if the digest is malformed, or if the crate
+    /// was built without the `sha2` feature
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        self.verify(self.source.open()?)
+    }
+
+    /// check the opened source against the digest, if there is one
+    pub(crate) fn verify(&self, source: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+        match self.sha256 {
+            None => Ok(source),
+            #[cfg(feature = "sha2")]
+            Some(ref hex) => Ok(Box::new(Verified::new(source, digest::from_hex(hex)?))),
+            #[cfg(not(feature = "sha2"))]
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sha-256 digests need the `sha2` feature",
+            )),
+        }
+    }
+}
+
+/// a declarative list of insertions which can be applied to any number of origins
+///
+/// with the `serde` feature, a plan serializes as e.g.
+/// `{"insertions": [{"at": {"after": "<head>"}, "source": {"file": "meta.html"}}]}`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct InsertionPlan {
+    /// the insertions, in no particular order
+    pub insertions: Vec<PlannedInsertion>,
+    /// whether the text matched by pattern and regex positions is removed
+    /// from the output, leaving just the insertions in its place
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "::std::ops::Not::not")
+    )]
+    pub remove_anchors: bool,
+    /// where insertions after the final line go if it lacks a newline
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "FinalLine::is_after")
+    )]
+    pub final_line: FinalLine,
+}
+
+/// where insertions after a line go when it's the final line of the origin,
+/// and lacks a newline
+///
+/// this only affects `AfterLine` positions: pinning a plan resolves them as
+/// `After` does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(Arbitrary))]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FinalLine {
+    /// just past the final line, joining it to the first inserted line
+    #[default]
+    After,
+    /// on a line of their own, after a newline added to the final line
+    Terminate,
+    /// on a line of their own as for `Terminate`, leaving the output's final
+    /// line without a newline like the origin's: the newline ending the last
+    /// insertion is removed
+    Preserve,
+    /// before the final line
+    Before,
+}
+
+impl FinalLine {
+    #[cfg(feature = "serde")]
+    fn is_after(&self) -> bool {
+        *self == FinalLine::After
+    }
+}
+
+impl InsertionPlan {
+    /// create an empty plan
+    pub fn new() -> InsertionPlan {
+        InsertionPlan::default()
+    }
+
+    /// add an insertion to the plan
+    pub fn insert(mut self, at: Position, source: Source) -> Self {
+        self.insertions.push(PlannedInsertion {
+            at,
+            source,
+            once: false,
+            sha256: None,
+        });
+        self
+    }
+
+    /// add an insertion which is skipped if its content is already in place
+    ///
+    /// the content is looked for immediately either side of the position, as
+    /// that's where an earlier application of the plan would have put it
+    pub fn insert_once(mut self, at: Position, source: Source) -> Self {
+        self.insertions.push(PlannedInsertion {
+            at,
+            source,
+            once: true,
+            sha256: None,
+        });
+        self
+    }
+
+    /// remove the text matched by pattern and regex positions from the output
+    ///
+    /// placeholders such as `<!-- nav -->` are then replaced by what's inserted
+    /// at them, rather than left behind. several insertions may share an
+    /// anchor, but the matches of different anchors mustn't overlap.
+    pub fn remove_anchors(mut self, remove: bool) -> Self {
+        self.remove_anchors = remove;
+        self
+    }
+
+    /// choose where insertions after the final line go if it lacks a newline
+    pub fn final_line(mut self, final_line: FinalLine) -> Self {
+        self.final_line = final_line;
+        self
+    }
+
+    /// find the origin index of each insertion, in order
+    ///
+    /// the origin is only read if some position isn't an offset or aligned
+    pub fn resolve<R: Read>(&self, origin: R) -> io::Result<Vec<usize>> {
+        let anchors = self
+            .insertions
+            .iter()
+            .map(|insertion| insertion.at.to_anchor())
+            .collect::<io::Result<Vec<_>>>()?;
+        anchor::resolve(origin, &anchors)
+    }
+
+    /// this plan with every position resolved to an offset into the origin
+    ///
+    /// a pinned plan can be applied without scanning, and can be stripped
+    ///
+    /// fails with `InvalidInput` if the plan removes its anchors, since
+    /// offsets can't describe the removals
+    pub fn pin<R: Read>(&self, origin: R) -> io::Result<InsertionPlan> {
+        if self.remove_anchors {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a plan which removes its anchors can't be pinned",
+            ));
+        }
+        let offsets = self.resolve(origin)?;
+        Ok(InsertionPlan {
+            insertions: offsets
+                .into_iter()
+                .zip(&self.insertions)
+                .map(|(offset, insertion)| PlannedInsertion {
+                    at: Position::Offset(offset),
+                    ..insertion.clone()
+                })
+                .collect(),
+            remove_anchors: false,
+            final_line: self.final_line,
+        })
+    }
+
+    /// recover the origin from an output of this plan, writing it to the target
+    ///
+    /// every position must be an offset, since an anchor can't be found again
+    /// once content has been inserted around it: `pin` the plan against the
+    /// origin before applying it. each inserted region is checked against its
+    /// source, failing with `InvalidData` once the output has been written if
+    /// any doesn't match.
+    pub fn strip<R: Read, W: Write>(&self, output: R, target: W) -> io::Result<()> {
+        let mut regions = Vec::with_capacity(self.insertions.len());
+        for insertion in &self.insertions {
+            let offset = match insertion.at {
+                Position::Offset(offset) => offset,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "only a plan of offsets can be stripped",
+                    ))
+                }
+            };
+            let mut content = Vec::new();
+            insertion.open()?.read_to_end(&mut content)?;
+            regions.push((offset, content));
+        }
+        // insertions at the same offset were made in the order they were planned
+        regions.sort_by_key(|&(offset, _)| offset);
+
+        let mismatch = Cell::new(false);
+        let mut plan = PlanBuilder::new();
+        let mut shift = 0;
+        for (offset, content) in regions {
+            if content.is_empty() {
+                continue;
+            }
+            let start = offset + shift;
+            shift += content.len();
+            let mismatch = &mismatch;
+            plan = plan.transform(start..start + content.len(), move |found| {
+                if found != &content[..] {
+                    mismatch.set(true);
+                }
+                Vec::new()
+            });
+        }
+        plan.execute(output, target)?;
+        if mismatch.get() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "inserted content doesn't match the plan",
+            ));
+        }
+        Ok(())
+    }
+
+    /// apply the plan to an origin, writing the result to the target
+    ///
+    /// a seekable origin is scanned for the plan's anchors, then rewound to
+    /// where it started to be copied. if the plan removes its anchors,
+    /// overlapping matches of different anchors fail with `InvalidInput`.
+    pub fn apply<R, W>(&self, origin: R, target: W) -> io::Result<()>
+    where
+        R: Read + Seek,
+        W: Write,
+    {
+        self.apply_with(origin, target, PlannedInsertion::open)
+    }
+
+    /// apply the plan, opening each insertion's content with `open`
+    pub(crate) fn apply_with<R, W, O>(&self, origin: R, target: W, open: O) -> io::Result<()>
+    where
+        R: Read + Seek,
+        W: Write,
+        O: Fn(&PlannedInsertion) -> io::Result<Box<dyn Read>>,
+    {
+        self.inserter_with(origin, target, open)?.execute()
+    }
+
+    /// prepare to apply the plan as `apply` does, opening each insertion's
+    /// content with `open`, but return the inserter rather than executing it
+    ///
+    /// the origin has been scanned and rewound, so further operations, such
+    /// as replacements, can be added before it's executed. content inserted
+    /// only once, and the newline ending an unterminated final line, are
+    /// in-memory bytes with a known length; everything else is inserted as
+    /// `open` returned it.
+    pub fn inserter_with<R, W, O>(
+        &self,
+        mut origin: R,
+        target: W,
+        open: O,
+    ) -> io::Result<Inserter<'static, R, W>>
+    where
+        R: Read + Seek,
+        W: Write,
+        O: Fn(&PlannedInsertion) -> io::Result<Box<dyn Read>>,
+    {
+        let start = origin.stream_position()?;
+        let mut anchors = self
+            .insertions
+            .iter()
+            .map(|insertion| insertion.at.to_anchor())
+            .collect::<io::Result<Vec<_>>>()?;
+        // the start of each line anchor's line, in case it's the final line
+        let count = anchors.len();
+        if self.final_line == FinalLine::Before {
+            for idx in 0..count {
+                match anchors[idx] {
+                    Anchor::AfterLine(n) => anchors.push(Anchor::BeforeLine(n)),
+                    Anchor::AfterLastLine => anchors.push(Anchor::BeforeLastLine),
+                    _ => (),
+                }
+            }
+        }
+        let mut ranges = anchor::locate(&mut origin, &anchors)?;
+        let mut line_starts = ranges.split_off(count).into_iter();
+        let end = origin.seek(SeekFrom::End(0))?;
+        let len = (end - start) as usize;
+        let unterminated = len > 0 && !ends_line(&mut origin, end - 1)?;
+
+        let mut offsets = Vec::with_capacity(count);
+        let mut finals = Vec::new();
+        for (idx, (range, anchor)) in ranges.iter().zip(&anchors).enumerate() {
+            let mut offset = anchor.position(range.clone());
+            if let Anchor::AfterLine(_) | Anchor::AfterLastLine = *anchor {
+                let line_start = line_starts.next();
+                if unterminated && offset == len {
+                    finals.push(idx);
+                    if let Some(line) = line_start {
+                        offset = line.start;
+                    }
+                }
+            }
+            offsets.push(offset);
+        }
+
+        let mut sources: Vec<(usize, InsertSource<'static>)> = Vec::with_capacity(count + 1);
+        if !finals.is_empty()
+            && (self.final_line == FinalLine::Terminate || self.final_line == FinalLine::Preserve)
+        {
+            sources.push((len, b"\n".into_insert_source()));
+        }
+        for (idx, (&offset, insertion)) in offsets.iter().zip(&self.insertions).enumerate() {
+            let last_final = self.final_line == FinalLine::Preserve && finals.last() == Some(&idx);
+            if !insertion.once && !last_final {
+                sources.push((offset, open(insertion)?.into_insert_source()));
+                continue;
+            }
+            let mut content = Vec::new();
+            open(insertion)?.read_to_end(&mut content)?;
+            if last_final && content.ends_with(b"\n") {
+                content.pop();
+            }
+            if !insertion.once || !in_place(&mut origin, start, offset, &content)? {
+                sources.push((offset, content.into_insert_source()));
+            }
+        }
+        origin.seek(SeekFrom::Start(start))?;
+        let mut inserter = Inserter::new(origin, target);
+        for (offset, source) in sources {
+            inserter = inserter.insert(offset, source);
+        }
+        if self.remove_anchors {
+            ranges.sort_by_key(|range| (range.start, range.end));
+            ranges.dedup();
+            for range in ranges {
+                if !range.is_empty() {
+                    inserter = inserter.operation(Operation::Delete(range));
+                }
+            }
+        }
+        Ok(inserter)
+    }
+}
+
+/// whether the origin byte at this index is a newline
+fn ends_line<R: Read + Seek>(origin: &mut R, index: u64) -> io::Result<bool> {
+    origin.seek(SeekFrom::Start(index))?;
+    let mut byte = [0];
+    origin.read_exact(&mut byte)?;
+    Ok(byte[0] == b'\n')
+}
+
+/// whether the content is in the origin ending or starting at the offset
+fn in_place<R: Read + Seek>(
+    origin: &mut R,
+    start: u64,
+    offset: usize,
+    content: &[u8],
+) -> io::Result<bool> {
+    if content.is_empty() {
+        return Ok(true);
+    }
+    let from = offset.saturating_sub(content.len());
+    origin.seek(SeekFrom::Start(start + from as u64))?;
+    let mut window = Vec::with_capacity(offset - from + content.len());
+    origin
+        .by_ref()
+        .take((offset - from + content.len()) as u64)
+        .read_to_end(&mut window)?;
+    let (before, after) = window.split_at((offset - from).min(window.len()));
+    Ok(before == content || after.starts_with(content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_plan() {
+        let plan = InsertionPlan::new()
+            .insert(
+                Position::After("<head>".into()),
+                Source::Text("<meta>".into()),
+            )
+            .insert(Position::Offset(0), Source::Text("<!doctype html>".into()));
+        let mut dest = Vec::new();
+        plan.apply(io::Cursor::new(b"<html><head></head>"), &mut dest)
+            .unwrap();
+        assert_eq!(&b"<!doctype html><html><head><meta></head>"[..], &dest[..]);
+
+        let plan = plan.insert(Position::AfterLine(9), Source::Text("".into()));
+        let err = plan
+            .apply(io::Cursor::new(b"<html><head></head>"), io::sink())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn removes_anchors() {
+        let plan = InsertionPlan::new()
+            .insert(
+                Position::Before("<!-- nav -->".into()),
+                Source::Text("<a>".into()),
+            )
+            .insert(
+                Position::After("<!-- nav -->".into()),
+                Source::Text("<b>".into()),
+            )
+            .insert(Position::AfterLine(1), Source::Text("<hr>".into()))
+            .remove_anchors(true);
+        let mut dest = Vec::new();
+        plan.apply(io::Cursor::new("<nav><!-- nav --></nav>\n"), &mut dest)
+            .unwrap();
+        assert_eq!("<nav><a><b></nav>\n<hr>", String::from_utf8(dest).unwrap());
+        let err = plan.pin(&b"<!-- nav -->"[..]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let plan = InsertionPlan::new()
+            .insert(Position::After("abc".into()), Source::Text("1".into()))
+            .insert(Position::After("bcd".into()), Source::Text("2".into()))
+            .remove_anchors(true);
+        let err = plan.apply(io::Cursor::new("abcd"), io::sink()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn verifies_digests() {
+        let source = Source::Text("bravo ".into());
+        let mut plan = InsertionPlan::new().insert(Position::Offset(6), source.clone());
+        plan.insertions[0].sha256 = Some("00".repeat(32));
+        let mut out = Vec::new();
+        let err = plan
+            .apply(io::Cursor::new("alpha charlie"), &mut out)
+            .unwrap_err();
+        if cfg!(feature = "sha2") {
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        } else {
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+
+        #[cfg(feature = "sha2")]
+        {
+            plan.insertions[0].sha256 = Some(source.sha256().unwrap());
+            let mut out = Vec::new();
+            plan.apply(io::Cursor::new("alpha charlie"), &mut out)
+                .unwrap();
+            assert_eq!(b"alpha bravo charlie", &out[..]);
+        }
+    }
+
+    #[test]
+    fn inserts_once() {
+        let plan = InsertionPlan::new()
+            .insert_once(
+                Position::After("[settings]\n".into()),
+                Source::Text("debug = true\n".into()),
+            )
+            .insert_once(
+                Position::Before("[other]".into()),
+                Source::Text("# other\n".into()),
+            )
+            .insert_once(Position::Offset(0), Source::Text("# managed\n".into()));
+        let mut once = Vec::new();
+        plan.apply(io::Cursor::new("[settings]\n[other]\n"), &mut once)
+            .unwrap();
+        assert_eq!(
+            "# managed\n[settings]\ndebug = true\n# other\n[other]\n",
+            String::from_utf8(once.clone()).unwrap()
+        );
+        let mut twice = Vec::new();
+        plan.apply(io::Cursor::new(&once), &mut twice).unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn prepares_an_inserter() {
+        let plan = InsertionPlan::new().insert_once(
+            Position::After("<head>".into()),
+            Source::Text("<meta>".into()),
+        );
+        let mut dest = Vec::new();
+        plan.inserter_with(
+            io::Cursor::new("<head><body>"),
+            &mut dest,
+            PlannedInsertion::open,
+        )
+        .unwrap()
+        .operation(Operation::ReplaceAll(b"body".to_vec(), b"main".to_vec()))
+        .execute()
+        .unwrap();
+        assert_eq!(b"<head><meta><main>".to_vec(), dest);
+    }
+
+    #[test]
+    fn handles_unterminated_final_line() {
+        let plan = InsertionPlan::new()
+            .insert(Position::AfterLine(2), Source::Text("c\n".into()))
+            .insert(Position::AfterLastLine, Source::Text("d\n".into()))
+            .insert(Position::AfterLine(1), Source::Text("x\n".into()));
+        let apply = |plan: &InsertionPlan, origin: &str| {
+            let mut dest = Vec::new();
+            plan.apply(io::Cursor::new(origin), &mut dest).unwrap();
+            String::from_utf8(dest).unwrap()
+        };
+        for (final_line, expected) in [
+            (FinalLine::After, "a\nx\nbc\nd\n"),
+            (FinalLine::Terminate, "a\nx\nb\nc\nd\n"),
+            (FinalLine::Preserve, "a\nx\nb\nc\nd"),
+            (FinalLine::Before, "a\nc\nd\nx\nb"),
+        ] {
+            let plan = plan.clone().final_line(final_line);
+            assert_eq!(expected, apply(&plan, "a\nb"));
+            assert_eq!("a\nx\nb\nc\nd\n", apply(&plan, "a\nb\n"));
+        }
+    }
+
+    #[test]
+    fn pins_and_strips() {
+        let origin = b"<html><head></head>";
+        let plan = InsertionPlan::new()
+            .insert(
+                Position::After("<head>".into()),
+                Source::Text("<meta>".into()),
+            )
+            .insert(Position::Offset(12), Source::Text("<title>".into()))
+            .insert(Position::Offset(0), Source::Text("<!doctype html>".into()));
+        let mut output = Vec::new();
+        plan.apply(io::Cursor::new(origin), &mut output).unwrap();
+
+        let err = plan.strip(output.as_slice(), io::sink()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let pinned = plan.pin(&origin[..]).unwrap();
+        assert_eq!(Position::Offset(12), pinned.insertions[0].at);
+        let mut stripped = Vec::new();
+        pinned.strip(output.as_slice(), &mut stripped).unwrap();
+        assert_eq!(&origin[..], &stripped[..]);
+
+        output[28] = b'!';
+        let err = pinned.strip(output.as_slice(), io::sink()).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn file_sources() {
+        let path = ::std::env::temp_dir().join(format!("plan-source-{}", ::std::process::id()));
+        fs::write(&path, "payload").unwrap();
+        let source = Source::File(path.clone());
+        assert_eq!(7, source.size().unwrap());
+        let mut content = String::new();
+        source.open().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!("payload", content);
+        fs::remove_file(&path).unwrap();
+        assert!(source.open().is_err());
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn regex_needs_feature() {
+        let err = Position::AfterRegex("x".into()).to_anchor().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_json() {
+        let json = r#"{"insertions":[{"at":{"before_line":3},"source":{"file":"a.txt"}},{"at":{"after":"x"},"source":{"text":"y"}}]}"#;
+        let plan: InsertionPlan = ::serde_json::from_str(json).unwrap();
+        assert_eq!(
+            InsertionPlan::new()
+                .insert(Position::BeforeLine(3), Source::File("a.txt".into()))
+                .insert(Position::After("x".into()), Source::Text("y".into())),
+            plan
+        );
+        assert_eq!(json, ::serde_json::to_string(&plan).unwrap());
+
+        let json = r#"{"at":{"chunk_boundary":{"after":100,"chunker":{"min_size":16,"avg_size":64,"max_size":256}}},"source":{"text":"y"}}"#;
+        let insertion: PlannedInsertion = ::serde_json::from_str(json).unwrap();
+        assert_eq!(
+            Position::ChunkBoundary {
+                after: 100,
+                chunker: Chunker::new(64),
+            },
+            insertion.at
+        );
+    }
+}
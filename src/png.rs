@@ -0,0 +1,240 @@
+use checksum::Crc32;
+use std::io::{self, Read, Write};
+
+/// the eight bytes every PNG stream starts with
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// largest permitted chunk data length
+pub const MAX_CHUNK_LEN: usize = 0x7FFF_FFFF;
+
+/// an ancillary chunk to be inserted into a PNG stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    kind: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    /// create a chunk of an arbitrary ancillary type
+    ///
+    /// the type must be four ascii letters with the ancillary bit set, i.e. its
+    /// first letter lowercase; critical chunks can't be inserted safely
+    pub fn new(kind: [u8; 4], data: Vec<u8>) -> io::Result<Chunk> {
+        if !kind.iter().all(u8::is_ascii_alphabetic) || !kind[0].is_ascii_lowercase() {
+            return Err(invalid_input(
+                "chunk type must be four letters and ancillary",
+            ));
+        }
+        if data.len() > MAX_CHUNK_LEN {
+            return Err(invalid_input("chunk data too long"));
+        }
+        Ok(Chunk { kind, data })
+    }
+
+    /// create a `tEXt` chunk holding latin-1 text under the given keyword
+    pub fn text(keyword: &str, text: &str) -> io::Result<Chunk> {
+        if !text.chars().all(|c| (c as u32) < 0x100) {
+            return Err(invalid_input("tEXt chunks hold latin-1 text only"));
+        }
+        let mut data = keyword_bytes(keyword)?;
+        data.extend(text.chars().map(|c| c as u8));
+        Chunk::new(*b"tEXt", data)
+    }
+
+    /// create an uncompressed `iTXt` chunk holding utf-8 text under the given keyword
+    pub fn international_text(keyword: &str, text: &str) -> io::Result<Chunk> {
+        let mut data = keyword_bytes(keyword)?;
+        // compression flag, compression method, empty language tag, empty translated keyword
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(text.as_bytes());
+        Chunk::new(*b"iTXt", data)
+    }
+
+    /// create an `eXIf` chunk holding raw exif data
+    pub fn exif(data: Vec<u8>) -> io::Result<Chunk> {
+        Chunk::new(*b"eXIf", data)
+    }
+
+    /// the four-letter type of this chunk
+    pub fn kind(&self) -> [u8; 4] {
+        self.kind
+    }
+
+    /// the framed chunk: length, type, data and CRC
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 12);
+        out.extend_from_slice(&(self.data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.kind);
+        out.extend_from_slice(&self.data);
+        let mut crc = Crc32::new();
+        crc.update(&self.kind);
+        crc.update(&self.data);
+        out.extend_from_slice(&crc.finish().to_be_bytes());
+        out
+    }
+
+    fn before_image_data(&self) -> bool {
+        &self.kind == b"eXIf"
+    }
+}
+
+/// insert ancillary chunks into a PNG stream
+///
+/// `eXIf` chunks are placed before the first `IDAT` chunk, as the format
+/// requires; all others are placed just before `IEND`. existing chunks are
+/// copied through untouched. fails if the origin isn't a complete PNG stream or
+/// if a second `eXIf` chunk would result.
+pub fn insert_chunks<R, W>(mut origin: R, mut target: W, chunks: &[Chunk]) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut signature = [0_u8; 8];
+    origin.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(invalid_data("missing PNG signature"));
+    }
+    target.write_all(&signature)?;
+
+    let mut early_written = false;
+    loop {
+        let mut header = [0_u8; 8];
+        origin.read_exact(&mut header)?;
+        let len = u64::from(u32::from_be_bytes([
+            header[0], header[1], header[2], header[3],
+        ]));
+        let kind = &header[4..];
+
+        if kind == b"eXIf" && chunks.iter().any(Chunk::before_image_data) {
+            return Err(invalid_data("PNG stream already has an eXIf chunk"));
+        }
+        if !early_written && (kind == b"IDAT" || kind == b"IEND") {
+            for chunk in chunks.iter().filter(|c| c.before_image_data()) {
+                target.write_all(&chunk.to_bytes())?;
+            }
+            early_written = true;
+        }
+        if kind == b"IEND" {
+            for chunk in chunks.iter().filter(|c| !c.before_image_data()) {
+                target.write_all(&chunk.to_bytes())?;
+            }
+        }
+
+        target.write_all(&header)?;
+        // chunk data and CRC
+        let copied = io::copy(&mut origin.by_ref().take(len + 4), &mut target)?;
+        if copied != len + 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated PNG chunk",
+            ));
+        }
+        if kind == b"IEND" {
+            break;
+        }
+    }
+
+    // anything trailing the IEND chunk is not ours to judge
+    io::copy(&mut origin, &mut target)?;
+    Ok(())
+}
+
+fn keyword_bytes(keyword: &str) -> io::Result<Vec<u8>> {
+    let valid = !keyword.is_empty()
+        && keyword.len() < 80
+        && keyword.bytes().all(|b| (0x20..0x7F).contains(&b))
+        && !keyword.starts_with(' ')
+        && !keyword.ends_with(' ');
+    if !valid {
+        return Err(invalid_input(
+            "PNG keywords must be 1-79 printable characters",
+        ));
+    }
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+    Ok(data)
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        Chunk {
+            kind: *kind,
+            data: data.to_vec(),
+        }
+        .to_bytes()
+    }
+
+    fn image() -> Vec<u8> {
+        let mut png = SIGNATURE.to_vec();
+        png.extend(raw_chunk(b"IHDR", &[0; 13]));
+        png.extend(raw_chunk(b"IDAT", b"pixels"));
+        png.extend(raw_chunk(b"IEND", b""));
+        png
+    }
+
+    fn chunk_types(png: &[u8]) -> Vec<String> {
+        let mut types = Vec::new();
+        let mut offset = 8;
+        while offset < png.len() {
+            let len = u32::from_be_bytes([
+                png[offset],
+                png[offset + 1],
+                png[offset + 2],
+                png[offset + 3],
+            ]) as usize;
+            types.push(String::from_utf8_lossy(&png[offset + 4..offset + 8]).into_owned());
+            offset += len + 12;
+        }
+        types
+    }
+
+    #[test]
+    fn known_crc() {
+        let iend = raw_chunk(b"IEND", b"");
+        assert_eq!(&[0xAE, 0x42, 0x60, 0x82], &iend[8..]);
+    }
+
+    #[test]
+    fn places_chunks() {
+        let chunks = vec![
+            Chunk::text("Comment", "hello").unwrap(),
+            Chunk::exif(vec![1, 2, 3]).unwrap(),
+        ];
+        let mut dest = Vec::new();
+        insert_chunks(image().as_slice(), &mut dest, &chunks).unwrap();
+        assert_eq!(
+            vec!["IHDR", "eXIf", "IDAT", "tEXt", "IEND"],
+            chunk_types(&dest)
+        );
+    }
+
+    #[test]
+    fn rejects_second_exif() {
+        let mut dest = Vec::new();
+        let mut png = SIGNATURE.to_vec();
+        png.extend(raw_chunk(b"eXIf", b"old"));
+        png.extend(raw_chunk(b"IEND", b""));
+        let err =
+            insert_chunks(png.as_slice(), &mut dest, &[Chunk::exif(vec![1]).unwrap()]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        assert!(Chunk::new(*b"IDAT", Vec::new()).is_err());
+        assert!(Chunk::text("", "x").is_err());
+        let mut dest = Vec::new();
+        assert!(insert_chunks(&b"not a png"[..], &mut dest, &[]).is_err());
+    }
+}
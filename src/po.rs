@@ -0,0 +1,373 @@
+use operation::PlanBuilder;
+use std::{
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+};
+
+/// a gettext translation entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    comments: Vec<String>,
+    context: Option<String>,
+    id: String,
+    translation: String,
+}
+
+impl Entry {
+    /// an entry translating `id` as `translation`
+    pub fn new<I: Into<String>, T: Into<String>>(id: I, translation: T) -> Entry {
+        Entry {
+            comments: Vec::new(),
+            context: None,
+            id: id.into(),
+            translation: translation.into(),
+        }
+    }
+
+    /// add a comment line, written as-is after its `#`: e.g. `. note` for an
+    /// extracted comment or `: src/main.rs:12` for a reference
+    pub fn comment<S: Into<String>>(mut self, comment: S) -> Self {
+        self.comments.push(comment.into());
+        self
+    }
+
+    /// disambiguate the entry with a `msgctxt`
+    pub fn context<S: Into<String>>(mut self, context: S) -> Self {
+        self.context = Some(context.into());
+        self
+    }
+
+    fn key(&self) -> (&str, &str) {
+        (&self.id, self.context.as_deref().unwrap_or(""))
+    }
+
+    /// the entry in .po syntax, ending with a newline
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for comment in &self.comments {
+            out.push('#');
+            out.push_str(comment);
+            out.push('\n');
+        }
+        if let Some(ref context) = self.context {
+            render_field(&mut out, "msgctxt", context);
+        }
+        render_field(&mut out, "msgid", &self.id);
+        render_field(&mut out, "msgstr", &self.translation);
+        out
+    }
+}
+
+/// write a keyword and its string, split after each embedded newline as
+/// gettext's tools do
+fn render_field(out: &mut String, keyword: &str, text: &str) {
+    out.push_str(keyword);
+    out.push(' ');
+    let mut lines: Vec<&str> = text.split_inclusive('\n').collect();
+    if lines.len() > 1 {
+        out.push_str("\"\"\n");
+    } else if lines.is_empty() {
+        lines.push("");
+    }
+    for line in lines {
+        out.push('"');
+        for c in line.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\t' => out.push_str("\\t"),
+                '\r' => out.push_str("\\r"),
+                c => out.push(c),
+            }
+        }
+        out.push_str("\"\n");
+    }
+}
+
+/// an entry found in the origin
+#[derive(Debug, Default)]
+struct Found {
+    start: usize,
+    context: Option<String>,
+    id: Option<String>,
+}
+
+/// the keyword whose string continues on the next line
+#[derive(Clone, Copy, PartialEq)]
+enum Continuing {
+    Context,
+    Id,
+    Other,
+}
+
+/// find the start, context and id of every entry in the origin, its length,
+/// and what must be appended to it to start a new entry
+///
+/// entries are separated by blank lines. an entry without an id, such as an
+/// obsolete `#~` entry, is still reported.
+fn scan<R: Read>(origin: R) -> io::Result<(Vec<Found>, usize, &'static str)> {
+    let mut origin = BufReader::new(origin);
+    let mut entries = Vec::new();
+    let mut current: Option<Found> = None;
+    let mut continuing = Continuing::Other;
+    let mut line = Vec::new();
+    let mut offset = 0;
+    let mut separator = "";
+    loop {
+        line.clear();
+        let len = origin.read_until(b'\n', &mut line)?;
+        if len == 0 {
+            break;
+        }
+        let text = line.strip_suffix(b"\n").unwrap_or(&line);
+        let text = text.strip_suffix(b"\r").unwrap_or(text);
+        if text.iter().all(u8::is_ascii_whitespace) {
+            entries.extend(current.take());
+            separator = if line.ends_with(b"\n") { "" } else { "\n" };
+        } else {
+            let entry = current.get_or_insert_with(|| Found {
+                start: offset,
+                ..Found::default()
+            });
+            separator = if line.ends_with(b"\n") { "\n" } else { "\n\n" };
+            let (field, rest) = if let Some(rest) = text.strip_prefix(b"msgctxt ") {
+                continuing = Continuing::Context;
+                (&mut entry.context, rest)
+            } else if let Some(rest) = text.strip_prefix(b"msgid ") {
+                continuing = Continuing::Id;
+                (&mut entry.id, rest)
+            } else if text.starts_with(b"\"") && continuing == Continuing::Context {
+                (&mut entry.context, text)
+            } else if text.starts_with(b"\"") && continuing == Continuing::Id {
+                (&mut entry.id, text)
+            } else {
+                if !text.starts_with(b"\"") {
+                    continuing = Continuing::Other;
+                }
+                offset += len;
+                continue;
+            };
+            field
+                .get_or_insert_with(String::new)
+                .push_str(&unquote(rest.trim_ascii())?);
+        }
+        offset += len;
+    }
+    entries.extend(current.take());
+    Ok((entries, offset, separator))
+}
+
+/// the contents of a quoted .po string
+fn unquote(quoted: &[u8]) -> io::Result<String> {
+    let inner = quoted
+        .strip_prefix(b"\"")
+        .and_then(|q| q.strip_suffix(b"\""))
+        .ok_or_else(|| invalid("malformed string"))?;
+    let inner = String::from_utf8_lossy(inner);
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(c) => out.push(c),
+            None => return Err(invalid("malformed string")),
+        }
+    }
+    Ok(out)
+}
+
+/// po inserter adds entries to a gettext .po file, each before the first
+/// existing entry which sorts after it
+///
+/// existing entries, their comments and formatting are copied untouched. the
+/// header entry stays first, and obsolete entries stay last.
+pub struct PoInserter<R, W> {
+    origin: R,
+    entries: Vec<Entry>,
+    target: W,
+}
+
+impl<R, W> PoInserter<R, W>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    /// create a new po inserter
+    ///
+    /// the origin is read from its current position
+    pub fn new(origin: R, target: W) -> PoInserter<R, W> {
+        PoInserter {
+            origin,
+            entries: Vec::new(),
+            target,
+        }
+    }
+
+    /// add an entry
+    pub fn entry(mut self, entry: Entry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// execute this po inserter, consuming it
+    ///
+    /// entries are sorted by id, then context. fails with `InvalidInput` if
+    /// an entry's id and context are already in the origin, or were added
+    /// twice, and with `InvalidData` if a string in the origin is malformed.
+    pub fn execute(mut self) -> io::Result<()> {
+        let start = self.origin.stream_position()?;
+        let (found, len, separator) = scan(&mut self.origin)?;
+        self.origin.seek(SeekFrom::Start(start))?;
+
+        let mut entries = mem::take(&mut self.entries);
+        entries.sort_by(|a, b| a.key().cmp(&b.key()));
+        for pair in entries.windows(2) {
+            if pair[0].key() == pair[1].key() {
+                return Err(duplicate(&pair[1]));
+            }
+        }
+        // the header, with an empty id, is never inserted before
+        let existing: Vec<_> = found
+            .iter()
+            .filter(|found| found.id.as_deref() != Some(""))
+            .collect();
+
+        let mut plan = PlanBuilder::new();
+        for entry in &entries {
+            let key = entry.key();
+            let mut before = None;
+            for found in &existing {
+                let found_key = match found.id {
+                    Some(ref id) => (&id[..], found.context.as_deref().unwrap_or("")),
+                    None => {
+                        before = Some(found.start);
+                        break;
+                    }
+                };
+                if found_key == key {
+                    return Err(duplicate(entry));
+                }
+                if found_key > key {
+                    before = Some(found.start);
+                    break;
+                }
+            }
+            plan = match before {
+                Some(position) => plan.insert(position, format!("{}\n", entry.render())),
+                None => plan.insert(len, format!("{}{}", separator, entry.render())),
+            };
+        }
+        plan.execute(self.origin, self.target)
+    }
+}
+
+fn duplicate(entry: &Entry) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("entry {:?} already exists", entry.id),
+    )
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PO: &str = r#"# translation of app
+msgid ""
+msgstr ""
+"Language: de\n"
+
+#: src/main.rs:3
+msgid "Apple"
+msgstr "Apfel"
+
+msgctxt "fruit"
+msgid ""
+"Orange"
+msgstr "Orange"
+
+#~ msgid "Pear"
+#~ msgstr "Birne"
+"#;
+
+    fn insert(origin: &str, entries: Vec<Entry>) -> io::Result<String> {
+        let mut dest = Vec::new();
+        let mut inserter = PoInserter::new(Cursor::new(origin), &mut dest);
+        for entry in entries {
+            inserter = inserter.entry(entry);
+        }
+        inserter.execute()?;
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn renders_entries() {
+        let entry = Entry::new("Line one\nLine \"two\"", "")
+            .comment(". shown on start")
+            .context("menu");
+        assert_eq!(
+            "#. shown on start\nmsgctxt \"menu\"\nmsgid \"\"\n\"Line one\\n\"\n\"Line \\\"two\\\"\"\nmsgstr \"\"\n",
+            entry.render()
+        );
+    }
+
+    #[test]
+    fn inserts_sorted() {
+        let out = insert(
+            PO,
+            vec![
+                Entry::new("Zucchini", "Zucchini"),
+                Entry::new("Banana", "Banane").comment(": src/main.rs:9"),
+                Entry::new("Aardvark", "Erdferkel"),
+            ],
+        )
+        .unwrap();
+        let expected = PO
+            .replace(
+                "#: src/main.rs:3",
+                "msgid \"Aardvark\"\nmsgstr \"Erdferkel\"\n\n#: src/main.rs:3",
+            )
+            .replace(
+                "msgctxt",
+                "#: src/main.rs:9\nmsgid \"Banana\"\nmsgstr \"Banane\"\n\nmsgctxt",
+            )
+            .replace(
+                "#~ msgid",
+                "msgid \"Zucchini\"\nmsgstr \"Zucchini\"\n\n#~ msgid",
+            );
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    fn appends_with_separating_blank_line() {
+        let origin = "msgid \"a\"\nmsgstr \"b\"";
+        assert_eq!(
+            "msgid \"a\"\nmsgstr \"b\"\n\nmsgid \"c\"\nmsgstr \"d\"\n",
+            insert(origin, vec![Entry::new("c", "d")]).unwrap()
+        );
+        assert_eq!(
+            "msgid \"c\"\nmsgstr \"d\"\n",
+            insert("", vec![Entry::new("c", "d")]).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let err = insert(PO, vec![Entry::new("Apple", "Apfel")]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(insert(PO, vec![Entry::new("Orange", "").context("fruit")]).is_err());
+        assert!(insert(PO, vec![Entry::new("Orange", "")]).is_ok());
+    }
+}
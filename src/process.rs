@@ -0,0 +1,125 @@
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    io::{self, Read},
+    process::{Child, ChildStdout, Command, Stdio},
+};
+
+/// the stdout of a command, spawned when first read
+///
+/// the command is only run once its output is needed, so a plan can hold many
+/// of them without running anything up front. once its output is exhausted the
+/// command is waited on, and a non-zero exit status is reported as an error.
+pub struct Process {
+    command: Command,
+    running: Option<(Child, ChildStdout)>,
+    finished: bool,
+}
+
+impl Process {
+    /// run the command when first read
+    ///
+    /// its stdin is closed and its stderr inherited
+    pub fn new(mut command: Command) -> Process {
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        Process {
+            command,
+            running: None,
+            finished: false,
+        }
+    }
+
+    fn spawn(&mut self) -> io::Result<&mut (Child, ChildStdout)> {
+        if self.running.is_none() {
+            let mut child = self.command.spawn().map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("spawning {:?}: {}", self.command.get_program(), e),
+                )
+            })?;
+            let stdout = child.stdout.take().expect("stdout is piped");
+            self.running = Some((child, stdout));
+        }
+        Ok(self.running.as_mut().expect("just spawned"))
+    }
+}
+
+impl Read for Process {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        let bytes_read = self.spawn()?.1.read(buf)?;
+        if bytes_read == 0 {
+            let (mut child, _) = self.running.take().expect("spawned above");
+            self.finished = true;
+            let status = child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "{:?} failed: {}",
+                    self.command.get_program(),
+                    status
+                )));
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for Command {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(Process::new(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Inserter;
+
+    fn shell(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        command
+    }
+
+    #[test]
+    fn inserts_stdout() {
+        let out = Inserter::new(&b"version: \n"[..], Vec::new())
+            .insert(9, shell("printf 1.2.3"))
+            .execute_to_vec()
+            .unwrap();
+        assert_eq!(b"version: 1.2.3\n".to_vec(), out);
+    }
+
+    #[test]
+    fn spawns_lazily() {
+        let path = ::std::env::temp_dir().join(format!("process-{}", ::std::process::id()));
+        let mut process = Process::new(shell(&format!("touch {}", path.display())));
+        assert!(!path.exists());
+        assert_eq!(0, process.read(&mut [0; 16]).unwrap());
+        assert!(path.exists());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_failure() {
+        let err = Inserter::new(&b"ab"[..], Vec::new())
+            .insert(1, shell("printf partial; exit 3"))
+            .execute_to_vec()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+        assert!(err.to_string().contains("failed"));
+    }
+
+    #[test]
+    fn reports_missing_program() {
+        let err = Inserter::new(&b"ab"[..], Vec::new())
+            .insert(1, Command::new("/nonexistent/program"))
+            .execute_to_vec()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
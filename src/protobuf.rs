@@ -0,0 +1,306 @@
+use inserter::Inserter;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+/// a field to be injected into a serialized protobuf message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    number: u32,
+    wire_type: u8,
+    value: Vec<u8>,
+}
+
+impl Field {
+    /// a varint field, for `int32`, `int64`, `uint32`, `uint64`, `bool` and enums
+    pub fn varint(number: u32, value: u64) -> Field {
+        Field {
+            number,
+            wire_type: VARINT,
+            value: encode_varint(value),
+        }
+    }
+
+    /// a zigzag-encoded varint field, for `sint32` and `sint64`
+    pub fn sint(number: u32, value: i64) -> Field {
+        Field::varint(number, ((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    /// a four byte field, for `fixed32`, `sfixed32` and `float`
+    pub fn fixed32(number: u32, value: [u8; 4]) -> Field {
+        Field {
+            number,
+            wire_type: FIXED32,
+            value: value.to_vec(),
+        }
+    }
+
+    /// an eight byte field, for `fixed64`, `sfixed64` and `double`
+    pub fn fixed64(number: u32, value: [u8; 8]) -> Field {
+        Field {
+            number,
+            wire_type: FIXED64,
+            value: value.to_vec(),
+        }
+    }
+
+    /// a length-delimited field, for `bytes`, `string` and packed repeated fields
+    pub fn bytes(number: u32, data: &[u8]) -> Field {
+        let mut value = encode_varint(data.len() as u64);
+        value.extend_from_slice(data);
+        Field {
+            number,
+            wire_type: LENGTH_DELIMITED,
+            value,
+        }
+    }
+
+    /// an embedded message made of the given fields
+    pub fn message(number: u32, fields: &[Field]) -> Field {
+        let data: Vec<u8> = fields.iter().flat_map(Field::to_bytes).collect();
+        Field::bytes(number, &data)
+    }
+
+    /// the encoded field: tag and wire value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = encode_varint(u64::from(self.number) << 3 | u64::from(self.wire_type));
+        out.extend_from_slice(&self.value);
+        out
+    }
+}
+
+/// inject a field into a serialized message without decoding it
+///
+/// `path` lists the field numbers of the embedded messages to descend into,
+/// taking the first occurrence of each; an empty path targets the top level.
+/// the field is placed in field number order, after any existing fields with the
+/// same number, and the lengths of every enclosing message are patched. fails
+/// with `NotFound` if a message along the path is missing.
+pub fn insert_field<R, W>(mut origin: R, target: W, path: &[u32], field: &Field) -> io::Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    if field.number == 0 || field.number >= 1 << 29 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "field numbers must be between 1 and 2^29 - 1",
+        ));
+    }
+    let end = origin.seek(SeekFrom::End(0))?;
+    origin.seek(SeekFrom::Start(0))?;
+    let mut scanner = Scanner {
+        reader: origin,
+        position: 0,
+    };
+
+    let mut lengths = Vec::with_capacity(path.len());
+    let mut limit = end;
+    for &number in path {
+        loop {
+            if scanner.position >= limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no embedded message for field {}", number),
+                ));
+            }
+            let (found, wire_type) = scanner.tag()?;
+            if found == number && wire_type == LENGTH_DELIMITED {
+                let start = scanner.position;
+                let len = scanner.varint()?;
+                lengths.push((start, scanner.position, len));
+                limit = scanner.position + len;
+                break;
+            }
+            scanner.skip(wire_type)?;
+        }
+    }
+
+    let mut position = limit;
+    while scanner.position < limit {
+        let before = scanner.position;
+        let (found, wire_type) = scanner.tag()?;
+        if found > field.number {
+            position = before;
+            break;
+        }
+        scanner.skip(wire_type)?;
+    }
+    if scanner.position > limit {
+        return Err(invalid("field overruns its message"));
+    }
+
+    let bytes = field.to_bytes();
+    let added = bytes.len() as u64;
+    let mut overwrites = Vec::new();
+    let mut insertions: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    // innermost first, as each length grows by the growth of those inside it
+    let mut growth = added;
+    for &(start, end, len) in lengths.iter().rev() {
+        let mut encoded = encode_varint(len + growth);
+        let old_len = (end - start) as usize;
+        if encoded.len() < old_len {
+            // a padded length keeps its width, continuing with zero groups
+            *encoded.last_mut().expect("varints aren't empty") |= 0x80;
+            encoded.resize(old_len - 1, 0x80);
+            encoded.push(0);
+        }
+        // varints only lengthen: overwrite in place, then insert any extra bytes
+        insertions
+            .entry(end)
+            .or_default()
+            .extend_from_slice(&encoded[old_len..]);
+        growth += (encoded.len() - old_len) as u64;
+        overwrites.push((start, encoded[..old_len].to_vec()));
+    }
+    insertions.entry(position).or_default().extend(bytes);
+
+    let mut origin = scanner.reader;
+    origin.seek(SeekFrom::Start(0))?;
+    let mut inserter = Inserter::new(origin, target);
+    for (start, patch) in &overwrites {
+        inserter = inserter.overwrite(*start as usize, patch);
+    }
+    for (at, data) in insertions {
+        inserter = inserter.insert(at as usize, io::Cursor::new(data));
+    }
+    inserter.execute()
+}
+
+/// a reader which tracks its position while walking fields
+struct Scanner<R> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: Read + Seek> Scanner<R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0_u8];
+        self.reader.read_exact(&mut byte)?;
+        self.position += 1;
+        Ok(byte[0])
+    }
+
+    fn varint(&mut self) -> io::Result<u64> {
+        let mut value = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("varint too long"))
+    }
+
+    fn tag(&mut self) -> io::Result<(u32, u8)> {
+        let tag = self.varint()?;
+        Ok(((tag >> 3) as u32, (tag & 7) as u8))
+    }
+
+    fn skip(&mut self, wire_type: u8) -> io::Result<()> {
+        let len = match wire_type {
+            VARINT => return self.varint().map(|_| ()),
+            FIXED64 => 8,
+            LENGTH_DELIMITED => self.varint()?,
+            FIXED32 => 4,
+            _ => return Err(invalid("unsupported wire type")),
+        };
+        self.position += len;
+        self.reader.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+}
+
+fn encode_varint(mut value: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(10);
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+    out
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn run(origin: &[u8], path: &[u32], field: &Field) -> io::Result<Vec<u8>> {
+        let mut dest = Vec::new();
+        insert_field(Cursor::new(origin), &mut dest, path, field)?;
+        Ok(dest)
+    }
+
+    #[test]
+    fn encodes_fields() {
+        assert_eq!(vec![0x08, 0x96, 0x01], Field::varint(1, 150).to_bytes());
+        assert_eq!(vec![0x10, 0x03], Field::sint(2, -2).to_bytes());
+        assert_eq!(
+            b"\x12\x07testing".to_vec(),
+            Field::bytes(2, b"testing").to_bytes()
+        );
+    }
+
+    #[test]
+    fn keeps_field_order() {
+        let mut origin = Field::varint(1, 1).to_bytes();
+        origin.extend(Field::varint(3, 3).to_bytes());
+        let out = run(&origin, &[], &Field::varint(2, 2)).unwrap();
+        assert_eq!(vec![0x08, 1, 0x10, 2, 0x18, 3], out);
+    }
+
+    #[test]
+    fn patches_nested_lengths() {
+        let inner = Field::message(4, &[Field::varint(1, 1)]);
+        let origin = Field::message(2, &[inner]).to_bytes();
+        let out = run(&origin, &[2, 4], &Field::bytes(2, b"x")).unwrap();
+        let expected = Field::message(
+            2,
+            &[Field::message(
+                4,
+                &[Field::varint(1, 1), Field::bytes(2, b"x")],
+            )],
+        );
+        assert_eq!(expected.to_bytes(), out);
+    }
+
+    #[test]
+    fn grows_length_varints() {
+        let inner = Field::message(4, &[Field::bytes(1, &[7; 120])]);
+        let origin = Field::message(2, &[inner]).to_bytes();
+        let out = run(&origin, &[2, 4], &Field::bytes(5, &[8; 10])).unwrap();
+        let expected = Field::message(
+            2,
+            &[Field::message(
+                4,
+                &[Field::bytes(1, &[7; 120]), Field::bytes(5, &[8; 10])],
+            )],
+        );
+        assert_eq!(expected.to_bytes(), out);
+    }
+
+    #[test]
+    fn keeps_padded_lengths() {
+        // field 2, with its length of 2 padded out to three bytes
+        let origin = vec![0x12, 0x82, 0x80, 0x00, 0x08, 0x01];
+        let out = run(&origin, &[2], &Field::varint(2, 2)).unwrap();
+        assert_eq!(vec![0x12, 0x84, 0x80, 0x00, 0x08, 0x01, 0x10, 0x02], out);
+    }
+
+    #[test]
+    fn missing_message() {
+        let origin = Field::varint(2, 5).to_bytes();
+        let err = run(&origin, &[2], &Field::varint(1, 1)).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
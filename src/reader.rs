@@ -0,0 +1,331 @@
+use segment::Piece;
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+};
+
+/// adds insertion combinators to every reader
+pub trait ReadInsertExt: Read + Sized {
+    /// splice the source into this reader at the given index
+    ///
+    /// the result is itself a reader, and further insertions on it are also
+    /// at indices into this reader
+    fn insert_at<'i, I: 'i + Read>(self, position: usize, source: I) -> Spliced<'i, Self> {
+        Spliced::new(self).insert_at(position, source)
+    }
+}
+
+impl<R: Read> ReadInsertExt for R {}
+
+/// a reader which splices insertions into an origin as it's read
+///
+/// unlike `Inserter`, this pulls data through on demand rather than pushing
+/// it to a writer. several insertions at the same index are all kept, in the
+/// order they were added. insertions past the end of the origin are appended.
+pub struct Spliced<'i, R> {
+    origin: R,
+    position: usize,
+    exhausted: bool,
+    insertions: VecDeque<(usize, Box<dyn 'i + Read>)>,
+}
+
+impl<'i, R: Read> Spliced<'i, R> {
+    /// create a new reader with the specified origin and no insertions
+    pub fn new(origin: R) -> Spliced<'i, R> {
+        Spliced {
+            origin,
+            position: 0,
+            exhausted: false,
+            insertions: VecDeque::new(),
+        }
+    }
+
+    /// splice the source into the output at the given origin index
+    ///
+    /// an index which has already been read past is inserted at the next read
+    pub fn insert_at<I: 'i + Read>(mut self, position: usize, source: I) -> Self {
+        let idx = self
+            .insertions
+            .iter()
+            .position(|&(other, _)| other > position)
+            .unwrap_or(self.insertions.len());
+        self.insertions.insert(idx, (position, Box::new(source)));
+        self
+    }
+
+    /// recover the origin reader, dropping any insertions not yet read
+    pub fn into_inner(self) -> R {
+        self.origin
+    }
+}
+
+impl<'i, R: Read> Read for Spliced<'i, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let next = self.insertions.front().map(|&(position, _)| position);
+            match next {
+                Some(position) if self.exhausted || position <= self.position => {
+                    let (_, ref mut source) = self.insertions[0];
+                    match source.read(buf)? {
+                        0 => {
+                            self.insertions.pop_front();
+                        }
+                        bytes_read => return Ok(bytes_read),
+                    }
+                }
+                _ if self.exhausted => return Ok(0),
+                next => {
+                    let limit = next.map_or(buf.len(), |position| {
+                        buf.len().min(position - self.position)
+                    });
+                    match self.origin.read(&mut buf[..limit])? {
+                        0 => self.exhausted = true,
+                        bytes_read => {
+                            self.position += bytes_read;
+                            return Ok(bytes_read);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// a plan compiled into a single reader, which pulls each insertion and
+/// each run of the origin through in turn
+///
+/// nothing is buffered: skipping a deleted run reads it into the caller's
+/// buffer and discards it. created by `PlanBuilder::into_chained_reader`
+pub struct Chained<'i, R> {
+    origin: R,
+    position: usize,
+    pieces: VecDeque<Piece<Box<dyn 'i + Read>>>,
+}
+
+impl<'i, R: Read> Chained<'i, R> {
+    pub(crate) fn new(origin: R, pieces: Vec<Piece<Box<dyn 'i + Read>>>) -> Chained<'i, R> {
+        Chained {
+            origin,
+            position: 0,
+            pieces: pieces.into(),
+        }
+    }
+
+    /// recover the origin reader, dropping whatever hasn't been read
+    pub fn into_inner(self) -> R {
+        self.origin
+    }
+}
+
+impl<'i, R: Read> Read for Chained<'i, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(piece) = self.pieces.front_mut() {
+            match *piece {
+                Piece::Insertion(ref mut source) => match source.read(buf)? {
+                    0 => {}
+                    bytes_read => return Ok(bytes_read),
+                },
+                Piece::Origin(end) | Piece::Skip(end) if self.position < end => {
+                    let limit = buf.len().min(end - self.position);
+                    let bytes_read = self.origin.read(&mut buf[..limit])?;
+                    self.position += bytes_read;
+                    match *piece {
+                        _ if bytes_read == 0 => {
+                            // the origin has ended, so only insertions remain
+                            self.pieces
+                                .retain(|piece| matches!(piece, Piece::Insertion(_)));
+                            continue;
+                        }
+                        Piece::Origin(_) => return Ok(bytes_read),
+                        _ => continue,
+                    }
+                }
+                _ => {}
+            }
+            self.pieces.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+/// a reader over several parts, one after another
+///
+/// errors are annotated with the index of the part they came from
+pub struct Concat<R> {
+    parts: Vec<R>,
+    current: usize,
+}
+
+impl<R: Read> Concat<R> {
+    /// create a new reader over the parts, in order
+    pub fn new<I: IntoIterator<Item = R>>(parts: I) -> Concat<R> {
+        Concat {
+            parts: parts.into_iter().collect(),
+            current: 0,
+        }
+    }
+
+    /// the index of the part currently being read
+    pub fn part(&self) -> usize {
+        self.current
+    }
+
+    /// recover the parts
+    pub fn into_parts(self) -> Vec<R> {
+        self.parts
+    }
+}
+
+impl<R: Read> Read for Concat<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while let Some(part) = self.parts.get_mut(self.current) {
+            match part.read(buf) {
+                Ok(0) => self.current += 1,
+                Ok(bytes_read) => return Ok(bytes_read),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    return Err(io::ErrorKind::Interrupted.into())
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!("origin part {}: {}", self.current, e),
+                    ))
+                }
+            }
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::InsertSource;
+    use {Inserter, PlanBuilder};
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+    }
+
+    fn read(mut reader: impl Read) -> Vec<u8> {
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn combinators() {
+        let out = read(
+            (&b"alpha charlie"[..])
+                .insert_at(100, &b"!"[..])
+                .insert_at(6, &b"bravo "[..])
+                .insert_at(0, &b"> "[..]),
+        );
+        assert_eq!(b"> alpha bravo charlie!".to_vec(), out);
+    }
+
+    #[test]
+    fn keeps_insertions_at_same_index_in_order() {
+        let out = read((&b"ad"[..]).insert_at(1, &b"b"[..]).insert_at(1, &b"c"[..]));
+        assert_eq!(b"abcd".to_vec(), out);
+    }
+
+    #[test]
+    fn small_reads() {
+        let mut spliced = (&b"abcdef"[..])
+            .insert_at(3, &b"123"[..])
+            .insert_at(5, &b""[..]);
+        let mut out = Vec::new();
+        let mut buf = [0; 2];
+        loop {
+            match spliced.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(b"abc123def".to_vec(), out);
+    }
+
+    #[test]
+    fn concatenates_parts() {
+        let mut dest = Vec::new();
+        Inserter::new_multi(vec![&b"alpha "[..], &b""[..], &b"charlie"[..]], &mut dest)
+            .insert(6, "bravo ")
+            .execute()
+            .unwrap();
+        assert_eq!(b"alpha bravo charlie".to_vec(), dest);
+    }
+
+    #[test]
+    fn annotates_part_errors() {
+        let failing: Vec<Box<dyn Read>> = vec![Box::new(&b"ok"[..]), Box::new(Failing)];
+        let err = Inserter::new_multi(failing, io::sink())
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::Other, err.kind());
+        assert_eq!("origin part 1: broken", err.to_string());
+    }
+
+    #[test]
+    fn nests_in_pipelines() {
+        let inner = (&b"bc"[..]).insert_at(1, &b"-"[..]);
+        let out = read((&b"ad"[..]).insert_at(1, inner).take(4));
+        assert_eq!(b"ab-c".to_vec(), out);
+    }
+
+    #[test]
+    fn chains_plans() {
+        let plan = || {
+            PlanBuilder::new()
+                .insert(0, "> ")
+                .delete(5..8)
+                .insert(6, InsertSource::reader(&b"[deleted]"[..]))
+                .replace(12..13, "E")
+                .insert(12, "!")
+                .insert(100, " end")
+        };
+        let origin = b"alpha bravo charlie";
+        let expected = plan().execute_to_vec(origin).unwrap();
+        let mut chained = plan().into_chained_reader(&origin[..]).unwrap();
+        let mut out = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match chained.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(expected, out);
+        assert_eq!(b"> alpha[deleted]avo E!harlie end".to_vec(), out);
+    }
+
+    #[test]
+    fn chains_only_what_it_can() {
+        let err = PlanBuilder::new()
+            .overwrite(0, b"x")
+            .into_chained_reader(&b"abc"[..])
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = PlanBuilder::new()
+            .delete(0..2)
+            .delete(1..3)
+            .into_chained_reader(&b"abc"[..])
+            .err()
+            .unwrap();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+}
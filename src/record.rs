@@ -0,0 +1,227 @@
+use inserter::Inserter;
+use operation::Operation;
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    io::{self, Read, Write},
+    ops::Range,
+};
+
+/// an edit in record indices
+enum Edit<'i> {
+    Insert(usize, InsertSource<'i>),
+    Delete(Range<usize>),
+    Overwrite(usize, Vec<u8>),
+}
+
+/// inserts whole records into a file of fixed-size records, such as an old
+/// database table or a sensor dump
+///
+/// positions are record indices rather than byte offsets. every insertion and
+/// overwrite must be a whole number of records, as must the origin: in-memory
+/// sources are checked before anything is written, and readers as they're
+/// read, failing with `InvalidData` at the end of one which isn't.
+pub struct RecordInserter<'i, R, W> {
+    origin: R,
+    target: W,
+    record_size: usize,
+    edits: Vec<Edit<'i>>,
+}
+
+impl<'i, R, W> RecordInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new inserter for records of this many bytes
+    pub fn new(origin: R, target: W, record_size: usize) -> RecordInserter<'i, R, W> {
+        RecordInserter {
+            origin,
+            target,
+            record_size,
+            edits: Vec::new(),
+        }
+    }
+
+    /// insert the source's records before the record at this index
+    pub fn insert<I: IntoInsertSource<'i>>(mut self, record: usize, source: I) -> Self {
+        self.edits
+            .push(Edit::Insert(record, source.into_insert_source()));
+        self
+    }
+
+    /// drop the records in this range of indices
+    pub fn delete(mut self, records: Range<usize>) -> Self {
+        self.edits.push(Edit::Delete(records));
+        self
+    }
+
+    /// replace the records in this range of indices with the source's records
+    pub fn replace<I: IntoInsertSource<'i>>(self, records: Range<usize>, source: I) -> Self {
+        let start = records.start;
+        self.delete(records).insert(start, source)
+    }
+
+    /// overwrite records from this index onwards with the bytes
+    pub fn overwrite(mut self, record: usize, bytes: &[u8]) -> Self {
+        self.edits.push(Edit::Overwrite(record, bytes.to_vec()));
+        self
+    }
+
+    /// the byte offset of a record, failing with `InvalidInput` on overflow
+    fn offset(&self, record: usize) -> io::Result<usize> {
+        record.checked_mul(self.record_size).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record {} is past the largest offset", record),
+            )
+        })
+    }
+
+    /// fail with `InvalidInput` unless the length is a whole number of records
+    fn check(&self, len: u64, what: &str) -> io::Result<()> {
+        if !len.is_multiple_of(self.record_size as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} of {} bytes isn't a whole number of {} byte records",
+                    what, len, self.record_size
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// execute this inserter, consuming it
+    ///
+    /// fails with `InvalidInput` if the record size is zero
+    pub fn execute(mut self) -> io::Result<()> {
+        if self.record_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zero record size",
+            ));
+        }
+        let mut operations = Vec::with_capacity(self.edits.len());
+        for edit in std::mem::take(&mut self.edits) {
+            operations.push(match edit {
+                Edit::Insert(record, source) => {
+                    let source = match source.len_hint() {
+                        Some(len) => {
+                            self.check(len, "insertion")?;
+                            source
+                        }
+                        None => InsertSource::reader(Whole {
+                            inner: source.into_reader(),
+                            record_size: self.record_size as u64,
+                            len: 0,
+                            what: "insertion",
+                        }),
+                    };
+                    Operation::Insert(self.offset(record)?, source)
+                }
+                Edit::Delete(records) => {
+                    Operation::Delete(self.offset(records.start)?..self.offset(records.end)?)
+                }
+                Edit::Overwrite(record, bytes) => {
+                    self.check(bytes.len() as u64, "overwrite")?;
+                    Operation::Overwrite(self.offset(record)?, bytes)
+                }
+            });
+        }
+        let origin = Whole {
+            inner: self.origin,
+            record_size: self.record_size as u64,
+            len: 0,
+            what: "origin",
+        };
+        let mut inserter = Inserter::new(origin, self.target);
+        for operation in operations {
+            inserter = inserter.operation(operation);
+        }
+        inserter.execute()
+    }
+}
+
+/// a reader which fails with `InvalidData` at its end unless it's produced a
+/// whole number of records
+struct Whole<R> {
+    inner: R,
+    record_size: u64,
+    len: u64,
+    what: &'static str,
+}
+
+impl<R: Read> Read for Whole<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.len += len as u64;
+        if len == 0 && !buf.is_empty() && !self.len.is_multiple_of(self.record_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} of {} bytes isn't a whole number of {} byte records",
+                    self.what, self.len, self.record_size
+                ),
+            ));
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECORDS: &[u8] = b"AAAABBBBCCCCDDDD";
+
+    #[test]
+    fn addresses_records() {
+        let mut out = Vec::new();
+        RecordInserter::new(RECORDS, &mut out, 4)
+            .insert(1, "xxxxyyyy")
+            .replace(2..3, "zzzz")
+            .overwrite(3, b"dddd")
+            .insert(100, "eeee")
+            .execute()
+            .unwrap();
+        assert_eq!(b"AAAAxxxxyyyyBBBBzzzzddddeeee".to_vec(), out);
+    }
+
+    #[test]
+    fn rejects_partial_records() {
+        let err = RecordInserter::new(RECORDS, io::sink(), 4)
+            .insert(1, "xxx")
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = RecordInserter::new(RECORDS, io::sink(), 4)
+            .overwrite(1, b"xxxxx")
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = RecordInserter::new(RECORDS, io::sink(), 0)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    fn checks_readers_as_they_are_read() {
+        let err = RecordInserter::new(RECORDS, io::sink(), 4)
+            .insert(1, InsertSource::reader(&b"xxxxyy"[..]))
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let err = RecordInserter::new(&RECORDS[..15], io::sink(), 4)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        let mut out = Vec::new();
+        RecordInserter::new(RECORDS, &mut out, 8)
+            .insert(1, InsertSource::reader(&b"xxxxyyyy"[..]))
+            .execute()
+            .unwrap();
+        assert_eq!(b"AAAABBBBxxxxyyyyCCCCDDDD".to_vec(), out);
+    }
+}
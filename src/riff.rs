@@ -0,0 +1,198 @@
+use inserter::{Endian, Inserter};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// a chunk to be inserted into a RIFF file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    id: [u8; 4],
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    /// create a chunk with the given four-character code
+    pub fn new(id: [u8; 4], data: Vec<u8>) -> Chunk {
+        Chunk { id, data }
+    }
+
+    /// create a `LIST` chunk of type `INFO` holding the given metadata
+    ///
+    /// tags are four-character codes such as `INAM` (title) or `IART` (artist)
+    pub fn info(tags: &[([u8; 4], &str)]) -> Chunk {
+        let mut data = b"INFO".to_vec();
+        for (tag, value) in tags {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            data.extend(Chunk::new(*tag, value).to_bytes());
+        }
+        Chunk::new(*b"LIST", data)
+    }
+
+    /// the framed chunk: id, little-endian size, data, and a pad byte if needed
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.data.len() + 9);
+        out.extend_from_slice(&self.id);
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+        if self.data.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+}
+
+/// where to place a new chunk among the top-level chunks of a RIFF file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// after every existing chunk
+    End,
+    /// before the first chunk with the given id
+    Before([u8; 4]),
+    /// after the first chunk with the given id
+    After([u8; 4]),
+}
+
+/// insert a chunk into a RIFF file such as a WAV or AVI
+///
+/// the top-level chunk headers are scanned to find the insertion point, then the
+/// file is streamed through an `Inserter` which splices in the new chunk and
+/// fixes up the enclosing `RIFF` size field to account for it. fails with
+/// `NotFound` if the placement names a chunk which doesn't exist.
+pub fn insert_chunk<R, W>(
+    mut origin: R,
+    target: W,
+    chunk: &Chunk,
+    placement: Placement,
+) -> io::Result<()>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    origin.seek(SeekFrom::Start(0))?;
+    let mut header = [0_u8; 12];
+    origin.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a RIFF file",
+        ));
+    }
+    let riff_size = u64::from(u32::from_le_bytes([
+        header[4], header[5], header[6], header[7],
+    ]));
+    let riff_end = 8 + riff_size;
+
+    let position = match placement {
+        Placement::End => riff_end,
+        Placement::Before(id) | Placement::After(id) => {
+            let mut offset = 12;
+            loop {
+                if offset + 8 > riff_end {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no `{}` chunk", String::from_utf8_lossy(&id)),
+                    ));
+                }
+                let mut chunk_header = [0_u8; 8];
+                origin.seek(SeekFrom::Start(offset))?;
+                origin.read_exact(&mut chunk_header)?;
+                let size = u64::from(u32::from_le_bytes([
+                    chunk_header[4],
+                    chunk_header[5],
+                    chunk_header[6],
+                    chunk_header[7],
+                ]));
+                let next = offset + 8 + size + size % 2;
+                if chunk_header[..4] == id {
+                    break match placement {
+                        Placement::Before(_) => offset,
+                        _ => next.min(riff_end),
+                    };
+                }
+                offset = next;
+            }
+        }
+    };
+
+    let bytes = chunk.to_bytes();
+    if riff_size + bytes.len() as u64 > u64::from(u32::MAX) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "RIFF file would exceed 4 GiB",
+        ));
+    }
+
+    origin.seek(SeekFrom::Start(0))?;
+    Inserter::new(origin, target)
+        .fixup(4, Endian::Little, 8..riff_end as usize)
+        .insert(position as usize, bytes.as_slice())
+        .execute()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn wave() -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        body.extend(Chunk::new(*b"fmt ", vec![1; 16]).to_bytes());
+        body.extend(Chunk::new(*b"data", vec![2; 5]).to_bytes());
+        let mut riff = b"RIFF".to_vec();
+        riff.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        riff.extend(body);
+        riff
+    }
+
+    fn chunk_ids(riff: &[u8]) -> Vec<String> {
+        let size = u32::from_le_bytes([riff[4], riff[5], riff[6], riff[7]]) as usize;
+        assert_eq!(riff.len(), size + 8, "RIFF size field");
+        let mut ids = Vec::new();
+        let mut at = 12;
+        while at < riff.len() {
+            ids.push(String::from_utf8_lossy(&riff[at..at + 4]).into_owned());
+            let len = u32::from_le_bytes([riff[at + 4], riff[at + 5], riff[at + 6], riff[at + 7]]);
+            at += 8 + len as usize + len as usize % 2;
+        }
+        ids
+    }
+
+    fn run(placement: Placement) -> io::Result<Vec<u8>> {
+        let mut dest = Vec::new();
+        let chunk = Chunk::info(&[(*b"INAM", "title")]);
+        insert_chunk(Cursor::new(wave()), &mut dest, &chunk, placement)?;
+        Ok(dest)
+    }
+
+    #[test]
+    fn appends_and_patches_size() {
+        let out = run(Placement::End).unwrap();
+        assert_eq!(vec!["fmt ", "data", "LIST"], chunk_ids(&out));
+    }
+
+    #[test]
+    fn inserts_before_data() {
+        let out = run(Placement::Before(*b"data")).unwrap();
+        assert_eq!(vec!["fmt ", "LIST", "data"], chunk_ids(&out));
+    }
+
+    #[test]
+    fn inserts_after_padded_chunk() {
+        let out = run(Placement::After(*b"data")).unwrap();
+        assert_eq!(vec!["fmt ", "data", "LIST"], chunk_ids(&out));
+    }
+
+    #[test]
+    fn info_chunk_layout() {
+        let chunk = Chunk::info(&[(*b"IART", "abc")]);
+        assert_eq!(
+            b"LIST\x10\0\0\0INFOIART\x04\0\0\0abc\0".to_vec(),
+            chunk.to_bytes()
+        );
+    }
+
+    #[test]
+    fn missing_chunk() {
+        let err = run(Placement::Before(*b"JUNK")).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+    }
+}
@@ -0,0 +1,103 @@
+use ropey::Rope;
+use std::io;
+use string_inserter::Error;
+
+type Insertions<'i> = Vec<(usize, &'i str)>;
+
+/// rope inserter applies insertions to a rope in place
+///
+/// each insertion is an O(log n) edit, so a document can go through many
+/// rounds of insertions without being copied. positions are byte indices into
+/// the rope as it was before this round, like those of `StringInserter`.
+/// several insertions at the same index are all kept, in the order they were
+/// added.
+pub struct RopeInserter<'r, 'i> {
+    rope: &'r mut Rope,
+    insertions: Insertions<'i>,
+}
+
+impl<'r, 'i> RopeInserter<'r, 'i> {
+    /// create a new inserter editing the given rope
+    pub fn new(rope: &'r mut Rope) -> RopeInserter<'r, 'i> {
+        RopeInserter {
+            rope,
+            insertions: Insertions::new(),
+        }
+    }
+
+    /// insert the source text at the given byte index of the original rope
+    pub fn insert(mut self, position: usize, source: &'i str) -> Self {
+        self.insertions.push((position, source));
+        self
+    }
+
+    /// execute this inserter, consuming it
+    ///
+    /// fails without editing the rope if any position isn't on a char boundary
+    pub fn execute(mut self) -> Result<(), Error> {
+        let len = self.rope.len_bytes();
+        let mut edits = Vec::with_capacity(self.insertions.len());
+        // a stable sort keeps same-index insertions in the order they were added
+        self.insertions.sort_by_key(|&(position, _)| position);
+        for (position, source) in self.insertions {
+            let position = position.min(len);
+            let char_index = self.rope.byte_to_char(position);
+            if self.rope.char_to_byte(char_index) != position {
+                return Err(Error::IoError(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("byte index {} is not a char boundary", position),
+                )));
+            }
+            edits.push((char_index, source));
+        }
+        // working backwards leaves the remaining char indices valid
+        for (char_index, source) in edits.into_iter().rev() {
+            self.rope.insert(char_index, source);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use StringInserter;
+
+    #[test]
+    fn repeated_rounds() {
+        let mut rope = Rope::from_str("alpha delta");
+        RopeInserter::new(&mut rope)
+            .insert(6, "bravo ")
+            .insert(6, "charlie ")
+            .execute()
+            .unwrap();
+        RopeInserter::new(&mut rope)
+            .insert(0, "> ")
+            .insert(100, "!")
+            .execute()
+            .unwrap();
+        assert_eq!("> alpha bravo charlie delta!", rope.to_string());
+    }
+
+    #[test]
+    fn multibyte_positions() {
+        let mut rope = Rope::from_str("naïve café");
+        RopeInserter::new(&mut rope)
+            .insert(7, "and ")
+            .execute()
+            .unwrap();
+        assert_eq!("naïve and café", rope.to_string());
+        let err = RopeInserter::new(&mut rope).insert(3, "x").execute();
+        assert!(err.is_err());
+        assert_eq!("naïve and café", rope.to_string());
+    }
+
+    #[test]
+    fn string_inserter_to_rope() {
+        let rope = StringInserter::new("alpha charlie")
+            .insert(6, "bravo ")
+            .execute_rope()
+            .unwrap();
+        assert_eq!("alpha bravo charlie", rope.to_string());
+    }
+}
@@ -0,0 +1,210 @@
+//! file conveniences confined to a directory
+//!
+//! a plan from an untrusted user can name any path in its file sources. these
+//! helpers resolve every path within a `cap_std::fs::Dir` instead, so that
+//! absolute paths, `..` and symlinks leading out of it all fail, and a host
+//! decides which directory a plan may touch.
+
+use cap_std::fs::{Dir, File, OpenOptions};
+use in_place::InPlaceInserter;
+use plan::{InsertionPlan, PlannedInsertion, Source};
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use util::temp_path;
+
+impl Source {
+    /// open a reader over the content, resolving a file within the directory
+    pub fn open_in(&self, dir: &Dir) -> io::Result<Box<dyn Read>> {
+        match *self {
+            Source::File(ref path) => Ok(Box::new(BufReader::new(dir.open(path)?))),
+            Source::Text(_) => self.open(),
+        }
+    }
+
+    /// the length of the content in bytes, resolving a file within the
+    /// directory
+    pub fn size_in(&self, dir: &Dir) -> io::Result<u64> {
+        match *self {
+            Source::File(ref path) => Ok(dir.metadata(path)?.len()),
+            Source::Text(_) => self.size(),
+        }
+    }
+}
+
+impl PlannedInsertion {
+    /// open a reader over the content as `open` does, resolving a file within
+    /// the directory
+    pub fn open_in(&self, dir: &Dir) -> io::Result<Box<dyn Read>> {
+        self.verify(self.source.open_in(dir)?)
+    }
+}
+
+impl InsertionPlan {
+    /// apply the plan as `apply` does, resolving file sources within the
+    /// directory
+    pub fn apply_in<R, W>(&self, dir: &Dir, origin: R, target: W) -> io::Result<()>
+    where
+        R: Read + io::Seek,
+        W: Write,
+    {
+        self.apply_with(origin, target, |insertion| insertion.open_in(dir))
+    }
+
+    /// replace a file in the directory with the plan applied to it
+    ///
+    /// the output is written to a sibling of the file and renamed over it, so
+    /// the file is either edited or untouched, never partly written. the
+    /// sibling takes the file's permissions first
+    pub fn rewrite_in<P: AsRef<Path>>(&self, dir: &Dir, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let temp = temp_path(path);
+        let origin = dir.open(path)?;
+        let permissions = origin.metadata()?.permissions();
+        let file = dir.open_with(&temp, OpenOptions::new().write(true).create_new(true))?;
+        let mut target = BufWriter::new(file);
+        let result = self
+            .apply_in(dir, BufReader::new(origin), &mut target)
+            .and_then(|()| {
+                let file = target.into_inner()?;
+                file.set_permissions(permissions)?;
+                file.sync_all()?;
+                dir.rename(&temp, dir, path)
+            });
+        if result.is_err() {
+            let _ = dir.remove_file(&temp);
+        }
+        result
+    }
+}
+
+impl<'i> InPlaceInserter<'i, File> {
+    /// open a file in the directory for reading and writing, to insert into in
+    /// place
+    pub fn open_in<P: AsRef<Path>>(dir: &Dir, path: P) -> io::Result<Self> {
+        let file = dir.open_with(path, OpenOptions::new().read(true).write(true))?;
+        Ok(InPlaceInserter::new(file))
+    }
+}
+
+/// every regular file in the directory and its subdirectories, as paths
+/// relative to it, sorted
+///
+/// symlinks are skipped rather than followed, so the walk never leaves the
+/// directory
+pub fn walk(dir: &Dir) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    walk_into(dir, Path::new(""), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn walk_into(dir: &Dir, prefix: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in dir.entries()? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let path = prefix.join(entry.file_name());
+        if file_type.is_dir() {
+            walk_into(&entry.open_dir()?, &path, files)?;
+        } else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std::ambient_authority;
+    use plan::Position;
+    use std::{fs, process};
+
+    fn temp_dir(name: &str) -> (PathBuf, Dir) {
+        let path = ::std::env::temp_dir().join(format!("{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("sub")).unwrap();
+        let dir = Dir::open_ambient_dir(&path, ambient_authority()).unwrap();
+        (path, dir)
+    }
+
+    #[test]
+    fn resolves_sources_within_the_dir() {
+        let (path, dir) = temp_dir("sandbox-sources");
+        dir.write("sub/payload", "payload").unwrap();
+        dir.write("page", "<head></head>").unwrap();
+        let plan = InsertionPlan::new().insert(
+            Position::After("<head>".into()),
+            Source::File("sub/payload".into()),
+        );
+        plan.rewrite_in(&dir, "page").unwrap();
+        assert_eq!(b"<head>payload</head>".to_vec(), dir.read("page").unwrap());
+        assert_eq!(
+            vec![PathBuf::from("page"), PathBuf::from("sub/payload")],
+            walk(&dir).unwrap()
+        );
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_dir() {
+        let (path, dir) = temp_dir("sandbox-escape");
+        let outside = ::std::env::temp_dir().join(format!("sandbox-outside-{}", process::id()));
+        fs::write(&outside, "secret").unwrap();
+        dir.write("page", "text").unwrap();
+        let mut escapes = vec![
+            outside.clone(),
+            Path::new("..").join(outside.file_name().unwrap()),
+        ];
+        #[cfg(unix)]
+        {
+            ::std::os::unix::fs::symlink(&outside, path.join("link")).unwrap();
+            escapes.push("link".into());
+        }
+        for escape in escapes {
+            let plan = InsertionPlan::new().insert(Position::Offset(0), Source::File(escape));
+            assert!(plan.rewrite_in(&dir, "page").is_err());
+        }
+        assert_eq!(b"text".to_vec(), dir.read("page").unwrap());
+        assert_eq!(vec![PathBuf::from("page")], walk(&dir).unwrap());
+        fs::remove_file(outside).unwrap();
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let (path, dir) = temp_dir("sandbox-permissions");
+        dir.write("script", "echo hi\n").unwrap();
+        fs::set_permissions(path.join("script"), fs::Permissions::from_mode(0o750)).unwrap();
+        let plan =
+            InsertionPlan::new().insert(Position::Offset(0), Source::Text("#!/bin/sh\n".into()));
+        plan.rewrite_in(&dir, "script").unwrap();
+        assert_eq!(
+            b"#!/bin/sh\necho hi\n".to_vec(),
+            dir.read("script").unwrap()
+        );
+        let mode = fs::metadata(path.join("script"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o750, mode & 0o777);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn inserts_in_place_within_the_dir() {
+        let (path, dir) = temp_dir("sandbox-in-place");
+        dir.write("file", "acd").unwrap();
+        InPlaceInserter::open_in(&dir, "file")
+            .unwrap()
+            .insert(1, "b")
+            .execute()
+            .unwrap();
+        assert_eq!(b"abcd".to_vec(), dir.read("file").unwrap());
+        assert!(InPlaceInserter::open_in(&dir, "../file").is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+/// incremental matcher for a fixed byte pattern, fed one byte at a time
+///
+/// this is a plain Knuth-Morris-Pratt automaton, so matches spanning buffer
+/// boundaries are found without any lookahead
+#[derive(Debug, Clone)]
+pub(crate) struct Finder {
+    needle: Vec<u8>,
+    failure: Vec<usize>,
+    matched: usize,
+}
+
+impl Finder {
+    /// create a finder for the given needle, which must not be empty
+    pub fn new(needle: &[u8]) -> Finder {
+        assert!(!needle.is_empty(), "cannot search for an empty pattern");
+        let mut failure = vec![0; needle.len()];
+        let mut k = 0;
+        for i in 1..needle.len() {
+            while k > 0 && needle[i] != needle[k] {
+                k = failure[k - 1];
+            }
+            if needle[i] == needle[k] {
+                k += 1;
+            }
+            failure[i] = k;
+        }
+        Finder {
+            needle: needle.to_vec(),
+            failure,
+            matched: 0,
+        }
+    }
+
+    /// feed a byte, returning true if it completes a match
+    ///
+    /// after a complete match the finder starts over; matches never overlap
+    pub fn feed(&mut self, byte: u8) -> bool {
+        while self.matched > 0 && self.needle[self.matched] != byte {
+            self.matched = self.failure[self.matched - 1];
+        }
+        if self.needle[self.matched] == byte {
+            self.matched += 1;
+        }
+        if self.matched == self.needle.len() {
+            self.matched = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// feed a byte, pushing onto `out` whichever input bytes can no longer
+    /// be part of a match
+    ///
+    /// held-back bytes are always a prefix of the needle, so they need not be
+    /// stored separately. returns true if the byte completes a match, in which
+    /// case the matched bytes are not emitted.
+    pub fn feed_through(&mut self, byte: u8, out: &mut Vec<u8>) -> bool {
+        let prev = self.matched;
+        if self.feed(byte) {
+            return true;
+        }
+        let emit = prev + 1 - self.matched;
+        if emit > prev {
+            out.extend_from_slice(&self.needle[..prev]);
+            out.push(byte);
+        } else {
+            out.extend_from_slice(&self.needle[..emit]);
+        }
+        false
+    }
+
+    /// the bytes currently held back as a partial match
+    pub fn pending(&self) -> &[u8] {
+        &self.needle[..self.matched]
+    }
+
+    /// forget any partial match
+    pub fn reset(&mut self) {
+        self.matched = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passthrough(needle: &[u8], haystack: &[u8]) -> (Vec<u8>, usize) {
+        let mut finder = Finder::new(needle);
+        let mut out = Vec::new();
+        let mut matches = 0;
+        for &byte in haystack {
+            if finder.feed_through(byte, &mut out) {
+                matches += 1;
+            }
+        }
+        out.extend_from_slice(finder.pending());
+        (out, matches)
+    }
+
+    #[test]
+    fn finds_overlapping_prefix() {
+        let (out, matches) = passthrough(b"aab", b"aaab");
+        assert_eq!(b"a", out.as_slice());
+        assert_eq!(1, matches);
+    }
+
+    #[test]
+    fn passes_through_non_matches() {
+        let (out, matches) = passthrough(b"{{", b"a { b {");
+        assert_eq!(b"a { b {", out.as_slice());
+        assert_eq!(0, matches);
+    }
+}
@@ -0,0 +1,193 @@
+//! maps of a plan's output, for callers driving their own i/o
+//!
+//! rather than writing the output, a plan can describe it as runs of the
+//! origin and the insertions between them, in order. a caller can then hand
+//! those to `writev`, `sendfile` or `copy_file_range`, or build an index of
+//! where each insertion lands, without the bytes passing through this crate.
+
+use std::{io, ops::Range};
+
+/// a run of the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// this range of the origin, as it is
+    Origin(Range<usize>),
+    /// the source of the plan's operation at this index, and its length if
+    /// known up front
+    Insertion { id: usize, len: Option<u64> },
+}
+
+impl Segment {
+    /// the number of bytes this segment contributes to the output, if known
+    pub fn len(&self) -> Option<u64> {
+        match *self {
+            Segment::Origin(ref range) => Some(range.len() as u64),
+            Segment::Insertion { len, .. } => len,
+        }
+    }
+
+    /// whether this segment is known to contribute nothing
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+/// one step through the origin and insertions
+pub(crate) enum Piece<T> {
+    /// pass the origin through up to this index
+    Origin(usize),
+    /// drop the origin up to this index
+    Skip(usize),
+    Insertion(T),
+}
+
+/// interleave the insertions, sorted by index, with runs of the origin kept
+/// or skipped around the deletions
+///
+/// the last piece passes the rest of the origin through. fails with
+/// `InvalidInput` if the deletions overlap
+pub(crate) fn lay_out<T>(
+    insertions: Vec<(usize, T)>,
+    mut deletions: Vec<Range<usize>>,
+) -> io::Result<Vec<Piece<T>>> {
+    deletions.retain(|range| range.start < range.end);
+    deletions.sort_by_key(|range| range.start);
+    if deletions.windows(2).any(|pair| pair[1].start < pair[0].end) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "overlapping deletions",
+        ));
+    }
+    let mut pieces = Vec::with_capacity(2 * (insertions.len() + deletions.len()) + 1);
+    let mut deletions = deletions.into_iter().peekable();
+    let mut position = 0;
+    // alternate between passing and skipping the origin up to the index
+    let mut advance = |pieces: &mut Vec<Piece<T>>, to: usize| {
+        while position < to {
+            match deletions.peek() {
+                Some(range) if range.start <= position => {
+                    position = range.end.min(to);
+                    pieces.push(Piece::Skip(position));
+                    if position == range.end {
+                        deletions.next();
+                    }
+                }
+                next => {
+                    position = next.map_or(to, |range| range.start.min(to));
+                    pieces.push(Piece::Origin(position));
+                }
+            }
+        }
+    };
+    for (position, insertion) in insertions {
+        advance(&mut pieces, position);
+        pieces.push(Piece::Insertion(insertion));
+    }
+    advance(&mut pieces, usize::MAX);
+    Ok(pieces)
+}
+
+/// the segments of the output for an origin of this length, merging adjacent
+/// runs of the origin and dropping empty ones
+pub(crate) fn segments(
+    pieces: Vec<Piece<(usize, Option<u64>)>>,
+    origin_len: usize,
+) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::with_capacity(pieces.len());
+    let mut position = 0;
+    for piece in pieces {
+        match piece {
+            Piece::Origin(end) => {
+                let end = end.min(origin_len);
+                if position < end {
+                    match segments.last_mut() {
+                        Some(Segment::Origin(ref mut range)) if range.end == position => {
+                            range.end = end
+                        }
+                        _ => segments.push(Segment::Origin(position..end)),
+                    }
+                }
+                position = position.max(end);
+            }
+            Piece::Skip(end) => position = position.max(end.min(origin_len)),
+            Piece::Insertion((id, len)) => segments.push(Segment::Insertion { id, len }),
+        }
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PlanBuilder;
+
+    #[test]
+    fn maps_output() {
+        let plan = PlanBuilder::new()
+            .insert(6, "bravo ")
+            .delete(0..2)
+            .replace(8..9, "H")
+            .insert(100, "!");
+        let segments = plan.segments(13).unwrap();
+        assert_eq!(
+            vec![
+                Segment::Origin(2..6),
+                Segment::Insertion {
+                    id: 0,
+                    len: Some(6)
+                },
+                Segment::Origin(6..8),
+                Segment::Insertion {
+                    id: 2,
+                    len: Some(1)
+                },
+                Segment::Origin(9..13),
+                Segment::Insertion {
+                    id: 3,
+                    len: Some(1)
+                },
+            ],
+            segments
+        );
+
+        let origin = b"alpha charlie";
+        let mut out = Vec::new();
+        for segment in &segments {
+            match *segment {
+                Segment::Origin(ref range) => out.extend_from_slice(&origin[range.clone()]),
+                Segment::Insertion { id, .. } => match plan.operations()[id].source() {
+                    Some(::source::InsertSource::Bytes(bytes)) => out.extend_from_slice(bytes),
+                    other => panic!("unexpected {:?}", other),
+                },
+            }
+        }
+        assert_eq!(plan.execute_to_vec(origin).unwrap(), out);
+    }
+
+    #[test]
+    fn lengths_sum_to_output_len() {
+        let plan = PlanBuilder::new()
+            .insert(3, "xyz")
+            .delete(5..20)
+            .insert(10, "in the deletion");
+        let segments = plan.segments(12).unwrap();
+        let len: u64 = segments.iter().map(|segment| segment.len().unwrap()).sum();
+        assert_eq!(plan.output_len(12), Some(len as usize));
+        assert!(segments.iter().all(|segment| !segment.is_empty()));
+    }
+
+    #[test]
+    fn maps_only_what_it_can() {
+        let err = PlanBuilder::new()
+            .overwrite(0, b"x")
+            .segments(8)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let err = PlanBuilder::new()
+            .delete(0..4)
+            .replace(3..5, "x")
+            .segments(8)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -0,0 +1,570 @@
+#[cfg(feature = "arbitrary")]
+use arbitrary::{Arbitrary, Unstructured};
+#[cfg(feature = "sha2")]
+use digest::Verified;
+use reader::Spliced;
+use std::{
+    borrow::Cow,
+    convert::TryInto,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Chain, Cursor, Read, Seek, Take},
+};
+
+/// something to insert, in the most efficient form available
+pub enum InsertSource<'i> {
+    /// bytes already in memory, written out directly
+    Bytes(Cow<'i, [u8]>),
+    /// a reader, and its remaining length if known up front
+    Reader(Box<dyn 'i + Read>, Option<u64>),
+    /// a value formatted straight into the output once its position is reached
+    Display(Box<dyn 'i + fmt::Display>),
+    /// content already known to break a bound placed on it, which fails a plan
+    /// with `InvalidData` before anything is written
+    Refused(String),
+}
+
+impl<'i> InsertSource<'i> {
+    /// wrap an arbitrary reader of unknown length
+    pub fn reader<R: 'i + Read>(reader: R) -> InsertSource<'i> {
+        InsertSource::Reader(Box::new(reader), None)
+    }
+
+    /// wrap a value to be formatted with `Display`, without an intermediate
+    /// string
+    pub fn display<T: 'i + fmt::Display>(value: T) -> InsertSource<'i> {
+        InsertSource::Display(Box::new(value))
+    }
+
+    /// the number of bytes this source will produce, if known up front
+    pub fn len_hint(&self) -> Option<u64> {
+        match *self {
+            InsertSource::Bytes(ref bytes) => Some(bytes.len() as u64),
+            InsertSource::Reader(_, hint) => hint,
+            InsertSource::Display(_) | InsertSource::Refused(_) => None,
+        }
+    }
+
+    /// this source as a reader
+    pub fn into_reader(self) -> Box<dyn 'i + Read> {
+        match self {
+            InsertSource::Bytes(Cow::Borrowed(bytes)) => Box::new(bytes),
+            InsertSource::Bytes(Cow::Owned(bytes)) => Box::new(Cursor::new(bytes)),
+            InsertSource::Reader(reader, _) => reader,
+            InsertSource::Display(value) => Box::new(Formatted {
+                value: Some(value),
+                formatted: Cursor::new(Vec::new()),
+            }),
+            InsertSource::Refused(message) => Box::new(Refusal(message)),
+        }
+    }
+
+    /// this source, failing with `InvalidData` if it produces more than
+    /// `max_len` bytes
+    ///
+    /// in-memory bytes are checked up front, and overlong ones are refused
+    /// before the plan writes anything; a reader is checked as it's read, so an
+    /// unbounded source can't grow the output indefinitely
+    pub fn max_len(self, max_len: u64) -> InsertSource<'i> {
+        match self {
+            InsertSource::Bytes(bytes) if bytes.len() as u64 <= max_len => {
+                InsertSource::Bytes(bytes)
+            }
+            InsertSource::Bytes(bytes) => InsertSource::Refused(format!(
+                "insertion of {} bytes is longer than its maximum {} bytes",
+                bytes.len(),
+                max_len
+            )),
+            source => {
+                let hint = source.len_hint();
+                InsertSource::Reader(
+                    Box::new(Measured {
+                        inner: source.into_reader(),
+                        remaining: max_len,
+                        len: max_len,
+                        exact: false,
+                    }),
+                    hint.filter(|&hint| hint <= max_len),
+                )
+            }
+        }
+    }
+
+    /// this source, failing if it doesn't produce exactly `len` bytes: with
+    /// `InvalidData` if it produces more, and `UnexpectedEof` if fewer
+    ///
+    /// the length is reported up front, so it counts towards output lengths
+    /// and presizing even for a reader
+    pub fn expect_len(self, len: u64) -> InsertSource<'i> {
+        match self {
+            InsertSource::Bytes(bytes) if bytes.len() as u64 == len => InsertSource::Bytes(bytes),
+            source => InsertSource::Reader(
+                Box::new(Measured {
+                    inner: source.into_reader(),
+                    remaining: len,
+                    len,
+                    exact: true,
+                }),
+                Some(len),
+            ),
+        }
+    }
+
+    /// this source, failing with `InvalidData` once it's been read if its
+    /// content doesn't have this sha-256 digest
+    #[cfg(feature = "sha2")]
+    pub fn expect_sha256(self, digest: [u8; 32]) -> InsertSource<'i> {
+        let hint = self.len_hint();
+        InsertSource::Reader(Box::new(Verified::new(self.into_reader(), digest)), hint)
+    }
+
+    /// this source followed by another
+    pub fn chain(self, next: InsertSource<'i>) -> InsertSource<'i> {
+        match (self, next) {
+            (InsertSource::Bytes(first), InsertSource::Bytes(second)) => {
+                let mut bytes = first.into_owned();
+                bytes.extend_from_slice(&second);
+                InsertSource::Bytes(Cow::Owned(bytes))
+            }
+            (refused @ InsertSource::Refused(_), _) | (_, refused @ InsertSource::Refused(_)) => {
+                refused
+            }
+            (first, second) => {
+                let hint = first
+                    .len_hint()
+                    .and_then(|first| second.len_hint().map(|second| first + second));
+                InsertSource::Reader(
+                    Box::new(first.into_reader().chain(second.into_reader())),
+                    hint,
+                )
+            }
+        }
+    }
+}
+
+impl<'i> fmt::Debug for InsertSource<'i> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InsertSource::Bytes(ref bytes) => match ::std::str::from_utf8(bytes) {
+                Ok(text) => f.debug_tuple("Bytes").field(&text).finish(),
+                Err(_) => f.debug_tuple("Bytes").field(bytes).finish(),
+            },
+            InsertSource::Reader(_, hint) => f
+                .debug_struct("Reader")
+                .field("len_hint", &hint)
+                .finish_non_exhaustive(),
+            InsertSource::Display(ref value) => {
+                f.debug_tuple("Display").field(&value.to_string()).finish()
+            }
+            InsertSource::Refused(ref message) => f.debug_tuple("Refused").field(message).finish(),
+        }
+    }
+}
+
+/// a reader over a refused source, which fails as soon as it's read
+struct Refusal(String);
+
+impl Read for Refusal {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::InvalidData, self.0.clone()))
+    }
+}
+
+/// a reader which fails once its inner reader exceeds a length, or if it's
+/// exact, ends short of it
+struct Measured<'i> {
+    inner: Box<dyn 'i + Read>,
+    remaining: u64,
+    len: u64,
+    exact: bool,
+}
+
+impl<'i> Read for Measured<'i> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // read one byte past the bound, to tell an overlong source from one
+        // which ends exactly on it
+        let limit = buf.len().min(
+            self.remaining
+                .saturating_add(1)
+                .try_into()
+                .unwrap_or(usize::MAX),
+        );
+        let len = self.inner.read(&mut buf[..limit])?;
+        if len as u64 > self.remaining {
+            let bound = if self.exact { "expected" } else { "maximum" };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("insertion longer than its {} {} bytes", bound, self.len),
+            ));
+        }
+        if len == 0 && limit > 0 && self.exact && self.remaining > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "insertion of {} bytes where {} were expected",
+                    self.len - self.remaining,
+                    self.len
+                ),
+            ));
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// a reader over a value, formatted on the first read
+struct Formatted<'i> {
+    value: Option<Box<dyn 'i + fmt::Display>>,
+    formatted: Cursor<Vec<u8>>,
+}
+
+impl<'i> Read for Formatted<'i> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(value) = self.value.take() {
+            self.formatted = Cursor::new(value.to_string().into_bytes());
+        }
+        self.formatted.read(buf)
+    }
+}
+
+/// conversion into something to insert
+///
+/// in-memory bytes and text are written out directly, and files report their
+/// length. other std readers are accepted as they are; wrap any other reader
+/// with `InsertSource::reader`.
+pub trait IntoInsertSource<'i> {
+    /// convert this into an insertion source
+    fn into_insert_source(self) -> InsertSource<'i>;
+}
+
+/// in-memory bytes, or a reader over bytes whose length may or may not be
+/// known up front
+#[cfg(feature = "arbitrary")]
+impl<'a, 'i> Arbitrary<'a> for InsertSource<'i> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let bytes = Vec::<u8>::arbitrary(u)?;
+        Ok(match u.choose_index(3)? {
+            0 => InsertSource::Bytes(Cow::Owned(bytes)),
+            1 => {
+                let len = bytes.len() as u64;
+                InsertSource::Reader(Box::new(Cursor::new(bytes)), Some(len))
+            }
+            _ => InsertSource::reader(Cursor::new(bytes)),
+        })
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for InsertSource<'i> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        self
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for &'i [u8] {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(Cow::Borrowed(self))
+    }
+}
+
+impl<'i, const N: usize> IntoInsertSource<'i> for &'i [u8; N] {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(Cow::Borrowed(self))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for Vec<u8> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(Cow::Owned(self))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for Cow<'i, [u8]> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(self)
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for &'i str {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(Cow::Borrowed(self.as_bytes()))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for String {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Bytes(Cow::Owned(self.into_bytes()))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for File {
+    fn into_insert_source(mut self) -> InsertSource<'i> {
+        // devices and pipes report a length unrelated to what they yield
+        let hint = self
+            .metadata()
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .and_then(|metadata| {
+                let position = self.stream_position().ok()?;
+                metadata.len().checked_sub(position)
+            });
+        InsertSource::Reader(Box::new(self), hint)
+    }
+}
+
+impl<'i, T: 'i + AsRef<[u8]>> IntoInsertSource<'i> for Cursor<T> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        let hint = (self.get_ref().as_ref().len() as u64).saturating_sub(self.position());
+        InsertSource::Reader(Box::new(self), Some(hint))
+    }
+}
+
+impl<'i> IntoInsertSource<'i> for Box<dyn 'i + Read> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::Reader(self, None)
+    }
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for &'i mut R {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+impl<'i, A: 'i + Read, B: 'i + Read> IntoInsertSource<'i> for Chain<A, B> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for Take<R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for BufReader<R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for Spliced<'i, R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+/// a reader which fills each buffer by calling a closure, for content such
+/// as padding, counters or test data which is produced on the fly
+///
+/// the closure returns how many bytes it wrote to the start of the buffer,
+/// and 0 once it's done
+pub struct Generator<F> {
+    fill: F,
+    remaining: Option<u64>,
+}
+
+impl<F> Generator<F>
+where
+    F: FnMut(&mut [u8]) -> io::Result<usize>,
+{
+    /// generate bytes until the closure returns 0
+    pub fn new(fill: F) -> Generator<F> {
+        Generator {
+            fill,
+            remaining: None,
+        }
+    }
+
+    /// generate at most `len` bytes, never offering the closure more room
+    /// than is left; the length is reported up front
+    pub fn with_len(len: u64, fill: F) -> Generator<F> {
+        Generator {
+            fill,
+            remaining: Some(len),
+        }
+    }
+}
+
+impl<F> Read for Generator<F>
+where
+    F: FnMut(&mut [u8]) -> io::Result<usize>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = match self.remaining {
+            Some(remaining) => buf.len().min(remaining.try_into().unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+        if limit == 0 {
+            return Ok(0);
+        }
+        let len = (self.fill)(&mut buf[..limit])?;
+        if len > limit {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "generator claimed to fill more than its buffer",
+            ));
+        }
+        if let Some(ref mut remaining) = self.remaining {
+            *remaining -= len as u64;
+        }
+        Ok(len)
+    }
+}
+
+impl<F> fmt::Debug for Generator<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Generator")
+            .field("remaining", &self.remaining)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'i, F> IntoInsertSource<'i> for Generator<F>
+where
+    F: 'i + FnMut(&mut [u8]) -> io::Result<usize>,
+{
+    fn into_insert_source(self) -> InsertSource<'i> {
+        let hint = self.remaining;
+        InsertSource::Reader(Box::new(self), hint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use Inserter;
+
+    #[test]
+    fn anything_reasonable() {
+        let mut dest = Vec::new();
+        let owned = String::from("charlie ");
+        Inserter::new(&b"alpha delta echo"[..], &mut dest)
+            .insert(6, b"bravo ")
+            .insert(6, owned)
+            .insert(11, "zulu ".as_bytes().take(0))
+            .insert(11, Cursor::new(vec![b'!']))
+            .insert(11, InsertSource::reader(&b"?"[..]))
+            .execute()
+            .unwrap();
+        assert_eq!(&b"alpha bravo charlie delta!? echo"[..], &dest[..]);
+    }
+
+    #[test]
+    fn len_hints() {
+        assert_eq!(Some(3), "abc".into_insert_source().len_hint());
+        let mut cursor = Cursor::new(b"abcdef".to_vec());
+        cursor.set_position(2);
+        assert_eq!(Some(4), cursor.into_insert_source().len_hint());
+        let chained = b"ab"
+            .into_insert_source()
+            .chain(InsertSource::reader(&b"c"[..]));
+        assert_eq!(None, chained.len_hint());
+
+        let path = std::env::temp_dir().join("insert_multiple_source_len_hint");
+        File::create(&path).unwrap().write_all(b"12345").unwrap();
+        assert_eq!(
+            Some(5),
+            File::open(&path).unwrap().into_insert_source().len_hint()
+        );
+        std::fs::remove_file(&path).unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            None,
+            File::open("/dev/zero")
+                .unwrap()
+                .into_insert_source()
+                .len_hint()
+        );
+    }
+
+    #[test]
+    fn reads_and_chains() {
+        let source = b"ab"
+            .into_insert_source()
+            .chain(String::from("cd").into_insert_source());
+        assert_eq!(Some(4), source.len_hint());
+        let mut out = Vec::new();
+        source.into_reader().read_to_end(&mut out).unwrap();
+        assert_eq!(b"abcd".to_vec(), out);
+    }
+
+    #[test]
+    fn generates_on_the_fly() {
+        let mut counter = 0_u8;
+        let count = Generator::new(move |buf: &mut [u8]| {
+            let len = buf.len().min(3);
+            for byte in &mut buf[..len] {
+                counter += 1;
+                *byte = b'0' + counter;
+            }
+            Ok(if counter > 6 { 0 } else { len })
+        });
+        let padding = Generator::with_len(4, |buf: &mut [u8]| {
+            buf.fill(b'.');
+            Ok(buf.len())
+        });
+        let padding = padding.into_insert_source();
+        assert_eq!(Some(4), padding.len_hint());
+        let out = Inserter::new(&b"[]"[..], Vec::new())
+            .insert(1, count)
+            .insert(2, padding)
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("[123456]....", out);
+    }
+
+    #[test]
+    fn bounds_length() {
+        let within = InsertSource::reader(&b"bravo "[..]).max_len(6);
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, within)
+            .insert(13, "!".into_insert_source().max_len(1))
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("alpha bravo charlie!", out);
+
+        let endless = Generator::new(|buf: &mut [u8]| {
+            buf.fill(b'y');
+            Ok(buf.len())
+        });
+        let err = Inserter::new(&b"alpha"[..], Vec::new())
+            .insert(0, endless.into_insert_source().max_len(10_000))
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        let overlong = "bravo".into_insert_source().max_len(4);
+        assert_eq!(None, overlong.len_hint());
+        let mut target = Vec::new();
+        let err = Inserter::new(&b"alpha charlie"[..], &mut target)
+            .insert(6, overlong)
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        assert!(target.is_empty());
+    }
+
+    #[test]
+    fn checks_expected_length() {
+        let exact = InsertSource::reader(&b"bravo "[..]).expect_len(6);
+        assert_eq!(Some(6), exact.len_hint());
+        let out = Inserter::new(&b"alpha charlie"[..], Vec::new())
+            .insert(6, exact)
+            .insert(13, "!".into_insert_source().expect_len(1))
+            .execute_to_string()
+            .unwrap();
+        assert_eq!("alpha bravo charlie!", out);
+
+        for (source, kind) in [
+            ("bravo", io::ErrorKind::UnexpectedEof),
+            ("bravo  ", io::ErrorKind::InvalidData),
+        ] {
+            let err = Inserter::new(&b"alpha charlie"[..], Vec::new())
+                .insert(6, InsertSource::reader(source.as_bytes()).expect_len(6))
+                .execute()
+                .unwrap_err();
+            assert_eq!(kind, err.kind());
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! sparse output, seeking over blocks of zeros rather than writing them
+//!
+//! a filesystem which supports holes leaves the blocks seeked over
+//! unallocated, so a disk image with large empty regions takes only the space
+//! of its contents, and isn't slowed down by writing gigabytes of zeros.
+
+use operation::PlanBuilder;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+/// runs of zeros are seeked over in blocks of this length, aligned to where
+/// the writer started
+pub const BLOCK_LEN: usize = 4096;
+
+/// a writer which seeks over whole blocks of zeros rather than writing them
+///
+/// what's seeked over must already read as zeros, so the target should end
+/// where writing starts, as a new or truncated file does. `finish` must be
+/// called to write any trailing partial block and extend the target over a
+/// trailing hole.
+#[derive(Debug)]
+pub struct SparseWriter<W> {
+    inner: W,
+    /// a partial block, waiting for the rest
+    block: Vec<u8>,
+    /// zeros seeked over but not yet followed by anything written
+    hole: u64,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// create a new writer, starting at the inner writer's position
+    pub fn new(inner: W) -> SparseWriter<W> {
+        SparseWriter {
+            inner,
+            block: Vec::with_capacity(BLOCK_LEN),
+            hole: 0,
+        }
+    }
+
+    /// write bytes after any pending hole
+    fn write_data(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if self.hole > 0 {
+            self.inner.seek(SeekFrom::Current(self.hole as i64))?;
+            self.hole = 0;
+        }
+        self.inner.write_all(bytes)
+    }
+
+    /// write or seek over whole blocks, returning the length of the trailing
+    /// partial block left unwritten
+    fn write_blocks(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut data = 0;
+        let mut blocks = bytes.chunks_exact(BLOCK_LEN);
+        for (idx, block) in blocks.by_ref().enumerate() {
+            if block.iter().all(|&byte| byte == 0) {
+                let start = idx * BLOCK_LEN;
+                self.write_data(&bytes[data..start])?;
+                self.hole += BLOCK_LEN as u64;
+                data = start + BLOCK_LEN;
+            }
+        }
+        let whole = bytes.len() - blocks.remainder().len();
+        self.write_data(&bytes[data..whole])?;
+        Ok(blocks.remainder().len())
+    }
+
+    /// write any partial block, and make sure a trailing hole is part of the
+    /// output, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let block = std::mem::take(&mut self.block);
+        self.write_data(&block)?;
+        if self.hole > 0 {
+            // writing the hole's last byte extends the target over the rest
+            self.inner.seek(SeekFrom::Current(self.hole as i64 - 1))?;
+            self.hole = 0;
+            self.inner.write_all(&[0])?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        if !self.block.is_empty() {
+            let take = rest.len().min(BLOCK_LEN - self.block.len());
+            self.block.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.block.len() < BLOCK_LEN {
+                return Ok(buf.len());
+            }
+            let block = std::mem::take(&mut self.block);
+            self.write_blocks(&block)?;
+            self.block = block;
+            self.block.clear();
+        }
+        let partial = self.write_blocks(rest)?;
+        self.block.extend_from_slice(&rest[rest.len() - partial..]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<'i> PlanBuilder<'i> {
+    /// apply the plan, leaving blocks of zeros in the output as holes in the
+    /// target file
+    ///
+    /// the target is truncated to its current position first, and written
+    /// from there
+    pub fn execute_sparse<R: Read>(self, origin: R, target: &mut File) -> io::Result<()> {
+        let start = target.stream_position()?;
+        target.set_len(start)?;
+        let mut sparse = SparseWriter::new(target);
+        self.execute(origin, &mut sparse)?;
+        sparse.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Cursor};
+
+    fn sparse(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = SparseWriter::new(&mut cursor);
+        for chunk in chunks {
+            writer.write_all(chunk).unwrap();
+        }
+        writer.finish().unwrap();
+        cursor.into_inner()
+    }
+
+    #[test]
+    fn seeks_over_zero_blocks() {
+        let zeros = vec![0; 3 * BLOCK_LEN];
+        let out = sparse(&[b"head", &zeros, b"tail"]);
+        let mut expected = b"head".to_vec();
+        expected.extend_from_slice(&zeros);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(expected, out);
+
+        let out = sparse(&[b"head", &zeros]);
+        assert_eq!(4 + zeros.len(), out.len());
+        let out = sparse(&[&zeros[..BLOCK_LEN + 1]]);
+        assert_eq!(vec![0; BLOCK_LEN + 1], out);
+    }
+
+    #[test]
+    fn leaves_holes_in_files() {
+        let path = ::std::env::temp_dir().join(format!("sparse-{}", ::std::process::id()));
+        let mut target = File::create(&path).unwrap();
+        target.write_all(b"stale contents to be truncated").unwrap();
+        target.seek(SeekFrom::Start(6)).unwrap();
+        let len = 64 * BLOCK_LEN as u64;
+        PlanBuilder::new()
+            .insert(1, io::repeat(0).take(len))
+            .insert(2, "!")
+            .execute_sparse(&b"ab"[..], &mut target)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(target.metadata().unwrap().blocks() * 512 < len);
+        }
+        drop(target);
+        let out = fs::read(&path).unwrap();
+        assert_eq!(6 + 3 + len as usize, out.len());
+        assert_eq!(b"stale a", &out[..7]);
+        assert!(out[7..7 + len as usize].iter().all(|&byte| byte == 0));
+        assert_eq!(b"b!", &out[7 + len as usize..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+const BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// a point in a subtitle stream, in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    /// parse an SRT timestamp of the form `HH:MM:SS,mmm`
+    ///
+    /// a fraction of fewer than three digits is scaled, so `,5` is 500 ms
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let s = s.trim();
+        let (clock, fraction) = s.split_at(s.find([',', '.'])?);
+        let fraction = &fraction[1..];
+        if fraction.is_empty()
+            || fraction.len() > 3
+            || !fraction.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let millis = fraction.parse::<u64>().ok()? * 10_u64.pow(3 - fraction.len() as u32);
+        let mut parts = clock.split(':');
+        let hours: u64 = parts.next()?.parse().ok()?;
+        let minutes: u64 = parts.next()?.parse().ok()?;
+        let seconds: u64 = parts.next()?.parse().ok()?;
+        if parts.next().is_some() || minutes >= 60 || seconds >= 60 {
+            return None;
+        }
+        hours
+            .checked_mul(3_600_000)?
+            .checked_add((minutes * 60 + seconds) * 1000 + millis)
+            .map(Timestamp)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.0 % 1000;
+        let seconds = self.0 / 1000 % 60;
+        let minutes = self.0 / 60_000 % 60;
+        let hours = self.0 / 3_600_000;
+        write!(
+            f,
+            "{:02}:{:02}:{:02},{:03}",
+            hours, minutes, seconds, millis
+        )
+    }
+}
+
+/// a single subtitle cue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cue {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub text: String,
+}
+
+/// insert a cue into an SRT stream, renumbering the cues which follow it
+///
+/// the new cue is placed before the first existing cue which starts later than
+/// it does, or at the end of the stream if there is none. subsequent cue indices
+/// are incremented in the same pass. cue text containing a blank line is
+/// rejected, since the blank line would end the cue early
+pub fn insert_cue<R, W>(origin: R, mut target: W, cue: &Cue) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    if cue
+        .text
+        .lines()
+        .any(|line| line.bytes().all(|b| b.is_ascii_whitespace()))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "SRT cue text must not contain a blank line",
+        ));
+    }
+    let mut origin = BufReader::new(origin);
+    let mut block: Vec<Vec<u8>> = Vec::new();
+    let mut newline: &[u8] = b"\n";
+    let mut inserted = false;
+    let mut last_index = 0;
+    let mut first = true;
+    let mut terminated = true;
+    // whether the output so far ends with a blank line separating cues
+    let mut separated = false;
+
+    loop {
+        let mut line = Vec::new();
+        let eof = origin.read_until(b'\n', &mut line)? == 0;
+        if first && line.starts_with(BOM) {
+            target.write_all(BOM)?;
+            line.drain(..BOM.len());
+        }
+        first = false;
+        if line.ends_with(b"\r\n") {
+            newline = b"\r\n";
+        }
+
+        let blank = line.iter().all(|b| b.is_ascii_whitespace());
+        if (eof || blank) && !block.is_empty() {
+            let (index, start) = parse_header(&block)?;
+            if !inserted && start > cue.start {
+                write_cue(&mut target, index, cue, newline)?;
+                target.write_all(newline)?;
+                inserted = true;
+            }
+            last_index = if inserted { index + 1 } else { index };
+            if inserted {
+                let ending = line_ending(&block[0]).to_vec();
+                block[0] = last_index.to_string().into_bytes();
+                block[0].extend_from_slice(&ending);
+            }
+            for line in block.drain(..) {
+                terminated = line.ends_with(b"\n");
+                target.write_all(&line)?;
+            }
+            separated = false;
+        }
+
+        if eof {
+            break;
+        }
+        if blank {
+            terminated = line.ends_with(b"\n");
+            separated = true;
+            target.write_all(&line)?;
+        } else {
+            block.push(line);
+        }
+    }
+
+    if !inserted {
+        if !terminated {
+            target.write_all(newline)?;
+        }
+        if last_index > 0 && !separated {
+            target.write_all(newline)?;
+        }
+        write_cue(&mut target, last_index + 1, cue, newline)?;
+    }
+    Ok(())
+}
+
+fn parse_header(block: &[Vec<u8>]) -> io::Result<(u64, Timestamp)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed SRT cue");
+    let index = block
+        .first()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .and_then(|line| line.trim().parse().ok())
+        .ok_or_else(malformed)?;
+    let start = block
+        .get(1)
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .and_then(|line| line.split("-->").next())
+        .and_then(Timestamp::parse)
+        .ok_or_else(malformed)?;
+    Ok((index, start))
+}
+
+fn line_ending(line: &[u8]) -> &[u8] {
+    if line.ends_with(b"\r\n") {
+        b"\r\n"
+    } else if line.ends_with(b"\n") {
+        b"\n"
+    } else {
+        b""
+    }
+}
+
+fn write_cue<W: Write>(target: &mut W, index: u64, cue: &Cue, newline: &[u8]) -> io::Result<()> {
+    write!(target, "{}", index)?;
+    target.write_all(newline)?;
+    write!(target, "{} --> {}", cue.start, cue.end)?;
+    target.write_all(newline)?;
+    for line in cue.text.lines() {
+        target.write_all(line.as_bytes())?;
+        target.write_all(newline)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGIN: &str = "1\n00:00:01,000 --> 00:00:02,000\nfirst\n\n\
+                          2\n00:00:05,000 --> 00:00:06,000\nsecond\nline two\n\n\
+                          3\n00:00:09,000 --> 00:00:10,000\nthird\n";
+
+    fn cue(start: u64, text: &str) -> Cue {
+        Cue {
+            start: Timestamp(start),
+            end: Timestamp(start + 500),
+            text: text.to_string(),
+        }
+    }
+
+    fn run(origin: &str, cue: &Cue) -> String {
+        let mut dest = Vec::new();
+        insert_cue(origin.as_bytes(), &mut dest, cue).unwrap();
+        String::from_utf8(dest).unwrap()
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        let ts = Timestamp::parse("01:02:03,004").unwrap();
+        assert_eq!(Timestamp(3_723_004), ts);
+        assert_eq!("01:02:03,004", ts.to_string());
+        assert_eq!(None, Timestamp::parse("00:61:00,000"));
+    }
+
+    #[test]
+    fn timestamp_edge_cases() {
+        assert_eq!(Some(Timestamp(1_500)), Timestamp::parse("00:00:01,5"));
+        assert_eq!(Some(Timestamp(1_050)), Timestamp::parse("00:00:01.05"));
+        assert_eq!(None, Timestamp::parse("00:00:01,"));
+        assert_eq!(None, Timestamp::parse("00:00:01,0005"));
+        assert_eq!(None, Timestamp::parse("00:00:01,+5"));
+        assert_eq!(
+            None,
+            Timestamp::parse(&format!("{}:00:00,000", u64::MAX / 1000))
+        );
+    }
+
+    #[test]
+    fn inserts_and_renumbers() {
+        let out = run(ORIGIN, &cue(3000, "new"));
+        assert_eq!(
+            "1\n00:00:01,000 --> 00:00:02,000\nfirst\n\n\
+             2\n00:00:03,000 --> 00:00:03,500\nnew\n\n\
+             3\n00:00:05,000 --> 00:00:06,000\nsecond\nline two\n\n\
+             4\n00:00:09,000 --> 00:00:10,000\nthird\n",
+            out
+        );
+    }
+
+    #[test]
+    fn inserts_at_beginning() {
+        let out = run(ORIGIN, &cue(0, "zero"));
+        assert!(out.starts_with("1\n00:00:00,000 --> 00:00:00,500\nzero\n\n2\n00:00:01,000"));
+        assert!(out.ends_with("4\n00:00:09,000 --> 00:00:10,000\nthird\n"));
+    }
+
+    #[test]
+    fn appends_at_end() {
+        let out = run(ORIGIN, &cue(20_000, "last"));
+        assert!(out.ends_with("third\n\n4\n00:00:20,000 --> 00:00:20,500\nlast\n"));
+    }
+
+    #[test]
+    fn appends_after_unterminated_cue() {
+        let out = run("1\n00:00:01,000 --> 00:00:02,000\nonly", &cue(5000, "next"));
+        assert_eq!(
+            "1\n00:00:01,000 --> 00:00:02,000\nonly\n\n2\n00:00:05,000 --> 00:00:05,500\nnext\n",
+            out
+        );
+    }
+
+    #[test]
+    fn appends_after_trailing_blank_line() {
+        let out = run(
+            "1\n00:00:01,000 --> 00:00:02,000\nonly\n\n",
+            &cue(5000, "next"),
+        );
+        assert_eq!(
+            "1\n00:00:01,000 --> 00:00:02,000\nonly\n\n2\n00:00:05,000 --> 00:00:05,500\nnext\n",
+            out
+        );
+    }
+
+    #[test]
+    fn rejects_blank_line_in_text() {
+        let mut dest = Vec::new();
+        let err = insert_cue(ORIGIN.as_bytes(), &mut dest, &cue(3000, "one\n\ntwo")).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(dest.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_cue() {
+        let mut dest = Vec::new();
+        let err = insert_cue("x\nnonsense\n".as_bytes(), &mut dest, &cue(0, "a")).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,198 @@
+use inserter::BUFFER_SIZE;
+use reader::Spliced;
+use std::io::{self, Read, Write};
+
+/// the outcome of a single step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// some data was moved; step again
+    Progress,
+    /// the origin or an insertion would block; step again once it's readable
+    NeedsRead,
+    /// the target would block; step again once it's writable
+    NeedsWrite,
+    /// everything has been written and flushed
+    Done,
+}
+
+/// drives a splice one bounded step at a time
+///
+/// each step makes at most one read or one write, so a caller with
+/// non-blocking sources or target can interleave the splice with an event
+/// loop: `WouldBlock` is reported as `NeedsRead` or `NeedsWrite` and the step
+/// can simply be retried later.
+pub struct Stepper<'i, R, W> {
+    spliced: Spliced<'i, R>,
+    target: W,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    finished: bool,
+}
+
+impl<'i, R: Read, W: Write> Stepper<'i, R, W> {
+    /// create a stepper copying the spliced reader to the target
+    pub fn new(spliced: Spliced<'i, R>, target: W) -> Stepper<'i, R, W> {
+        Stepper {
+            spliced,
+            target,
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            finished: false,
+        }
+    }
+
+    /// do a bounded amount of work
+    ///
+    /// once this has returned `Done`, it keeps doing so
+    pub fn step(&mut self) -> io::Result<Step> {
+        if self.start < self.end {
+            return match self.target.write(&self.buffer[self.start..self.end]) {
+                Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.start += written;
+                    Ok(Step::Progress)
+                }
+                Err(e) => blocked(e, Step::NeedsWrite),
+            };
+        }
+        if self.finished {
+            return match self.target.flush() {
+                Ok(()) => Ok(Step::Done),
+                Err(e) => blocked(e, Step::NeedsWrite),
+            };
+        }
+        match self.spliced.read(&mut self.buffer) {
+            Ok(0) => {
+                self.finished = true;
+                Ok(Step::Progress)
+            }
+            Ok(bytes_read) => {
+                self.start = 0;
+                self.end = bytes_read;
+                Ok(Step::Progress)
+            }
+            Err(e) => blocked(e, Step::NeedsRead),
+        }
+    }
+
+    /// step until done
+    ///
+    /// fails with `WouldBlock` rather than spinning if the sources or target
+    /// aren't ready, after which stepping can resume
+    pub fn finish(&mut self) -> io::Result<()> {
+        loop {
+            match self.step()? {
+                Step::Progress => {}
+                Step::Done => return Ok(()),
+                Step::NeedsRead | Step::NeedsWrite => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    /// recover the target
+    pub fn into_target(self) -> W {
+        self.target
+    }
+}
+
+/// report an error which only means "not yet" as the given step
+fn blocked(error: io::Error, step: Step) -> io::Result<Step> {
+    match error.kind() {
+        io::ErrorKind::WouldBlock => Ok(step),
+        io::ErrorKind::Interrupted => Ok(Step::Progress),
+        _ => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::InsertSource;
+    use Inserter;
+
+    /// blocks on every other call
+    struct Stuttering<T> {
+        inner: T,
+        block: bool,
+    }
+
+    impl<T> Stuttering<T> {
+        fn new(inner: T) -> Stuttering<T> {
+            Stuttering { inner, block: true }
+        }
+
+        fn stutter(&mut self) -> io::Result<()> {
+            self.block = !self.block;
+            if self.block {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl<T: Read> Read for Stuttering<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.stutter()?;
+            let len = buf.len().min(4);
+            self.inner.read(&mut buf[..len])
+        }
+    }
+
+    impl<T: Write> Write for Stuttering<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stutter()?;
+            self.inner.write(&buf[..buf.len().min(3)])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn steps_through_would_block() {
+        let mut stepper = Inserter::new(
+            Stuttering::new(&b"alpha charlie"[..]),
+            Stuttering::new(Vec::new()),
+        )
+        .insert(6, InsertSource::reader(Stuttering::new(&b"bravo "[..])))
+        .into_stepper()
+        .unwrap();
+        let (mut reads, mut writes) = (0, 0);
+        loop {
+            match stepper.step().unwrap() {
+                Step::Progress => {}
+                Step::NeedsRead => reads += 1,
+                Step::NeedsWrite => writes += 1,
+                Step::Done => break,
+            }
+        }
+        assert!(reads > 0 && writes > 0);
+        assert_eq!(Step::Done, stepper.step().unwrap());
+        assert_eq!(
+            &b"alpha bravo charlie"[..],
+            &stepper.into_target().inner[..]
+        );
+    }
+
+    #[test]
+    fn finishes_in_one_go() {
+        let mut stepper = Inserter::new(&b"ac"[..], Vec::new())
+            .insert(1, "b")
+            .into_stepper()
+            .unwrap();
+        stepper.finish().unwrap();
+        assert_eq!(b"abc".to_vec(), stepper.into_target());
+    }
+
+    #[test]
+    fn only_insertions_can_step() {
+        let result = Inserter::new(&b"ac"[..], Vec::new())
+            .overwrite(0, b"x")
+            .into_stepper();
+        assert_eq!(io::ErrorKind::InvalidInput, result.err().unwrap().kind());
+    }
+}
@@ -1,8 +1,86 @@
-use inserter::Inserter;
-use std::io::{self, Cursor};
+use operation::PlanBuilder;
+#[cfg(feature = "ropey")]
+use rope::RopeInserter;
+#[cfg(feature = "ropey")]
+use ropey::Rope;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::rc::Rc;
+use std::str;
 use std::string::FromUtf8Error;
+use vec_inserter::VecInserter;
 
-type Insertions<'i> = Vec<(usize, &'i str)>;
+type Insertions<'i> = Vec<(usize, Cow<'i, str>)>;
+
+/// a change of case for a region of text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    /// every letter in upper case
+    Upper,
+    /// every letter in lower case
+    Lower,
+    /// the first letter of every word in upper case, and the rest in lower case
+    Title,
+}
+
+impl Case {
+    /// the text in this case
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Case::Upper => text.to_uppercase(),
+            Case::Lower => text.to_lowercase(),
+            Case::Title => {
+                let mut out = String::with_capacity(text.len());
+                let mut in_word = false;
+                for c in text.chars() {
+                    if in_word {
+                        out.extend(c.to_lowercase());
+                    } else {
+                        out.extend(c.to_uppercase());
+                    }
+                    in_word = c.is_alphanumeric() || c == '\'';
+                }
+                out
+            }
+        }
+    }
+}
+
+/// how a region of the origin is rewritten
+#[derive(Clone)]
+enum Rewrite<'i> {
+    Case(Case),
+    With(Rc<dyn 'i + Fn(&str) -> String>),
+}
+
+impl<'i> Rewrite<'i> {
+    fn apply(&self, text: &str) -> String {
+        match *self {
+            Rewrite::Case(case) => case.apply(text),
+            Rewrite::With(ref rewrite) => rewrite(text),
+        }
+    }
+}
+
+impl<'i> fmt::Debug for Rewrite<'i> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rewrite::Case(case) => f.debug_tuple("Case").field(&case).finish(),
+            Rewrite::With(_) => f.debug_tuple("With").finish_non_exhaustive(),
+        }
+    }
+}
+
+/// an invalid sequence in the output which lossy execution replaced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replacement {
+    /// the byte index in the output of the replacement character
+    pub position: usize,
+    /// the bytes it replaced
+    pub invalid: Vec<u8>,
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -23,43 +101,178 @@ impl From<FromUtf8Error> for Error {
 }
 
 /// inserter keeps track of origin, target writer, and all points of insertion
+///
+/// the origin and insertions may each be borrowed or owned, so an inserter
+/// can be built up from formatted strings or returned from a function
+#[derive(Debug, Clone)]
 pub struct StringInserter<'o, 'i> {
-    origin: &'o str,
+    origin: Cow<'o, str>,
     insertions: Insertions<'i>,
+    regions: Vec<(Range<usize>, Rewrite<'i>)>,
 }
 
 impl<'o, 'i> StringInserter<'o, 'i> {
     /// create a new inserter with the specified origin document and target
-    pub fn new(origin: &'o str) -> StringInserter<'o, 'i> {
+    pub fn new<O: Into<Cow<'o, str>>>(origin: O) -> StringInserter<'o, 'i> {
         StringInserter {
-            origin,
+            origin: origin.into(),
             insertions: Insertions::new(),
+            regions: Vec::new(),
         }
     }
 
     /// insert the source document into the output document at the given origin index
-    pub fn insert(mut self, position: usize, source: &'i str) -> Self {
-        self.insertions.push((position, source));
+    pub fn insert<S: Into<Cow<'i, str>>>(mut self, position: usize, source: S) -> Self {
+        self.insertions.push((position, source.into()));
+        self
+    }
+
+    /// change the case of the origin text in the given range
+    ///
+    /// like every region operation, the range is in origin byte indices. it
+    /// must lie on char boundaries within the origin, mustn't overlap another
+    /// region, and mustn't contain an insertion other than at its ends:
+    /// otherwise execution fails with `InvalidInput`.
+    pub fn case(self, range: Range<usize>, case: Case) -> Self {
+        self.region(range, Rewrite::Case(case))
+    }
+
+    /// replace the origin text in the given range with the function's output
+    pub fn rewrite<F: 'i + Fn(&str) -> String>(self, range: Range<usize>, rewrite: F) -> Self {
+        self.region(range, Rewrite::With(Rc::new(rewrite)))
+    }
+
+    fn region(mut self, range: Range<usize>, rewrite: Rewrite<'i>) -> Self {
+        self.regions.push((range, rewrite));
         self
     }
 
+    /// the queued insertions, in the order they were added
+    pub fn insertions(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.insertions
+            .iter()
+            .map(|&(position, ref source)| (position, &**source))
+    }
+
+    /// remove and return every queued insertion at the given origin index
+    pub fn remove_at(&mut self, position: usize) -> Vec<Cow<'i, str>> {
+        let (removed, kept): (Insertions, Insertions) = self
+            .insertions
+            .drain(..)
+            .partition(|&(other, _)| other == position);
+        self.insertions = kept;
+        removed.into_iter().map(|(_, source)| source).collect()
+    }
+
+    /// remove every queued insertion
+    pub fn clear(&mut self) {
+        self.insertions.clear()
+    }
+
     /// execute this inserter, consuming it
     pub fn execute(self) -> Result<String, Error> {
-        // this just delegates to Inserter, of course
-        let mut buffer: Vec<u8> = Vec::with_capacity(
-            self.origin.len() + self.insertions.iter().map(|(_, i)| i.len()).sum::<usize>(),
-        );
-        {
-            let mut inserter = Inserter::new(self.origin.as_bytes(), Cursor::new(&mut buffer));
-            for (position, item) in self.insertions.iter() {
-                inserter = inserter.insert(*position, item.as_bytes());
+        String::from_utf8(self.execute_bytes()?).map_err(|e| e.into())
+    }
+
+    /// execute this inserter, replacing any invalid sequences which
+    /// misplaced insertions produce with U+FFFD rather than failing
+    ///
+    /// the replacements are returned in the order they occur in the output
+    pub fn execute_lossy(self) -> io::Result<(String, Vec<Replacement>)> {
+        let bytes = self.execute_bytes()?;
+        let mut out = String::with_capacity(bytes.len());
+        let mut replacements = Vec::new();
+        for chunk in bytes.utf8_chunks() {
+            out.push_str(chunk.valid());
+            if !chunk.invalid().is_empty() {
+                replacements.push(Replacement {
+                    position: out.len(),
+                    invalid: chunk.invalid().to_vec(),
+                });
+                out.push(char::REPLACEMENT_CHARACTER);
             }
-            if let Err(err) = inserter.execute() {
-                return Err(err.into());
+        }
+        Ok((out, replacements))
+    }
+
+    /// execute this inserter, appending the output to `out`
+    ///
+    /// space is reserved for exactly the output. if it isn't valid utf-8, `out`
+    /// is left as it was
+    pub fn execute_into(self, out: &mut String) -> Result<(), Error> {
+        let mut bytes = std::mem::take(out).into_bytes();
+        let start = bytes.len();
+        let appended = self.append_bytes(&mut bytes);
+        if appended.is_ok() && str::from_utf8(&bytes[start..]).is_ok() {
+            *out = String::from_utf8(bytes).expect("the output was just checked");
+            return Ok(());
+        }
+        let tail = bytes.split_off(start);
+        *out = String::from_utf8(bytes).expect("the existing contents are a string");
+        appended?;
+        Err(String::from_utf8(tail).unwrap_err().into())
+    }
+
+    fn execute_bytes(self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.append_bytes(&mut out)?;
+        Ok(out)
+    }
+
+    fn append_bytes(self, out: &mut Vec<u8>) -> io::Result<()> {
+        if !self.regions.is_empty() {
+            return self.append_regions(out);
+        }
+        // this just delegates to VecInserter, of course
+        let mut inserter = VecInserter::new(self.origin.as_bytes());
+        for (position, item) in self.insertions.iter() {
+            inserter = inserter.insert(*position, item.as_bytes());
+        }
+        inserter.execute_into(out);
+        Ok(())
+    }
+
+    /// insertions and rewritten regions, in a single pass over the origin
+    fn append_regions(self, out: &mut Vec<u8>) -> io::Result<()> {
+        let origin = &*self.origin;
+        let mut plan = PlanBuilder::new();
+        for (position, item) in self.insertions.iter() {
+            plan = plan.insert(*position, item.as_bytes());
+        }
+        for (range, rewrite) in self.regions.iter() {
+            if range.start > range.end
+                || range.end > origin.len()
+                || !origin.is_char_boundary(range.start)
+                || !origin.is_char_boundary(range.end)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "region not on char boundaries within the origin",
+                ));
             }
+            plan = plan.transform(range.clone(), move |bytes| {
+                let text = str::from_utf8(bytes).expect("regions lie on char boundaries");
+                rewrite.apply(text).into_bytes()
+            });
         }
+        plan.execute_into_vec(origin.as_bytes(), out)
+    }
 
-        String::from_utf8(buffer).map_err(|e| e.into())
+    /// execute this inserter, consuming it and producing a rope
+    ///
+    /// further rounds of insertions can then be applied with `RopeInserter`
+    #[cfg(feature = "ropey")]
+    pub fn execute_rope(self) -> Result<Rope, Error> {
+        if !self.regions.is_empty() {
+            return self.execute().map(|out| Rope::from_str(&out));
+        }
+        let mut rope = Rope::from_str(&self.origin);
+        let mut inserter = RopeInserter::new(&mut rope);
+        for (position, item) in self.insertions.iter() {
+            inserter = inserter.insert(*position, item);
+        }
+        inserter.execute()?;
+        Ok(rope)
     }
 }
 
@@ -117,4 +330,127 @@ mod tests {
 
         assert_eq!("alpha bravo charlie delta echo fox golf hotel", &out);
     }
+
+    #[test]
+    fn owned_strings() {
+        fn greeting(name: &str) -> StringInserter<'static, 'static> {
+            StringInserter::new(String::from("hello "))
+                .insert(6, format!("{}!", name))
+                .insert(6, Cow::Borrowed(" bye"))
+        }
+
+        assert_eq!("hello world! bye", greeting("world").execute().unwrap());
+    }
+
+    #[test]
+    fn inspect_and_remove() {
+        let mut inserter = StringInserter::new("ac")
+            .insert(1, "b")
+            .insert(2, "!")
+            .insert(2, "?");
+        assert_eq!(
+            vec![(1, "b"), (2, "!"), (2, "?")],
+            inserter.insertions().collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["!", "?"], inserter.remove_at(2));
+        assert_eq!("abc", inserter.clone().execute().unwrap());
+        inserter.clear();
+        assert_eq!("ac", inserter.execute().unwrap());
+    }
+
+    #[test]
+    fn rewrites_regions_alongside_insertions() {
+        let out = StringInserter::new("the quick brown fox")
+            .insert(4, "very ")
+            .case(4..9, Case::Upper)
+            .case(10..19, Case::Title)
+            .rewrite(0..3, |text| text.chars().rev().collect())
+            .execute()
+            .unwrap();
+        assert_eq!("eht very QUICK Brown Fox", out);
+    }
+
+    #[test]
+    fn lossy_execution_marks_replacements() {
+        let (out, replacements) = StringInserter::new("naïve café")
+            .insert(3, "!")
+            .insert(11, "?")
+            .execute_lossy()
+            .unwrap();
+        assert_eq!("na\u{fffd}!\u{fffd}ve caf\u{fffd}?\u{fffd}", out);
+        assert_eq!(
+            vec![
+                Replacement {
+                    position: 2,
+                    invalid: vec![0xc3],
+                },
+                Replacement {
+                    position: 6,
+                    invalid: vec![0xaf],
+                },
+                Replacement {
+                    position: 15,
+                    invalid: vec![0xc3],
+                },
+                Replacement {
+                    position: 19,
+                    invalid: vec![0xa9],
+                },
+            ],
+            replacements
+        );
+        assert!(StringInserter::new("naïve")
+            .insert(3, "!")
+            .execute()
+            .is_err());
+
+        let (out, replacements) = StringInserter::new("café")
+            .insert(5, "!")
+            .execute_lossy()
+            .unwrap();
+        assert_eq!("café!", out);
+        assert!(replacements.is_empty());
+    }
+
+    #[test]
+    fn cases() {
+        assert_eq!("ÉCOLE", Case::Upper.apply("école"));
+        assert_eq!("straße", Case::Lower.apply("STRAßE"));
+        assert_eq!("Don't Stop-Me Now", Case::Title.apply("don't stop-ME now"));
+    }
+
+    #[test]
+    fn regions_must_lie_on_char_boundaries() {
+        for range in [0..1, 2..10] {
+            match StringInserter::new("éa").case(range, Case::Upper).execute() {
+                Err(Error::IoError(e)) => assert_eq!(io::ErrorKind::InvalidInput, e.kind()),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn execute_into_appends() {
+        let mut out = String::from("> ");
+        StringInserter::new("hello world")
+            .insert(5, ",")
+            .execute_into(&mut out)
+            .unwrap();
+        assert_eq!("> hello, world", out);
+
+        StringInserter::new("école")
+            .case(0..2, Case::Upper)
+            .execute_into(&mut out)
+            .unwrap();
+        assert_eq!("> hello, worldÉcole", out);
+
+        match StringInserter::new("naïve")
+            .insert(3, "!")
+            .execute_into(&mut out)
+        {
+            Err(Error::Utf8Error(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!("> hello, worldÉcole", out);
+    }
 }
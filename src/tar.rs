@@ -0,0 +1,350 @@
+use std::io::{self, Read, Write};
+
+/// tar archives are made of blocks of this size
+pub const BLOCK_SIZE: usize = 512;
+
+/// a regular file to be inserted into a tar archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    name: String,
+    data: Vec<u8>,
+    mode: u32,
+    mtime: u64,
+}
+
+impl Entry {
+    /// create a new entry with the given path inside the archive, mode 0644
+    pub fn new(name: &str, data: Vec<u8>) -> Entry {
+        Entry {
+            name: name.to_string(),
+            data,
+            mode: 0o644,
+            mtime: 0,
+        }
+    }
+
+    /// set the permission bits of this entry
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// set the modification time of this entry, in seconds since the unix epoch
+    pub fn mtime(mut self, mtime: u64) -> Self {
+        self.mtime = mtime;
+        self
+    }
+
+    /// the ustar header block for this entry
+    fn header(&self) -> io::Result<[u8; BLOCK_SIZE]> {
+        let mut header = [0_u8; BLOCK_SIZE];
+        let (prefix, name) = split_name(&self.name)?;
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        octal(&mut header[100..108], u64::from(self.mode))?;
+        octal(&mut header[108..116], 0)?;
+        octal(&mut header[116..124], 0)?;
+        octal(&mut header[124..136], self.data.len() as u64)?;
+        octal(&mut header[136..148], self.mtime)?;
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        let sum = checksum(&header);
+        octal(&mut header[148..155], sum)?;
+        header[155] = b' ';
+        Ok(header)
+    }
+
+    fn write_to<W: Write>(&self, target: &mut W) -> io::Result<()> {
+        target.write_all(&self.header()?)?;
+        target.write_all(&self.data)?;
+        target.write_all(&[0; BLOCK_SIZE][..padding(self.data.len() as u64) as usize])
+    }
+}
+
+/// insert a new entry into a tar archive at the given entry index
+///
+/// extension headers (pax and gnu long names) are kept together with the entry
+/// they describe. an index past the last entry appends before the trailing
+/// zero blocks, which are preserved; if the origin lacks them, they're added.
+/// every origin header up to the end-of-archive marker is read, and its
+/// checksum verified before it's written, including those after the insertion
+/// point. whatever follows the marker is copied as it is
+pub fn insert_entry<R, W>(
+    mut origin: R,
+    mut target: W,
+    index: usize,
+    entry: &Entry,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let mut block = [0_u8; BLOCK_SIZE];
+    let mut count = 0;
+    let mut in_extension = false;
+    let mut pending = Some(entry);
+
+    loop {
+        if !read_block(&mut origin, &mut block)? {
+            // no end-of-archive marker: finish the archive properly
+            if let Some(entry) = pending {
+                entry.write_to(&mut target)?;
+            }
+            return target.write_all(&[0; 2 * BLOCK_SIZE]);
+        }
+        if block.iter().all(|&b| b == 0) {
+            if let Some(entry) = pending {
+                entry.write_to(&mut target)?;
+            }
+            target.write_all(&block)?;
+            io::copy(&mut origin, &mut target)?;
+            return Ok(());
+        }
+
+        let stored = parse_octal(&block[148..156])?;
+        if stored != checksum(&block) {
+            return Err(invalid("tar header checksum mismatch"));
+        }
+        if !in_extension && count == index {
+            if let Some(entry) = pending.take() {
+                entry.write_to(&mut target)?;
+            }
+        }
+
+        in_extension = match block[156] {
+            b'x' | b'g' | b'L' | b'K' => true,
+            _ => {
+                count += 1;
+                false
+            }
+        };
+        target.write_all(&block)?;
+        copy_entry_data(&mut origin, &mut target, &block)?;
+    }
+}
+
+fn copy_entry_data<R: Read, W: Write>(
+    origin: &mut R,
+    target: &mut W,
+    header: &[u8; BLOCK_SIZE],
+) -> io::Result<()> {
+    let size = parse_size(&header[124..136])?;
+    let len = size + padding(size);
+    if io::copy(&mut origin.by_ref().take(len), target)? != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated tar entry",
+        ));
+    }
+    Ok(())
+}
+
+/// fill the block, returning false on a clean end of stream
+fn read_block<R: Read>(origin: &mut R, block: &mut [u8; BLOCK_SIZE]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < BLOCK_SIZE {
+        match origin.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated tar block",
+                ))
+            }
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64
+}
+
+/// the header checksum, computed with the checksum field itself as spaces
+fn checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(idx, &b)| {
+            if (148..156).contains(&idx) {
+                u64::from(b' ')
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum()
+}
+
+fn split_name(name: &str) -> io::Result<(&str, &str)> {
+    if name.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "tar entry name must not be empty",
+        ));
+    }
+    if name.len() <= 100 {
+        return Ok(("", name));
+    }
+    // ustar allows a 155 byte prefix, split at a directory separator
+    name.char_indices()
+        .filter(|&(idx, c)| c == '/' && idx <= 155 && name.len() - idx - 1 <= 100)
+        .map(|(idx, _)| (&name[..idx], &name[idx + 1..]))
+        .find(|(_, rest)| !rest.is_empty())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "tar entry name too long"))
+}
+
+/// write a zero-padded, nul-terminated octal number filling the field
+fn octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    if digits.len() >= field.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "value too large for tar header",
+        ));
+    }
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+    Ok(())
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = field
+        .iter()
+        .cloned()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| (b'0'..=b'7').contains(&b));
+    let mut value = 0_u64;
+    for digit in digits {
+        value = value
+            .checked_mul(8)
+            .ok_or_else(|| invalid("tar number overflow"))?
+            + u64::from(digit - b'0');
+    }
+    Ok(value)
+}
+
+fn parse_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        // gnu base-256 encoding for large sizes
+        return field[1..]
+            .iter()
+            .try_fold(u64::from(field[0] & 0x7F), |acc, &b| {
+                acc.checked_mul(256)
+                    .map(|acc| acc + u64::from(b))
+                    .ok_or_else(|| invalid("tar size overflow"))
+            });
+    }
+    parse_octal(field)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for entry in entries {
+            entry.write_to(&mut out).unwrap();
+        }
+        out.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+        out
+    }
+
+    fn names(tar: &[u8]) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut at = 0;
+        while tar[at..at + BLOCK_SIZE].iter().any(|&b| b != 0) {
+            let header = &tar[at..at + BLOCK_SIZE];
+            let end = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+            names.push(String::from_utf8(header[..end].to_vec()).unwrap());
+            let size = parse_octal(&header[124..136]).unwrap();
+            at += BLOCK_SIZE + (size + padding(size)) as usize;
+        }
+        assert_eq!(tar.len(), at + 2 * BLOCK_SIZE, "trailing zero blocks");
+        names
+    }
+
+    fn run(origin: &[u8], index: usize) -> Vec<u8> {
+        let mut dest = Vec::new();
+        insert_entry(origin, &mut dest, index, &Entry::new("new", vec![7; 600])).unwrap();
+        assert_eq!(0, dest.len() % BLOCK_SIZE);
+        dest
+    }
+
+    #[test]
+    fn insert_in_middle() {
+        let origin = archive(&[Entry::new("a", vec![1; 10]), Entry::new("b", vec![2; 512])]);
+        assert_eq!(vec!["a", "new", "b"], names(&run(&origin, 1)));
+    }
+
+    #[test]
+    fn insert_past_end() {
+        let origin = archive(&[Entry::new("a", vec![1; 10])]);
+        assert_eq!(vec!["a", "new"], names(&run(&origin, 5)));
+    }
+
+    #[test]
+    fn keeps_extension_headers_with_their_entry() {
+        let mut pax = Entry::new("PaxHeader", b"10 a=b\n".to_vec())
+            .header()
+            .unwrap();
+        pax[156] = b'x';
+        let sum = checksum(&pax);
+        octal(&mut pax[148..155], sum).unwrap();
+        let mut origin = archive(&[Entry::new("a", Vec::new())]);
+        origin.truncate(BLOCK_SIZE);
+        origin.extend_from_slice(&pax);
+        origin.extend_from_slice(b"10 a=b\n");
+        origin.extend_from_slice(&[0; BLOCK_SIZE - 7]);
+        origin.extend(archive(&[Entry::new("b", Vec::new())]));
+        assert_eq!(vec!["a", "new", "PaxHeader", "b"], names(&run(&origin, 1)));
+    }
+
+    #[test]
+    fn long_names_use_prefix() {
+        let name = format!("{}/{}", "d".repeat(120), "file");
+        let header = Entry::new(&name, Vec::new()).header().unwrap();
+        assert_eq!(b"file\0", &header[..5]);
+        assert_eq!(b'd', header[345]);
+    }
+
+    #[test]
+    fn rejects_corrupt_header() {
+        let mut origin = archive(&[Entry::new("a", Vec::new())]);
+        origin[0] = b'z';
+        let mut dest = Vec::new();
+        let err = insert_entry(
+            origin.as_slice(),
+            &mut dest,
+            5,
+            &Entry::new("x", Vec::new()),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // a bad header at or past the insertion point is caught before it's written
+        let entries = [Entry::new("a", Vec::new()), Entry::new("b", Vec::new())];
+        for &index in [0, 1].iter() {
+            let mut origin = archive(&entries);
+            origin[BLOCK_SIZE] = b'z';
+            let mut dest = Vec::new();
+            let err = insert_entry(
+                origin.as_slice(),
+                &mut dest,
+                index,
+                &Entry::new("x", Vec::new()),
+            )
+            .unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidData, err.kind());
+            assert!(!dest.chunks(BLOCK_SIZE).any(|block| block[0] == b'z'));
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use scan::Finder;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+};
+
+use inserter::BUFFER_SIZE;
+
+/// longest placeholder name, in bytes, before the template is rejected
+pub const MAX_NAME_LEN: usize = 256;
+
+type Values<'i> = HashMap<Vec<u8>, Option<Box<dyn 'i + Read>>>;
+
+/// template inserter scans the origin for placeholders like `{{name}}` and
+/// replaces each with the reader registered under that name
+pub struct TemplateInserter<'i, R, W> {
+    origin: R,
+    open: Vec<u8>,
+    close: Vec<u8>,
+    values: Values<'i>,
+    target: W,
+}
+
+impl<'i, R, W> TemplateInserter<'i, R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// create a new template inserter with the default `{{` `}}` delimiters
+    pub fn new(origin: R, target: W) -> TemplateInserter<'i, R, W> {
+        TemplateInserter {
+            origin,
+            open: b"{{".to_vec(),
+            close: b"}}".to_vec(),
+            values: Values::new(),
+            target,
+        }
+    }
+
+    /// use custom placeholder delimiters
+    ///
+    /// panics if either delimiter is empty
+    pub fn delimiters(mut self, open: &str, close: &str) -> Self {
+        assert!(
+            !open.is_empty() && !close.is_empty(),
+            "placeholder delimiters must not be empty"
+        );
+        self.open = open.as_bytes().to_vec();
+        self.close = close.as_bytes().to_vec();
+        self
+    }
+
+    /// register the source document to be streamed in place of the named placeholder
+    ///
+    /// whitespace just inside the delimiters is ignored, so `{{ name }}` also matches
+    pub fn register<I: 'i + Read>(mut self, name: &str, source: I) -> Self {
+        self.values
+            .insert(name.trim().as_bytes().to_vec(), Some(Box::new(source)));
+        self
+    }
+
+    /// execute this template inserter, consuming it
+    ///
+    /// fails if a placeholder is unterminated, unregistered, or appears more
+    /// than once: registered readers can only be streamed a single time
+    pub fn execute(mut self) -> io::Result<()> {
+        let mut open = Finder::new(&self.open);
+        let mut close = Finder::new(&self.close);
+        let mut name: Option<Vec<u8>> = None;
+        let mut out = Vec::with_capacity(BUFFER_SIZE * 2);
+        let mut buffer = [0_u8; BUFFER_SIZE];
+
+        loop {
+            let bytes_read = match self.origin.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(bytes_read) => bytes_read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            for &byte in &buffer[..bytes_read] {
+                let complete = match name {
+                    None => {
+                        if open.feed_through(byte, &mut out) {
+                            name = Some(Vec::new());
+                        }
+                        false
+                    }
+                    Some(ref mut name) => {
+                        name.push(byte);
+                        if name.len() > MAX_NAME_LEN + self.close.len() {
+                            return Err(invalid("placeholder name too long"));
+                        }
+                        close.feed(byte)
+                    }
+                };
+                if complete {
+                    let mut placeholder = name.take().expect("only completed inside a placeholder");
+                    let name_len = placeholder.len() - self.close.len();
+                    placeholder.truncate(name_len);
+                    self.target.write_all(&out)?;
+                    out.clear();
+                    self.substitute(&placeholder)?;
+                }
+            }
+
+            if out.len() >= BUFFER_SIZE {
+                self.target.write_all(&out)?;
+                out.clear();
+            }
+        }
+
+        if name.is_some() {
+            return Err(invalid("unterminated placeholder"));
+        }
+        out.extend_from_slice(open.pending());
+        self.target.write_all(&out)?;
+        Ok(())
+    }
+
+    fn substitute(&mut self, placeholder: &[u8]) -> io::Result<()> {
+        let key = String::from_utf8_lossy(placeholder)
+            .trim()
+            .as_bytes()
+            .to_vec();
+        match self.values.get_mut(&key) {
+            None => Err(invalid(&format!(
+                "unregistered placeholder `{}`",
+                String::from_utf8_lossy(&key)
+            ))),
+            Some(slot) => match slot.take() {
+                None => Err(invalid(&format!(
+                    "placeholder `{}` used more than once",
+                    String::from_utf8_lossy(&key)
+                ))),
+                Some(mut source) => io::copy(&mut source, &mut self.target).map(|_| ()),
+            },
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, values: &[(&str, &str)]) -> io::Result<String> {
+        let mut dest = Vec::new();
+        {
+            let mut inserter = TemplateInserter::new(template.as_bytes(), &mut dest);
+            for (name, value) in values {
+                inserter = inserter.register(name, value.as_bytes());
+            }
+            inserter.execute()?;
+        }
+        Ok(String::from_utf8(dest).unwrap())
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let out = render(
+            "hello {{name}}, welcome to {{ place }}!",
+            &[("name", "world"), ("place", "the crate")],
+        )
+        .unwrap();
+        assert_eq!("hello world, welcome to the crate!", out);
+    }
+
+    #[test]
+    fn custom_delimiters() {
+        let mut dest = Vec::new();
+        TemplateInserter::new("a <%x%> c {{x}}".as_bytes(), &mut dest)
+            .delimiters("<%", "%>")
+            .register("x", "b".as_bytes())
+            .execute()
+            .unwrap();
+        assert_eq!(b"a b c {{x}}", dest.as_slice());
+    }
+
+    #[test]
+    fn placeholder_spanning_buffers() {
+        let prefix = "-".repeat(BUFFER_SIZE - 3);
+        let out = render(&format!("{}{{{{long}}}}-", prefix), &[("long", "!")]).unwrap();
+        assert_eq!(format!("{}!-", prefix), out);
+    }
+
+    #[test]
+    fn rejects_unregistered_placeholder() {
+        let err = render("{{missing}}", &[]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn rejects_unterminated_placeholder() {
+        let err = render("{{oops", &[("oops", "")]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,691 @@
+//! helpers for testing sources, targets and format layers: readers and
+//! writers which misbehave on schedule, and a reference model of the engine
+
+use checksum::Checksum;
+use inserter::{overlap, Endian};
+use operation::{Operation, PlanBuilder, Transform};
+use source::{InsertSource, IntoInsertSource};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    io::{self, Read, Write},
+    ops::Range,
+};
+
+/// what happens on a single call to `read` or `write`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// pass the call through, limited to at most this many bytes. a read
+    /// limited to 0 bytes reports a spurious end of file; a write, a target
+    /// which accepts nothing
+    Short(usize),
+    /// fail with `Interrupted`, which callers are expected to retry
+    Interrupted,
+    /// fail with `WouldBlock`, as a non-blocking stream does when not ready
+    WouldBlock,
+    /// fail with an error of this kind
+    Fail(io::ErrorKind),
+}
+
+/// a reader or writer which applies a schedule of faults to its calls
+///
+/// each call takes the next fault from the schedule; once the schedule runs
+/// out, calls pass straight through, unless it cycles. independently, every
+/// call can be made to fail once a given number of bytes has passed.
+#[derive(Debug)]
+pub struct Faulty<T> {
+    inner: T,
+    schedule: VecDeque<Fault>,
+    cycle: bool,
+    fail_at: Option<(u64, io::ErrorKind)>,
+    passed: u64,
+    calls: usize,
+}
+
+impl<T> Faulty<T> {
+    /// apply these faults to the first calls, in order
+    pub fn new<I: IntoIterator<Item = Fault>>(inner: T, schedule: I) -> Faulty<T> {
+        Faulty {
+            inner,
+            schedule: schedule.into_iter().collect(),
+            cycle: false,
+            fail_at: None,
+            passed: 0,
+            calls: 0,
+        }
+    }
+
+    /// every call passes at most this many bytes
+    pub fn short(inner: T, len: usize) -> Faulty<T> {
+        Faulty::new(inner, [Fault::Short(len)]).cycle()
+    }
+
+    /// every other call is interrupted
+    pub fn interrupting(inner: T) -> Faulty<T> {
+        Faulty::new(inner, [Fault::Interrupted, Fault::Short(usize::MAX)]).cycle()
+    }
+
+    /// repeat the schedule once it runs out
+    pub fn cycle(mut self) -> Self {
+        self.cycle = true;
+        self
+    }
+
+    /// fail every call with an error of this kind once this many bytes have
+    /// passed, as a stream which breaks mid-way does
+    pub fn fail_at(mut self, bytes: u64, kind: io::ErrorKind) -> Self {
+        self.fail_at = Some((bytes, kind));
+        self
+    }
+
+    /// how many calls have been made, faulty or not
+    pub fn calls(&self) -> usize {
+        self.calls
+    }
+
+    /// how many bytes have passed through
+    pub fn passed(&self) -> u64 {
+        self.passed
+    }
+
+    /// get a reference to the wrapped reader or writer
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// unwrap this, returning the wrapped reader or writer
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// the most bytes this call may pass, or the error it fails with
+    fn next_call(&mut self) -> io::Result<usize> {
+        self.calls += 1;
+        let mut limit = usize::MAX;
+        if let Some((at, kind)) = self.fail_at {
+            if self.passed >= at {
+                return Err(io::Error::new(kind, "injected failure"));
+            }
+            limit = (at - self.passed).try_into().unwrap_or(usize::MAX);
+        }
+        let fault = match self.schedule.pop_front() {
+            Some(fault) => {
+                if self.cycle {
+                    self.schedule.push_back(fault);
+                }
+                fault
+            }
+            None => return Ok(limit),
+        };
+        match fault {
+            Fault::Short(len) => Ok(limit.min(len)),
+            Fault::Interrupted => Err(io::ErrorKind::Interrupted.into()),
+            Fault::WouldBlock => Err(io::ErrorKind::WouldBlock.into()),
+            Fault::Fail(kind) => Err(io::Error::new(kind, "injected failure")),
+        }
+    }
+}
+
+impl<R: Read> Read for Faulty<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let limit = self.next_call()?.min(buf.len());
+        let len = self.inner.read(&mut buf[..limit])?;
+        self.passed += len as u64;
+        Ok(len)
+    }
+}
+
+impl<'i, R: 'i + Read> IntoInsertSource<'i> for Faulty<R> {
+    fn into_insert_source(self) -> InsertSource<'i> {
+        InsertSource::reader(self)
+    }
+}
+
+impl<W: Write> Write for Faulty<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let limit = self.next_call()?.min(buf.len());
+        let len = self.inner.write(&buf[..limit])?;
+        self.passed += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// apply the plan to the origin in memory, the slow and simple way
+///
+/// this is a reference model of `PlanBuilder::execute`, against which the
+/// streaming engine and layers built on it can be property tested: for any
+/// plan, both give the same output, or both fail with the same kind of error.
+/// the output is laid out position by position, then search and replace,
+/// fixups and checksums are applied to it as a whole.
+pub fn reference_apply(origin: &[u8], plan: PlanBuilder) -> io::Result<Vec<u8>> {
+    let mut insertions = Vec::new();
+    let mut patches = Vec::new();
+    let mut deleted = Vec::new();
+    let mut replacements = Vec::new();
+    for operation in plan.into_operations() {
+        match operation {
+            Operation::Insert(position, source) => insertions.push((position, read(source)?)),
+            Operation::Delete(range) => {
+                if range.start < range.end {
+                    patches.push(Patch::new(range.start, range.len(), Kind::Delete));
+                    deleted.push(range);
+                }
+            }
+            Operation::Replace(range, source) => {
+                if range.start < range.end {
+                    patches.push(Patch::new(range.start, range.len(), Kind::Delete));
+                    deleted.push(range.clone());
+                }
+                insertions.push((range.start, read(source)?));
+            }
+            Operation::Overwrite(position, bytes) => {
+                patches.push(Patch::new(position, bytes.len(), Kind::Bytes(bytes)))
+            }
+            Operation::Transform(range, transform) => {
+                let len = range.end.saturating_sub(range.start);
+                let kind = Kind::Transform(range.clone(), transform);
+                patches.push(Patch::new(range.start, len, kind));
+            }
+            Operation::Copy(range, position) => {
+                let copied = clamp(origin, range.start, range.end).to_vec();
+                insertions.push((position, copied));
+            }
+            Operation::ReplaceAll(pattern, replacement) => {
+                if pattern.is_empty() {
+                    return Err(invalid("empty search pattern"));
+                }
+                replacements.push((pattern, replacement));
+            }
+            Operation::Fixup(position, endian, range) => {
+                patches.push(Patch::new(position, 4, Kind::Fixup(endian, range)))
+            }
+            Operation::Checksum(position, endian, region, checksum) => {
+                let kind = Kind::Checksum(endian, region, checksum);
+                patches.push(Patch::new(position, kind.width(), kind));
+            }
+        }
+    }
+    // sorting is stable, so insertions at one position keep the order they
+    // were added in
+    insertions.sort_by_key(|&(position, _)| position);
+    patches.sort_by_key(|patch| patch.start);
+    validate(&insertions, &patches, !replacements.is_empty())?;
+
+    let (mut segments, fields) = lay_out(origin, insertions, patches)?;
+    if !replacements.is_empty() {
+        // fields were rejected, so their indices into the segments don't matter
+        segments = replace_all(segments, &replacements);
+    }
+    for (idx, field) in fields {
+        let value = match field {
+            Field::Fixup(endian, range, original) => {
+                let mut value = i64::from(original);
+                for (segment, bytes) in &segments {
+                    match *segment {
+                        Segment::Insertion(at) if range.start <= at && at <= range.end => {
+                            value += bytes.len() as i64
+                        }
+                        Segment::Transformed(ref transformed, original_len)
+                            if range.start <= transformed.start && transformed.end <= range.end =>
+                        {
+                            value += bytes.len() as i64 - original_len as i64
+                        }
+                        _ => {}
+                    }
+                }
+                for deletion in &deleted {
+                    value -= overlap(&range, deletion) as i64;
+                }
+                if value < 0 || value > i64::from(u32::MAX) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "fixup value out of range",
+                    ));
+                }
+                match endian {
+                    Endian::Big => (value as u32).to_be_bytes().to_vec(),
+                    Endian::Little => (value as u32).to_le_bytes().to_vec(),
+                }
+            }
+            Field::Checksum(endian, region, mut checksum) => {
+                // the field itself is summed as zeros, as it's still unfilled
+                for (segment, bytes) in &segments {
+                    match *segment {
+                        Segment::Insertion(at) if region.start <= at && at <= region.end => {
+                            checksum.update(bytes)
+                        }
+                        Segment::Transformed(ref range, _)
+                            if region.start <= range.start && range.end <= region.end =>
+                        {
+                            checksum.update(bytes)
+                        }
+                        Segment::Origin(at) | Segment::Field(at) => {
+                            let bytes = clamp(
+                                bytes,
+                                region.start.saturating_sub(at),
+                                region.end.saturating_sub(at),
+                            );
+                            checksum.update(bytes);
+                        }
+                        _ => {}
+                    }
+                }
+                let width = checksum.width();
+                let value = checksum.value();
+                match endian {
+                    Endian::Big => value.to_be_bytes()[8 - width..].to_vec(),
+                    Endian::Little => value.to_le_bytes()[..width].to_vec(),
+                }
+            }
+        };
+        segments[idx].1 = value;
+    }
+    Ok(segments.into_iter().flat_map(|(_, bytes)| bytes).collect())
+}
+
+/// a change to a run of origin bytes
+struct Patch<'i> {
+    start: usize,
+    len: usize,
+    kind: Kind<'i>,
+}
+
+impl<'i> Patch<'i> {
+    fn new(start: usize, len: usize, kind: Kind<'i>) -> Patch<'i> {
+        Patch { start, len, kind }
+    }
+}
+
+enum Kind<'i> {
+    Bytes(Vec<u8>),
+    Delete,
+    Transform(Range<usize>, Transform<'i>),
+    Fixup(Endian, Range<usize>),
+    Checksum(Endian, Range<usize>, Box<dyn 'i + Checksum>),
+}
+
+impl<'i> Kind<'i> {
+    fn width(&self) -> usize {
+        match *self {
+            Kind::Checksum(_, _, ref checksum) => checksum.width(),
+            _ => 4,
+        }
+    }
+}
+
+/// where a run of output bytes comes from, in origin indices
+enum Segment {
+    /// origin bytes, or overwriting bytes, from this index on
+    Origin(usize),
+    /// an insertion at this index
+    Insertion(usize),
+    /// the output of transforming this range, which held this many origin bytes
+    Transformed(Range<usize>, usize),
+    /// a fixup or checksum field at this index
+    Field(usize),
+}
+
+/// the output, as runs of bytes and where they come from
+type Segments = Vec<(Segment, Vec<u8>)>;
+
+/// a field's value, to be computed from the whole output
+enum Field<'i> {
+    Fixup(Endian, Range<usize>, u32),
+    Checksum(Endian, Range<usize>, Box<dyn 'i + Checksum>),
+}
+
+/// reject the plans which the engine rejects
+fn validate(insertions: &[(usize, Vec<u8>)], patches: &[Patch], replacing: bool) -> io::Result<()> {
+    let regions = |fixups: bool| {
+        patches.iter().filter_map(move |patch| match patch.kind {
+            Kind::Fixup(_, ref range) if fixups => Some((patch.start, range)),
+            Kind::Checksum(_, ref region, _) => Some((patch.start, region)),
+            _ => None,
+        })
+    };
+    for (idx, patch) in patches.iter().enumerate() {
+        let end = patch.start + patch.len;
+        if idx > 0 {
+            let previous = &patches[idx - 1];
+            if patch.start == previous.start || patch.start < previous.start + previous.len {
+                return Err(invalid("overlapping overwrites"));
+            }
+        }
+        match patch.kind {
+            Kind::Bytes(_) | Kind::Delete => continue,
+            Kind::Fixup(_, _) | Kind::Checksum(_, _, _) if replacing => {
+                return Err(invalid("search and replace with fixups or checksums"))
+            }
+            _ => {}
+        }
+        if insertions
+            .iter()
+            .any(|&(at, _)| patch.start < at && at < end)
+        {
+            return Err(invalid("insertion within a field or transformed range"));
+        }
+        match patch.kind {
+            Kind::Transform(ref range, _) => {
+                let straddles = regions(true).any(|(_, outer)| {
+                    overlap(range, outer) > 0
+                        && (range.start < outer.start || outer.end < range.end)
+                });
+                if straddles {
+                    return Err(invalid(
+                        "transform straddling a fixup range or checksum region",
+                    ));
+                }
+            }
+            _ => {
+                let covered = regions(false).any(|(start, region)| {
+                    start != patch.start && patch.start < region.end && region.start < end
+                });
+                if covered {
+                    return Err(invalid(
+                        "fixup or checksum field within another checksum region",
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the output as runs of bytes, with their fields still zeroed
+fn lay_out<'i>(
+    origin: &[u8],
+    insertions: Vec<(usize, Vec<u8>)>,
+    patches: Vec<Patch<'i>>,
+) -> io::Result<(Segments, Vec<(usize, Field<'i>)>)> {
+    let mut segments = Vec::new();
+    let mut fields = Vec::new();
+    let mut insertions = insertions.into_iter().peekable();
+    let mut patches = patches.into_iter().peekable();
+    // an overwrite or deletion in progress: its start, its bytes, and its end
+    let mut overlay: Option<(usize, Option<Vec<u8>>, usize)> = None;
+    let mut position = 0;
+    loop {
+        while let Some((_, bytes)) = insertions.next_if(|&(at, _)| at == position) {
+            segments.push((Segment::Insertion(position), bytes));
+        }
+        if let Some(patch) = patches.next_if(|patch| patch.start == position) {
+            let end = position + patch.len;
+            match patch.kind {
+                Kind::Bytes(bytes) => overlay = Some((position, Some(bytes), end)),
+                Kind::Delete => overlay = Some((position, None, end)),
+                Kind::Transform(range, mut transform) => {
+                    let original = clamp(origin, position, end);
+                    let transformed = transform(original);
+                    segments.push((Segment::Transformed(range, original.len()), transformed));
+                    position = end;
+                    continue;
+                }
+                Kind::Fixup(endian, range) => {
+                    let field = origin.get(position..end).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "fixup field past the end of the origin",
+                        )
+                    })?;
+                    let field = [field[0], field[1], field[2], field[3]];
+                    let original = match endian {
+                        Endian::Big => u32::from_be_bytes(field),
+                        Endian::Little => u32::from_le_bytes(field),
+                    };
+                    fields.push((segments.len(), Field::Fixup(endian, range, original)));
+                    segments.push((Segment::Field(position), vec![0; 4]));
+                    position = end;
+                    continue;
+                }
+                Kind::Checksum(endian, region, checksum) => {
+                    fields.push((segments.len(), Field::Checksum(endian, region, checksum)));
+                    segments.push((Segment::Field(position), vec![0; patch.len]));
+                    position = end;
+                    continue;
+                }
+            }
+        }
+        if overlay.as_ref().is_some_and(|&(_, _, end)| end <= position) {
+            overlay = None;
+        }
+        let next = [
+            insertions.peek().map(|&(at, _)| at),
+            patches.peek().map(|patch| patch.start),
+            overlay.as_ref().map(|&(_, _, end)| end),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .cloned();
+        match (overlay.as_ref(), next) {
+            (Some(&(start, ref bytes, end)), next) => {
+                let stop = next.unwrap_or(end);
+                if let Some(ref bytes) = *bytes {
+                    let overwritten = bytes[position - start..stop - start].to_vec();
+                    segments.push((Segment::Origin(position), overwritten));
+                }
+                position = stop;
+            }
+            (None, next) if position < origin.len() => {
+                let stop = next.map_or(origin.len(), |next| next.min(origin.len()));
+                segments.push((Segment::Origin(position), origin[position..stop].to_vec()));
+                position = stop;
+            }
+            // past the end of the origin, insertions and overwrites follow directly
+            (None, Some(next)) => position = next,
+            (None, None) => break,
+        }
+    }
+    Ok((segments, fields))
+}
+
+/// replace every pattern within each run of contiguous origin bytes, one
+/// pattern after another
+fn replace_all(segments: Segments, replacements: &[(Vec<u8>, Vec<u8>)]) -> Segments {
+    let mut out = Vec::with_capacity(segments.len());
+    let mut run: Option<(usize, usize, Vec<u8>)> = None;
+    let finish = |run: Option<(usize, usize, Vec<u8>)>, out: &mut Vec<_>| {
+        if let Some((start, _, mut bytes)) = run {
+            for (pattern, replacement) in replacements {
+                bytes = replace(&bytes, pattern, replacement);
+            }
+            out.push((Segment::Origin(start), bytes));
+        }
+    };
+    for (segment, bytes) in segments {
+        match segment {
+            Segment::Origin(at) => match run {
+                Some((_, ref mut next, ref mut held)) if *next == at => {
+                    *next += bytes.len();
+                    held.extend(bytes);
+                }
+                _ => {
+                    finish(run.take(), &mut out);
+                    run = Some((at, at + bytes.len(), bytes));
+                }
+            },
+            segment => {
+                finish(run.take(), &mut out);
+                out.push((segment, bytes));
+            }
+        }
+    }
+    finish(run, &mut out);
+    out
+}
+
+/// replace the leftmost occurrences of the pattern, which never overlap
+fn replace(bytes: &[u8], pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx..].starts_with(pattern) {
+            out.extend_from_slice(replacement);
+            idx += pattern.len();
+        } else {
+            out.push(bytes[idx]);
+            idx += 1;
+        }
+    }
+    out
+}
+
+fn read(source: InsertSource) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    source.into_reader().read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// the bytes within `start..end`, clamped to the slice
+fn clamp(bytes: &[u8], start: usize, end: usize) -> &[u8] {
+    let end = end.min(bytes.len());
+    &bytes[start.min(end)..end]
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use checksum::Crc32;
+    use operation::Reverse;
+    use Inserter;
+
+    #[test]
+    fn follows_schedule() {
+        let mut reader = Faulty::new(
+            &b"abcdef"[..],
+            [Fault::Short(2), Fault::Interrupted, Fault::WouldBlock],
+        );
+        let mut buf = [0; 8];
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        let kinds: Vec<_> = (0..2)
+            .map(|_| reader.read(&mut buf).unwrap_err().kind())
+            .collect();
+        assert_eq!(
+            vec![io::ErrorKind::Interrupted, io::ErrorKind::WouldBlock],
+            kinds
+        );
+        assert_eq!(4, reader.read(&mut buf).unwrap());
+        assert_eq!(4, reader.calls());
+    }
+
+    #[test]
+    fn engine_retries_short_and_interrupted_calls() {
+        let mut target = Faulty::interrupting(Faulty::short(Vec::new(), 2));
+        Inserter::new(
+            Faulty::interrupting(Faulty::short(&b"alpha charlie"[..], 3)),
+            &mut target,
+        )
+        .insert(6, Faulty::interrupting(Faulty::short(&b"bravo "[..], 1)))
+        .execute()
+        .unwrap();
+        assert_eq!(
+            b"alpha bravo charlie",
+            &target.into_inner().into_inner()[..]
+        );
+    }
+
+    #[test]
+    fn fails_mid_stream() {
+        let mut target = Faulty::new(Vec::new(), []).fail_at(4, io::ErrorKind::BrokenPipe);
+        let err = Inserter::new(&b"alpha charlie"[..], &mut target)
+            .insert(6, &b"bravo "[..])
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::BrokenPipe, err.kind());
+        assert_eq!(b"alph".to_vec(), target.into_inner());
+    }
+
+    /// check the engine against the reference model, building the plan twice
+    /// as it can't be cloned
+    fn matches_reference<F: Fn() -> PlanBuilder<'static>>(origin: &[u8], plan: F) {
+        let engine = plan().execute_to_vec(origin);
+        let reference = reference_apply(origin, plan());
+        match (engine, reference) {
+            (Ok(engine), Ok(reference)) => {
+                assert_eq!(reference, engine);
+                let mut delta = Vec::new();
+                plan().execute_delta(origin, &mut delta).unwrap();
+                let mut patched = Vec::new();
+                ::delta::patch(io::Cursor::new(origin), delta.as_slice(), &mut patched).unwrap();
+                assert_eq!(engine, patched);
+            }
+            (Err(engine), Err(reference)) => assert_eq!(reference.kind(), engine.kind()),
+            (engine, reference) => panic!("engine {:?}, reference {:?}", engine, reference),
+        }
+    }
+
+    #[test]
+    fn reference_matches_engine() {
+        let origin = b"\x00\x00\x00\x08alpha bravo charlie";
+        matches_reference(origin, || {
+            PlanBuilder::new()
+                .insert(10, &b"-"[..])
+                .delete(4..6)
+                .replace(6..8, "PH")
+                .overwrite(22, b"ie and delta")
+                .copy(4..9, 40)
+                .insert(40, "|")
+        });
+        matches_reference(origin, || {
+            PlanBuilder::new()
+                .replace_all(b"a", b"aa")
+                .replace_all(b"aa b", b"B")
+                .insert(10, "")
+                .reverse(13..20, Reverse::Bytes)
+                .delete(20..21)
+        });
+        matches_reference(origin, || {
+            PlanBuilder::new()
+                .fixup(0, Endian::Big, 4..12)
+                .insert(12, "!!")
+                .delete(5..6)
+                .reverse(6..9, Reverse::Lines)
+                .checksum(30, Endian::Little, 4..30, Crc32::new())
+                .insert(4, "x")
+        });
+        matches_reference(origin, || {
+            PlanBuilder::new()
+                .replace_all(b"a b", b"-")
+                .insert(9, InsertSource::reader(&b""[..]))
+        });
+        matches_reference(origin, || {
+            PlanBuilder::new().delete(2..6).overwrite(5, b"x")
+        });
+        matches_reference(origin, || PlanBuilder::new().fixup(24, Endian::Big, 0..1));
+        matches_reference(origin, || {
+            PlanBuilder::new()
+                .reverse(4..12, Reverse::Bytes)
+                .insert(8, "x")
+        });
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_plans_match_reference() {
+        use arbitrary::{Arbitrary, Unstructured};
+
+        let origin: Vec<u8> = (0..=255).map(|b: u8| b % 8).collect();
+        let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+        for _ in 0..5000 {
+            let data: Vec<u8> = (0..256)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            matches_reference(&origin, || {
+                PlanBuilder::arbitrary(&mut Unstructured::new(&data)).unwrap()
+            });
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// a token bucket limiting the rate at which bytes are produced
+///
+/// the bucket holds a tenth of a second's worth of bytes, so output may burst
+/// that far ahead of the rate
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    rate: u64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Throttle {
+    /// limit output to `rate` bytes per second
+    pub(crate) fn new(rate: u64) -> Throttle {
+        let rate = rate.max(1);
+        let capacity = (rate as f64 / 10.0).max(1.0);
+        Throttle {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    /// wait until `len` more bytes may be produced
+    pub(crate) fn take(&mut self, len: usize) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity);
+        self.tokens -= len as f64;
+        if self.tokens < 0.0 {
+            // sleep off the debt; the refill after waking pays it back
+            thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bursts_then_waits() {
+        let mut throttle = Throttle::new(100_000);
+        let start = Instant::now();
+        throttle.take(10_000);
+        assert!(start.elapsed() < Duration::from_millis(50));
+        throttle.take(10_000);
+        throttle.take(10_000);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,186 @@
+//! multipart uploads to object stores, as targets
+//!
+//! the output is uploaded in parts as it's written, rather than staged on
+//! disk first. the store's async operations are run on a tokio runtime, so an
+//! upload mustn't be written from within one of that runtime's tasks.
+
+use object_store::{
+    path::Path, MultipartUpload, ObjectStore, PutPayload, PutResult, WriteMultipart,
+};
+use std::io::{self, Write};
+use tokio::runtime::Handle;
+
+/// the smallest part s3 accepts, other than the last
+pub const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+fn store_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::other(err),
+    }
+}
+
+/// a target which uploads what's written to it to an object, in parts of a
+/// fixed size uploaded concurrently
+///
+/// the object exists only once the upload is finished. an upload which fails
+/// or is dropped should be aborted, so that the store discards its parts.
+pub struct Upload {
+    handle: Handle,
+    upload: Option<Box<dyn MultipartUpload>>,
+    writer: Option<WriteMultipart>,
+    part_size: usize,
+    concurrency: usize,
+}
+
+impl Upload {
+    /// start a multipart upload to this location, running on the runtime
+    pub fn new(store: &dyn ObjectStore, location: &Path, handle: Handle) -> io::Result<Upload> {
+        let upload = handle
+            .block_on(store.put_multipart(location))
+            .map_err(store_error)?;
+        Ok(Upload {
+            handle,
+            upload: Some(upload),
+            writer: None,
+            part_size: MIN_PART_SIZE,
+            concurrency: 8,
+        })
+    }
+
+    /// upload parts of this size, which is at least `MIN_PART_SIZE`
+    ///
+    /// this has no effect once anything has been written
+    pub fn part_size(mut self, part_size: usize) -> Self {
+        self.part_size = part_size.max(MIN_PART_SIZE);
+        self
+    }
+
+    /// upload at most this many parts at once, buffering no more than one
+    /// further part: writes block until there's capacity
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    fn writer(&mut self) -> &mut WriteMultipart {
+        if let Some(upload) = self.upload.take() {
+            self.writer = Some(WriteMultipart::new_with_chunk_size(upload, self.part_size));
+        }
+        self.writer
+            .as_mut()
+            .expect("an upload has a writer once written")
+    }
+
+    /// upload the last part and complete the object
+    pub fn finish(mut self) -> io::Result<PutResult> {
+        let handle = self.handle.clone();
+        if let Some(mut upload) = self.upload.take() {
+            // an object needs at least one part, even if it's empty
+            handle
+                .block_on(upload.put_part(PutPayload::new()))
+                .map_err(store_error)?;
+            return handle.block_on(upload.complete()).map_err(store_error);
+        }
+        let writer = self
+            .writer
+            .take()
+            .expect("an upload has a writer once written");
+        let _entered = handle.enter();
+        handle.block_on(writer.finish()).map_err(store_error)
+    }
+
+    /// abandon the upload, asking the store to discard the parts uploaded so far
+    pub fn abort(mut self) -> io::Result<()> {
+        let handle = self.handle.clone();
+        let _entered = handle.enter();
+        let aborted = match (self.upload.take(), self.writer.take()) {
+            (Some(mut upload), _) => handle.block_on(upload.abort()),
+            (_, Some(writer)) => handle.block_on(writer.abort()),
+            (None, None) => Ok(()),
+        };
+        aborted.map_err(store_error)
+    }
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let handle = self.handle.clone();
+        let concurrency = self.concurrency;
+        // parts are uploaded by tasks spawned on the runtime
+        let _entered = handle.enter();
+        let writer = self.writer();
+        handle
+            .block_on(writer.wait_for_capacity(concurrency))
+            .map_err(store_error)?;
+        writer.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use tokio::runtime::{Builder, Runtime};
+    use Inserter;
+
+    fn runtime() -> Runtime {
+        Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()
+            .unwrap()
+    }
+
+    fn download(runtime: &Runtime, store: &InMemory, location: &Path) -> Vec<u8> {
+        let got = runtime.block_on(store.get(location)).unwrap();
+        runtime.block_on(got.bytes()).unwrap().to_vec()
+    }
+
+    #[test]
+    fn uploads_output_in_parts() {
+        let runtime = runtime();
+        let store = InMemory::new();
+        let location = Path::from("spliced/out.bin");
+        let origin = vec![7; 2 * MIN_PART_SIZE + 100];
+        let mut upload = Upload::new(&store, &location, runtime.handle().clone())
+            .unwrap()
+            .part_size(1024)
+            .concurrency(2);
+        Inserter::new(origin.as_slice(), &mut upload)
+            .insert(MIN_PART_SIZE, "inserted")
+            .execute()
+            .unwrap();
+        upload.finish().unwrap();
+
+        let uploaded = download(&runtime, &store, &location);
+        assert_eq!(origin.len() + 8, uploaded.len());
+        assert_eq!(b"inserted", &uploaded[MIN_PART_SIZE..MIN_PART_SIZE + 8]);
+    }
+
+    #[test]
+    fn uploads_empty_objects() {
+        let runtime = runtime();
+        let store = InMemory::new();
+        let location = Path::from("empty");
+        let upload = Upload::new(&store, &location, runtime.handle().clone()).unwrap();
+        upload.finish().unwrap();
+        assert!(download(&runtime, &store, &location).is_empty());
+    }
+
+    #[test]
+    fn aborted_uploads_leave_nothing() {
+        let runtime = runtime();
+        let store = InMemory::new();
+        let location = Path::from("aborted");
+        let mut upload = Upload::new(&store, &location, runtime.handle().clone()).unwrap();
+        upload.write_all(b"partial").unwrap();
+        upload.abort().unwrap();
+        let missing = runtime.block_on(store.head(&location)).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, store_error(missing).kind());
+    }
+}
@@ -0,0 +1,180 @@
+use inserter::Endian;
+use std::borrow::Cow;
+use std::io;
+use vec_inserter::VecInserter;
+
+type Insertions<'i> = Vec<(usize, Cow<'i, str>)>;
+
+/// utf-16 inserter splices text into a utf-16 document, such as a windows
+/// registry export
+///
+/// positions are in code units, not counting any byte order mark, which is
+/// kept and decides the byte order. documents without one are little-endian
+/// unless configured otherwise. insertions are encoded in the document's byte
+/// order; like the other inserters, positions past the end append.
+#[derive(Debug, Clone)]
+pub struct Utf16Inserter<'o, 'i> {
+    origin: &'o [u8],
+    endian: Endian,
+    insertions: Insertions<'i>,
+}
+
+impl<'o, 'i> Utf16Inserter<'o, 'i> {
+    /// create a new inserter with the specified origin document
+    pub fn new(origin: &'o [u8]) -> Utf16Inserter<'o, 'i> {
+        Utf16Inserter {
+            origin,
+            endian: Endian::Little,
+            insertions: Insertions::new(),
+        }
+    }
+
+    /// the byte order of a document without a byte order mark
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// insert the text into the output document at the given code unit index
+    pub fn insert<S: Into<Cow<'i, str>>>(mut self, position: usize, source: S) -> Self {
+        self.insertions.push((position, source.into()));
+        self
+    }
+
+    /// the byte order mark's length and the document's byte order
+    fn byte_order(&self) -> (usize, Endian) {
+        match self.origin {
+            [0xff, 0xfe, ..] => (2, Endian::Little),
+            [0xfe, 0xff, ..] => (2, Endian::Big),
+            _ => (0, self.endian),
+        }
+    }
+
+    /// execute this inserter, consuming it
+    ///
+    /// fails with `InvalidData` if the origin is an odd number of bytes, and
+    /// with `InvalidInput` if an insertion would split a surrogate pair
+    pub fn execute(self) -> io::Result<Vec<u8>> {
+        let (bom, endian) = self.byte_order();
+        if !self.origin.len().is_multiple_of(2) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "utf-16 document with an odd number of bytes",
+            ));
+        }
+        let unit = |idx: usize| {
+            let bytes = [self.origin[bom + 2 * idx], self.origin[bom + 2 * idx + 1]];
+            match endian {
+                Endian::Big => u16::from_be_bytes(bytes),
+                Endian::Little => u16::from_le_bytes(bytes),
+            }
+        };
+        let units = (self.origin.len() - bom) / 2;
+        let mut encoded = Vec::with_capacity(self.insertions.len());
+        for (position, text) in self.insertions.iter() {
+            let position = (*position).min(units);
+            if 0 < position
+                && position < units
+                && (0xd800..0xdc00).contains(&unit(position - 1))
+                && (0xdc00..0xe000).contains(&unit(position))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "insertion at code unit {} splits a surrogate pair",
+                        position
+                    ),
+                ));
+            }
+            let bytes: Vec<u8> = text
+                .encode_utf16()
+                .flat_map(|unit| match endian {
+                    Endian::Big => unit.to_be_bytes(),
+                    Endian::Little => unit.to_le_bytes(),
+                })
+                .collect();
+            encoded.push((bom + 2 * position, bytes));
+        }
+        let mut inserter = VecInserter::new(self.origin);
+        for (position, bytes) in encoded.iter() {
+            inserter = inserter.insert(*position, bytes);
+        }
+        Ok(inserter.execute())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(text: &str, endian: Endian, bom: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        if bom {
+            out.extend(encode("\u{feff}", endian, false));
+        }
+        for unit in text.encode_utf16() {
+            out.extend_from_slice(&match endian {
+                Endian::Big => unit.to_be_bytes(),
+                Endian::Little => unit.to_le_bytes(),
+            });
+        }
+        out
+    }
+
+    #[test]
+    fn keeps_byte_order_marks() {
+        for &endian in &[Endian::Little, Endian::Big] {
+            let origin = encode("[HKEY]\r\n\"a\"=1\r\n", endian, true);
+            let out = Utf16Inserter::new(&origin)
+                .insert(0, "; exported\r\n")
+                .insert(8, "\"b\"=2\r\n")
+                .insert(100, "; end")
+                .execute()
+                .unwrap();
+            assert_eq!(
+                encode(
+                    "; exported\r\n[HKEY]\r\n\"b\"=2\r\n\"a\"=1\r\n; end",
+                    endian,
+                    true
+                ),
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn defaults_to_little_endian() {
+        let origin = encode("ac", Endian::Little, false);
+        let out = Utf16Inserter::new(&origin)
+            .insert(1, "b")
+            .execute()
+            .unwrap();
+        assert_eq!(encode("abc", Endian::Little, false), out);
+
+        let origin = encode("ac", Endian::Big, false);
+        let out = Utf16Inserter::new(&origin)
+            .endian(Endian::Big)
+            .insert(1, "b")
+            .execute()
+            .unwrap();
+        assert_eq!(encode("abc", Endian::Big, false), out);
+    }
+
+    #[test]
+    fn rejects_split_surrogates() {
+        let origin = encode("a\u{1f600}b", Endian::Little, true);
+        let err = Utf16Inserter::new(&origin)
+            .insert(2, "x")
+            .execute()
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        let out = Utf16Inserter::new(&origin)
+            .insert(3, "x")
+            .execute()
+            .unwrap();
+        assert_eq!(encode("a\u{1f600}xb", Endian::Little, true), out);
+
+        let err = Utf16Inserter::new(&origin[1..]).execute().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
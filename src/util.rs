@@ -0,0 +1,54 @@
+//! small helpers shared by the library and the `insert-multiple` tool
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// read as much of the buffer as the stream allows, returning how much was read
+pub(crate) fn fill<R: Read>(origin: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match origin.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// a sibling of `path` to write to before renaming over it
+///
+/// each call gives a new name, so rewrites of the same path don't share one;
+/// the caller should still create it with `create_new`
+#[cfg_attr(not(feature = "cap-std"), allow(dead_code))]
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(
+        ".insert-multiple.{}.{}",
+        process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_each_temp_path_apart() {
+        let path = Path::new("dir/file.txt");
+        let (first, second) = (temp_path(path), temp_path(path));
+        assert_ne!(first, second);
+        assert_eq!(Some(Path::new("dir")), first.parent());
+        assert!(first
+            .to_string_lossy()
+            .starts_with("dir/file.txt.insert-multiple."));
+    }
+}
@@ -0,0 +1,95 @@
+type Insertions<'i, T> = Vec<(usize, &'i [T])>;
+
+/// vec inserter splices in-memory slices into an in-memory origin
+///
+/// the output is allocated once at its exact final size and filled with
+/// slice copies, skipping the buffered copy loop of `Inserter`. several
+/// insertions at the same index are all kept, in the order they were added.
+pub struct VecInserter<'o, 'i, T> {
+    origin: &'o [T],
+    insertions: Insertions<'i, T>,
+}
+
+impl<'o, 'i, T: Clone> VecInserter<'o, 'i, T> {
+    /// create a new inserter with the specified origin slice
+    pub fn new(origin: &'o [T]) -> VecInserter<'o, 'i, T> {
+        VecInserter {
+            origin,
+            insertions: Insertions::new(),
+        }
+    }
+
+    /// insert the source slice into the output at the given origin index
+    pub fn insert(mut self, position: usize, source: &'i [T]) -> Self {
+        self.insertions.push((position, source));
+        self
+    }
+
+    /// the length of the output
+    pub fn output_len(&self) -> usize {
+        self.origin.len() + self.insertions.iter().map(|(_, s)| s.len()).sum::<usize>()
+    }
+
+    /// execute this inserter, consuming it
+    pub fn execute(self) -> Vec<T> {
+        let mut out = Vec::with_capacity(self.output_len());
+        self.execute_into(&mut out);
+        out
+    }
+
+    /// execute this inserter, appending the output to `out`
+    pub fn execute_into(mut self, out: &mut Vec<T>) {
+        out.reserve_exact(self.output_len());
+        // a stable sort keeps same-index insertions in the order they were added
+        self.insertions.sort_by_key(|&(position, _)| position);
+        let mut copied = 0;
+        for (position, source) in self.insertions {
+            let position = position.min(self.origin.len());
+            out.extend_from_slice(&self.origin[copied..position]);
+            out.extend_from_slice(source);
+            copied = position;
+        }
+        out.extend_from_slice(&self.origin[copied..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave() {
+        let out = VecInserter::new(b"alpha bravo delta hotel")
+            .insert(18, b"echo fox golf ")
+            .insert(12, b"charlie ")
+            .execute();
+        assert_eq!(
+            &b"alpha bravo charlie delta echo fox golf hotel"[..],
+            &out[..]
+        );
+        assert_eq!(out.len(), out.capacity());
+    }
+
+    #[test]
+    fn ends_and_duplicates() {
+        let origin = [2, 3];
+        let out = VecInserter::new(&origin)
+            .insert(9, &[5])
+            .insert(0, &[0])
+            .insert(0, &[1])
+            .insert(2, &[4])
+            .execute();
+        assert_eq!(vec![0, 1, 2, 3, 4, 5], out);
+    }
+
+    #[test]
+    fn non_copy_items() {
+        let origin = vec!["a".to_string(), "c".to_string()];
+        let insertion = vec!["b".to_string()];
+        let mut out = vec!["start".to_string()];
+        VecInserter::new(&origin)
+            .insert(1, &insertion)
+            .execute_into(&mut out);
+        assert_eq!(vec!["start", "a", "b", "c"], out);
+    }
+}
@@ -0,0 +1,376 @@
+use checksum::crc32;
+use std::io::{self, Read, Write};
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+
+const LOCAL_HEADER_LEN: usize = 30;
+const CENTRAL_HEADER_LEN: usize = 46;
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+
+/// the earliest representable DOS date, 1980-01-01
+pub const DOS_EPOCH: u16 = (1 << 5) | 1;
+
+/// a file to be inserted into a zip archive, stored without compression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    name: String,
+    data: Vec<u8>,
+    date: u16,
+    time: u16,
+}
+
+impl Entry {
+    /// create a new entry with the given path inside the archive
+    pub fn new(name: &str, data: Vec<u8>) -> Entry {
+        Entry {
+            name: name.to_string(),
+            data,
+            date: DOS_EPOCH,
+            time: 0,
+        }
+    }
+
+    /// set the DOS-format modification date and time of this entry
+    pub fn modified(mut self, date: u16, time: u16) -> Self {
+        self.date = date;
+        self.time = time;
+        self
+    }
+
+    fn flags(&self) -> u16 {
+        // bit 11 marks utf-8 names
+        if self.name.is_ascii() {
+            0
+        } else {
+            0x0800
+        }
+    }
+
+    fn local_record(&self) -> io::Result<Vec<u8>> {
+        let name_len = u16_field(self.name.len(), "entry name")?;
+        let size = u32_field(self.data.len() as u64, "entry data")?;
+        let mut out = Vec::with_capacity(LOCAL_HEADER_LEN + self.name.len() + self.data.len());
+        out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&10_u16.to_le_bytes());
+        out.extend_from_slice(&self.flags().to_le_bytes());
+        out.extend_from_slice(&0_u16.to_le_bytes());
+        out.extend_from_slice(&self.time.to_le_bytes());
+        out.extend_from_slice(&self.date.to_le_bytes());
+        out.extend_from_slice(&crc32(&self.data).to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&name_len.to_le_bytes());
+        out.extend_from_slice(&0_u16.to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+        out.extend_from_slice(&self.data);
+        Ok(out)
+    }
+
+    fn central_record(&self, local_offset: u64) -> io::Result<Vec<u8>> {
+        let name_len = u16_field(self.name.len(), "entry name")?;
+        let size = u32_field(self.data.len() as u64, "entry data")?;
+        let local_offset = u32_field(local_offset, "archive")?;
+        let mut out = Vec::with_capacity(CENTRAL_HEADER_LEN + self.name.len());
+        out.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&20_u16.to_le_bytes());
+        out.extend_from_slice(&10_u16.to_le_bytes());
+        out.extend_from_slice(&self.flags().to_le_bytes());
+        out.extend_from_slice(&0_u16.to_le_bytes());
+        out.extend_from_slice(&self.time.to_le_bytes());
+        out.extend_from_slice(&self.date.to_le_bytes());
+        out.extend_from_slice(&crc32(&self.data).to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&name_len.to_le_bytes());
+        // extra length, comment length, disk number, internal and external attributes
+        out.extend_from_slice(&[0; 12]);
+        out.extend_from_slice(&local_offset.to_le_bytes());
+        out.extend_from_slice(self.name.as_bytes());
+        Ok(out)
+    }
+}
+
+/// insert a new entry into a zip archive at the given entry index
+///
+/// the local file entries are copied through, the new entry is spliced in
+/// before the `index`th one, and the central directory and end of central
+/// directory record are rewritten in the same pass so that every offset points
+/// at the right place. an index past the last entry appends. zip64 archives and
+/// entries whose sizes are only given in a trailing data descriptor are not
+/// supported.
+pub fn insert_entry<R, W>(
+    mut origin: R,
+    mut target: W,
+    index: usize,
+    entry: &Entry,
+) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    let local = entry.local_record()?;
+    let mut offset = 0_u64;
+    let mut local_count = 0;
+    let mut central_count = 0;
+    // origin offset at which the new entry was spliced in
+    let mut inserted_at: Option<u64> = None;
+    let mut central_inserted: Option<usize> = None;
+
+    loop {
+        let signature = read_u32(&mut origin)?;
+        // the new entry goes before the index'th local entry, or after the last one
+        if inserted_at.is_none() && (signature != LOCAL_HEADER || local_count == index) {
+            target.write_all(&local)?;
+            inserted_at = Some(offset);
+        }
+        let shift = |at: u64| match inserted_at {
+            Some(inserted) if at >= inserted => at + local.len() as u64,
+            _ => at,
+        };
+
+        match signature {
+            LOCAL_HEADER => {
+                let mut header = [0_u8; LOCAL_HEADER_LEN];
+                header[..4].copy_from_slice(&signature.to_le_bytes());
+                origin.read_exact(&mut header[4..])?;
+                let flags = le16(&header[6..]);
+                let compressed = le32(&header[18..]);
+                let variable = u64::from(le16(&header[26..])) + u64::from(le16(&header[28..]));
+                if compressed == 0xFFFF_FFFF {
+                    return Err(unsupported("zip64 entries are not supported"));
+                }
+                if flags & 0x08 != 0 && compressed == 0 {
+                    return Err(unsupported(
+                        "entries sized only by a data descriptor are not supported",
+                    ));
+                }
+                target.write_all(&header)?;
+                copy_exact(&mut origin, &mut target, variable + u64::from(compressed))?;
+                offset += LOCAL_HEADER_LEN as u64 + variable + u64::from(compressed);
+                if flags & 0x08 != 0 {
+                    offset += copy_data_descriptor(&mut origin, &mut target)?;
+                }
+                local_count += 1;
+            }
+            CENTRAL_HEADER => {
+                if central_count == index && central_inserted.is_none() {
+                    let record = entry.central_record(inserted_at.unwrap_or(offset))?;
+                    target.write_all(&record)?;
+                    central_inserted = Some(record.len());
+                }
+                let mut header = [0_u8; CENTRAL_HEADER_LEN];
+                header[..4].copy_from_slice(&signature.to_le_bytes());
+                origin.read_exact(&mut header[4..])?;
+                let local_offset = u64::from(le32(&header[42..]));
+                if local_offset == 0xFFFF_FFFF {
+                    return Err(unsupported("zip64 entries are not supported"));
+                }
+                let local_offset = u32_field(shift(local_offset), "archive")?;
+                header[42..46].copy_from_slice(&local_offset.to_le_bytes());
+                let variable = u64::from(le16(&header[28..]))
+                    + u64::from(le16(&header[30..]))
+                    + u64::from(le16(&header[32..]));
+                target.write_all(&header)?;
+                copy_exact(&mut origin, &mut target, variable)?;
+                central_count += 1;
+            }
+            END_OF_CENTRAL_DIRECTORY => {
+                let central_len = match central_inserted {
+                    Some(len) => len,
+                    None => {
+                        let record = entry.central_record(inserted_at.unwrap_or(offset))?;
+                        target.write_all(&record)?;
+                        record.len()
+                    }
+                };
+                let mut record = [0_u8; END_OF_CENTRAL_DIRECTORY_LEN];
+                record[..4].copy_from_slice(&signature.to_le_bytes());
+                origin.read_exact(&mut record[4..])?;
+                if le16(&record[4..]) != 0 || le16(&record[6..]) != 0 {
+                    return Err(unsupported("multi-disk archives are not supported"));
+                }
+                for field in [8, 10].iter() {
+                    let count = u16_field(usize::from(le16(&record[*field..])) + 1, "entry count")?;
+                    record[*field..*field + 2].copy_from_slice(&count.to_le_bytes());
+                }
+                let size = u32_field(
+                    u64::from(le32(&record[12..])) + central_len as u64,
+                    "central directory",
+                )?;
+                record[12..16].copy_from_slice(&size.to_le_bytes());
+                let start = u32_field(shift(u64::from(le32(&record[16..]))), "archive")?;
+                record[16..20].copy_from_slice(&start.to_le_bytes());
+                target.write_all(&record)?;
+                // the archive comment, and anything after it, passes through
+                io::copy(&mut origin, &mut target)?;
+                return Ok(());
+            }
+            _ => return Err(unsupported("unrecognized or unsupported zip record")),
+        }
+    }
+}
+
+fn copy_data_descriptor<R: Read, W: Write>(origin: &mut R, target: &mut W) -> io::Result<u64> {
+    let first = read_u32(origin)?;
+    target.write_all(&first.to_le_bytes())?;
+    // crc and two sizes, optionally preceded by a signature
+    let remaining = if first == DATA_DESCRIPTOR { 12 } else { 8 };
+    copy_exact(origin, target, remaining)?;
+    Ok(4 + remaining)
+}
+
+fn copy_exact<R: Read, W: Write>(origin: &mut R, target: &mut W, len: u64) -> io::Result<()> {
+    if io::copy(&mut origin.by_ref().take(len), target)? != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated zip archive",
+        ));
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(origin: &mut R) -> io::Result<u32> {
+    let mut bytes = [0_u8; 4];
+    origin.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn u16_field(value: usize, what: &str) -> io::Result<u16> {
+    if value > usize::from(u16::MAX) {
+        return Err(unsupported(&format!("{} too large for zip", what)));
+    }
+    Ok(value as u16)
+}
+
+fn u32_field(value: u64, what: &str) -> io::Result<u32> {
+    if value >= 0xFFFF_FFFF {
+        return Err(unsupported(&format!("{} too large without zip64", what)));
+    }
+    Ok(value as u32)
+}
+
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(entries: &[Entry]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for entry in entries {
+            central.extend(entry.central_record(out.len() as u64).unwrap());
+            out.extend(entry.local_record().unwrap());
+        }
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&central_offset.to_le_bytes());
+        out.extend_from_slice(&3_u16.to_le_bytes());
+        out.extend_from_slice(b"hi!");
+        out
+    }
+
+    /// walk the central directory, returning each entry's name and data as
+    /// found through its local header offset
+    fn read_back(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let eocd = zip.len() - END_OF_CENTRAL_DIRECTORY_LEN - 3;
+        assert_eq!(END_OF_CENTRAL_DIRECTORY, le32(&zip[eocd..]));
+        let count = le16(&zip[eocd + 10..]) as usize;
+        let mut at = le32(&zip[eocd + 16..]) as usize;
+        assert_eq!(
+            eocd - at,
+            le32(&zip[eocd + 12..]) as usize,
+            "central directory size"
+        );
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(CENTRAL_HEADER, le32(&zip[at..]));
+            let name_len = le16(&zip[at + 28..]) as usize;
+            let local = le32(&zip[at + 42..]) as usize;
+            assert_eq!(LOCAL_HEADER, le32(&zip[local..]));
+            let size = le32(&zip[local + 18..]) as usize;
+            let data_start = local + LOCAL_HEADER_LEN + le16(&zip[local + 26..]) as usize;
+            let name = &zip[at + CENTRAL_HEADER_LEN..at + CENTRAL_HEADER_LEN + name_len];
+            entries.push((
+                String::from_utf8(name.to_vec()).unwrap(),
+                zip[data_start..data_start + size].to_vec(),
+            ));
+            at += CENTRAL_HEADER_LEN + name_len;
+        }
+        entries
+    }
+
+    fn run(index: usize) -> Vec<(String, Vec<u8>)> {
+        let origin = archive(&[
+            Entry::new("a.txt", b"alpha".to_vec()),
+            Entry::new("b.txt", b"bravo".to_vec()),
+        ]);
+        let mut dest = Vec::new();
+        insert_entry(
+            origin.as_slice(),
+            &mut dest,
+            index,
+            &Entry::new("new.txt", b"inserted".to_vec()),
+        )
+        .unwrap();
+        assert!(dest.ends_with(b"hi!"));
+        read_back(&dest)
+    }
+
+    fn names(entries: &[(String, Vec<u8>)]) -> Vec<&str> {
+        entries.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn insert_in_middle() {
+        let entries = run(1);
+        assert_eq!(vec!["a.txt", "new.txt", "b.txt"], names(&entries));
+        assert_eq!(b"bravo".to_vec(), entries[2].1);
+        assert_eq!(b"inserted".to_vec(), entries[1].1);
+    }
+
+    #[test]
+    fn insert_at_beginning() {
+        let entries = run(0);
+        assert_eq!(vec!["new.txt", "a.txt", "b.txt"], names(&entries));
+        assert_eq!(b"alpha".to_vec(), entries[1].1);
+    }
+
+    #[test]
+    fn insert_past_end() {
+        let entries = run(10);
+        assert_eq!(vec!["a.txt", "b.txt", "new.txt"], names(&entries));
+        assert_eq!(b"inserted".to_vec(), entries[2].1);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        let mut dest = Vec::new();
+        let err = insert_entry(
+            &b"not a zip"[..],
+            &mut dest,
+            0,
+            &Entry::new("x", Vec::new()),
+        )
+        .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}